@@ -58,7 +58,7 @@ pub async fn main() {
     params.set_rate_control_method(constants::RateControlMethod::VBR);
     params.set_target_kbps(target_kbps);
     // 24000/1001 = 23.976 fps
-    params.set_framerate(24000, 1001);
+    params.set_framerate(24000, 1001).unwrap();
 
     // Input frame config
     params.set_fourcc(constants::FourCC::IyuvOrI420);
@@ -112,8 +112,8 @@ pub async fn main() {
         // If data was written to the bitstream we try to copy the bitstream data to our output file
         if bytes_written > 0 {
             let bitstream_size = bitstream.size();
-            let bytes_copied = std::io::copy(&mut bitstream, &mut output).unwrap();
-            assert_eq!(bitstream_size as u64, bytes_copied);
+            let bytes_copied = bitstream.drain_to(&mut output).unwrap();
+            assert_eq!(bitstream_size as usize, bytes_copied);
         }
     }
 
@@ -129,8 +129,8 @@ pub async fn main() {
 
         if bytes_written > 0 {
             let bitstream_size = bitstream.size();
-            let bytes_copied = std::io::copy(&mut bitstream, &mut output).unwrap();
-            assert_eq!(bitstream_size as u64, bytes_copied);
+            let bytes_copied = bitstream.drain_to(&mut output).unwrap();
+            assert_eq!(bitstream_size as usize, bytes_copied);
         }
     }
 
@@ -61,7 +61,7 @@ pub async fn main() {
     mfx_params.set_rate_control_method(constants::RateControlMethod::CBR);
     mfx_params.set_target_kbps(target_kbps);
     // 24000/1001 = 23.976 fps
-    mfx_params.set_framerate(24000, 1001);
+    mfx_params.set_framerate(24000, 1001).unwrap();
 
     // Input frame config
     mfx_params.set_fourcc(FourCC::YUY2);
@@ -87,14 +87,14 @@ pub async fn main() {
     vpp_params.set_in_height(hw_height);
     vpp_params.set_in_width(hw_width);
     vpp_params.set_in_crop(0, 0, width, height);
-    vpp_params.set_in_framerate(24000, 1001);
+    vpp_params.set_in_framerate(24000, 1001).unwrap();
     
     vpp_params.set_out_fourcc(FourCC::YUY2);
     vpp_params.set_out_picstruct(constants::PicStruct::Progressive);
     vpp_params.set_out_height(hw_height);
     vpp_params.set_out_width(hw_width);
     vpp_params.set_out_crop(0, 0, width, height);
-    vpp_params.set_out_framerate(24000, 1001);
+    vpp_params.set_out_framerate(24000, 1001).unwrap();
 
     let mut vpp = session.video_processor(&mut vpp_params).unwrap();
 
@@ -129,8 +129,8 @@ pub async fn main() {
 
         if bytes_written > 0 {
             let bitstream_size = bitstream.size();
-            let bytes_copied = std::io::copy(&mut bitstream, &mut output).unwrap();
-            assert_eq!(bitstream_size as u64, bytes_copied);
+            let bytes_copied = bitstream.drain_to(&mut output).unwrap();
+            assert_eq!(bitstream_size as usize, bytes_copied);
         }
 
     }
@@ -147,8 +147,8 @@ pub async fn main() {
 
         if bytes_written > 0 {
             let bitstream_size = bitstream.size();
-            let bytes_copied = std::io::copy(&mut bitstream, &mut output).unwrap();
-            assert_eq!(bitstream_size as u64, bytes_copied);
+            let bytes_copied = bitstream.drain_to(&mut output).unwrap();
+            assert_eq!(bitstream_size as usize, bytes_copied);
         }
     }
 
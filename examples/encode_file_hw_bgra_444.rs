@@ -61,7 +61,7 @@ pub async fn main() {
     mfx_params.set_rate_control_method(constants::RateControlMethod::CBR);
     mfx_params.set_target_kbps(target_kbps);
     // 24000/1001 = 23.976 fps
-    mfx_params.set_framerate(24000, 1001);
+    mfx_params.set_framerate(24000, 1001).unwrap();
 
     // Input frame config
     mfx_params.set_fourcc(FourCC::BGR4);
@@ -110,8 +110,8 @@ pub async fn main() {
 
         if bytes_written > 0 {
             let bitstream_size = bitstream.size();
-            let bytes_copied = std::io::copy(&mut bitstream, &mut output).unwrap();
-            assert_eq!(bitstream_size as u64, bytes_copied);
+            let bytes_copied = bitstream.drain_to(&mut output).unwrap();
+            assert_eq!(bitstream_size as usize, bytes_copied);
         }
 
     }
@@ -128,8 +128,8 @@ pub async fn main() {
 
         if bytes_written > 0 {
             let bitstream_size = bitstream.size();
-            let bytes_copied = std::io::copy(&mut bitstream, &mut output).unwrap();
-            assert_eq!(bitstream_size as u64, bytes_copied);
+            let bytes_copied = bitstream.drain_to(&mut output).unwrap();
+            assert_eq!(bitstream_size as usize, bytes_copied);
         }
     }
 
@@ -48,7 +48,7 @@ pub async fn main() {
     vpp_params.set_in_height(hw_height);
     vpp_params.set_in_width(hw_width);
     vpp_params.set_in_crop(0, 0, width, height);
-    vpp_params.set_in_framerate(24000, 1001);
+    vpp_params.set_in_framerate(24000, 1001).unwrap();
 
     vpp_params.set_out_fourcc(FourCC::NV12);
     vpp_params.set_out_picstruct(constants::PicStruct::Progressive);
@@ -56,7 +56,7 @@ pub async fn main() {
     vpp_params.set_out_height(hw_height);
     vpp_params.set_out_width(hw_width);
     vpp_params.set_out_crop(0, 0, width, height);
-    vpp_params.set_out_framerate(24000, 1001);
+    vpp_params.set_out_framerate(24000, 1001).unwrap();
 
     let mut vpp = session.video_processor(&mut vpp_params).unwrap();
 
@@ -1,19 +1,72 @@
+#[cfg(feature = "async")]
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
 use std::{
     io::{self, Write},
     mem,
+    ops::{Deref, DerefMut},
     sync::Arc,
 };
 
 use ffi::mfxBitstream;
 use intel_onevpl_sys as ffi;
 use std::sync::Mutex;
+#[cfg(feature = "async")]
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{
+    constants::{BitstreamDataFlags, Codec, FrameType, PicStruct},
+    decode::DecodeErrorReport,
+    encode::EncodedUnitsInfo,
+    ExtBuffer,
+};
+
+/// The most units [`Bitstream::encoded_units`] can report for a frame.
+const MAX_ENCODED_UNITS: u16 = 256;
+
+/// The memory behind a [`Bitstream`].
+#[derive(Debug)]
+enum Buffer<'a> {
+    Writable(&'a mut [u8]),
+    /// From [`Bitstream::from_slice`], the library only reads from it when decoding
+    ReadOnly(&'a [u8]),
+}
+
+impl Buffer<'_> {
+    fn writable(&mut self) -> io::Result<&mut [u8]> {
+        match self {
+            Buffer::Writable(buffer) => Ok(buffer),
+            Buffer::ReadOnly(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "bitstream was created from a read only slice",
+            )),
+        }
+    }
+}
 
-use crate::constants::{BitstreamDataFlags, Codec, FrameType, PicStruct};
+impl Deref for Buffer<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            Buffer::Writable(buffer) => buffer,
+            Buffer::ReadOnly(buffer) => buffer,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Bitstream<'a> {
-    buffer: Arc<Mutex<&'a mut [u8]>>,
+    buffer: Arc<Mutex<Buffer<'a>>>,
     pub(crate) inner: mfxBitstream,
+    bytes_moved: u64,
+    pub(crate) error_report: Option<Box<DecodeErrorReport>>,
+    units_info: Option<Box<EncodedUnitsInfo>>,
+    /// Storage UnitInfo of `units_info` points at
+    unit_info: Vec<ffi::mfxEncodedUnitInfo>,
+    ext_params: Vec<*mut ffi::mfxExtBuffer>,
 }
 unsafe impl Send for Bitstream<'_> {}
 
@@ -25,9 +78,36 @@ impl<'a> Bitstream<'a> {
         bitstream.Data = buffer.as_mut_ptr();
         bitstream.MaxLength = buffer.len() as u32;
         bitstream.__bindgen_anon_1.__bindgen_anon_1.CodecId = codec as u32;
+        Self::new(Buffer::Writable(buffer), bitstream)
+    }
+
+    /// Wraps data that is already in memory, Eg. a memory mapped file, as a full bitstream for decoding without copying it. The decoder advances [`Bitstream::offset`] as it consumes data.
+    ///
+    /// The data can't be written to, [`io::Write`] returns an [`io::ErrorKind::Unsupported`] error. Encoders reject it as their output with [`MfxStatus::Unsupported`](crate::MfxStatus::Unsupported). Don't use it with [`crate::Session::decode_header`] for data that needs to be written after.
+    pub fn from_slice(data: &'a [u8], codec: Codec) -> Self {
+        let mut bitstream: mfxBitstream = unsafe { mem::zeroed() };
+        // The library never writes to the data of a decode bitstream
+        bitstream.Data = data.as_ptr() as *mut u8;
+        bitstream.MaxLength = data.len() as u32;
+        bitstream.DataLength = data.len() as u32;
+        bitstream.__bindgen_anon_1.__bindgen_anon_1.CodecId = codec as u32;
+        Self::new(Buffer::ReadOnly(data), bitstream)
+    }
+
+    /// Whether the bitstream was created with [`Bitstream::from_slice`] and can't be written to.
+    pub(crate) fn is_read_only(&self) -> bool {
+        matches!(*self.buffer.lock().unwrap(), Buffer::ReadOnly(_))
+    }
+
+    fn new(buffer: Buffer<'a>, inner: mfxBitstream) -> Self {
         Self {
             buffer: Arc::new(Mutex::new(buffer)),
-            inner: bitstream,
+            inner,
+            bytes_moved: 0,
+            error_report: None,
+            units_info: None,
+            unit_info: Vec::new(),
+            ext_params: Vec::new(),
         }
     }
 
@@ -48,6 +128,11 @@ impl<'a> Bitstream<'a> {
         self.inner.DataLength
     }
 
+    /// Total number of bytes moved to the start of the buffer when compacting it while writing. Useful for profiling refills.
+    pub fn bytes_moved(&self) -> u64 {
+        self.bytes_moved
+    }
+
     /// Reading or writing offset in the buffer
     pub fn offset(&self) -> u32 {
         self.inner.DataOffset
@@ -59,26 +144,319 @@ impl<'a> Bitstream<'a> {
         self.inner.DataLength = size as u32;
     }
 
+    /// Writes all the data in the bitstream to `writer` in a single [`Write::write_all`] and empties the bitstream. Use this for encoder output instead of [`io::copy`], which goes through the [`io::Read`] implementation a chunk at a time and moves the remaining data to the start of the buffer after every chunk.
+    ///
+    /// Returns the number of bytes written. On error the bitstream is left as is, even if part of the data made it to `writer`.
+    pub fn drain_to<W: Write>(&mut self, writer: &mut W) -> io::Result<usize> {
+        let start = self.inner.DataOffset as usize;
+        let len = self.inner.DataLength as usize;
+        let buffer = self.buffer.lock().unwrap();
+
+        writer.write_all(&buffer[start..start + len])?;
+
+        self.inner.DataOffset = match &*buffer {
+            Buffer::Writable(_) => 0,
+            // Same as reading, the data of a read only buffer is consumed rather than removed
+            Buffer::ReadOnly(_) => (start + len) as u32,
+        };
+        self.inner.DataLength = 0;
+
+        Ok(len)
+    }
+
+    /// Empties the bitstream by resetting the data offset and length, keeping the buffer. Lets the same bitstream be reused for a new encode or decode session instead of creating a new one. Flags, timestamps and the codec are left as is.
+    pub fn clear(&mut self) {
+        self.inner.DataOffset = 0;
+        self.inner.DataLength = 0;
+    }
+
+    pub fn flags(&self) -> BitstreamDataFlags {
+        BitstreamDataFlags::from_bits_truncate(self.inner.DataFlag)
+    }
+
     pub fn set_flags(&mut self, flags: BitstreamDataFlags) {
         self.inner.DataFlag = flags.bits();
     }
 
+    /// Marks this buffer as containing the end of the stream. Set this once the
+    /// last of the input has been written to the bitstream so the decoder can
+    /// output its remaining frames while it still has data to chew on.
+    ///
+    /// Passing [`None`] as the bitstream to [`crate::decode::Decoder::decode`]
+    /// also drains the decoder, but only after all the data in the bitstream
+    /// has been consumed. You still need to drain with [`None`] after
+    /// [`MfxStatus::MoreData`](crate::MfxStatus::MoreData) is returned when
+    /// using this flag.
+    pub fn set_end_of_stream(&mut self, eos: bool) {
+        let mut flags = self.flags();
+        flags.set(BitstreamDataFlags::END_OF_STREAM, eos);
+        self.set_flags(flags);
+    }
+
+    /// Tells the decoder the buffer contains exactly one complete frame (or a
+    /// complementary field pair) so it doesn't have to wait for the start of
+    /// the next frame. If the buffer contains an incomplete frame the decoder
+    /// will produce corrupted output.
+    pub fn set_complete_frame(&mut self, complete: bool) {
+        let mut flags = self.flags();
+        flags.set(BitstreamDataFlags::COMPLETE_FRAME, complete);
+        self.set_flags(flags);
+    }
+
     #[doc = " The FrameType enumerator itemizes frame types. Use bit-ORed values to specify all that apply."]
+    ///
+    /// Set by the encoder for every frame it outputs.
     pub fn frame_type(&self) -> FrameType {
-        FrameType::from_bits(self.inner.FrameType as ffi::_bindgen_ty_37).unwrap()
+        FrameType::from_bits_truncate(self.inner.FrameType as ffi::_bindgen_ty_37)
     }
 
-    pub fn pic_struct(&self) -> PicStruct {
-        PicStruct::from_repr(self.inner.PicStruct as ffi::_bindgen_ty_6).unwrap()
+    /// Whether the encoded frame is an I or IDR frame, which makes it a point decoding can start from. Useful for building a seek index.
+    pub fn is_keyframe(&self) -> bool {
+        self.frame_type().intersects(FrameType::I | FrameType::IDR)
     }
 
+    /// Picture structure of the encoded frame. `None` when the value is a combination of flags (Eg. a top field first frame with a repeated field) that [`PicStruct`] has no variant for.
+    pub fn pic_struct(&self) -> Option<PicStruct> {
+        PicStruct::from_repr(self.inner.PicStruct as ffi::_bindgen_ty_6)
+    }
+
+    /// Presentation timestamp in units of 90KHz. When encoding, the encoder
+    /// copies the timestamp of the input [`crate::FrameSurface`] here so the
+    /// output can be muxed without tracking timestamps separately.
     pub fn timestamp(&self) -> u64 {
         self.inner.TimeStamp
     }
 
+    /// Sets the presentation timestamp of the data in the bitstream. When
+    /// decoding, the decoder attaches it to the frame decoded from this data.
+    pub fn set_timestamp(&mut self, timestamp: u64) {
+        self.inner.TimeStamp = timestamp;
+    }
+
+    /// Decode timestamp in units of 90KHz, set by the encoder.
     pub fn decode_timestamp(&self) -> i64 {
         self.inner.DecodeTimeStamp
     }
+
+    pub fn set_decode_timestamp(&mut self, timestamp: i64) {
+        self.inner.DecodeTimeStamp = timestamp;
+    }
+
+    /// Finds the NAL units in the Annex B AVC or HEVC data currently in the bitstream, for checking encoder output in tests. Offsets are relative to [`Bitstream::offset`] and point at the NAL unit header, after the start code.
+    #[cfg(test)]
+    pub(crate) fn nal_units(&self) -> impl Iterator<Item = NalUnit> {
+        let hevc = self.codec() == Codec::HEVC;
+        let buffer = self.buffer.lock().unwrap();
+        let start = self.inner.DataOffset as usize;
+        let data = &buffer[start..start + self.inner.DataLength as usize];
+
+        let mut starts = Vec::new();
+        let mut i = 0;
+        while i + 3 < data.len() {
+            if data[i..i + 3] == [0, 0, 1] {
+                starts.push(i + 3);
+                i += 3;
+            } else {
+                i += 1;
+            }
+        }
+
+        let units: Vec<_> = starts
+            .iter()
+            .enumerate()
+            .map(|(i, &offset)| {
+                let mut end = starts.get(i + 1).map_or(data.len(), |next| next - 3);
+                // The zero of a 4 byte start code belongs to the next NAL unit
+                if end < data.len() && end > offset && data[end - 1] == 0 {
+                    end -= 1;
+                }
+                let nal_type = if hevc {
+                    (data[offset] >> 1) & 0x3f
+                } else {
+                    data[offset] & 0x1f
+                };
+                NalUnit {
+                    offset,
+                    nal_type,
+                    length: end - offset,
+                }
+            })
+            .collect();
+        units.into_iter()
+    }
+
+    /// Walks the AV1 OBUs (open bitstream units) in the data currently in the bitstream. Offsets are relative to [`Bitstream::offset`]. Stops at the first OBU that is cut off by the end of the data.
+    ///
+    /// OBUs without a size field are assumed to run to the end of the data, as in the low overhead bitstream format every OBU should have one.
+    pub fn obu_units(&self) -> impl Iterator<Item = ObuUnit> {
+        let buffer = self.buffer.lock().unwrap();
+        let start = self.inner.DataOffset as usize;
+        let data = &buffer[start..start + self.inner.DataLength as usize];
+
+        let mut units = Vec::new();
+        let mut offset = 0;
+        while let Some(unit) = parse_obu(data, offset) {
+            offset += unit.length;
+            units.push(unit);
+        }
+        units.into_iter()
+    }
+
+    /// Asks the decoder to report the errors it finds in this bitstream (corrupt SPS/PPS, slice errors, missing frames). Read the report with [`crate::decode::Decoder::last_error_report`] after each decode call.
+    pub fn attach_error_report(&mut self) {
+        if self.error_report.is_some() {
+            return;
+        }
+
+        let mut report = Box::<DecodeErrorReport>::default();
+        self.ext_params.push(report.header());
+        self.error_report = Some(report);
+
+        self.inner.ExtParam = self.ext_params.as_mut_ptr();
+        self.inner.NumExtParam = self.ext_params.len() as u16;
+    }
+
+    /// Asks the encoder to describe the units (NAL units for AVC and HEVC) it writes to this bitstream, so they can be packetized without parsing the output. The encoder must have been initialized with [`crate::encode::ExtraCodingOption3::set_encoded_units_info`] turned on. Read them with [`Bitstream::encoded_units`] after each encode call.
+    pub fn attach_encoded_units_info(&mut self) {
+        if self.units_info.is_some() {
+            return;
+        }
+
+        // Never resized afterwards, so the pointer stays valid
+        self.unit_info = vec![unsafe { mem::zeroed() }; MAX_ENCODED_UNITS as usize];
+        let mut info = Box::<EncodedUnitsInfo>::default();
+        info.UnitInfo = self.unit_info.as_mut_ptr();
+        info.NumUnitsAlloc = MAX_ENCODED_UNITS;
+        self.ext_params.push(info.header());
+        self.units_info = Some(info);
+
+        self.inner.ExtParam = self.ext_params.as_mut_ptr();
+        self.inner.NumExtParam = self.ext_params.len() as u16;
+    }
+
+    /// The units of the last frame encoded into this bitstream, see [`Bitstream::attach_encoded_units_info`]. Empty if it wasn't attached.
+    pub fn encoded_units(&self) -> Vec<EncodedUnit> {
+        let info = match self.units_info.as_ref() {
+            Some(info) => info,
+            None => return Vec::new(),
+        };
+
+        // More units than fit are counted but not stored
+        let count = u16::min(info.NumUnitsEncoded, info.NumUnitsAlloc) as usize;
+        self.unit_info[..count]
+            .iter()
+            .map(|unit| EncodedUnit {
+                offset: unit.Offset as usize,
+                unit_type: unit.Type,
+                length: unit.Size as usize,
+            })
+            .collect()
+    }
+}
+
+/// A unit of encoded data reported by [`Bitstream::encoded_units`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EncodedUnit {
+    /// Offset of the unit from the start of the frame's data, including its start code
+    pub offset: usize,
+    /// Codec dependent unit type, the nal_unit_type for AVC and HEVC
+    pub unit_type: u16,
+    /// Length of the unit including its start code
+    pub length: usize,
+}
+
+/// A NAL unit found by [`Bitstream::nal_units`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg(test)]
+pub(crate) struct NalUnit {
+    /// Offset of the NAL unit header
+    pub offset: usize,
+    /// nal_unit_type, as defined by the codec (Eg. 5 is an IDR slice for AVC, 19 and 20 for HEVC)
+    pub nal_type: u8,
+    /// Length of the NAL unit without its start code
+    pub length: usize,
+}
+
+/// AV1 OBU types. See section 6.2.2 of the AV1 specification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObuType {
+    SequenceHeader,
+    TemporalDelimiter,
+    FrameHeader,
+    TileGroup,
+    Metadata,
+    Frame,
+    RedundantFrameHeader,
+    TileList,
+    Padding,
+    Reserved(u8),
+}
+
+impl From<u8> for ObuType {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => ObuType::SequenceHeader,
+            2 => ObuType::TemporalDelimiter,
+            3 => ObuType::FrameHeader,
+            4 => ObuType::TileGroup,
+            5 => ObuType::Metadata,
+            6 => ObuType::Frame,
+            7 => ObuType::RedundantFrameHeader,
+            8 => ObuType::TileList,
+            15 => ObuType::Padding,
+            value => ObuType::Reserved(value),
+        }
+    }
+}
+
+/// An OBU found by [`Bitstream::obu_units`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ObuUnit {
+    /// Offset of the OBU header
+    pub offset: usize,
+    pub obu_type: ObuType,
+    /// Length of the whole OBU including its header
+    pub length: usize,
+}
+
+/// Parses the OBU starting at `offset`.
+fn parse_obu(data: &[u8], offset: usize) -> Option<ObuUnit> {
+    let header = *data.get(offset)?;
+    let obu_type = ObuType::from((header >> 3) & 0xf);
+    let has_extension = header & 0b100 != 0;
+    let has_size = header & 0b10 != 0;
+
+    let mut position = offset + 1 + has_extension as usize;
+    let payload_len = if has_size {
+        // leb128
+        let mut value = 0usize;
+        let mut i = 0;
+        loop {
+            let byte = *data.get(position)?;
+            position += 1;
+            value |= ((byte & 0x7f) as usize) << (i * 7);
+            i += 1;
+            if byte & 0x80 == 0 {
+                break value;
+            }
+            if i == 8 {
+                return None;
+            }
+        }
+    } else {
+        data.len().checked_sub(position)?
+    };
+
+    let end = position.checked_add(payload_len)?;
+    if end > data.len() {
+        return None;
+    }
+
+    Some(ObuUnit {
+        offset,
+        obu_type,
+        length: end - offset,
+    })
 }
 
 impl io::Write for Bitstream<'_> {
@@ -86,6 +464,7 @@ impl io::Write for Bitstream<'_> {
         let data_offset = self.inner.DataOffset as usize;
         let data_len = self.inner.DataLength as usize;
         let mut buffer = self.buffer.lock().unwrap();
+        let buffer = buffer.writable()?;
 
         if data_len >= buffer.len() {
             return Ok(0);
@@ -96,6 +475,7 @@ impl io::Write for Bitstream<'_> {
             let data_end = data_offset + data_len;
             buffer.copy_within(data_offset..data_end, 0);
             self.inner.DataOffset = 0;
+            self.bytes_moved += data_len as u64;
         }
 
         let free_buffer_len = buffer.len() - data_len;
@@ -114,20 +494,144 @@ impl io::Write for Bitstream<'_> {
 impl io::Read for Bitstream<'_> {
     fn read(&mut self, mut buf: &mut [u8]) -> io::Result<usize> {
         let mut buffer = self.buffer.lock().unwrap();
-        let bytes = buf.write(&buffer[..self.inner.DataLength as usize])?;
-        buffer.copy_within(bytes..self.inner.DataLength as usize, 0);
+        let bytes = match &mut *buffer {
+            Buffer::Writable(buffer) => {
+                let bytes = buf.write(&buffer[..self.inner.DataLength as usize])?;
+                buffer.copy_within(bytes..self.inner.DataLength as usize, 0);
+                bytes
+            }
+            // Can't move the data, consume it like the decoder does instead
+            Buffer::ReadOnly(buffer) => {
+                let start = self.inner.DataOffset as usize;
+                let bytes = buf.write(&buffer[start..start + self.inner.DataLength as usize])?;
+                self.inner.DataOffset += bytes as u32;
+                bytes
+            }
+        };
         self.inner.DataLength -= bytes as u32;
 
         Ok(bytes)
     }
 }
 
+// The buffer is only ever locked through `&mut self`, so locking it can't block the executor.
+
+/// Same as the [`io::Write`] implementation, writes return 0 once the bitstream is full.
+#[cfg(feature = "async")]
+impl AsyncWrite for Bitstream<'_> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(io::Write::write(self.get_mut(), buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Same as the [`io::Read`] implementation, reaching the end of the data in the bitstream is reported as the end of the stream.
+#[cfg(feature = "async")]
+impl AsyncRead for Bitstream<'_> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let bytes = io::Read::read(self.get_mut(), buf.initialize_unfilled())?;
+        buf.advance(bytes);
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// A [`Bitstream`] for continuous decoding that avoids moving the remaining data on every refill.
+///
+/// [`Bitstream`] moves the unconsumed data to the start of the buffer every time it is written to, which is O(n) per refill and adds up for high bitrate streams. Data passed to the library must be contiguous, so the data can't actually wrap around the end of the buffer. Instead, new data is appended after the unconsumed data and the buffer is only compacted when the free space at the end is too small for the write.
+///
+/// Derefs to [`Bitstream`], so pass `&mut ring` wherever a `&mut Bitstream` is expected.
+#[derive(Debug)]
+pub struct RingBitstream<'a> {
+    inner: Bitstream<'a>,
+}
+
+impl<'a> RingBitstream<'a> {
+    pub fn with_codec(buffer: &'a mut [u8], codec: Codec) -> Self {
+        Self {
+            inner: Bitstream::with_codec(buffer, codec),
+        }
+    }
+}
+
+impl<'a> Deref for RingBitstream<'a> {
+    type Target = Bitstream<'a>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl DerefMut for RingBitstream<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
+
+impl io::Write for RingBitstream<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let bitstream = &mut self.inner;
+        let data_offset = bitstream.inner.DataOffset as usize;
+        let data_len = bitstream.inner.DataLength as usize;
+        let mut buffer = bitstream.buffer.lock().unwrap();
+        let buffer = buffer.writable()?;
+
+        // Only compact once the write no longer fits after the existing data
+        if data_offset > 0 && data_offset + data_len + buf.len() > buffer.len() {
+            buffer.copy_within(data_offset..data_offset + data_len, 0);
+            bitstream.inner.DataOffset = 0;
+            bitstream.bytes_moved += data_len as u64;
+        }
+
+        let data_end = (bitstream.inner.DataOffset + bitstream.inner.DataLength) as usize;
+        let copy_len = usize::min(buffer.len() - data_end, buf.len());
+        buffer[data_end..data_end + copy_len].copy_from_slice(&buf[..copy_len]);
+        bitstream.inner.DataLength += copy_len as u32;
+
+        Ok(copy_len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use rand::Fill;
-    use std::io::Read;
+    use std::io::{Read, Write};
+
+    use crate::constants::{Codec, PicStruct};
+
+    use super::{Bitstream, NalUnit, ObuType, ObuUnit, RingBitstream};
+
+    #[test]
+    fn bitstream_combined_pic_struct() {
+        let mut buffer = vec![0u8; 16];
+        let mut bitstream = Bitstream::with_codec(&mut buffer, Codec::AVC);
+
+        bitstream.inner.PicStruct = PicStruct::FieldTff.repr() as u16;
+        assert_eq!(bitstream.pic_struct(), Some(PicStruct::FieldTff));
 
-    use super::Bitstream;
+        // pic_struct=5 in H.264, no single variant for it
+        bitstream.inner.PicStruct =
+            (PicStruct::FieldTff.repr() | PicStruct::FieldRepeated.repr()) as u16;
+        assert_eq!(bitstream.pic_struct(), None);
+    }
 
     #[test]
     fn bitstream_read_write() {
@@ -157,4 +661,255 @@ mod tests {
 
         assert_eq!(bytes_read, copy_input_data.len());
     }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn bitstream_async_read_write() {
+        // Fully qualified since the std traits are in scope too
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let input_data: Vec<u8> = (0..4096).map(|i| i as u8).collect();
+        let mut buffer = vec![0u8; 8192];
+        let mut bitstream = Bitstream::with_codec(&mut buffer, Codec::HEVC);
+
+        for chunk in input_data.chunks(1000) {
+            AsyncWriteExt::write_all(&mut bitstream, chunk).await.unwrap();
+        }
+        AsyncWriteExt::flush(&mut bitstream).await.unwrap();
+        assert_eq!(bitstream.size() as usize, input_data.len());
+
+        let mut output = Vec::new();
+        AsyncReadExt::read_to_end(&mut bitstream, &mut output).await.unwrap();
+        assert_eq!(output, input_data);
+        assert_eq!(bitstream.size(), 0);
+
+        // Writes stop once the bitstream is full
+        let refill = vec![0xabu8; 8192];
+        AsyncWriteExt::write_all(&mut bitstream, &refill).await.unwrap();
+        assert!(AsyncWriteExt::write_all(&mut bitstream, &[0]).await.is_err());
+    }
+
+    #[test]
+    fn bitstream_from_slice() {
+        let data: Vec<u8> = (0..4096).map(|i| i as u8).collect();
+        let mut bitstream = Bitstream::from_slice(&data, Codec::HEVC);
+        assert_eq!(bitstream.len(), data.len());
+        assert_eq!(bitstream.size() as usize, data.len());
+        assert_eq!(bitstream.inner.Data as *const u8, data.as_ptr());
+
+        let mut output = vec![0u8; 1000];
+        assert_eq!(bitstream.read(&mut output).unwrap(), 1000);
+        assert_eq!(output, data[..1000]);
+        assert_eq!(bitstream.offset(), 1000);
+
+        let mut output = Vec::new();
+        bitstream.read_to_end(&mut output).unwrap();
+        assert_eq!(output, data[1000..]);
+        assert_eq!(bitstream.size(), 0);
+
+        assert_eq!(
+            bitstream.write(&[0]).unwrap_err().kind(),
+            std::io::ErrorKind::Unsupported
+        );
+    }
+
+    /// Keeps everything written and counts the calls
+    #[derive(Default)]
+    struct CountingWriter {
+        data: Vec<u8>,
+        writes: usize,
+    }
+
+    impl Write for CountingWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.writes += 1;
+            self.data.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn bitstream_drain_to() {
+        let mut rng = rand::thread_rng();
+        let mut input_data = vec![0u8; 8192];
+        input_data[..].try_fill(&mut rng).unwrap();
+        let copy_input_data = input_data.clone();
+
+        let mut bitstream = Bitstream::with_codec(&mut input_data, Codec::HEVC);
+        bitstream.set_size(copy_input_data.len());
+
+        let mut output = CountingWriter::default();
+        assert_eq!(
+            bitstream.drain_to(&mut output).unwrap(),
+            copy_input_data.len()
+        );
+        assert_eq!(output.data, copy_input_data);
+        assert_eq!(output.writes, 1);
+        assert_eq!((bitstream.size(), bitstream.offset()), (0, 0));
+
+        // Nothing left to write
+        assert_eq!(bitstream.drain_to(&mut output).unwrap(), 0);
+        assert_eq!(output.writes, 1);
+
+        // Only the data after the offset is written
+        let mut bitstream = Bitstream::from_slice(&copy_input_data, Codec::HEVC);
+        bitstream.read_exact(&mut [0u8; 1000]).unwrap();
+        let mut output = CountingWriter::default();
+        assert_eq!(
+            bitstream.drain_to(&mut output).unwrap(),
+            copy_input_data.len() - 1000
+        );
+        assert_eq!(output.data, copy_input_data[1000..]);
+        assert_eq!(bitstream.size(), 0);
+    }
+
+    #[test]
+    fn bitstream_clear() {
+        let input_data: Vec<u8> = (0..4096).map(|i| i as u8).collect();
+        let mut buffer = vec![0u8; 8192];
+        let mut bitstream = Bitstream::with_codec(&mut buffer, Codec::HEVC);
+
+        bitstream.write_all(&input_data).unwrap();
+        let mut output = Vec::new();
+        bitstream.read_to_end(&mut output).unwrap();
+        assert_eq!(output, input_data);
+
+        // Leave the offsets in the state the library leaves them after consuming data
+        bitstream.write_all(&input_data).unwrap();
+        bitstream.inner.DataOffset += 1000;
+        bitstream.inner.DataLength -= 1000;
+
+        bitstream.clear();
+        assert_eq!(bitstream.size(), 0);
+        assert_eq!(bitstream.offset(), 0);
+        assert_eq!(bitstream.len(), 8192);
+
+        // The whole buffer is writable again
+        let refill = vec![0xffu8; 8192];
+        assert_eq!(bitstream.write(&refill).unwrap(), refill.len());
+        assert_eq!(bitstream.size() as usize, refill.len());
+        let mut output = Vec::new();
+        bitstream.read_to_end(&mut output).unwrap();
+        assert_eq!(output, refill);
+    }
+
+    /// Consumes up to a frame's worth of data like the decoder does.
+    fn consume_frame(bitstream: &mut Bitstream, consumed: &mut Vec<u8>) {
+        let frame_len = usize::min(1000, bitstream.size() as usize);
+        let offset = bitstream.offset() as usize;
+        consumed.extend_from_slice(&bitstream.buffer.lock().unwrap()[offset..offset + frame_len]);
+        bitstream.inner.DataOffset += frame_len as u32;
+        bitstream.inner.DataLength -= frame_len as u32;
+    }
+
+    #[test]
+    fn ring_bitstream_moves_less() {
+        let chunk: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+
+        let mut buffer = vec![0u8; 64 * 1024];
+        let mut bitstream = Bitstream::with_codec(&mut buffer, Codec::HEVC);
+        let mut plain = Vec::new();
+        // Keep a partial frame in the buffer so there is always data left to move
+        bitstream.write_all(&chunk[..500]).unwrap();
+        for _ in 0..1000 {
+            assert_eq!(bitstream.write(&chunk).unwrap(), chunk.len());
+            consume_frame(&mut bitstream, &mut plain);
+        }
+
+        let mut buffer = vec![0u8; 64 * 1024];
+        let mut ring = RingBitstream::with_codec(&mut buffer, Codec::HEVC);
+        let mut consumed = Vec::new();
+        ring.write_all(&chunk[..500]).unwrap();
+        for _ in 0..1000 {
+            assert_eq!(ring.write(&chunk).unwrap(), chunk.len());
+            consume_frame(&mut ring, &mut consumed);
+        }
+
+        // Both hand the decoder the same data
+        assert_eq!(plain, consumed);
+        assert!(
+            ring.bytes_moved() < bitstream.bytes_moved() / 10,
+            "{} >= {}",
+            ring.bytes_moved(),
+            bitstream.bytes_moved()
+        );
+    }
+
+    #[test]
+    fn av1_obu_units() {
+        #[rustfmt::skip]
+        let mut data = vec![
+            // Temporal delimiter, empty payload
+            0x12, 0x00,
+            // Sequence header, 3 byte payload
+            0x0a, 0x03, 0xaa, 0xbb, 0xcc,
+            // Frame with an extension byte and a 2 byte leb128 size (130)
+            0x36, 0x00, 0x82, 0x01,
+        ];
+        data.resize(data.len() + 130, 0);
+        // Padding with its size cut off by the end of the data
+        data.extend([0x7a, 0x80]);
+
+        let mut buffer = vec![0u8; 1024];
+        let mut bitstream = Bitstream::with_codec(&mut buffer, Codec::AV1);
+        bitstream.write_all(&data).unwrap();
+
+        let units: Vec<_> = bitstream.obu_units().collect();
+        assert_eq!(
+            units,
+            [
+                ObuUnit {
+                    offset: 0,
+                    obu_type: ObuType::TemporalDelimiter,
+                    length: 2,
+                },
+                ObuUnit {
+                    offset: 2,
+                    obu_type: ObuType::SequenceHeader,
+                    length: 5,
+                },
+                ObuUnit {
+                    offset: 7,
+                    obu_type: ObuType::Frame,
+                    length: 134,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn hevc_nal_units() {
+        #[rustfmt::skip]
+        let data = [
+            // VPS with a 4 byte start code
+            0, 0, 0, 1, 0x40, 0x01, 0xaa,
+            // IDR_W_RADL slice
+            0, 0, 1, 0x26, 0x01, 0xbb, 0xcc,
+        ];
+
+        let mut buffer = vec![0u8; 1024];
+        let mut bitstream = Bitstream::with_codec(&mut buffer, Codec::HEVC);
+        bitstream.write_all(&data).unwrap();
+
+        let units: Vec<_> = bitstream.nal_units().collect();
+        assert_eq!(
+            units,
+            [
+                NalUnit {
+                    offset: 4,
+                    nal_type: 32,
+                    length: 3,
+                },
+                NalUnit {
+                    offset: 10,
+                    nal_type: 19,
+                    length: 4,
+                },
+            ]
+        );
+    }
 }
@@ -0,0 +1,301 @@
+use std::{
+    any::Any,
+    ffi::c_void,
+    fmt::Debug,
+    mem::zeroed,
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{Arc, Mutex},
+};
+
+use ffi::MfxStatus;
+use intel_onevpl_sys as ffi;
+
+use crate::{
+    constants::{BrcStatus, FrameType},
+    videoparams::ExtBuffer,
+    MfxVideoParams,
+};
+
+/// A user provided bitrate controller. The encoder asks it for the QP of every frame with [`BitrateController::frame_ctrl`] and reports the resulting frame size back with [`BitrateController::update`]. Attach it with [`MfxVideoParams::set_external_brc`].
+///
+/// See https://spec.oneapi.io/versions/latest/elements/oneVPL/source/API_ref/VPL_structs_enc.html#mfxextbrc for more info.
+pub trait BitrateController: Send {
+    /// Called when the encoder is initialized.
+    fn init(&mut self, _params: &MfxVideoParams) -> MfxStatus {
+        MfxStatus::NoneOrDone
+    }
+    /// Called when the encoder is reset with new parameters.
+    fn reset(&mut self, _params: &MfxVideoParams) -> MfxStatus {
+        MfxStatus::NoneOrDone
+    }
+    /// Called when the encoder is closed.
+    fn close(&mut self) -> MfxStatus {
+        MfxStatus::NoneOrDone
+    }
+    /// Sets the QP (and optionally other controls) the frame should be encoded with.
+    fn frame_ctrl(&mut self, frame: &BrcFrameParam, ctrl: &mut BrcFrameCtrl) -> MfxStatus;
+    /// Called after the frame was encoded with [`BrcFrameParam::coded_frame_size`] filled in. Anything other than [`BrcStatus::Ok`] in `status` makes the encoder recode (or skip/pad) the frame.
+    fn update(
+        &mut self,
+        frame: &BrcFrameParam,
+        ctrl: &BrcFrameCtrl,
+        status: &mut BrcFrameStatus,
+    ) -> MfxStatus;
+}
+
+type SharedController = Mutex<Box<dyn BitrateController>>;
+
+/// Wraps mfxExtBRC. Added by [`MfxVideoParams::set_external_brc`].
+///
+/// `pthis` points at the controller on the heap rather than at this struct, so it stays valid however often the buffer is moved or cloned. Clones share the same controller.
+#[derive(Clone)]
+pub struct ExternalBrc {
+    inner: ffi::mfxExtBRC,
+    /// Owns what `inner.pthis` points at
+    _controller: Arc<SharedController>,
+}
+
+unsafe impl Send for ExternalBrc {}
+
+impl Debug for ExternalBrc {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ExternalBrc")
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+/// Runs `f` with the controller `pthis` points at. Unwinding into the library is undefined behaviour, so a panicking controller is reported as [`MfxStatus::Unknown`] instead. The controller's lock is poisoned by the panic, later calls fail the same way.
+fn with_controller(
+    pthis: ffi::mfxHDL,
+    f: impl FnOnce(&mut dyn BitrateController) -> MfxStatus,
+) -> i32 {
+    let controller = unsafe { (pthis as *const SharedController).as_ref() };
+    let mut controller = match controller.map(|c| c.lock()) {
+        Some(Ok(controller)) => controller,
+        Some(Err(_)) => return MfxStatus::Unknown as i32,
+        None => return MfxStatus::NullPtr as i32,
+    };
+    match catch_unwind(AssertUnwindSafe(|| f(&mut **controller))) {
+        Ok(status) => status as i32,
+        Err(_) => MfxStatus::Unknown as i32,
+    }
+}
+
+/// Copies the parameters the library passes to the callbacks. Extension buffers are not carried over.
+fn params_from_raw(par: *mut ffi::mfxVideoParam) -> Option<MfxVideoParams> {
+    let par = unsafe { par.as_ref() }?;
+    let mut params = MfxVideoParams::default();
+    **params = *par;
+    params.NumExtParam = 0;
+    params.ExtParam = std::ptr::null_mut();
+    Some(params)
+}
+
+impl ExternalBrc {
+    pub fn new(controller: Box<dyn BitrateController>) -> Self {
+        extern "C" fn init(pthis: ffi::mfxHDL, par: *mut ffi::mfxVideoParam) -> i32 {
+            let params = match params_from_raw(par) {
+                Some(params) => params,
+                None => return MfxStatus::NullPtr as i32,
+            };
+            with_controller(pthis, |controller| controller.init(&params))
+        }
+
+        extern "C" fn reset(pthis: ffi::mfxHDL, par: *mut ffi::mfxVideoParam) -> i32 {
+            let params = match params_from_raw(par) {
+                Some(params) => params,
+                None => return MfxStatus::NullPtr as i32,
+            };
+            with_controller(pthis, |controller| controller.reset(&params))
+        }
+
+        extern "C" fn close(pthis: ffi::mfxHDL) -> i32 {
+            with_controller(pthis, |controller| controller.close())
+        }
+
+        extern "C" fn get_frame_ctrl(
+            pthis: ffi::mfxHDL,
+            par: *mut ffi::mfxBRCFrameParam,
+            ctrl: *mut ffi::mfxBRCFrameCtrl,
+        ) -> i32 {
+            let (par, ctrl) = match unsafe { (par.as_ref(), ctrl.as_mut()) } {
+                (Some(par), Some(ctrl)) => (par, ctrl),
+                _ => return MfxStatus::NullPtr as i32,
+            };
+            let frame = BrcFrameParam { inner: par };
+            let mut ctrl = BrcFrameCtrl { inner: ctrl };
+            with_controller(pthis, |controller| controller.frame_ctrl(&frame, &mut ctrl))
+        }
+
+        extern "C" fn update(
+            pthis: ffi::mfxHDL,
+            par: *mut ffi::mfxBRCFrameParam,
+            ctrl: *mut ffi::mfxBRCFrameCtrl,
+            status: *mut ffi::mfxBRCFrameStatus,
+        ) -> i32 {
+            let (par, ctrl, status) =
+                match unsafe { (par.as_ref(), ctrl.as_mut(), status.as_mut()) } {
+                    (Some(par), Some(ctrl), Some(status)) => (par, ctrl, status),
+                    _ => return MfxStatus::NullPtr as i32,
+                };
+            let frame = BrcFrameParam { inner: par };
+            let ctrl = BrcFrameCtrl { inner: ctrl };
+            let mut status = BrcFrameStatus { inner: status };
+            with_controller(pthis, |controller| controller.update(&frame, &ctrl, &mut status))
+        }
+
+        let controller = Arc::new(Mutex::new(controller));
+
+        let mut inner: ffi::mfxExtBRC = unsafe { zeroed() };
+        inner.Header.BufferId = ffi::MFX_EXTBUFF_BRC as u32;
+        inner.Header.BufferSz = std::mem::size_of::<ffi::mfxExtBRC>() as u32;
+        inner.pthis = Arc::as_ptr(&controller) as *mut c_void;
+        inner.Init = Some(init);
+        inner.Reset = Some(reset);
+        inner.Close = Some(close);
+        inner.GetFrameCtrl = Some(get_frame_ctrl);
+        inner.Update = Some(update);
+
+        Self {
+            inner,
+            _controller: controller,
+        }
+    }
+}
+
+impl ExtBuffer for ExternalBrc {
+    fn header(&mut self) -> *mut ffi::mfxExtBuffer {
+        &mut self.inner.Header
+    }
+    fn buffer_id(&self) -> u32 {
+        self.inner.Header.BufferId
+    }
+    fn clone_boxed(&self) -> Box<dyn ExtBuffer> {
+        Box::new(self.clone())
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[doc = " Describes frame parameters required for external BRC functions."]
+pub struct BrcFrameParam<'a> {
+    inner: &'a ffi::mfxBRCFrameParam,
+}
+
+impl BrcFrameParam<'_> {
+    #[doc = "< Frame belongs to a new scene if non zero."]
+    pub fn scene_change(&self) -> bool {
+        self.inner.SceneChange != 0
+    }
+    #[doc = "< Frame is a Long Term Reference frame if non zero."]
+    pub fn long_term(&self) -> bool {
+        self.inner.LongTerm != 0
+    }
+    #[doc = "< Frame Complexity Frame spatial complexity if non zero. Zero if complexity is not available."]
+    pub fn frame_complexity(&self) -> u32 {
+        self.inner.FrameCmplx
+    }
+    #[doc = "< The frame number in a sequence of reordered frames starting from encoder Init."]
+    pub fn encoded_order(&self) -> u32 {
+        self.inner.EncodedOrder
+    }
+    #[doc = "< The frame number in a sequence of frames in display order starting from last IDR."]
+    pub fn display_order(&self) -> u32 {
+        self.inner.DisplayOrder
+    }
+    #[doc = "< Size of the frame in bytes after encoding. Only valid in [`BitrateController::update`]."]
+    pub fn coded_frame_size(&self) -> u32 {
+        self.inner.CodedFrameSize
+    }
+    pub fn frame_type(&self) -> FrameType {
+        FrameType::from_bits_truncate(self.inner.FrameType as ffi::_bindgen_ty_37)
+    }
+    #[doc = "< B-pyramid or P-pyramid layer the frame belongs to."]
+    pub fn pyramid_layer(&self) -> u16 {
+        self.inner.PyramidLayer
+    }
+    #[doc = "< Number of recodings performed for this frame."]
+    pub fn num_recode(&self) -> u16 {
+        self.inner.NumRecode
+    }
+}
+
+#[doc = " Specifies controls for next frame encoding provided by external BRC functions."]
+pub struct BrcFrameCtrl<'a> {
+    inner: &'a mut ffi::mfxBRCFrameCtrl,
+}
+
+impl BrcFrameCtrl<'_> {
+    pub fn qp(&self) -> i32 {
+        self.inner.QpY
+    }
+    #[doc = "< Frame-level Luma QP."]
+    pub fn set_qp(&mut self, qp: i32) {
+        self.inner.QpY = qp;
+    }
+    #[doc = "< Max frame size in bytes. Option for repack feature. Driver calls PAK until current frame size is less than or equal to maxFrameSize, or number of repacking for this frame is equal to maxNumRePak. Repack is available if there is driver support, MaxFrameSize !=0, and MaxNumRePak != 0. Ignored if maxNumRePak == 0."]
+    pub fn set_max_frame_size(&mut self, bytes: u32) {
+        self.inner.MaxFrameSize = bytes;
+    }
+    #[doc = "< Number of possible repacks in driver if current frame size > maxFrameSize. Ignored if maxFrameSize==0. See maxFrameSize description. Possible values are in the range of 0 to 8."]
+    pub fn set_max_num_repak(&mut self, num: u16) {
+        self.inner.MaxNumRepak = num;
+    }
+}
+
+#[doc = " Specifies instructions for the encoder provided by external BRC after each frame encoding."]
+pub struct BrcFrameStatus<'a> {
+    inner: &'a mut ffi::mfxBRCFrameStatus,
+}
+
+impl BrcFrameStatus<'_> {
+    pub fn set_status(&mut self, status: BrcStatus) {
+        self.inner.BRCStatus = status.repr() as u16;
+    }
+    #[doc = "< Size in bytes, coded frame should be padded to when Status = MFX_BRC_PANIC_SMALL_FRAME."]
+    pub fn set_min_frame_size(&mut self, bytes: u32) {
+        self.inner.MinFrameSize = bytes;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Panicking;
+
+    impl BitrateController for Panicking {
+        fn frame_ctrl(&mut self, _frame: &BrcFrameParam, _ctrl: &mut BrcFrameCtrl) -> MfxStatus {
+            panic!("frame_ctrl");
+        }
+
+        fn update(
+            &mut self,
+            _frame: &BrcFrameParam,
+            _ctrl: &BrcFrameCtrl,
+            _status: &mut BrcFrameStatus,
+        ) -> MfxStatus {
+            panic!("update");
+        }
+    }
+
+    #[test]
+    fn panicking_controller() {
+        let brc = ExternalBrc::new(Box::new(Panicking));
+        let mut par: ffi::mfxBRCFrameParam = unsafe { zeroed() };
+        let mut ctrl: ffi::mfxBRCFrameCtrl = unsafe { zeroed() };
+
+        let get_frame_ctrl = brc.inner.GetFrameCtrl.unwrap();
+        let status = unsafe { get_frame_ctrl(brc.inner.pthis, &mut par, &mut ctrl) };
+        assert_eq!(status, MfxStatus::Unknown as i32);
+
+        // The panic poisoned the controller, it stays unusable
+        let close = brc.inner.Close.unwrap();
+        assert_eq!(unsafe { close(brc.inner.pthis) }, MfxStatus::Unknown as i32);
+    }
+}
@@ -72,7 +72,7 @@ pub enum NalUnitType {
 
 #[cfg_attr(target_os = "linux", EnumRepr(type = "u32"))]
 #[cfg_attr(target_os = "windows", EnumRepr(type = "i32"))]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[doc = " The ColorFourCC enumerator itemizes color formats."]
 pub enum FourCC {
     #[doc = "< NV12 color planes. Native format for 4:2:0/8b Gen hardware implementation."]
@@ -138,11 +138,116 @@ pub enum FourCC {
     BGRP = ffi::MFX_FOURCC_BGRP,
 }
 
+impl FourCC {
+    /// How frames in this format are laid out in memory. See [`PlaneLayout`].
+    pub fn plane_layout(&self) -> PlaneLayout {
+        let (bits_per_sample, planes) = match self {
+            FourCC::NV12 | FourCC::NV21 => (8, SEMI_PLANAR_420_8),
+            FourCC::P010 => (10, SEMI_PLANAR_420_16),
+            FourCC::P016 => (16, SEMI_PLANAR_420_16),
+            FourCC::NV16 => (8, SEMI_PLANAR_422_8),
+            FourCC::P210 => (10, SEMI_PLANAR_422_16),
+            FourCC::YV12 | FourCC::IyuvOrI420 => (8, PLANAR_420_8),
+            FourCC::I010 => (10, PLANAR_420_16),
+            FourCC::I422 => (8, PLANAR_422_8),
+            FourCC::I210 => (10, PLANAR_422_16),
+            FourCC::RGBP | FourCC::BGRP => (8, PLANAR_444_8),
+            FourCC::P8 | FourCC::P8Texture => (8, PACKED_1),
+            FourCC::YUY2 | FourCC::UYVY => (8, PACKED_2),
+            FourCC::R16 => (16, PACKED_2),
+            // Green has 6
+            FourCC::RGB565 => (5, PACKED_2),
+            FourCC::RGB3 => (8, PACKED_3),
+            FourCC::Rgb4OrBgra | FourCC::BGR4 | FourCC::AYUV | FourCC::AyuvRgb4 => (8, PACKED_4),
+            FourCC::A2RGB10 | FourCC::Y210 | FourCC::Y410 => (10, PACKED_4),
+            FourCC::Y216 => (16, PACKED_4),
+            FourCC::ARGB16 | FourCC::ABGR16 | FourCC::Y416 => (16, PACKED_8),
+        };
+
+        PlaneLayout {
+            bits_per_sample,
+            planes,
+        }
+    }
+}
+
+/// The planes of a [`FourCC`], in the order they are stored in memory and in raw files. Packed formats, where all the channels of a pixel are stored together (Eg. YUY2 or BGRA), have a single plane. Semi planar formats like NV12 have a luma plane followed by an interleaved chroma plane.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PlaneLayout {
+    /// Significant bits of each sample. 10 bit formats store their samples in 16 bits.
+    pub bits_per_sample: u8,
+    pub planes: &'static [PlaneDescriptor],
+}
+
+impl PlaneLayout {
+    /// Bytes in a row of `plane` without padding, for a frame `width` pixels wide. Subsampled planes of odd sized frames round up, so the last pixel still has a chroma sample.
+    pub fn row_bytes(&self, plane: usize, width: usize) -> usize {
+        let plane = &self.planes[plane];
+        let subsampling = plane.x_subsampling as usize;
+        (width + subsampling - 1) / subsampling * plane.bytes_per_pixel as usize
+    }
+
+    /// Number of rows of `plane` for a frame `height` pixels high, rounded up like [`PlaneLayout::row_bytes`].
+    pub fn rows(&self, plane: usize, height: usize) -> usize {
+        let subsampling = self.planes[plane].y_subsampling as usize;
+        (height + subsampling - 1) / subsampling
+    }
+
+    /// The pitch of `plane` when the first plane has a pitch of `pitch`. Eg. half the pitch for the chroma planes of I420, the same pitch for the interleaved chroma plane of NV12.
+    pub fn pitch(&self, plane: usize, pitch: usize) -> usize {
+        let first = &self.planes[0];
+        let plane = &self.planes[plane];
+        pitch * plane.bytes_per_pixel as usize
+            / (plane.x_subsampling as usize * first.bytes_per_pixel as usize)
+    }
+
+    /// Size in bytes of a whole frame without padding.
+    pub fn frame_size(&self, width: usize, height: usize) -> usize {
+        (0..self.planes.len())
+            .map(|plane| self.row_bytes(plane, width) * self.rows(plane, height))
+            .sum()
+    }
+}
+
+/// One plane of a [`PlaneLayout`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PlaneDescriptor {
+    /// The plane has one pixel for every `x_subsampling` pixels of the frame horizontally, Eg. 2 for the chroma planes of 4:2:0 and 4:2:2 formats.
+    pub x_subsampling: u8,
+    /// The plane has one row for every `y_subsampling` rows of the frame, Eg. 2 for the chroma planes of 4:2:0 formats.
+    pub y_subsampling: u8,
+    /// Bytes each pixel of the plane takes, Eg. 2 for the interleaved UV pairs of NV12 or 4 for BGRA.
+    pub bytes_per_pixel: u8,
+}
+
+const fn plane(x_subsampling: u8, y_subsampling: u8, bytes_per_pixel: u8) -> PlaneDescriptor {
+    PlaneDescriptor {
+        x_subsampling,
+        y_subsampling,
+        bytes_per_pixel,
+    }
+}
+
+const SEMI_PLANAR_420_8: &[PlaneDescriptor] = &[plane(1, 1, 1), plane(2, 2, 2)];
+const SEMI_PLANAR_420_16: &[PlaneDescriptor] = &[plane(1, 1, 2), plane(2, 2, 4)];
+const SEMI_PLANAR_422_8: &[PlaneDescriptor] = &[plane(1, 1, 1), plane(2, 1, 2)];
+const SEMI_PLANAR_422_16: &[PlaneDescriptor] = &[plane(1, 1, 2), plane(2, 1, 4)];
+const PLANAR_420_8: &[PlaneDescriptor] = &[plane(1, 1, 1), plane(2, 2, 1), plane(2, 2, 1)];
+const PLANAR_420_16: &[PlaneDescriptor] = &[plane(1, 1, 2), plane(2, 2, 2), plane(2, 2, 2)];
+const PLANAR_422_8: &[PlaneDescriptor] = &[plane(1, 1, 1), plane(2, 1, 1), plane(2, 1, 1)];
+const PLANAR_422_16: &[PlaneDescriptor] = &[plane(1, 1, 2), plane(2, 1, 2), plane(2, 1, 2)];
+const PLANAR_444_8: &[PlaneDescriptor] = &[plane(1, 1, 1), plane(1, 1, 1), plane(1, 1, 1)];
+const PACKED_1: &[PlaneDescriptor] = &[plane(1, 1, 1)];
+const PACKED_2: &[PlaneDescriptor] = &[plane(1, 1, 2)];
+const PACKED_3: &[PlaneDescriptor] = &[plane(1, 1, 3)];
+const PACKED_4: &[PlaneDescriptor] = &[plane(1, 1, 4)];
+const PACKED_8: &[PlaneDescriptor] = &[plane(1, 1, 8)];
+
 #[doc = " This enum itemizes hardware acceleration stack to use."]
 #[cfg_attr(target_os = "linux", EnumRepr(type = "u32"))]
 #[cfg_attr(target_os = "windows", EnumRepr(type = "i32"))]
 // #[repf(i32)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AccelerationMode {
     #[doc = "< Hardware acceleration is not applicable."]
     NA = ffi::mfxAccelerationMode_MFX_ACCEL_MODE_NA,
@@ -163,6 +268,12 @@ pub enum AccelerationMode {
     HDDLUNITE = ffi::mfxAccelerationMode_MFX_ACCEL_MODE_VIA_HDDLUNITE,
 }
 
+impl Into<FilterProperty> for AccelerationMode {
+    fn into(self) -> FilterProperty {
+        FilterProperty::U32(self.repr() as u32)
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 #[cfg_attr(target_os = "linux", EnumRepr(type = "u32"))]
 #[cfg_attr(target_os = "windows", EnumRepr(type = "i32"))]
@@ -193,6 +304,76 @@ pub enum PicStruct {
     FieldPairNext = ffi::MFX_PICSTRUCT_FIELD_PAIRED_NEXT,
 }
 
+impl PicStruct {
+    /// True for pictures made up of fields, including single fields and repeated fields.
+    pub fn is_interlaced(&self) -> bool {
+        matches!(
+            self,
+            PicStruct::FieldTff
+                | PicStruct::FieldBff
+                | PicStruct::FieldRepeated
+                | PicStruct::FieldSingle
+                | PicStruct::FieldTop
+                | PicStruct::FieldBottom
+                | PicStruct::FieldPairedPrev
+                | PicStruct::FieldPairNext
+        )
+    }
+
+    /// True for progressive frames, including ones that are doubled or tripled for display.
+    pub fn is_progressive(&self) -> bool {
+        matches!(
+            self,
+            PicStruct::Progressive | PicStruct::FrameDoubling | PicStruct::FrameTripling
+        )
+    }
+
+    /// Which field comes first for interlaced frames. [`None`] for progressive frames and single fields.
+    pub fn field_order(&self) -> Option<FieldOrder> {
+        match self {
+            PicStruct::FieldTff => Some(FieldOrder::TopFieldFirst),
+            PicStruct::FieldBff => Some(FieldOrder::BottomFieldFirst),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for PicStruct {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            PicStruct::Unknown => "Unknown",
+            PicStruct::Progressive => "Progressive",
+            PicStruct::FieldTff => "Top field first",
+            PicStruct::FieldBff => "Bottom field first",
+            PicStruct::FieldRepeated => "Repeated field",
+            PicStruct::FrameDoubling => "Frame doubling",
+            PicStruct::FrameTripling => "Frame tripling",
+            PicStruct::FieldSingle => "Single field",
+            PicStruct::FieldTop => "Top field",
+            PicStruct::FieldBottom => "Bottom field",
+            PicStruct::FieldPairedPrev => "Field paired with previous",
+            PicStruct::FieldPairNext => "Field paired with next",
+        };
+        f.write_str(name)
+    }
+}
+
+/// The order the two fields of an interlaced frame are displayed in. See [`PicStruct::field_order`].
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FieldOrder {
+    TopFieldFirst,
+    BottomFieldFirst,
+}
+
+impl From<FieldOrder> for PicStruct {
+    fn from(order: FieldOrder) -> Self {
+        match order {
+            FieldOrder::TopFieldFirst => PicStruct::FieldTff,
+            FieldOrder::BottomFieldFirst => PicStruct::FieldBff,
+        }
+    }
+}
+
 bitflags! {
     #[doc = " The mfxMemoryFlags enumerator specifies memory access mode."]
     pub struct MemoryFlag: ffi::mfxMemoryFlags {
@@ -280,6 +461,141 @@ impl Into<FilterProperty> for Codec {
     }
 }
 
+#[cfg_attr(target_os = "linux", EnumRepr(type = "u32"))]
+#[cfg_attr(target_os = "windows", EnumRepr(type = "i32"))]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[doc = " AVC profiles, see [`crate::MfxVideoParams::set_codec_profile`]."]
+pub enum AvcProfile {
+    Baseline = ffi::MFX_PROFILE_AVC_BASELINE,
+    Main = ffi::MFX_PROFILE_AVC_MAIN,
+    Extended = ffi::MFX_PROFILE_AVC_EXTENDED,
+    High = ffi::MFX_PROFILE_AVC_HIGH,
+    High10 = ffi::MFX_PROFILE_AVC_HIGH10,
+    High422 = ffi::MFX_PROFILE_AVC_HIGH_422,
+    ConstrainedBaseline = ffi::MFX_PROFILE_AVC_CONSTRAINED_BASELINE,
+    ConstrainedHigh = ffi::MFX_PROFILE_AVC_CONSTRAINED_HIGH,
+    ProgressiveHigh = ffi::MFX_PROFILE_AVC_PROGRESSIVE_HIGH,
+}
+
+impl From<AvcProfile> for u16 {
+    fn from(value: AvcProfile) -> Self {
+        value.repr() as u16
+    }
+}
+
+#[cfg_attr(target_os = "linux", EnumRepr(type = "u32"))]
+#[cfg_attr(target_os = "windows", EnumRepr(type = "i32"))]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[doc = " AVC levels, see [`crate::MfxVideoParams::set_codec_level`]."]
+pub enum AvcLevel {
+    #[doc = "< Level 1."]
+    Level1 = ffi::MFX_LEVEL_AVC_1,
+    #[doc = "< Level 1b."]
+    Level1b = ffi::MFX_LEVEL_AVC_1b,
+    #[doc = "< Level 1.1."]
+    Level1_1 = ffi::MFX_LEVEL_AVC_11,
+    #[doc = "< Level 1.2."]
+    Level1_2 = ffi::MFX_LEVEL_AVC_12,
+    #[doc = "< Level 1.3."]
+    Level1_3 = ffi::MFX_LEVEL_AVC_13,
+    #[doc = "< Level 2."]
+    Level2 = ffi::MFX_LEVEL_AVC_2,
+    #[doc = "< Level 2.1."]
+    Level2_1 = ffi::MFX_LEVEL_AVC_21,
+    #[doc = "< Level 2.2."]
+    Level2_2 = ffi::MFX_LEVEL_AVC_22,
+    #[doc = "< Level 3."]
+    Level3 = ffi::MFX_LEVEL_AVC_3,
+    #[doc = "< Level 3.1."]
+    Level3_1 = ffi::MFX_LEVEL_AVC_31,
+    #[doc = "< Level 3.2."]
+    Level3_2 = ffi::MFX_LEVEL_AVC_32,
+    #[doc = "< Level 4."]
+    Level4 = ffi::MFX_LEVEL_AVC_4,
+    #[doc = "< Level 4.1."]
+    Level4_1 = ffi::MFX_LEVEL_AVC_41,
+    #[doc = "< Level 4.2."]
+    Level4_2 = ffi::MFX_LEVEL_AVC_42,
+    #[doc = "< Level 5."]
+    Level5 = ffi::MFX_LEVEL_AVC_5,
+    #[doc = "< Level 5.1."]
+    Level5_1 = ffi::MFX_LEVEL_AVC_51,
+    #[doc = "< Level 5.2."]
+    Level5_2 = ffi::MFX_LEVEL_AVC_52,
+    #[doc = "< Level 6."]
+    Level6 = ffi::MFX_LEVEL_AVC_6,
+    #[doc = "< Level 6.1."]
+    Level6_1 = ffi::MFX_LEVEL_AVC_61,
+    #[doc = "< Level 6.2."]
+    Level6_2 = ffi::MFX_LEVEL_AVC_62,
+}
+
+impl From<AvcLevel> for u16 {
+    fn from(value: AvcLevel) -> Self {
+        value.repr() as u16
+    }
+}
+
+#[cfg_attr(target_os = "linux", EnumRepr(type = "u32"))]
+#[cfg_attr(target_os = "windows", EnumRepr(type = "i32"))]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[doc = " HEVC profiles, see [`crate::MfxVideoParams::set_codec_profile`]."]
+pub enum HevcProfile {
+    Main = ffi::MFX_PROFILE_HEVC_MAIN,
+    Main10 = ffi::MFX_PROFILE_HEVC_MAIN10,
+    #[doc = "< Main Still Picture."]
+    MainSp = ffi::MFX_PROFILE_HEVC_MAINSP,
+    #[doc = "< Format range extensions, Eg. 4:2:2 and 4:4:4."]
+    Rext = ffi::MFX_PROFILE_HEVC_REXT,
+    #[doc = "< Screen content coding extensions."]
+    Scc = ffi::MFX_PROFILE_HEVC_SCC,
+}
+
+impl From<HevcProfile> for u16 {
+    fn from(value: HevcProfile) -> Self {
+        value.repr() as u16
+    }
+}
+
+#[cfg_attr(target_os = "linux", EnumRepr(type = "u32"))]
+#[cfg_attr(target_os = "windows", EnumRepr(type = "i32"))]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[doc = " HEVC levels of the main tier, see [`crate::MfxVideoParams::set_codec_level`]. OR with `ffi::MFX_TIER_HEVC_HIGH` for the high tier."]
+pub enum HevcLevel {
+    #[doc = "< Level 1."]
+    Level1 = ffi::MFX_LEVEL_HEVC_1,
+    #[doc = "< Level 2."]
+    Level2 = ffi::MFX_LEVEL_HEVC_2,
+    #[doc = "< Level 2.1."]
+    Level2_1 = ffi::MFX_LEVEL_HEVC_21,
+    #[doc = "< Level 3."]
+    Level3 = ffi::MFX_LEVEL_HEVC_3,
+    #[doc = "< Level 3.1."]
+    Level3_1 = ffi::MFX_LEVEL_HEVC_31,
+    #[doc = "< Level 4."]
+    Level4 = ffi::MFX_LEVEL_HEVC_4,
+    #[doc = "< Level 4.1."]
+    Level4_1 = ffi::MFX_LEVEL_HEVC_41,
+    #[doc = "< Level 5."]
+    Level5 = ffi::MFX_LEVEL_HEVC_5,
+    #[doc = "< Level 5.1."]
+    Level5_1 = ffi::MFX_LEVEL_HEVC_51,
+    #[doc = "< Level 5.2."]
+    Level5_2 = ffi::MFX_LEVEL_HEVC_52,
+    #[doc = "< Level 6."]
+    Level6 = ffi::MFX_LEVEL_HEVC_6,
+    #[doc = "< Level 6.1."]
+    Level6_1 = ffi::MFX_LEVEL_HEVC_61,
+    #[doc = "< Level 6.2."]
+    Level6_2 = ffi::MFX_LEVEL_HEVC_62,
+}
+
+impl From<HevcLevel> for u16 {
+    fn from(value: HevcLevel) -> Self {
+        value.repr() as u16
+    }
+}
+
 #[cfg_attr(target_os = "linux", EnumRepr(type = "u32"))]
 #[cfg_attr(target_os = "windows", EnumRepr(type = "i32"))]
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
@@ -418,6 +734,22 @@ impl Into<FilterProperty> for ImplementationType {
     }
 }
 
+bitflags! {
+    #[doc = " The ErrorTypes enumerator uses bit-ORed values to itemize bitstream error types."]
+    pub struct DecodeErrorTypes: u32 {
+        #[doc = "< Invalid/corrupted PPS."]
+        const PPS = ffi::MFX_ERROR_PPS as u32;
+        #[doc = "< Invalid/corrupted SPS."]
+        const SPS = ffi::MFX_ERROR_SPS as u32;
+        #[doc = "< Invalid/corrupted slice header."]
+        const SLICE_HEADER = ffi::MFX_ERROR_SLICEHEADER as u32;
+        #[doc = "< Invalid/corrupted slice data."]
+        const SLICE_DATA = ffi::MFX_ERROR_SLICEDATA as u32;
+        #[doc = "< Missed frames."]
+        const FRAME_GAP = ffi::MFX_ERROR_FRAME_GAP as u32;
+    }
+}
+
 bitflags! {
     #[doc = " The IOPattern enumerator itemizes memory access patterns for API functions. Use bit-ORed values to specify an input access\npattern and an output access pattern."]
     pub struct IoPattern: u16 {
@@ -445,9 +777,21 @@ bitflags! {
     }
 }
 
+#[cfg_attr(target_os = "linux", EnumRepr(type = "u32"))]
+#[cfg_attr(target_os = "windows", EnumRepr(type = "i32"))]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[doc = " The ImageStabMode enumerator itemizes image stabilization modes. See mfxExtVPPImageStab for more details."]
+pub enum ImageStabMode {
+    #[doc = "< Upscale mode."]
+    Upscale = ffi::MFX_IMAGESTAB_MODE_UPSCALE,
+    #[doc = "< Boxing mode."]
+    Boxing = ffi::MFX_IMAGESTAB_MODE_BOXING,
+}
+
 #[doc = " The mfxSkipMode enumerator describes the decoder skip-mode options."]
 #[cfg_attr(target_os = "linux", EnumRepr(type = "u32"))]
 #[cfg_attr(target_os = "windows", EnumRepr(type = "i32"))]
+#[derive(Debug, Clone, Copy)]
 pub enum SkipMode {
     NoSkip = ffi::mfxSkipMode_MFX_SKIPMODE_NOSKIP,
     #[doc = " Do not skip any frames."]
@@ -456,7 +800,7 @@ pub enum SkipMode {
     Less = ffi::mfxSkipMode_MFX_SKIPMODE_LESS,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[cfg_attr(target_os = "linux", EnumRepr(type = "u32"))]
 #[cfg_attr(target_os = "windows", EnumRepr(type = "i32"))]
 pub enum ChromaFormat {
@@ -476,6 +820,21 @@ pub enum ChromaFormat {
     Reserved1 = ffi::MFX_CHROMAFORMAT_RESERVED1,
 }
 
+impl std::fmt::Display for ChromaFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ChromaFormat::Monochrome => "4:0:0",
+            ChromaFormat::YUV420 => "4:2:0",
+            ChromaFormat::YUV422 => "4:2:2",
+            ChromaFormat::YUV444 => "4:4:4",
+            ChromaFormat::YUV411 => "4:1:1",
+            ChromaFormat::YUV422V => "4:2:2 (vertical)",
+            ChromaFormat::Reserved1 => "Reserved",
+        };
+        f.write_str(name)
+    }
+}
+
 #[derive(Debug)]
 #[cfg_attr(target_os = "linux", EnumRepr(type = "u32"))]
 #[cfg_attr(target_os = "windows", EnumRepr(type = "i32"))]
@@ -491,6 +850,36 @@ pub enum CodingOptionValue {
     Adaptive = ffi::MFX_CODINGOPTION_ADAPTIVE,
 }
 
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(target_os = "linux", EnumRepr(type = "u32"))]
+#[cfg_attr(target_os = "windows", EnumRepr(type = "i32"))]
+#[doc = " The IntraRefreshTypes enumerator itemizes types of intra refresh."]
+pub enum IntraRefreshType {
+    #[doc = "< Encode without refresh."]
+    No = ffi::MFX_REFRESH_NO,
+    #[doc = "< Vertical refresh, by column of MBs."]
+    Vertical = ffi::MFX_REFRESH_VERTICAL,
+    #[doc = "< Horizontal refresh, by rows of MBs."]
+    Horizontal = ffi::MFX_REFRESH_HORIZONTAL,
+    #[doc = "< Horizontal refresh by slices without overlapping."]
+    Slice = ffi::MFX_REFRESH_SLICE,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(target_os = "linux", EnumRepr(type = "u32"))]
+#[cfg_attr(target_os = "windows", EnumRepr(type = "i32"))]
+#[doc = " The PartialBitstreamOutput enumerator indicates flags of partial bitstream output type."]
+pub enum PartialBitstreamGranularity {
+    #[doc = "< Do not use partial output"]
+    None = ffi::MFX_PARTIAL_BITSTREAM_NONE,
+    #[doc = "< Partial bitstream output will be aligned to slice granularity"]
+    Slice = ffi::MFX_PARTIAL_BITSTREAM_SLICE,
+    #[doc = "< Partial bitstream output will be aligned to user-defined block size granularity"]
+    Block = ffi::MFX_PARTIAL_BITSTREAM_BLOCK,
+    #[doc = "< Partial bitstream output will be return any coded data available at the end of SyncOperation timeout"]
+    Any = ffi::MFX_PARTIAL_BITSTREAM_ANY,
+}
+
 #[derive(Debug)]
 #[cfg_attr(target_os = "linux", EnumRepr(type = "u32"))]
 #[cfg_attr(target_os = "windows", EnumRepr(type = "i32"))]
@@ -504,6 +893,19 @@ pub enum BRefControl {
     Pyramid = ffi::MFX_B_REF_PYRAMID,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(target_os = "linux", EnumRepr(type = "u32"))]
+#[cfg_attr(target_os = "windows", EnumRepr(type = "i32"))]
+#[doc = " The PRefType enumerator itemizes models of reference list construction and DPB management when GopRefDist=1."]
+pub enum PRefType {
+    #[doc = "< Allow encoder to decide."]
+    Default = ffi::MFX_P_REF_DEFAULT,
+    #[doc = "< Regular sliding window used for DPB removal process."]
+    Simple = ffi::MFX_P_REF_SIMPLE,
+    #[doc = "< Let N be the max reference list's size. Encoder treats each N's frame as a 'strong' reference and the others as 'weak' references. The encoder uses a 'weak' reference only for prediction of the next frame and removes it from DPB immediately after use. 'Strong' references are removed from DPB by a sliding window."]
+    Pyramid = ffi::MFX_P_REF_PYRAMID,
+}
+
 #[derive(Debug)]
 #[cfg_attr(target_os = "linux", EnumRepr(type = "u32"))]
 #[cfg_attr(target_os = "windows", EnumRepr(type = "i32"))]
@@ -530,6 +932,36 @@ pub enum ContentInfo {
     NonVideoScreen = ffi::MFX_CONTENT_NON_VIDEO_SCREEN,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(target_os = "linux", EnumRepr(type = "u32"))]
+#[cfg_attr(target_os = "windows", EnumRepr(type = "i32"))]
+#[doc = " The BRCStatus enumerator itemizes instructions to the encoder by mfxExtBrc::Update."]
+pub enum BrcStatus {
+    #[doc = "< CodedFrameSize is acceptable, no further recoding/padding/skip required, proceed to next frame."]
+    Ok = ffi::MFX_BRC_OK,
+    #[doc = "< Coded frame is too big, recoding required."]
+    BigFrame = ffi::MFX_BRC_BIG_FRAME,
+    #[doc = "< Coded frame is too small, recoding required."]
+    SmallFrame = ffi::MFX_BRC_SMALL_FRAME,
+    #[doc = "< Coded frame is too big, no further recoding possible - skip frame."]
+    PanicBigFrame = ffi::MFX_BRC_PANIC_BIG_FRAME,
+    #[doc = "< Coded frame is too small, no further recoding possible - required padding to mfxBRCFrameStatus::MinFrameSize."]
+    PanicSmallFrame = ffi::MFX_BRC_PANIC_SMALL_FRAME,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(target_os = "linux", EnumRepr(type = "u32"))]
+#[cfg_attr(target_os = "windows", EnumRepr(type = "i32"))]
+/// Content protection scheme of the bitstream, see [`crate::MfxVideoParams::set_protected`].
+pub enum ProtectedMode {
+    /// The content is not protected.
+    None = 0,
+    #[doc = "< The protection scheme is based on the Widevine* DRM from Google*."]
+    CencWvClassic = ffi::MFX_PROTECTION_CENC_WV_CLASSIC,
+    #[doc = "< The protection scheme is based on the Widevine* Modular DRM* from Google*."]
+    CencWvGoogleDash = ffi::MFX_PROTECTION_CENC_WV_GOOGLE_DASH,
+}
+
 #[cfg_attr(target_os = "linux", bitmask_enum::bitmask(u32))]
 #[cfg_attr(target_os = "windows", bitmask_enum::bitmask(i32))]
 #[doc = "The ExtMemFrameType enumerator specifies the memory type of frame. It is a bit-ORed value of the following.\n\\verbatim embed:rst\nFor information on working with video memory surfaces, see the :ref:`Working with Hardware Acceleration section<hw-acceleration>`.\n\\endverbatim"]
@@ -565,6 +997,66 @@ pub enum ExtMemFrameType {
     #[doc = "< For DX11 allocation use shared resource bind flag."]
     #[doc = "< Frames are in video memory and belong to video encoder render targets."]
     VideoMemoryEncoderTarget = ffi::MFX_MEMTYPE_VIDEO_MEMORY_ENCODER_TARGET,
+
+    // The same bits under each of their names, the combined flags above predate these
+    #[doc = "< Memory page for persistent use."]
+    PersistentMemory = ffi::MFX_MEMTYPE_PERSISTENT_MEMORY,
+    #[doc = "< Allocation request for I/O frames"]
+    ExternalFrame = ffi::MFX_MEMTYPE_EXTERNAL_FRAME,
+    #[doc = "< Frames are in video memory and belong to video decoder render targets."]
+    DXVA2DecoderTarget = ffi::MFX_MEMTYPE_DXVA2_DECODER_TARGET,
+    #[doc = "< Frames are in video memory and belong to video decoder render targets."]
+    VideoMemoryDecoderTarget = ffi::MFX_MEMTYPE_VIDEO_MEMORY_DECODER_TARGET,
+    #[doc = "< Frames are in video memory and belong to video processor render targets."]
+    DXVA2ProcessorTarget = ffi::MFX_MEMTYPE_DXVA2_PROCESSOR_TARGET,
+    #[doc = "< Frames are in video memory and belong to video processor render targets."]
+    VideoMemoryProcessorTarget = ffi::MFX_MEMTYPE_VIDEO_MEMORY_PROCESSOR_TARGET,
+    #[doc = "< Application requests frame handle export to some associated object."]
+    ExportFrame = ffi::MFX_MEMTYPE_EXPORT_FRAME,
+    #[doc = "< For DX11 allocation use shared resource bind flag."]
+    SharedResource = ffi::MFX_MEMTYPE_SHARED_RESOURCE,
+}
+
+// Converting from the raw value never fails, unknown bits are kept as is
+impl ExtMemFrameType {
+    /// The frames are in video memory, for the decoder, video processor or encoder.
+    pub fn is_video_memory(&self) -> bool {
+        self.intersects(
+            Self::VideoMemoryDecoderTarget
+                | Self::VideoMemoryProcessorTarget
+                | Self::VideoMemoryEncoderTarget,
+        )
+    }
+
+    /// The frames are in system memory.
+    pub fn is_system_memory(&self) -> bool {
+        self.contains(Self::SystemMemory)
+    }
+
+    /// The frames are only used by the library itself (Eg. reference frames) and never handed to the application.
+    pub fn is_internal(&self) -> bool {
+        self.contains(Self::InternalFrame)
+    }
+
+    /// The frames are the input or output of a function and the application reads or writes them.
+    pub fn is_external(&self) -> bool {
+        self.contains(Self::ExternalFrame)
+    }
+
+    /// The request comes from an encoder, the frames are its input or reconstructed frames.
+    pub fn is_for_encode(&self) -> bool {
+        self.contains(Self::FromEncode)
+    }
+
+    /// The request comes from a decoder, the frames are its output or reference frames.
+    pub fn is_for_decode(&self) -> bool {
+        self.contains(Self::FromDecode)
+    }
+
+    /// The request comes from a video processor, for either its input or output frames.
+    pub fn is_for_vpp(&self) -> bool {
+        self.intersects(Self::FromVppIn | Self::FromVppOut)
+    }
 }
 
 /// Memory ID type.
@@ -579,3 +1071,11 @@ impl From<usize> for MemId {
 
 /// Handle type.
 pub struct Handle(pub ffi::mfxHDL);
+
+/// The parts of the library a session can run, see [`crate::Session::supported_io_patterns`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Component {
+    Decode,
+    Encode,
+    Vpp,
+}
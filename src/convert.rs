@@ -0,0 +1,210 @@
+//! CPU pixel format conversions for [`crate::FrameSurface::convert_to`].
+//!
+//! All functions work on tightly packed frames (no pitch padding) as produced
+//! by [`crate::FrameSurface::to_packed_vec`]. YUV is assumed to be limited
+//! range (16-235).
+
+use crate::constants::FourCC;
+
+/// Standard used to convert between YUV and RGB.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ColorMatrix {
+    Bt601,
+    Bt709,
+}
+
+impl ColorMatrix {
+    /// BT.709 is used for HD content, BT.601 for everything smaller.
+    pub fn for_height(height: usize) -> Self {
+        if height >= 720 {
+            ColorMatrix::Bt709
+        } else {
+            ColorMatrix::Bt601
+        }
+    }
+
+    fn yuv_to_rgb(&self, y: u8, u: u8, v: u8) -> [u8; 3] {
+        // Limited range coefficients
+        let (v_r, u_g, v_g, u_b) = match self {
+            ColorMatrix::Bt601 => (1.596, 0.392, 0.813, 2.017),
+            ColorMatrix::Bt709 => (1.793, 0.213, 0.533, 2.112),
+        };
+        let c = 1.164 * (y as f32 - 16.0);
+        let d = u as f32 - 128.0;
+        let e = v as f32 - 128.0;
+        [
+            clamp(c + v_r * e),
+            clamp(c - u_g * d - v_g * e),
+            clamp(c + u_b * d),
+        ]
+    }
+
+    fn rgb_to_yuv(&self, [r, g, b]: [u8; 3]) -> [u8; 3] {
+        let (r, g, b) = (r as f32, g as f32, b as f32);
+        match self {
+            ColorMatrix::Bt601 => [
+                clamp(16.0 + 0.257 * r + 0.504 * g + 0.098 * b),
+                clamp(128.0 - 0.148 * r - 0.291 * g + 0.439 * b),
+                clamp(128.0 + 0.439 * r - 0.368 * g - 0.071 * b),
+            ],
+            ColorMatrix::Bt709 => [
+                clamp(16.0 + 0.183 * r + 0.614 * g + 0.062 * b),
+                clamp(128.0 - 0.101 * r - 0.339 * g + 0.439 * b),
+                clamp(128.0 + 0.439 * r - 0.399 * g - 0.040 * b),
+            ],
+        }
+    }
+}
+
+fn clamp(value: f32) -> u8 {
+    (value + 0.5).clamp(0.0, 255.0) as u8
+}
+
+/// Byte offsets of R, G and B within a 4 byte pixel.
+fn rgb_offsets(format: FourCC) -> Option<[usize; 3]> {
+    match format {
+        FourCC::Rgb4OrBgra => Some([2, 1, 0]),
+        FourCC::BGR4 => Some([0, 1, 2]),
+        _ => None,
+    }
+}
+
+/// Width and height of the 4:2:0 chroma planes. Odd sizes round up, the last column or row of blocks covers a single pixel.
+fn chroma_size(width: usize, height: usize) -> (usize, usize) {
+    ((width + 1) / 2, (height + 1) / 2)
+}
+
+/// Returns the `(u, v)` sample for the 2x2 block containing pixel `(x, y)`.
+fn chroma(
+    source: &[u8],
+    format: FourCC,
+    width: usize,
+    height: usize,
+    x: usize,
+    y: usize,
+) -> Option<(u8, u8)> {
+    let luma_len = width * height;
+    let (chroma_width, chroma_height) = chroma_size(width, height);
+    let chroma_len = chroma_width * chroma_height;
+    let (x, y) = (x / 2, y / 2);
+    match format {
+        FourCC::NV12 => {
+            let i = luma_len + (y * chroma_width + x) * 2;
+            Some((source[i], source[i + 1]))
+        }
+        FourCC::NV21 => {
+            let i = luma_len + (y * chroma_width + x) * 2;
+            Some((source[i + 1], source[i]))
+        }
+        FourCC::IyuvOrI420 => {
+            let i = y * chroma_width + x;
+            Some((source[luma_len + i], source[luma_len + chroma_len + i]))
+        }
+        FourCC::YV12 => {
+            let i = y * chroma_width + x;
+            Some((source[luma_len + chroma_len + i], source[luma_len + i]))
+        }
+        _ => None,
+    }
+}
+
+/// Converts a packed 4:2:0 frame to 32 bit RGB.
+pub(crate) fn yuv420_to_rgb(
+    source: &[u8],
+    from: FourCC,
+    to: FourCC,
+    width: usize,
+    height: usize,
+    matrix: ColorMatrix,
+) -> Option<Vec<u8>> {
+    let offsets = rgb_offsets(to)?;
+    let mut output = vec![u8::MAX; width * height * 4];
+    for y in 0..height {
+        for x in 0..width {
+            let (u, v) = chroma(source, from, width, height, x, y)?;
+            let rgb = matrix.yuv_to_rgb(source[y * width + x], u, v);
+            let pixel = &mut output[(y * width + x) * 4..];
+            for (offset, value) in offsets.iter().zip(rgb) {
+                pixel[*offset] = value;
+            }
+        }
+    }
+    Some(output)
+}
+
+/// Converts a packed 32 bit RGB frame to 4:2:0, averaging chroma over each 2x2 block.
+pub(crate) fn rgb_to_yuv420(
+    source: &[u8],
+    from: FourCC,
+    to: FourCC,
+    width: usize,
+    height: usize,
+    matrix: ColorMatrix,
+) -> Option<Vec<u8>> {
+    let offsets = rgb_offsets(from)?;
+    let luma_len = width * height;
+    let (chroma_width, chroma_height) = chroma_size(width, height);
+    let chroma_len = chroma_width * chroma_height;
+    let mut output = vec![0u8; luma_len + chroma_len * 2];
+
+    let rgb = |x: usize, y: usize| {
+        let pixel = &source[(y * width + x) * 4..];
+        [pixel[offsets[0]], pixel[offsets[1]], pixel[offsets[2]]]
+    };
+
+    for y in 0..height {
+        for x in 0..width {
+            output[y * width + x] = matrix.rgb_to_yuv(rgb(x, y))[0];
+        }
+    }
+
+    for y in 0..chroma_height {
+        for x in 0..chroma_width {
+            // Blocks on the right and bottom edge of odd sized frames are cut off
+            let (mut u, mut v, mut count) = (0u32, 0u32, 0u32);
+            for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                let (px, py) = (x * 2 + dx, y * 2 + dy);
+                if px >= width || py >= height {
+                    continue;
+                }
+                let [_, pu, pv] = matrix.rgb_to_yuv(rgb(px, py));
+                u += pu as u32;
+                v += pv as u32;
+                count += 1;
+            }
+            let (u, v) = (((u + count / 2) / count) as u8, ((v + count / 2) / count) as u8);
+
+            let i = y * chroma_width + x;
+            match to {
+                FourCC::NV12 => {
+                    output[luma_len + i * 2] = u;
+                    output[luma_len + i * 2 + 1] = v;
+                }
+                FourCC::NV21 => {
+                    output[luma_len + i * 2] = v;
+                    output[luma_len + i * 2 + 1] = u;
+                }
+                FourCC::IyuvOrI420 => {
+                    output[luma_len + i] = u;
+                    output[luma_len + chroma_len + i] = v;
+                }
+                FourCC::YV12 => {
+                    output[luma_len + i] = v;
+                    output[luma_len + chroma_len + i] = u;
+                }
+                _ => return None,
+            }
+        }
+    }
+
+    Some(output)
+}
+
+/// Swaps between BGRA and RGBA.
+pub(crate) fn swap_red_blue(source: &[u8]) -> Vec<u8> {
+    let mut output = source.to_vec();
+    for pixel in output.chunks_exact_mut(4) {
+        pixel.swap(0, 2);
+    }
+    output
+}
@@ -1,23 +1,121 @@
 use ffi::MfxStatus;
 use intel_onevpl_sys as ffi;
-use std::time::Instant;
-use tokio::task;
-use tracing::trace;
+use std::{
+    mem,
+    sync::{
+        atomic::{AtomicU16, AtomicU32, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tracing::{trace, warn};
 
 use crate::{
     bitstream::Bitstream,
-    constants::{FourCC, SkipMode},
-    get_library, FrameSurface, Session, videoparams::MfxVideoParams,
+    constants::{
+        BitstreamDataFlags, ChromaFormat, Codec, DecodeErrorTypes, FourCC, PicStruct, SkipMode,
+    },
+    get_library, report_status,
+    utils::{InFlight, InFlightPermit},
+    videoparams::{ext_buffer, MfxVideoParams},
+    FrameSurface, Session, SessionRef,
 };
 
+pub type DecodeStat = ffi::mfxDecodeStat;
+
+/// The stream properties found by [`Session::decode_header_info`], see [`MfxVideoParams::header_info`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeaderInfo {
+    pub codec: Codec,
+    /// Codec dependent profile, Eg. `ffi::MFX_PROFILE_HEVC_MAIN`
+    pub profile: u16,
+    /// Codec dependent level, Eg. `ffi::MFX_LEVEL_HEVC_41`. For HEVC the tier is ORed in (`ffi::MFX_TIER_HEVC_HIGH`)
+    pub level: u16,
+    /// Aligned width of the surfaces
+    pub width: u16,
+    /// Aligned height of the surfaces
+    pub height: u16,
+    /// Visible width and height
+    pub crop: (u16, u16),
+    /// Numerator and denominator
+    pub frame_rate: (u32, u32),
+    pub chroma_format: Option<ChromaFormat>,
+    /// Bits per luma sample. Zero if the implementation doesn't report it
+    pub bit_depth: u16,
+}
+
+ext_buffer!(
+    #[doc = "Wraps mfxExtDecodeErrorReport. Attach to the input with [`Bitstream::attach_error_report`] and read it back with [`Decoder::last_error_report`]."]
+    DecodeErrorReport,
+    ffi::mfxExtDecodeErrorReport,
+    ffi::MFX_EXTBUFF_DECODE_ERROR_REPORT
+);
+
+impl DecodeErrorReport {
+    #[doc = "< Bitstream error types (bit-ORed values). See ErrorTypes enumerator for the list of types."]
+    pub fn error_types(&self) -> DecodeErrorTypes {
+        DecodeErrorTypes::from_bits_truncate(self.inner.ErrorTypes)
+    }
+}
+
+/// A decoded frame along with the metadata needed to mux it. Returned by [`Decoder::decode_frame`].
+#[derive(Debug)]
+pub struct DecodedFrame<'a> {
+    pub surface: FrameSurface<'a>,
+    /// Presentation timestamp in units of 90KHz, taken from the bitstream the frame was decoded from
+    pub timestamp: u64,
+    pub pic_struct: PicStruct,
+}
+
+/// How [`Decoder::decode`] sets the [`BitstreamDataFlags`](crate::constants::BitstreamDataFlags) of the bitstreams passed to it. See [`Decoder::set_bitstream_mode`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BitstreamMode {
+    /// Leave the flags alone, apart from clearing the ones an earlier mode set. The decoder waits for the start of the next frame before outputting a frame.
+    #[default]
+    Stream,
+    /// Every bitstream holds exactly one complete frame (or complementary field pair), mark it with `COMPLETE_FRAME` so the frame is output right away.
+    CompleteFrames,
+    /// The rest of the input is in the bitstream, mark it with `END_OF_STREAM` so the decoder outputs its remaining frames while there is still data left. Switch to this once your input runs out, then drain as usual. [`Decoder::drain`] does both for you.
+    EndOfStream,
+}
+
+impl BitstreamMode {
+    /// The flags this mode sets on every bitstream
+    fn flags(&self) -> BitstreamDataFlags {
+        match self {
+            BitstreamMode::Stream => BitstreamDataFlags::empty(),
+            BitstreamMode::CompleteFrames => BitstreamDataFlags::COMPLETE_FRAME,
+            BitstreamMode::EndOfStream => BitstreamDataFlags::END_OF_STREAM,
+        }
+    }
+}
+
 pub struct Decoder<'a: 'b, 'b> {
-    session: &'a Session<'b>,
+    session: SessionRef<'a, 'b>,
+    bitstream_mode: BitstreamMode,
+    /// The [`BitstreamDataFlags`] the bitstream mode set on the last bitstream, cleared again once the mode no longer asks for them
+    mode_flags: AtomicU16,
+    /// Number of times skipping has been raised with [`SkipMode::More`]
+    skip_level: AtomicU32,
+    fast_forward: Option<Duration>,
+    /// Surface size requested by the last [`MfxStatus::ReallocSurface`]
+    realloc_size: Mutex<Option<(u16, u16)>>,
+    last_error_report: Mutex<Option<DecodeErrorReport>>,
+    /// One permit per frame [`Decoder::decode`] or `Decoder::decode_blocking` may have in flight
+    in_flight: InFlight,
 }
 
 impl<'a: 'b, 'b> Decoder<'a, 'b> {
     #[tracing::instrument]
     pub fn new(
         session: &'a Session<'b>,
+        params: MfxVideoParams,
+    ) -> Result<Self, MfxStatus> {
+        Self::init(SessionRef::Borrowed(session), params)
+    }
+
+    pub(crate) fn init(
+        session: SessionRef<'a, 'b>,
         mut params: MfxVideoParams,
     ) -> Result<Self, MfxStatus> {
         let lib = get_library().unwrap();
@@ -25,135 +123,283 @@ impl<'a: 'b, 'b> Decoder<'a, 'b> {
         let status: MfxStatus =
             unsafe { lib.MFXVideoDECODE_Init(session.inner.0, &mut **params) }.into();
 
-        trace!("Decode init = {:?}", status);
+        report_status("MFXVideoDECODE_Init", status);
 
         if status != MfxStatus::NoneOrDone {
             return Err(status);
         }
 
-        let decoder = Self { session };
+        let decoder = Self {
+            session,
+            bitstream_mode: BitstreamMode::Stream,
+            mode_flags: AtomicU16::new(0),
+            skip_level: AtomicU32::new(0),
+            fast_forward: None,
+            realloc_size: Mutex::new(None),
+            last_error_report: Mutex::new(None),
+            in_flight: InFlight::new(params.async_depth()),
+        };
 
         Ok(decoder)
     }
 
-    // fn queue_decode(
-    //     &self,
-    //     bitstream: Option<&mut Bitstream<'_>>,
-    // ) -> Result<FrameSurface, MfxStatus> {
-    //     let lib = get_library().unwrap();
+    /// Submits the bitstream to the decoder without waiting for the output
+    /// surface to be ready. [`Decoder::decode`], [`Decoder::decode_unsynced`]
+    /// and `Decoder::decode_blocking` are built on top of this. Takes the
+    /// slot in [`InFlight`] the frame occupies so every caller is bounded by
+    /// AsyncDepth.
+    fn queue_decode(
+        &self,
+        _permit: &InFlightPermit<'_>,
+        bitstream: Option<&mut Bitstream<'_>>,
+        work_surface: Option<&mut FrameSurface<'_>>,
+    ) -> Result<(FrameSurface<'_>, ffi::mfxSyncPoint), MfxStatus> {
+        let lib = get_library().unwrap();
+
+        // If bitstream is null than we are draining
+        let mut error_report = None;
+        let bitstream = if let Some(bitstream) = bitstream {
+            // Flags are sticky, clear the ones an earlier mode set but the current one doesn't want
+            let wanted = self.bitstream_mode.flags();
+            let previous = BitstreamDataFlags::from_bits_truncate(
+                self.mode_flags.swap(wanted.bits(), Ordering::Relaxed),
+            );
+            bitstream.set_flags((bitstream.flags() - (previous - wanted)) | wanted);
+            if let Some(report) = bitstream.error_report.as_mut() {
+                // The decoder only sets the errors it finds
+                report.ErrorTypes = 0;
+                error_report = Some(&mut **report as *mut DecodeErrorReport);
+            }
+            &mut bitstream.inner
+        } else {
+            std::ptr::null_mut()
+        };
+
+        let mut sync_point: ffi::mfxSyncPoint = std::ptr::null_mut();
+        let surface_work = work_surface.map(|s| s.inner as *mut _).unwrap_or(std::ptr::null_mut());
+        let session = self.session.inner.0;
 
-    //     // If bitstream is null than we are draining
-    //     let bitstream = if let Some(bitstream) = bitstream {
-    //         &mut bitstream.inner
-    //     } else {
-    //         std::ptr::null_mut()
-    //     };
+        let mut output_surface: *mut ffi::mfxFrameSurface1 = std::ptr::null_mut();
 
-    //     let mut sync_point: ffi::mfxSyncPoint = std::ptr::null_mut();
-    //     let surface_work = std::ptr::null_mut();
-    //     let session = self.session.inner;
+        let status: MfxStatus = unsafe {
+            lib.MFXVideoDECODE_DecodeFrameAsync(
+                session,
+                bitstream,
+                surface_work,
+                &mut output_surface,
+                &mut sync_point,
+            )
+        }
+        .into();
+        report_status("MFXVideoDECODE_DecodeFrameAsync", status);
 
-    //     let mut output_surface: *mut ffi::mfxFrameSurface1 = std::ptr::null_mut();
-    //     // dbg!(sync_point, output_surface);
+        if let Some(report) = error_report {
+            let report = unsafe { *report };
+            if !report.error_types().is_empty() {
+                warn!("Bitstream errors: {:?}", report.error_types());
+            }
+            *self.last_error_report.lock().unwrap() = Some(report);
+        }
 
-    //     let status: MfxStatus = unsafe {
-    //         lib.MFXVideoDECODE_DecodeFrameAsync(
-    //             session,
-    //             bitstream,
-    //             surface_work,
-    //             &mut output_surface,
-    //             &mut sync_point,
-    //         )
-    //     }
-    //     .into();
+        self.check_decode_status(status)?;
 
-    //     trace!("Decode frame start = {:?}", status);
+        Ok((FrameSurface::try_from(output_surface)?, sync_point))
+    }
 
-    //     if status != MfxStatus::NoneOrDone {
-    //         return Err(status);
-    //     }
+    fn check_decode_status(&self, status: MfxStatus) -> Result<(), MfxStatus> {
+        match status {
+            MfxStatus::NoneOrDone => Ok(()),
+            MfxStatus::ReallocSurface => {
+                let params = self.params()?;
+                let size = (params.width(), params.height());
+                warn!("Decoder needs a bigger work surface: {}x{}", size.0, size.1);
+                *self.realloc_size.lock().unwrap() = Some(size);
+                Err(status)
+            }
+            status => Err(status),
+        }
+    }
 
-    //     let output_surface = FrameSurface::try_from(output_surface)?;
+    /// The error report filled in by the last decode call whose bitstream had [`Bitstream::attach_error_report`] called on it.
+    pub fn last_error_report(&self) -> Option<DecodeErrorReport> {
+        *self.last_error_report.lock().unwrap()
+    }
 
-    //     Ok(output_surface)
-    // }
+    /// The surface size the decoder asked for the last time it returned [`MfxStatus::ReallocSurface`].
+    ///
+    /// When decoding into your own work surfaces (with [`MfxVideoParams::set_realloc_request`] enabled) the decoder returns [`MfxStatus::ReallocSurface`] if the work surface is too small for the next frame. Allocate a surface of at least this size and pass it to the next decode call along with the same bitstream.
+    pub fn realloc_size(&self) -> Option<(u16, u16)> {
+        *self.realloc_size.lock().unwrap()
+    }
 
     /// Decodes the input bitstream to a single output frame. This async
     /// function automatically calls synchronize to wait for the frame to be
     /// decoded.
     ///
+    /// Concurrent calls are limited to the AsyncDepth the decoder was created
+    /// with, extra calls wait for a frame to finish before being submitted.
+    ///
     /// See
     /// https://spec.oneapi.io/versions/latest/elements/oneVPL/source/API_ref/VPL_func_vid_decode.html#mfxvideodecode-decodeframeasync
     /// for more info.
+    #[cfg(feature = "async")]
     pub async fn decode(
         &self,
-        bitstream: Option<&mut Bitstream<'_>>,
-        work_surface: Option<&mut FrameSurface<'_>>,
+        mut bitstream: Option<&mut Bitstream<'_>>,
+        mut work_surface: Option<&mut FrameSurface<'_>>,
         timeout: Option<u32>,
     ) -> Result<FrameSurface, MfxStatus> {
+        // Held until the frame is synchronized
+        let permit = self.in_flight.acquire().await;
         let decode_start = Instant::now();
 
-        // FIXME: All this is really just a call to queue_decode but I can't get it to compile
-        let mut output_surface = {
-            let lib = get_library().unwrap();
+        let (mut output_surface, _) = self
+            .session
+            .busy_retry()
+            .run_async(|| {
+                self.queue_decode(&permit, bitstream.as_deref_mut(), work_surface.as_deref_mut())
+            })
+            .await?;
+        output_surface.synchronize_async(timeout).await?;
 
-            // If bitstream is null than we are draining
-            let bitstream = if let Some(bitstream) = bitstream {
-                &mut bitstream.inner
-            } else {
-                std::ptr::null_mut()
-            };
+        trace_decoded_frame(&output_surface, decode_start);
+        self.adjust_skip(decode_start.elapsed());
 
-            let mut sync_point: ffi::mfxSyncPoint = std::ptr::null_mut();
-            let surface_work = work_surface.map(|s| s.inner as *mut _).unwrap_or(std::ptr::null_mut());
-            let session = self.session.inner.0;
+        Ok(output_surface)
+    }
 
-            let mut output_surface: *mut ffi::mfxFrameSurface1 = std::ptr::null_mut();
-            // dbg!(sync_point, output_surface);
-
-            let status: MfxStatus = unsafe {
-                lib.MFXVideoDECODE_DecodeFrameAsync(
-                    session,
-                    bitstream,
-                    surface_work,
-                    &mut output_surface,
-                    &mut sync_point,
-                )
-            }
-            .into();
+    /// Same as [`Decoder::decode`] but returns the surface straight away, together with its sync point, instead of waiting for it to be decoded. Wait for it with [`Session::sync`] or [`FrameSurface::synchronize`] before touching the pixels. Useful to sync several frames at once.
+    ///
+    /// Unlike [`Decoder::decode`], the time spent decoding isn't known so [`Decoder::set_fast_forward`] has no effect.
+    ///
+    /// The returned frame counts towards AsyncDepth until it is synchronized with [`FrameSurface::synchronize`] or dropped, syncing it with [`Session::sync`] alone doesn't free its slot. Submitting blocks the thread while AsyncDepth frames are in flight, so holding AsyncDepth unsynced frames on the thread calling this blocks it forever. Use [`Decoder::decode_unsynced_async`] from async code.
+    pub fn decode_unsynced(
+        &self,
+        mut bitstream: Option<&mut Bitstream<'_>>,
+        mut work_surface: Option<&mut FrameSurface<'_>>,
+    ) -> Result<(FrameSurface, ffi::mfxSyncPoint), MfxStatus> {
+        let permit = self.in_flight.acquire_blocking();
+        let (mut output_surface, sync_point) = self.session.busy_retry().run(|| {
+            self.queue_decode(&permit, bitstream.as_deref_mut(), work_surface.as_deref_mut())
+        })?;
+        output_surface.in_flight = Some(permit);
+
+        Ok((output_surface, sync_point))
+    }
+
+    /// Same as [`Decoder::decode_unsynced`] but waits for a free slot and retries a busy device without blocking the executor.
+    #[cfg(feature = "async")]
+    pub async fn decode_unsynced_async(
+        &self,
+        mut bitstream: Option<&mut Bitstream<'_>>,
+        mut work_surface: Option<&mut FrameSurface<'_>>,
+    ) -> Result<(FrameSurface, ffi::mfxSyncPoint), MfxStatus> {
+        let permit = self.in_flight.acquire().await;
+        let (mut output_surface, sync_point) = self
+            .session
+            .busy_retry()
+            .run_async(|| {
+                self.queue_decode(&permit, bitstream.as_deref_mut(), work_surface.as_deref_mut())
+            })
+            .await?;
+        output_surface.in_flight = Some(permit);
+
+        Ok((output_surface, sync_point))
+    }
 
-            trace!("Decode frame start = {:?}", status);
+    /// Same as [`Decoder::decode`] but also returns the frame's presentation timestamp and picture structure.
+    #[cfg(feature = "async")]
+    pub async fn decode_frame(
+        &self,
+        bitstream: Option<&mut Bitstream<'_>>,
+        work_surface: Option<&mut FrameSurface<'_>>,
+        timeout: Option<u32>,
+    ) -> Result<DecodedFrame, MfxStatus> {
+        let surface = self.decode(bitstream, work_surface, timeout).await?;
+        let timestamp = surface.timestamp();
+        let pic_struct = PicStruct::from_repr(surface.inner.Info.PicStruct as ffi::_bindgen_ty_6)
+            .unwrap_or(PicStruct::Unknown);
+
+        Ok(DecodedFrame {
+            surface,
+            timestamp,
+            pic_struct,
+        })
+    }
 
-            if status != MfxStatus::NoneOrDone {
-                return Err(status);
+    /// Drains the decoder once the input has run out. `bitstream` holds the
+    /// last of the input, it is marked with
+    /// [`BitstreamDataFlags::END_OF_STREAM`] and decoded from until it is used
+    /// up, after which the frames still buffered in the decoder are returned.
+    /// Call until it returns [`MfxStatus::MoreData`].
+    #[cfg(feature = "async")]
+    pub async fn drain(
+        &self,
+        bitstream: &mut Bitstream<'_>,
+        timeout: Option<u32>,
+    ) -> Result<FrameSurface, MfxStatus> {
+        if bitstream.size() > 0 {
+            bitstream.set_end_of_stream(true);
+            match self.decode(Some(bitstream), None, timeout).await {
+                Err(MfxStatus::MoreData) => {}
+                result => return result,
             }
+        }
 
-            FrameSurface::try_from(output_surface)?
-        };
+        self.decode(None, None, timeout).await
+    }
 
-        let output_surface = task::spawn_blocking(move || {
-            output_surface.synchronize(timeout)?;
-            Ok(output_surface) as Result<FrameSurface, MfxStatus>
-        })
-        .await
-        .unwrap()?;
+    /// Same as [`Decoder::decode`] but blocks the current thread while
+    /// waiting for the frame to be decoded instead of requiring an async
+    /// runtime.
+    #[cfg(feature = "blocking")]
+    pub fn decode_blocking(
+        &self,
+        mut bitstream: Option<&mut Bitstream<'_>>,
+        mut work_surface: Option<&mut FrameSurface<'_>>,
+        timeout: Option<u32>,
+    ) -> Result<FrameSurface, MfxStatus> {
+        let permit = self.in_flight.acquire_blocking();
+        let decode_start = Instant::now();
 
-        let frame_info = output_surface.inner.Info;
-        let format = FourCC::from_repr(frame_info.FourCC as ffi::_bindgen_ty_5).unwrap();
-        let height = unsafe { frame_info.__bindgen_anon_1.__bindgen_anon_1.CropH };
-        let width = unsafe { frame_info.__bindgen_anon_1.__bindgen_anon_1.CropW };
+        let (mut output_surface, _) = self.session.busy_retry().run(|| {
+            self.queue_decode(&permit, bitstream.as_deref_mut(), work_surface.as_deref_mut())
+        })?;
+        output_surface.synchronize(timeout)?;
 
-        trace!(
-            "Decoded frame = {:?} {}x{} {:?}",
-            format,
-            width,
-            height,
-            decode_start.elapsed()
-        );
+        trace_decoded_frame(&output_surface, decode_start);
+        self.adjust_skip(decode_start.elapsed());
 
         Ok(output_surface)
     }
 
+    /// Low latency mode. When enabled, every bitstream passed to
+    /// [`Decoder::decode`] is marked with
+    /// [`BitstreamDataFlags::COMPLETE_FRAME`](crate::constants::BitstreamDataFlags::COMPLETE_FRAME)
+    /// so the decoder outputs a frame as soon as it has been given one instead
+    /// of waiting for the start of the next frame.
+    ///
+    /// The caller must guarantee each call to decode is given exactly one
+    /// complete frame (or complementary field pair). If a bitstream contains an
+    /// incomplete frame the decoder will produce corrupted output.
+    ///
+    /// Disabling it clears the flag again on the next bitstream passed to decode.
+    pub fn set_complete_frames(&mut self, complete: bool) {
+        self.set_bitstream_mode(match complete {
+            true => BitstreamMode::CompleteFrames,
+            false => BitstreamMode::Stream,
+        });
+    }
+
+    /// Controls which flags are set on the bitstreams passed to [`Decoder::decode`]. Defaults to [`BitstreamMode::Stream`]. Flags set because of the previous mode are cleared from the next bitstream, flags you set yourself are left alone.
+    pub fn set_bitstream_mode(&mut self, mode: BitstreamMode) {
+        self.bitstream_mode = mode;
+    }
+
+    pub fn bitstream_mode(&self) -> BitstreamMode {
+        self.bitstream_mode
+    }
+
     pub fn surface(&self) -> Result<FrameSurface, MfxStatus> {
         let lib = get_library().unwrap();
         let session = self.session.inner.0;
@@ -165,7 +411,7 @@ impl<'a: 'b, 'b> Decoder<'a, 'b> {
 
         // dbg!(sync_point, output_surface);
 
-        trace!("Get decode surface = {:?}", status);
+        report_status("MFXMemory_GetSurfaceForDecode", status);
 
         if status != MfxStatus::NoneOrDone {
             return Err(status);
@@ -178,25 +424,76 @@ impl<'a: 'b, 'b> Decoder<'a, 'b> {
 
     /// The application may use this API function to increase decoding performance by sacrificing output quality.
     ///
+    /// Each call with [`SkipMode::More`] or [`SkipMode::Less`] moves the skip level one step, [`SkipMode::NoSkip`] resets it. This can be called between calls to [`Decoder::decode`] to adjust skipping mid-stream.
+    ///
     /// See https://spec.oneapi.io/versions/latest/elements/oneVPL/source/API_ref/VPL_func_vid_decode.html#mfxvideodecode-setskipmode for more info.
-    pub fn set_skip(&mut self, mode: SkipMode) -> Result<(), MfxStatus> {
+    pub fn set_skip(&self, mode: SkipMode) -> Result<(), MfxStatus> {
         let lib = get_library().unwrap();
         let session = self.session.inner.0;
 
         let status: MfxStatus =
             unsafe { lib.MFXVideoDECODE_SetSkipMode(session, mode.repr()) }.into();
 
-        // dbg!(sync_point, output_surface);
-
-        trace!("Decode frame start = {:?}", status);
+        report_status("MFXVideoDECODE_SetSkipMode", status);
 
         if status != MfxStatus::NoneOrDone {
             return Err(status);
         }
 
+        match mode {
+            SkipMode::NoSkip => self.skip_level.store(0, Ordering::Relaxed),
+            SkipMode::More => {
+                self.skip_level.fetch_add(1, Ordering::Relaxed);
+            }
+            SkipMode::Less => {
+                let _ = self
+                    .skip_level
+                    .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |level| {
+                        level.checked_sub(1)
+                    });
+            }
+        };
+
         Ok(())
     }
 
+    /// How many steps skipping has been raised above [`SkipMode::NoSkip`].
+    pub fn skip_level(&self) -> u32 {
+        self.skip_level.load(Ordering::Relaxed)
+    }
+
+    /// Fast forward mode. When set, the decoder raises its skip level whenever
+    /// decoding a frame takes longer than `frame_deadline`, and lowers it
+    /// again once frames are decoded in less than half the deadline. Useful
+    /// when a player is scrubbing forward and needs to catch up.
+    ///
+    /// Setting this to [`None`] stops adjusting the skip level but leaves it
+    /// where it is. Call [`Decoder::set_skip`] with [`SkipMode::NoSkip`] to
+    /// reset it.
+    pub fn set_fast_forward(&mut self, frame_deadline: Option<Duration>) {
+        self.fast_forward = frame_deadline;
+    }
+
+    fn adjust_skip(&self, elapsed: Duration) {
+        let deadline = match self.fast_forward {
+            Some(deadline) => deadline,
+            None => return,
+        };
+
+        let mode = if elapsed > deadline {
+            SkipMode::More
+        } else if elapsed < deadline / 2 && self.skip_level() > 0 {
+            SkipMode::Less
+        } else {
+            return;
+        };
+
+        // The runtime refuses to go past its highest/lowest skip level, that's fine
+        if let Err(e) = self.set_skip(mode) {
+            trace!("Fast forward could not adjust skip level: {:?}", e);
+        }
+    }
+
     /// Stops the current decoding operation and restores internal structures or
     /// parameters for a new decoding operation.
     ///
@@ -205,22 +502,138 @@ impl<'a: 'b, 'b> Decoder<'a, 'b> {
     /// * It restarts decoding from a new position
     ///
     /// See https://spec.oneapi.io/versions/latest/elements/oneVPL/source/API_ref/VPL_func_vid_decode.html#mfxvideodecode-reset for more info.
-    pub fn reset(&mut self, mut params: MfxVideoParams) -> Result<(), MfxStatus> {
+    pub fn reset(&mut self, params: MfxVideoParams) -> Result<(), MfxStatus> {
+        self.reset_with(params)
+    }
+
+    /// Same as [`Decoder::reset`], for when frames decoded before the reset are still borrowed.
+    fn reset_with(&self, mut params: MfxVideoParams) -> Result<(), MfxStatus> {
         let lib = get_library().unwrap();
         let session = self.session.inner.0;
 
         let status: MfxStatus =
             unsafe { lib.MFXVideoDECODE_Reset(session, &mut **params) }.into();
 
-        trace!("Decode reset = {:?}", status);
+        report_status("MFXVideoDECODE_Reset", status);
+
+        if status != MfxStatus::NoneOrDone {
+            return Err(status);
+        }
+
+        Ok(())
+    }
+
+    /// Closes the decoder and initializes it again with `params`. Unlike [`Decoder::reset`] the new stream can be larger than the one the decoder was first initialized with, which is what [`MfxStatus::IncompatibleVideoParam`] asks for. Drain the decoder first, the frames it still holds are dropped.
+    #[cfg(feature = "async")]
+    pub(crate) fn reinit(&mut self, mut params: MfxVideoParams) -> Result<(), MfxStatus> {
+        let lib = get_library().unwrap();
+        let session = self.session.inner.0;
+
+        let status: MfxStatus = unsafe { lib.MFXVideoDECODE_Close(session) }.into();
+        report_status("MFXVideoDECODE_Close", status);
+        if status != MfxStatus::NoneOrDone {
+            return Err(status);
+        }
 
+        let status: MfxStatus = unsafe { lib.MFXVideoDECODE_Init(session, &mut **params) }.into();
+        report_status("MFXVideoDECODE_Init", status);
         if status != MfxStatus::NoneOrDone {
             return Err(status);
         }
 
+        self.in_flight = InFlight::new(params.async_depth());
+
         Ok(())
     }
 
+    /// Frame accurate seek for scrubbing. Resets the decoder, then decodes `bitstream_at_keyframe`, which has to start at a keyframe, and returns the frame `target_frames` frames after it in display order. The frames in between are decoded and discarded, so `0` returns the keyframe itself.
+    ///
+    /// If the bitstream runs out before the target is reached the decoder is drained, so pass the whole GOP (or the rest of the stream). [`MfxStatus::MoreData`] is returned if the target is past the end of the stream.
+    ///
+    /// [`SkipMode`] is left alone while discarding: the runtimes skip frames (which then aren't output and can't be counted) or drop deblocking (which changes the reference frames) so the target would no longer match a linear decode.
+    #[cfg(feature = "async")]
+    pub async fn seek(
+        &mut self,
+        bitstream_at_keyframe: &mut Bitstream<'_>,
+        target_frames: u32,
+    ) -> Result<FrameSurface, MfxStatus> {
+        let params = self.params()?;
+        self.reset(params)?;
+
+        let mut draining = false;
+        let mut discarded = 0;
+        loop {
+            let bitstream = if draining {
+                None
+            } else {
+                Some(&mut *bitstream_at_keyframe)
+            };
+            let frame = match self.decode(bitstream, None, None).await {
+                Ok(frame) => frame,
+                Err(MfxStatus::MoreData) if !draining => {
+                    draining = true;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+
+            if discarded == target_frames {
+                return Ok(frame);
+            }
+            discarded += 1;
+        }
+    }
+
+    /// Call this when decoding returns [`MfxStatus::VideoParamChanged`]. The decoder has parsed a new sequence header (Eg. a resolution switch in an adaptive bitrate stream) and must be reset with the new parameters, otherwise the output surfaces keep the old size.
+    ///
+    /// The frames still buffered in the decoder belong to the old sequence and the reset would drop them, so the decoder is drained first. Then the new parameters are fetched and the decoder is reset with them. Returns the new parameters, so VPP or output buffers can be resized to match, along with the drained frames, which come before any frame decoded afterwards.
+    #[cfg(feature = "async")]
+    pub async fn handle_param_change(
+        &mut self,
+    ) -> Result<(MfxVideoParams, Vec<FrameSurface>), MfxStatus> {
+        let mut drained = Vec::new();
+        loop {
+            match self.decode(None, None, None).await {
+                Ok(frame) => drained.push(frame),
+                Err(MfxStatus::MoreData) => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        let params = self.params()?;
+        self.reset_with(params.clone())?;
+
+        trace!(
+            "Decoder reset after parameter change, crop = {:?}, {} frames drained",
+            params.crop(),
+            drained.len()
+        );
+
+        Ok((params, drained))
+    }
+
+    /// Obtains statistics collected during decoding, such as the number of
+    /// decoded, skipped, and corrupted frames.
+    ///
+    /// See https://spec.oneapi.io/versions/latest/elements/oneVPL/source/API_ref/VPL_func_vid_decode.html#mfxvideodecode-getdecodestat for more info.
+    pub fn stats(&self) -> Result<DecodeStat, MfxStatus> {
+        let lib = get_library().unwrap();
+        let session = self.session.inner.0;
+
+        let mut stats: DecodeStat = unsafe { mem::zeroed() };
+
+        let status: MfxStatus =
+            unsafe { lib.MFXVideoDECODE_GetDecodeStat(session, &mut stats) }.into();
+
+        report_status("MFXVideoDECODE_GetDecodeStat", status);
+
+        if status != MfxStatus::NoneOrDone {
+            return Err(status);
+        }
+
+        Ok(stats)
+    }
+
     /// Retrieves current working parameters.
     ///
     /// See https://spec.oneapi.io/versions/latest/elements/oneVPL/source/API_ref/VPL_func_vid_decode.html#mfxvideodecode-getvideoparam for more info.
@@ -234,7 +647,7 @@ impl<'a: 'b, 'b> Decoder<'a, 'b> {
             unsafe { lib.MFXVideoDECODE_GetVideoParam(session, &mut **params) }
                 .into();
 
-        trace!("Decode get params = {:?}", status);
+        report_status("MFXVideoDECODE_GetVideoParam", status);
 
         if status != MfxStatus::NoneOrDone {
             return Err(status);
@@ -242,6 +655,51 @@ impl<'a: 'b, 'b> Decoder<'a, 'b> {
 
         Ok(params)
     }
+
+    /// Verifies decoder support for specified parameters, Eg. a [`crate::constants::ProtectedMode`].
+    ///
+    /// See
+    /// https://spec.oneapi.io/versions/latest/elements/oneVPL/source/API_ref/VPL_func_vid_decode.html#mfxvideodecode-query
+    /// for more info.
+    pub fn query(
+        session: &Session,
+        input_params: Option<&MfxVideoParams>,
+    ) -> Result<MfxVideoParams, (MfxStatus, MfxVideoParams)> {
+        let lib = get_library().unwrap();
+        let session = session.inner.0;
+
+        let input_params = input_params
+            .map(|p| &***p as *const _ as *mut _)
+            .unwrap_or(std::ptr::null_mut());
+
+        let mut params = MfxVideoParams::default();
+
+        let status: MfxStatus =
+            unsafe { lib.MFXVideoDECODE_Query(session, input_params, &mut **params) }.into();
+
+        report_status("MFXVideoDECODE_Query", status);
+
+        if status != MfxStatus::NoneOrDone {
+            return Err((status, params));
+        }
+
+        Ok(params)
+    }
+}
+
+fn trace_decoded_frame(surface: &FrameSurface, decode_start: Instant) {
+    let frame_info = surface.inner.Info;
+    let format = FourCC::from_repr(frame_info.FourCC as ffi::_bindgen_ty_5).unwrap();
+    let height = unsafe { frame_info.__bindgen_anon_1.__bindgen_anon_1.CropH };
+    let width = unsafe { frame_info.__bindgen_anon_1.__bindgen_anon_1.CropW };
+
+    trace!(
+        "Decoded frame = {:?} {}x{} {:?}",
+        format,
+        width,
+        height,
+        decode_start.elapsed()
+    );
 }
 
 impl Drop for Decoder<'_, '_> {
@@ -252,16 +710,41 @@ impl Drop for Decoder<'_, '_> {
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "async"))]
 mod tests {
-    use std::io;
+    use std::{io, time::Duration};
 
     use tracing_test::traced_test;
 
-    use crate::{Loader, constants::{ImplementationType, ApiVersion, Codec, IoPattern}, bitstream::Bitstream};
+    use crate::{MfxStatus, MfxVideoParams, Loader, constants::{ImplementationType, ApiVersion, BitstreamDataFlags, Codec, IoPattern, SkipMode}, bitstream::Bitstream};
+
+    use super::{BitstreamMode, Decoder};
+    use crate::{constants::PicStruct, Session};
     
     const DEFAULT_BUFFER_SIZE: usize = 1024 * 1024 * 2; // 2MB
 
+    /// Loader for a software implementation that decodes HEVC
+    fn hevc_loader() -> Loader {
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+        loader.require_decoder(Codec::HEVC);
+        loader
+    }
+
+    /// Reads from `input` into the free space of `bitstream`, returns how many bytes were read
+    fn refill(bitstream: &mut Bitstream, input: &mut impl io::Read) -> u64 {
+        let free_buffer_len = (bitstream.len() - bitstream.size() as usize) as u64;
+        io::copy(&mut io::Read::take(input, free_buffer_len), bitstream).unwrap()
+    }
+
+    /// Decoder for the stream in `bitstream`, which has to start with its headers
+    fn hevc_decoder<'a>(session: &'a Session, bitstream: &mut Bitstream) -> Decoder<'a, 'a> {
+        let params = session
+            .decode_header(bitstream, IoPattern::OUT_SYSTEM_MEMORY)
+            .unwrap();
+        session.decoder(params).unwrap()
+    }
+
     #[traced_test]
     #[tokio::test]
     async fn decode_hevc_file_frame() {
@@ -316,82 +799,309 @@ mod tests {
 
     #[traced_test]
     #[tokio::test]
-    async fn decode_hevc_file_video() {
-        // Open file to read from
-        let mut file = std::fs::File::open("tests/frozen.hevc").unwrap();
-
-        let mut loader = Loader::new().unwrap();
+    async fn decode_hevc_frame_from_slice() {
+        let mut loader = hevc_loader();
 
-        let config = loader.new_config().unwrap();
-        // Set software decoding
-        config
-            .set_filter_property("mfxImplDescription.Impl", ImplementationType::SOFTWARE, None)
-            .unwrap();
+        let session = loader.new_session(0).unwrap();
 
-        let config = loader.new_config().unwrap();
-        // Set decode HEVC
-        config
-            .set_filter_property(
-                "mfxImplDescription.mfxDecoderDescription.decoder.CodecID",
-                Codec::HEVC,
-                None,
-            )
-            .unwrap();
+        // Stands in for a memory mapped file
+        let data = std::fs::read("tests/frozen.hevc").unwrap();
+        let mut bitstream = Bitstream::from_slice(&data, Codec::HEVC);
 
-        let config = loader.new_config().unwrap();
-        // Set required API version to 2.2
-        config
-            .set_filter_property(
-                "mfxImplDescription.ApiVersion.Version",
-                ApiVersion::new(2, 2),
-                None,
-            )
-            .unwrap();
+        let decoder = hevc_decoder(&session, &mut bitstream);
 
-        let session = loader.new_session(0).unwrap();
+        let _frame = decoder.decode(Some(&mut bitstream), None, None).await.unwrap();
+        assert!(bitstream.offset() > 0);
+        assert_eq!((bitstream.offset() + bitstream.size()) as usize, data.len());
+    }
 
-        let mut buffer: Vec<u8> = vec![0; DEFAULT_BUFFER_SIZE];
-        let mut bitstream = Bitstream::with_codec(&mut buffer, Codec::HEVC);
-        let free_buffer_len = (bitstream.len() - bitstream.size() as usize) as u64;
-        let bytes_read = io::copy(
-            &mut io::Read::take(&mut file, free_buffer_len),
-            &mut bitstream,
-        )
-        .unwrap();
-        assert_ne!(bytes_read, 0);
+    #[traced_test]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn decode_unsynced_bounded_by_async_depth() {
+        let data = std::fs::read("tests/frozen.hevc").unwrap();
 
-        let params = session
+        let mut loader = hevc_loader();
+        let session = loader.new_session(0).unwrap();
+        let mut bitstream = Bitstream::from_slice(&data, Codec::HEVC);
+        let mut params = session
             .decode_header(&mut bitstream, IoPattern::OUT_SYSTEM_MEMORY)
             .unwrap();
-
+        params.set_async_depth(1);
         let decoder = session.decoder(params).unwrap();
 
-        loop {
-            let free_buffer_len = (bitstream.len() - bitstream.size() as usize) as u64;
-            let bytes_read = io::copy(
-                &mut io::Read::take(&mut file, free_buffer_len),
-                &mut bitstream,
-            )
+        let (mut frame, _) = decoder
+            .decode_unsynced_async(Some(&mut bitstream), None)
+            .await
             .unwrap();
 
-            let _frame = decoder.decode(Some(&mut bitstream), None, None).await.unwrap();
-
-            if bytes_read == 0 {
-                break;
-            }
-        }
+        // The unsynced frame still holds the only slot
+        let blocked = tokio::time::timeout(
+            Duration::from_millis(100),
+            decoder.decode_unsynced_async(Some(&mut bitstream), None),
+        )
+        .await;
+        assert!(blocked.is_err());
+
+        // Synchronizing frees it, the frame itself stays usable
+        frame.synchronize(None).unwrap();
+        let (mut next, _) = decoder
+            .decode_unsynced_async(Some(&mut bitstream), None)
+            .await
+            .unwrap();
+        next.synchronize(None).unwrap();
+        assert!(!frame.to_packed_vec().unwrap().is_empty());
     }
 
     #[traced_test]
-    #[tokio::test]
-    async fn decode_hevc_1080p_file_frame() {
-        // Open file to read from
-        let file = std::fs::File::open("tests/frozen1080.hevc").unwrap();
+    #[tokio::test(flavor = "multi_thread")]
+    async fn decode_unsynced_matches_decode() {
+        let data = std::fs::read("tests/frozen.hevc").unwrap();
 
-        let mut loader = Loader::new().unwrap();
+        let mut loader = hevc_loader();
+        let session = loader.new_session(0).unwrap();
+        let mut bitstream = Bitstream::from_slice(&data, Codec::HEVC);
+        let decoder = hevc_decoder(&session, &mut bitstream);
+        let mut expected = decoder.decode(Some(&mut bitstream), None, None).await.unwrap();
+
+        let mut loader = hevc_loader();
+        let unsynced_session = loader.new_session(0).unwrap();
+        let mut bitstream = Bitstream::from_slice(&data, Codec::HEVC);
+        let params = unsynced_session
+            .decode_header(&mut bitstream, IoPattern::OUT_SYSTEM_MEMORY)
+            .unwrap();
+        let unsynced_decoder = unsynced_session.decoder(params).unwrap();
+        let (mut frame, sync_point) = unsynced_decoder
+            .decode_unsynced(Some(&mut bitstream), None)
+            .unwrap();
+        assert!(!sync_point.is_null());
+        unsynced_session.sync(sync_point, None).unwrap();
 
-        let config = loader.new_config().unwrap();
-        // Set software decoding
+        assert_eq!(
+            frame.to_packed_vec().unwrap(),
+            expected.to_packed_vec().unwrap()
+        );
+    }
+
+    #[traced_test]
+    #[test]
+    fn decode_protected_params() {
+        use crate::constants::ProtectedMode;
+
+        let mut loader = hevc_loader();
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut data = std::fs::read("tests/frozen.hevc").unwrap();
+        let mut bitstream = Bitstream::with_codec(&mut data, Codec::HEVC);
+        bitstream.set_size(bitstream.len());
+
+        let mut params = session
+            .decode_header(&mut bitstream, IoPattern::OUT_SYSTEM_MEMORY)
+            .unwrap();
+        assert_eq!(params.protected(), Some(ProtectedMode::None));
+
+        params.set_protected(ProtectedMode::CencWvGoogleDash);
+        assert_eq!(params.protected(), Some(ProtectedMode::CencWvGoogleDash));
+        let cloned = params.clone();
+        assert_eq!(cloned.protected(), Some(ProtectedMode::CencWvGoogleDash));
+
+        // Needs DRM infrastructure the software implementation doesn't have
+        match super::Decoder::query(&session, Some(&params)) {
+            Ok(supported) => {
+                assert_eq!(supported.protected(), Some(ProtectedMode::CencWvGoogleDash))
+            }
+            Err((status, _)) => {
+                tracing::warn!("Skipping protected decode, not supported: {:?}", status);
+            }
+        }
+    }
+
+    #[traced_test]
+    #[test]
+    fn encode_params_from_decoded_hevc() {
+        let mut loader = hevc_loader();
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut data = std::fs::read("tests/frozen.hevc").unwrap();
+        let mut bitstream = Bitstream::with_codec(&mut data, Codec::HEVC);
+        bitstream.set_size(bitstream.len());
+
+        let source = session
+            .decode_header(&mut bitstream, IoPattern::OUT_SYSTEM_MEMORY)
+            .unwrap();
+        let mut params = MfxVideoParams::encode_from_source(&source, Codec::AVC);
+        params.set_target_kbps(2000);
+
+        assert_eq!(params.codec(), Codec::AVC);
+        assert_eq!(params.io_pattern(), IoPattern::IN_SYSTEM_MEMORY);
+        assert_eq!((params.width(), params.height()), (source.width(), source.height()));
+        assert_eq!(params.crop(), source.crop());
+        let (info, source_info) = (params.header_info(), source.header_info());
+        assert_eq!(info.frame_rate, source_info.frame_rate);
+        assert_eq!(info.chroma_format, source_info.chroma_format);
+        // The source profile isn't carried over to the new codec
+        assert_eq!(info.profile, 0);
+    }
+
+    #[traced_test]
+    #[test]
+    fn decode_header_info_hevc() {
+        use crate::constants::ChromaFormat;
+        use intel_onevpl_sys as ffi;
+
+        let mut loader = hevc_loader();
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut data = std::fs::read("tests/frozen.hevc").unwrap();
+        let mut bitstream = Bitstream::with_codec(&mut data, Codec::HEVC);
+        bitstream.set_size(bitstream.len());
+
+        let (params, info) = session
+            .decode_header_info(&mut bitstream, IoPattern::OUT_SYSTEM_MEMORY)
+            .unwrap();
+        // Nothing was consumed
+        assert_eq!(bitstream.offset(), 0);
+
+        // 8 bit 4:2:0
+        assert_eq!(info.codec, Codec::HEVC);
+        assert_eq!(info.profile, ffi::MFX_PROFILE_HEVC_MAIN as u16);
+        assert_ne!(info.level, 0);
+        assert_eq!(info.chroma_format, Some(ChromaFormat::YUV420));
+        assert_eq!(info.bit_depth, 8);
+        assert_eq!(info.crop, params.crop());
+        assert_eq!((info.width, info.height), (params.width(), params.height()));
+        assert_eq!(info, params.header_info());
+    }
+
+    #[traced_test]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn decode_on_spawned_task() {
+        let loader = hevc_loader();
+
+        // The session owns the loader, so the decoder borrows nothing and is 'static
+        let session = loader.into_shared_session(0).unwrap();
+
+        let mut data = std::fs::read("tests/frozen.hevc").unwrap();
+        let params = {
+            let mut bitstream = Bitstream::with_codec(&mut data, Codec::HEVC);
+            bitstream.set_size(bitstream.len());
+            session
+                .decode_header(&mut bitstream, IoPattern::OUT_SYSTEM_MEMORY)
+                .unwrap()
+        };
+
+        let decoder = session.decoder(params).unwrap();
+        // The session and its loader stay alive as long as the decoder does
+        drop(session);
+
+        let frames = tokio::spawn(async move {
+            let mut bitstream = Bitstream::with_codec(&mut data, Codec::HEVC);
+            bitstream.set_size(bitstream.len());
+            bitstream.set_end_of_stream(true);
+
+            let mut frames = 0;
+            loop {
+                match decoder.decode(Some(&mut bitstream), None, None).await {
+                    Ok(_) => frames += 1,
+                    Err(MfxStatus::MoreData) => break,
+                    Err(e) => panic!("{:?}", e),
+                }
+            }
+            loop {
+                match decoder.decode(None, None, None).await {
+                    Ok(_) => frames += 1,
+                    Err(MfxStatus::MoreData) => break,
+                    Err(e) => panic!("{:?}", e),
+                }
+            }
+            frames
+        })
+        .await
+        .unwrap();
+
+        assert!(frames > 0);
+    }
+
+    #[traced_test]
+    #[tokio::test]
+    async fn decode_hevc_file_video() {
+        // Open file to read from
+        let mut file = std::fs::File::open("tests/frozen.hevc").unwrap();
+
+        let mut loader = Loader::new().unwrap();
+
+        let config = loader.new_config().unwrap();
+        // Set software decoding
+        config
+            .set_filter_property("mfxImplDescription.Impl", ImplementationType::SOFTWARE, None)
+            .unwrap();
+
+        let config = loader.new_config().unwrap();
+        // Set decode HEVC
+        config
+            .set_filter_property(
+                "mfxImplDescription.mfxDecoderDescription.decoder.CodecID",
+                Codec::HEVC,
+                None,
+            )
+            .unwrap();
+
+        let config = loader.new_config().unwrap();
+        // Set required API version to 2.2
+        config
+            .set_filter_property(
+                "mfxImplDescription.ApiVersion.Version",
+                ApiVersion::new(2, 2),
+                None,
+            )
+            .unwrap();
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut buffer: Vec<u8> = vec![0; DEFAULT_BUFFER_SIZE];
+        let mut bitstream = Bitstream::with_codec(&mut buffer, Codec::HEVC);
+        let free_buffer_len = (bitstream.len() - bitstream.size() as usize) as u64;
+        let bytes_read = io::copy(
+            &mut io::Read::take(&mut file, free_buffer_len),
+            &mut bitstream,
+        )
+        .unwrap();
+        assert_ne!(bytes_read, 0);
+
+        let params = session
+            .decode_header(&mut bitstream, IoPattern::OUT_SYSTEM_MEMORY)
+            .unwrap();
+
+        let decoder = session.decoder(params).unwrap();
+
+        loop {
+            let free_buffer_len = (bitstream.len() - bitstream.size() as usize) as u64;
+            let bytes_read = io::copy(
+                &mut io::Read::take(&mut file, free_buffer_len),
+                &mut bitstream,
+            )
+            .unwrap();
+
+            let _frame = decoder.decode(Some(&mut bitstream), None, None).await.unwrap();
+
+            if bytes_read == 0 {
+                break;
+            }
+        }
+    }
+
+    #[traced_test]
+    #[tokio::test]
+    async fn decode_hevc_1080p_file_frame() {
+        // Open file to read from
+        let file = std::fs::File::open("tests/frozen1080.hevc").unwrap();
+
+        let mut loader = Loader::new().unwrap();
+
+        let config = loader.new_config().unwrap();
+        // Set software decoding
         config
             .set_filter_property("mfxImplDescription.Impl", ImplementationType::SOFTWARE, None)
             .unwrap();
@@ -433,4 +1143,662 @@ mod tests {
 
         let _frame = decoder.decode(Some(&mut bitstream), None, None).await.unwrap();
     }
-}
\ No newline at end of file
+
+    #[cfg(feature = "blocking")]
+    #[traced_test]
+    #[test]
+    fn decode_hevc_file_frame_blocking() {
+        // Open file to read from
+        let mut file = std::fs::File::open("tests/frozen.hevc").unwrap();
+
+        let mut loader = hevc_loader();
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut buffer: Vec<u8> = vec![0; DEFAULT_BUFFER_SIZE];
+        let mut bitstream = Bitstream::with_codec(&mut buffer, Codec::HEVC);
+        assert_ne!(refill(&mut bitstream, &mut file), 0);
+
+        let decoder = hevc_decoder(&session, &mut bitstream);
+
+        let _frame = decoder.decode_blocking(Some(&mut bitstream), None, None).unwrap();
+    }
+
+    #[traced_test]
+    #[tokio::test]
+    async fn decode_hevc_file_stats() {
+        // Open file to read from
+        let mut file = std::fs::File::open("tests/frozen.hevc").unwrap();
+
+        let mut loader = hevc_loader();
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut buffer: Vec<u8> = vec![0; DEFAULT_BUFFER_SIZE];
+        let mut bitstream = Bitstream::with_codec(&mut buffer, Codec::HEVC);
+        assert_ne!(refill(&mut bitstream, &mut file), 0);
+
+        let decoder = hevc_decoder(&session, &mut bitstream);
+
+        let mut frames = 0;
+        loop {
+            match decoder.decode(Some(&mut bitstream), None, None).await {
+                Ok(_frame) => frames += 1,
+                Err(MfxStatus::MoreData) => {
+                    let bytes_read = refill(&mut bitstream, &mut file);
+
+                    if bytes_read == 0 {
+                        break;
+                    }
+                }
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+
+        // Drain
+        loop {
+            match decoder.decode(None, None, None).await {
+                Ok(_frame) => frames += 1,
+                Err(MfxStatus::MoreData) => break,
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+
+        let stats = decoder.stats().unwrap();
+        assert_ne!(frames, 0);
+        assert_eq!(stats.NumFrame, frames);
+    }
+
+    #[traced_test]
+    #[tokio::test]
+    async fn decode_hevc_file_end_of_stream() {
+        // Open file to read from
+        let mut file = std::fs::File::open("tests/frozen.hevc").unwrap();
+
+        let mut loader = hevc_loader();
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut buffer: Vec<u8> = vec![0; DEFAULT_BUFFER_SIZE];
+        let mut bitstream = Bitstream::with_codec(&mut buffer, Codec::HEVC);
+        assert_ne!(refill(&mut bitstream, &mut file), 0);
+
+        let decoder = hevc_decoder(&session, &mut bitstream);
+
+        let mut frames = 0;
+        loop {
+            match decoder.decode(Some(&mut bitstream), None, None).await {
+                Ok(_frame) => frames += 1,
+                Err(MfxStatus::MoreData) => {
+                    let bytes_read = refill(&mut bitstream, &mut file);
+
+                    // The final partial buffer has been decoded
+                    if bitstream.flags().contains(BitstreamDataFlags::END_OF_STREAM) {
+                        break;
+                    }
+
+                    if bytes_read == 0 {
+                        bitstream.set_end_of_stream(true);
+                    }
+                }
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+
+        assert_ne!(frames, 0);
+    }
+
+    #[traced_test]
+    #[tokio::test]
+    async fn decode_hevc_drain() {
+        let data = std::fs::read("tests/frozen.hevc").unwrap();
+
+        let mut loader = hevc_loader();
+
+        let session = loader.new_session(0).unwrap();
+
+        // Hold back the last access unit so there is input left over to drain with
+        let mut last = 0;
+        while last + first_hevc_access_unit_len(&data[last..]) < data.len() {
+            last += first_hevc_access_unit_len(&data[last..]);
+        }
+
+        let mut buffer: Vec<u8> = vec![0; data.len()];
+        let mut bitstream = Bitstream::with_codec(&mut buffer, Codec::HEVC);
+        io::Write::write_all(&mut bitstream, &data[..last]).unwrap();
+
+        let decoder = hevc_decoder(&session, &mut bitstream);
+
+        let mut frames = 0;
+        loop {
+            match decoder.decode(Some(&mut bitstream), None, None).await {
+                Ok(_) => frames += 1,
+                Err(MfxStatus::MoreData) => break,
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+
+        io::Write::write_all(&mut bitstream, &data[last..]).unwrap();
+        let mut drained = 0;
+        loop {
+            match decoder.drain(&mut bitstream, None).await {
+                Ok(_) => drained += 1,
+                Err(MfxStatus::MoreData) => break,
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+
+        assert!(bitstream.flags().contains(BitstreamDataFlags::END_OF_STREAM));
+        assert_eq!(bitstream.size(), 0);
+        assert_ne!(frames, 0);
+        // The held back frame only comes out while draining
+        assert_ne!(drained, 0);
+    }
+
+    /// Returns the length of the first access unit in an annex B HEVC stream.
+    fn first_hevc_access_unit_len(data: &[u8]) -> usize {
+        let mut seen_vcl = false;
+        let mut i = 0;
+        while i + 5 < data.len() {
+            if data[i..i + 3] != [0, 0, 1] {
+                i += 1;
+                continue;
+            }
+            let nal_type = (data[i + 3] >> 1) & 0x3f;
+            let first_slice_in_pic = data[i + 5] & 0x80 != 0;
+            // 4 byte start codes belong to the next access unit
+            let start = if i > 0 && data[i - 1] == 0 { i - 1 } else { i };
+            match nal_type {
+                0..=31 if seen_vcl && first_slice_in_pic => return start,
+                0..=31 => seen_vcl = true,
+                32..=35 | 39 | 41..=44 | 48..=55 if seen_vcl => return start,
+                _ => {}
+            }
+            i += 3;
+        }
+        data.len()
+    }
+
+    #[traced_test]
+    #[tokio::test]
+    async fn decode_hevc_complete_frame() {
+        let data = std::fs::read("tests/frozen.hevc").unwrap();
+        let frame_len = first_hevc_access_unit_len(&data);
+        assert!(frame_len < data.len());
+
+        let mut loader = hevc_loader();
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut buffer: Vec<u8> = vec![0; DEFAULT_BUFFER_SIZE];
+        let mut bitstream = Bitstream::with_codec(&mut buffer, Codec::HEVC);
+        io::Write::write_all(&mut bitstream, &data[..frame_len]).unwrap();
+
+        let mut decoder = hevc_decoder(&session, &mut bitstream);
+        decoder.set_complete_frames(true);
+
+        // Only the first frame is in the bitstream, we should still get it back
+        let frame = decoder.decode(Some(&mut bitstream), None, None).await.unwrap();
+        assert!(!frame.as_raw().is_null());
+    }
+
+    #[traced_test]
+    #[tokio::test]
+    async fn decode_hevc_bitstream_modes() {
+        let data = std::fs::read("tests/frozen.hevc").unwrap();
+
+        // Split the start of the stream into access units
+        let mut frames = Vec::new();
+        let mut offset = 0;
+        while frames.len() < 8 && offset < data.len() {
+            let len = first_hevc_access_unit_len(&data[offset..]);
+            frames.push(&data[offset..offset + len]);
+            offset += len;
+        }
+
+        let mut loader = hevc_loader();
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut output_frames = Vec::new();
+        for mode in [BitstreamMode::Stream, BitstreamMode::CompleteFrames] {
+            let mut buffer: Vec<u8> = vec![0; DEFAULT_BUFFER_SIZE];
+            let mut bitstream = Bitstream::with_codec(&mut buffer, Codec::HEVC);
+            io::Write::write_all(&mut bitstream, frames[0]).unwrap();
+
+            let params = session
+                .peek_header(&mut bitstream, IoPattern::OUT_SYSTEM_MEMORY)
+                .unwrap();
+            let mut decoder = session.decoder(params).unwrap();
+            decoder.set_bitstream_mode(mode);
+            assert_eq!(decoder.bitstream_mode(), mode);
+
+            // Feed one frame at a time and count what comes out before draining
+            let mut count = 0;
+            for (i, frame) in frames.iter().enumerate() {
+                if i > 0 {
+                    io::Write::write_all(&mut bitstream, frame).unwrap();
+                }
+                match decoder.decode(Some(&mut bitstream), None, None).await {
+                    Ok(_) => count += 1,
+                    Err(MfxStatus::MoreData) => {}
+                    Err(e) => panic!("{:?}", e),
+                }
+            }
+            output_frames.push(count);
+
+            if mode == BitstreamMode::CompleteFrames {
+                assert!(bitstream.flags().contains(BitstreamDataFlags::COMPLETE_FRAME));
+
+                // Switching back clears the flag the mode set, but not the ones set by hand
+                decoder.set_complete_frames(false);
+                bitstream.set_end_of_stream(true);
+                match decoder.decode(Some(&mut bitstream), None, None).await {
+                    Ok(_) | Err(MfxStatus::MoreData) => {}
+                    Err(e) => panic!("{:?}", e),
+                }
+                assert_eq!(bitstream.flags(), BitstreamDataFlags::END_OF_STREAM);
+            }
+        }
+
+        // Without the flag every frame waits for the start of the next one
+        assert!(output_frames[1] > output_frames[0], "{:?}", output_frames);
+    }
+
+    #[traced_test]
+    #[tokio::test]
+    async fn decode_hevc_frame_timestamps() {
+        let data = std::fs::read("tests/frozen.hevc").unwrap();
+
+        let mut loader = hevc_loader();
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut buffer: Vec<u8> = vec![0; DEFAULT_BUFFER_SIZE];
+        let mut bitstream = Bitstream::with_codec(&mut buffer, Codec::HEVC);
+        io::Write::write_all(&mut bitstream, &data[..first_hevc_access_unit_len(&data)]).unwrap();
+
+        let params = session
+            .peek_header(&mut bitstream, IoPattern::OUT_SYSTEM_MEMORY)
+            .unwrap();
+        let mut decoder = session.decoder(params).unwrap();
+        decoder.set_bitstream_mode(BitstreamMode::CompleteFrames);
+
+        // Give every access unit its own timestamp, 90KHz clock at 30 fps
+        let mut input_timestamps = Vec::new();
+        let mut timestamps = Vec::new();
+        let mut offset = 0;
+        while offset < data.len() {
+            let len = first_hevc_access_unit_len(&data[offset..]);
+            if offset > 0 {
+                io::Write::write_all(&mut bitstream, &data[offset..offset + len]).unwrap();
+            }
+            offset += len;
+
+            let timestamp = input_timestamps.len() as u64 * 3000;
+            bitstream.set_timestamp(timestamp);
+            input_timestamps.push(timestamp);
+
+            match decoder.decode_frame(Some(&mut bitstream), None, None).await {
+                Ok(frame) => {
+                    assert_eq!(frame.pic_struct, PicStruct::Progressive);
+                    timestamps.push(frame.timestamp);
+                }
+                Err(MfxStatus::MoreData) => {}
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+        while let Ok(frame) = decoder.decode_frame(None, None, None).await {
+            timestamps.push(frame.timestamp);
+        }
+
+        // Frames come out in display order, which can differ from the order they were fed in
+        assert!(timestamps.len() > 1);
+        assert!(timestamps.last() > timestamps.first(), "{:?}", timestamps);
+        assert!(timestamps.iter().all(|t| input_timestamps.contains(t)));
+    }
+
+    #[traced_test]
+    #[test]
+    fn peek_hevc_header_twice() {
+        // Open file to read from
+        let mut file = std::fs::File::open("tests/frozen.hevc").unwrap();
+
+        let mut loader = hevc_loader();
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut buffer: Vec<u8> = vec![0; DEFAULT_BUFFER_SIZE];
+        let mut bitstream = Bitstream::with_codec(&mut buffer, Codec::HEVC);
+        assert_ne!(refill(&mut bitstream, &mut file), 0);
+
+        let offset = bitstream.offset();
+        let size = bitstream.size();
+
+        let first = session
+            .peek_header(&mut bitstream, IoPattern::OUT_SYSTEM_MEMORY)
+            .unwrap();
+        assert_eq!(bitstream.offset(), offset);
+        assert_eq!(bitstream.size(), size);
+
+        let mut params = MfxVideoParams::default();
+        params.set_io_pattern(IoPattern::OUT_SYSTEM_MEMORY);
+        params.set_async_depth(4);
+        let second = session
+            .peek_header_with_params(&mut bitstream, params)
+            .unwrap();
+        assert_eq!(bitstream.offset(), offset);
+        assert_eq!(bitstream.size(), size);
+
+        assert_eq!(first.width(), second.width());
+        assert_eq!(first.height(), second.height());
+        assert_eq!(second.async_depth(), 4);
+    }
+
+    #[traced_test]
+    #[tokio::test]
+    async fn decode_hevc_seek_matches_linear_decode() {
+        const TARGET: u32 = 15;
+
+        let mut loader = hevc_loader();
+
+        let session = loader.new_session(0).unwrap();
+
+        let data = std::fs::read("tests/frozen.hevc").unwrap();
+        let mut buffer = data.clone();
+        let mut bitstream = Bitstream::with_codec(&mut buffer, Codec::HEVC);
+        bitstream.set_size(bitstream.len());
+
+        let mut decoder = hevc_decoder(&session, &mut bitstream);
+
+        let mut linear = None;
+        for _ in 0..=TARGET {
+            let mut frame = decoder.decode(Some(&mut bitstream), None, None).await.unwrap();
+            linear = Some(frame.to_packed_vec().unwrap());
+        }
+
+        // The stream starts with a keyframe
+        let mut buffer = data.clone();
+        let mut bitstream = Bitstream::with_codec(&mut buffer, Codec::HEVC);
+        bitstream.set_size(bitstream.len());
+        let mut frame = decoder.seek(&mut bitstream, TARGET).await.unwrap();
+        assert_eq!(Some(frame.to_packed_vec().unwrap()), linear);
+        drop(frame);
+
+        let mut buffer = data;
+        let mut bitstream = Bitstream::with_codec(&mut buffer, Codec::HEVC);
+        bitstream.set_size(bitstream.len());
+        assert_eq!(
+            decoder.seek(&mut bitstream, u32::MAX).await.err(),
+            Some(MfxStatus::MoreData)
+        );
+    }
+
+    #[traced_test]
+    #[tokio::test]
+    async fn decode_hevc_toggle_skip() {
+        // Open file to read from
+        let mut file = std::fs::File::open("tests/frozen.hevc").unwrap();
+
+        let mut loader = hevc_loader();
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut buffer: Vec<u8> = vec![0; DEFAULT_BUFFER_SIZE];
+        let mut bitstream = Bitstream::with_codec(&mut buffer, Codec::HEVC);
+        assert_ne!(refill(&mut bitstream, &mut file), 0);
+
+        let decoder = hevc_decoder(&session, &mut bitstream);
+
+        let _frame = decoder.decode(Some(&mut bitstream), None, None).await.unwrap();
+
+        decoder.set_skip(SkipMode::More).unwrap();
+        assert_eq!(decoder.skip_level(), 1);
+        let _frame = decoder.decode(Some(&mut bitstream), None, None).await.unwrap();
+
+        decoder.set_skip(SkipMode::NoSkip).unwrap();
+        assert_eq!(decoder.skip_level(), 0);
+        let _frame = decoder.decode(Some(&mut bitstream), None, None).await.unwrap();
+    }
+
+    #[traced_test]
+    #[tokio::test]
+    async fn status_hook_sees_decode() {
+        use std::sync::{Arc, Mutex};
+
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let hook_calls = calls.clone();
+        crate::set_status_hook(Box::new(move |function, status| {
+            hook_calls.lock().unwrap().push((function.to_string(), status));
+        }));
+
+        // Open file to read from
+        let mut file = std::fs::File::open("tests/frozen.hevc").unwrap();
+
+        let mut loader = hevc_loader();
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut buffer: Vec<u8> = vec![0; DEFAULT_BUFFER_SIZE];
+        let mut bitstream = Bitstream::with_codec(&mut buffer, Codec::HEVC);
+        assert_ne!(refill(&mut bitstream, &mut file), 0);
+
+        let decoder = hevc_decoder(&session, &mut bitstream);
+        let _frame = decoder.decode(Some(&mut bitstream), None, None).await.unwrap();
+
+        crate::clear_status_hook();
+
+        let calls = calls.lock().unwrap();
+        assert!(calls.contains(&("MFXLoad".to_string(), MfxStatus::NoneOrDone)));
+        assert!(calls.contains(&("MFXCreateConfig".to_string(), MfxStatus::NoneOrDone)));
+        assert!(calls.contains(&("MFXVideoDECODE_Init".to_string(), MfxStatus::NoneOrDone)));
+        assert!(calls
+            .iter()
+            .any(|(function, _)| function == "MFXVideoDECODE_DecodeFrameAsync"));
+    }
+
+    #[traced_test]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn decode_av1_without_film_grain() {
+        use crate::{
+            constants::{ChromaFormat, FourCC},
+            encode::EncodeCtrl,
+            FrameSurface,
+        };
+
+        const WIDTH: u16 = 320;
+        const HEIGHT: u16 = 180;
+
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+        loader.require_decoder(Codec::AV1);
+        loader.require_encoder(Codec::AV1);
+
+        let session = match loader.new_session(0) {
+            Ok(session) => session,
+            Err(e) => {
+                tracing::warn!("Skipping, no AV1 support: {:?}", e);
+                return;
+            }
+        };
+
+        // There is no AV1 sample in tests/ so encode a few frames first
+        let mut params = MfxVideoParams::default();
+        params.set_codec(Codec::AV1);
+        params.set_framerate(30, 1).unwrap();
+        params.set_fourcc(FourCC::IyuvOrI420);
+        params.set_chroma_format(ChromaFormat::YUV420);
+        params.set_io_pattern(IoPattern::IN_SYSTEM_MEMORY);
+        params.set_width(WIDTH);
+        params.set_height(HEIGHT);
+        params.set_crop(WIDTH, HEIGHT);
+        let mut encoder = session.encoder(params).unwrap();
+
+        let mut buffer: Vec<u8> = vec![0; DEFAULT_BUFFER_SIZE];
+        let mut bitstream = Bitstream::with_codec(&mut buffer, Codec::AV1);
+        let frame_size = FrameSurface::frame_size(FourCC::IyuvOrI420, WIDTH, HEIGHT);
+        for i in 0..5u8 {
+            let mut surface = encoder.get_surface().unwrap();
+            let mut source = io::Cursor::new(vec![i * 40; frame_size]);
+            surface
+                .read_raw_frame(&mut source, FourCC::IyuvOrI420)
+                .await
+                .unwrap();
+            match encoder
+                .encode(&mut EncodeCtrl::new(), Some(surface), &mut bitstream, None)
+                .await
+            {
+                Ok(_) | Err(MfxStatus::MoreData) => {}
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+        while encoder
+            .encode(&mut EncodeCtrl::new(), None, &mut bitstream, None)
+            .await
+            .is_ok()
+        {}
+        drop(encoder);
+
+        let mut params = session
+            .decode_header(&mut bitstream, IoPattern::OUT_SYSTEM_MEMORY)
+            .unwrap();
+        params.set_av1_film_grain(false);
+
+        let decoder = session.decoder(params).unwrap();
+        assert!(!decoder.params().unwrap().av1_film_grain());
+
+        let _frame = decoder.decode(Some(&mut bitstream), None, None).await.unwrap();
+    }
+
+    #[traced_test]
+    #[tokio::test]
+    async fn decode_hevc_resolution_switch() {
+        // Two streams back to back, the second one has a different resolution
+        let mut data = std::fs::read("tests/frozen1080.hevc").unwrap();
+        let mut second = std::fs::read("tests/frozen.hevc").unwrap();
+
+        let mut loader = hevc_loader();
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut buffer: Vec<u8> = vec![0; DEFAULT_BUFFER_SIZE];
+        let mut bitstream = Bitstream::with_codec(&mut buffer, Codec::HEVC);
+        let header_len = first_hevc_access_unit_len(&second);
+        io::Write::write_all(&mut bitstream, &second[..header_len]).unwrap();
+        let expected = session
+            .decode_header(&mut bitstream, IoPattern::OUT_SYSTEM_MEMORY)
+            .unwrap()
+            .crop();
+        drop(bitstream);
+
+        data.append(&mut second);
+        let mut input = io::Cursor::new(data);
+
+        let mut bitstream = Bitstream::with_codec(&mut buffer, Codec::HEVC);
+        refill(&mut bitstream, &mut input);
+
+        let params = session
+            .decode_header(&mut bitstream, IoPattern::OUT_SYSTEM_MEMORY)
+            .unwrap();
+        assert_ne!(params.crop(), expected);
+        let mut decoder = session.decoder(params).unwrap();
+
+        let mut changed = false;
+        let mut last_crop = None;
+        loop {
+            let status = match decoder.decode(Some(&mut bitstream), None, None).await {
+                Ok(frame) => {
+                    let info = unsafe { frame.inner.Info.__bindgen_anon_1.__bindgen_anon_1 };
+                    last_crop = Some((info.CropW, info.CropH));
+                    continue;
+                }
+                Err(e) => e,
+            };
+
+            match status {
+                MfxStatus::MoreData => {
+                    if refill(&mut bitstream, &mut input) == 0 {
+                        break;
+                    }
+                }
+                MfxStatus::VideoParamChanged => {
+                    let (params, drained) = decoder.handle_param_change().await.unwrap();
+                    assert_eq!(params.crop(), expected);
+                    // The frames buffered before the change still have the old size
+                    for frame in drained {
+                        let info = unsafe { frame.inner.Info.__bindgen_anon_1.__bindgen_anon_1 };
+                        assert_ne!((info.CropW, info.CropH), expected);
+                    }
+                    changed = true;
+                }
+                e => panic!("{:?}", e),
+            }
+        }
+
+        assert!(changed);
+        assert_eq!(last_crop, Some(expected));
+    }
+
+    #[traced_test]
+    #[test]
+    fn decode_realloc_surface_reports_size() {
+        let data = std::fs::read("tests/frozen.hevc").unwrap();
+
+        let mut loader = hevc_loader();
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut buffer: Vec<u8> = vec![0; DEFAULT_BUFFER_SIZE];
+        let mut bitstream = Bitstream::with_codec(&mut buffer, Codec::HEVC);
+        io::Write::write_all(&mut bitstream, &data[..first_hevc_access_unit_len(&data)]).unwrap();
+
+        let mut params = session
+            .decode_header(&mut bitstream, IoPattern::OUT_SYSTEM_MEMORY)
+            .unwrap();
+        params.set_realloc_request(true);
+        let (width, height) = (params.width(), params.height());
+
+        let decoder = session.decoder(params).unwrap();
+        assert_eq!(decoder.realloc_size(), None);
+
+        // The software decoder never asks for a bigger surface, so fake the status
+        assert_eq!(
+            decoder.check_decode_status(MfxStatus::ReallocSurface),
+            Err(MfxStatus::ReallocSurface)
+        );
+        assert_eq!(decoder.realloc_size(), Some((width, height)));
+
+        assert_eq!(decoder.check_decode_status(MfxStatus::NoneOrDone), Ok(()));
+    }
+
+    #[traced_test]
+    #[tokio::test]
+    async fn decode_truncated_frame_reports_errors() {
+        let data = std::fs::read("tests/frozen.hevc").unwrap();
+        let frame_len = first_hevc_access_unit_len(&data);
+
+        let mut loader = hevc_loader();
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut buffer: Vec<u8> = vec![0; DEFAULT_BUFFER_SIZE];
+        let mut bitstream = Bitstream::with_codec(&mut buffer, Codec::HEVC);
+        bitstream.attach_error_report();
+        // Cut the first frame's slice data short
+        io::Write::write_all(&mut bitstream, &data[..frame_len * 2 / 3]).unwrap();
+
+        let params = session
+            .peek_header(&mut bitstream, IoPattern::OUT_SYSTEM_MEMORY)
+            .unwrap();
+
+        let mut decoder = session.decoder(params).unwrap();
+        decoder.set_complete_frames(true);
+        assert!(decoder.last_error_report().is_none());
+
+        // The decoder may or may not manage to output something from the broken frame
+        let _ = decoder.decode(Some(&mut bitstream), None, None).await;
+
+        let report = decoder.last_error_report().unwrap();
+        assert!(!report.error_types().is_empty());
+    }
+}
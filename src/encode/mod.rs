@@ -1,25 +1,35 @@
 use ffi::MfxStatus;
 use intel_onevpl_sys as ffi;
+#[cfg(feature = "async")]
+use std::collections::VecDeque;
 use std::{mem, time::Instant};
+#[cfg(feature = "async")]
 use tokio::task;
 use tracing::{debug, trace, warn};
 
 pub use crate::videoparams::{
-    ExtraCodingOption, ExtraCodingOption1, ExtraCodingOption2, ExtraCodingOption3,
+    ChromaLocInfo, ExtraCodingOption, ExtraCodingOption1, ExtraCodingOption2, ExtraCodingOption3,
+    HrdConfig,
 };
 use crate::{
     bitstream::Bitstream,
-    constants::{FrameType, NalUnitType, SkipFrame},
-    get_library,
-    videoparams::MfxVideoParams,
-    FrameSurface, Session,
+    brc::ExternalBrc,
+    constants::{Codec, FrameType, IoPattern, NalUnitType, PartialBitstreamGranularity, SkipFrame},
+    get_library, report_status,
+    videoparams::{ext_buffer, ExtBuffer, MfxVideoParams},
+    FrameSurface, Session, SessionRef,
 };
+#[cfg(feature = "async")]
+use crate::constants::RateControlMethod;
 
 pub type EncodeStat = ffi::mfxEncodeStat;
 
 #[derive(Debug, Clone, Copy)]
 pub struct EncodeCtrl {
     inner: ffi::mfxEncodeCtrl,
+    ref_list_ctrl: Option<AvcRefListCtrl>,
+    /// ExtParam points here, see [`EncodeCtrl::update_ext_params`]
+    ext_buffer_ptrs: [*mut ffi::mfxExtBuffer; 1],
 }
 unsafe impl Send for EncodeCtrl {}
 
@@ -27,6 +37,8 @@ impl EncodeCtrl {
     pub fn new() -> Self {
         Self {
             inner: unsafe { mem::zeroed() },
+            ref_list_ctrl: None,
+            ext_buffer_ptrs: [std::ptr::null_mut()],
         }
     }
     pub fn set_nal_unit_type(&mut self, type_: NalUnitType) {
@@ -35,32 +47,213 @@ impl EncodeCtrl {
     pub fn set_skip(&mut self, skip: SkipFrame) {
         self.inner.SkipFrame = skip as u16;
     }
+    /// Overrides the QP of the next frame. Only honoured when the encoder uses [`RateControlMethod::CQP`], other rate control methods ignore it. Zero means no override. See [`Encoder::encode_with_qp`] for a checked version.
     pub fn set_qp(&mut self, qp: u16) {
         self.inner.QP = qp;
     }
+    /// Required for every frame when encoding in encoded order, see [`MfxVideoParams::set_encode_order`].
     pub fn set_frame_type(&mut self, type_: FrameType) {
         self.inner.FrameType = type_.bits() as u16;
     }
+    /// Controls which frames this frame references and which are kept as long-term references. Only applies to the frame this control is submitted with.
+    pub fn set_ref_list_ctrl(&mut self, ctrl: AvcRefListCtrl) {
+        self.ref_list_ctrl = Some(ctrl);
+    }
+    pub fn ref_list_ctrl(&self) -> Option<&AvcRefListCtrl> {
+        self.ref_list_ctrl.as_ref()
+    }
+
+    /// Points ExtParam at the attached buffers. Done right before the frame is submitted since the control may have been moved after they were attached.
+    fn update_ext_params(&mut self) {
+        match self.ref_list_ctrl.as_mut() {
+            Some(ref_list_ctrl) => {
+                self.ext_buffer_ptrs[0] = ref_list_ctrl.header();
+                self.inner.NumExtParam = 1;
+                self.inner.ExtParam = self.ext_buffer_ptrs.as_mut_ptr();
+            }
+            None => {
+                self.inner.NumExtParam = 0;
+                self.inner.ExtParam = std::ptr::null_mut();
+            }
+        }
+    }
+}
+
+const FRAME_ORDER_UNKNOWN: u32 = ffi::MFX_FRAMEORDER_UNKNOWN as u32;
+
+/// Wraps mfxExtAVCRefListCtrl. Attach to a single frame with [`EncodeCtrl::set_ref_list_ctrl`] to manage references manually, Eg. marking a frame as a long-term reference and referencing it after packet loss.
+///
+/// Frames are identified by their [`FrameSurface::frame_order`], so set it on every input surface with [`FrameSurface::set_frame_order`].
+#[derive(Debug, Clone, Copy)]
+pub struct AvcRefListCtrl {
+    inner: ffi::mfxExtAVCRefListCtrl,
+}
+
+unsafe impl Send for AvcRefListCtrl {}
+
+impl Default for AvcRefListCtrl {
+    fn default() -> Self {
+        let mut inner: ffi::mfxExtAVCRefListCtrl = unsafe { mem::zeroed() };
+        inner.Header.BufferId = ffi::MFX_EXTBUFF_AVC_REFLIST_CTRL as u32;
+        inner.Header.BufferSz = mem::size_of::<ffi::mfxExtAVCRefListCtrl>() as u32;
+        // Zero is a valid frame order, unused entries have to be marked as unknown
+        for entry in inner
+            .PreferredRefList
+            .iter_mut()
+            .chain(inner.RejectedRefList.iter_mut())
+            .chain(inner.LongTermRefList.iter_mut())
+        {
+            entry.FrameOrder = FRAME_ORDER_UNKNOWN;
+        }
+        Self { inner }
+    }
+}
+
+impl std::ops::Deref for AvcRefListCtrl {
+    type Target = ffi::mfxExtAVCRefListCtrl;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl ExtBuffer for AvcRefListCtrl {
+    fn header(&mut self) -> *mut ffi::mfxExtBuffer {
+        &mut self.inner.Header
+    }
+    fn buffer_id(&self) -> u32 {
+        self.inner.Header.BufferId
+    }
+    fn clone_boxed(&self) -> Box<dyn ExtBuffer> {
+        Box::new(*self)
+    }
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+}
+
+impl AvcRefListCtrl {
+    #[doc = "< Specify the number of reference frames in the active reference list L0. This number should be less than or equal to the NumRefFrame parameter from encoding initialization."]
+    pub fn set_num_ref_idx_l0_active(&mut self, num: u16) {
+        self.inner.NumRefIdxL0Active = num;
+    }
+    #[doc = "< Specify the number of reference frames in the active reference list L1. This number should be less than or equal to the NumRefFrame parameter from encoding initialization."]
+    pub fn set_num_ref_idx_l1_active(&mut self, num: u16) {
+        self.inner.NumRefIdxL1Active = num;
+    }
+    /// Adds a frame the encoder should prefer to reference. Returns [`MfxStatus::NotEnoughBuffer`] once all 32 entries are used.
+    pub fn add_preferred(&mut self, frame_order: u32) -> Result<(), MfxStatus> {
+        let entry = self
+            .inner
+            .PreferredRefList
+            .iter_mut()
+            .find(|entry| entry.FrameOrder == FRAME_ORDER_UNKNOWN)
+            .ok_or(MfxStatus::NotEnoughBuffer)?;
+        entry.FrameOrder = frame_order;
+        Ok(())
+    }
+    /// Adds a frame the encoder must not reference. Returns [`MfxStatus::NotEnoughBuffer`] once all 16 entries are used.
+    pub fn add_rejected(&mut self, frame_order: u32) -> Result<(), MfxStatus> {
+        let entry = self
+            .inner
+            .RejectedRefList
+            .iter_mut()
+            .find(|entry| entry.FrameOrder == FRAME_ORDER_UNKNOWN)
+            .ok_or(MfxStatus::NotEnoughBuffer)?;
+        entry.FrameOrder = frame_order;
+        Ok(())
+    }
+    /// Marks a frame, usually the one being submitted, as a long-term reference so it stays available to later frames. Returns [`MfxStatus::NotEnoughBuffer`] once all 16 entries are used.
+    pub fn add_long_term(&mut self, frame_order: u32) -> Result<(), MfxStatus> {
+        let entry = self
+            .inner
+            .LongTermRefList
+            .iter_mut()
+            .find(|entry| entry.FrameOrder == FRAME_ORDER_UNKNOWN)
+            .ok_or(MfxStatus::NotEnoughBuffer)?;
+        entry.FrameOrder = frame_order;
+        Ok(())
+    }
+}
+
+ext_buffer!(
+    #[doc = "Wraps mfxExtPartialBitstreamParam. Attach to the encoder params to have [`Encoder::encode_partial`] return parts of a frame as soon as they are encoded, instead of waiting for the whole frame."]
+    PartialBitstreamParam,
+    ffi::mfxExtPartialBitstreamParam,
+    ffi::MFX_EXTBUFF_PARTIAL_BITSTREAM_PARAM
+);
+
+impl PartialBitstreamParam {
+    pub fn set_granularity(&mut self, granularity: PartialBitstreamGranularity) {
+        (*self).inner.Granularity = granularity.repr() as u16;
+    }
+    #[doc = " Output block granularity for Block granularity mode. Valid only for the Block granularity."]
+    pub fn set_block_size(&mut self, size: u32) {
+        (*self).inner.BlockSize = size;
+    }
+}
+
+ext_buffer!(
+    #[doc = "Wraps mfxExtEncodedUnitsInfo. Attach to the output with [`Bitstream::attach_encoded_units_info`] and read it back with [`Bitstream::encoded_units`]."]
+    EncodedUnitsInfo,
+    ffi::mfxExtEncodedUnitsInfo,
+    ffi::MFX_EXTBUFF_ENCODED_UNITS_INFO
+);
+
+/// The result of [`Encoder::encode_partial`] and [`Encoder::next_partial`], holding the number of bytes written to the output bitstream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncodeOutput {
+    /// Part of the frame was written. Forward it and call [`Encoder::next_partial`] for the rest.
+    Partial(usize),
+    /// The rest of the frame was written.
+    Complete(usize),
+}
+
+impl EncodeOutput {
+    pub fn bytes(&self) -> usize {
+        match self {
+            EncodeOutput::Partial(bytes) | EncodeOutput::Complete(bytes) => *bytes,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct Encoder<'a, 'b: 'a> {
-    session: &'a Session<'b>,
+    session: SessionRef<'a, 'b>,
     suggested_buffer_size: usize,
+    encoded_order: bool,
+    /// Every frame needs a timestamp when there is no frame rate
+    variable_framerate: bool,
+    io_pattern: IoPattern,
+    /// Sync point of the frame [`Encoder::encode_partial`] has only returned part of so far
+    #[cfg(feature = "async")]
+    pending_partial: Option<ffi::mfxSyncPoint>,
+    /// The library calls into this until the encoder is closed
+    _external_brc: Option<ExternalBrc>,
 }
 
 // unsafe impl Send for Encoder<'_, '_> {}
 
 impl<'a, 'b: 'a> Encoder<'a, 'b> {
     #[tracing::instrument]
-    pub fn new(session: &'a Session<'b>, mut params: MfxVideoParams) -> Result<Self, MfxStatus> {
+    pub fn new(session: &'a Session<'b>, params: MfxVideoParams) -> Result<Self, MfxStatus> {
+        Self::init(SessionRef::Borrowed(session), params)
+    }
+
+    pub(crate) fn init(
+        session: SessionRef<'a, 'b>,
+        mut params: MfxVideoParams,
+    ) -> Result<Self, MfxStatus> {
         let lib = get_library().unwrap();
         let session_inner = session.inner.0;
 
         let status: MfxStatus =
             unsafe { lib.MFXVideoENCODE_Init(session_inner, &mut **params) }.into();
 
-        trace!("Encode init = {:?}", status);
+        report_status("MFXVideoENCODE_Init", status);
 
         match status {
             MfxStatus::NoneOrDone => {},
@@ -72,32 +265,51 @@ impl<'a, 'b: 'a> Encoder<'a, 'b> {
         let mut encoder = Self {
             session,
             suggested_buffer_size: 0,
+            encoded_order: false,
+            variable_framerate: false,
+            io_pattern: params.io_pattern(),
+            #[cfg(feature = "async")]
+            pending_partial: None,
+            _external_brc: params.ext_buffer::<ExternalBrc>().cloned(),
         };
 
         let params = encoder.params()?;
         encoder.suggested_buffer_size = params.suggested_buffer_size();
+        encoder.encoded_order = params.encode_order() != 0;
+        encoder.variable_framerate = params.is_variable_framerate();
+        encoder.io_pattern = params.io_pattern();
 
         Ok(encoder)
     }
 
-    /// Takes a single input frame in either encoded or display order and generates its output bitstream. Make sure the output buffer is at least the size of params.BufferSizeInKB after you've created a new encoder.
-    ///
-    /// To mark the end of the encoding sequence, call this function with `input` set to [`None`]. Repeat the call to drain any remaining internally cached bitstreams (one frame at a time) until [`MfxStatus::MoreData`] is returned.
-    ///
-    /// Returns the number of bytes written to output.
-    ///
-    /// See https://spec.oneapi.io/versions/latest/elements/oneVPL/source/API_ref/VPL_func_vid_encode.html#mfxvideoencode-encodeframeasync for more info.
-    pub async fn encode(
+    /// Same as [`Encoder::new`] but when `params` are rejected with [`MfxStatus::IncompatibleVideoParam`] or [`MfxStatus::InvalidVideoParam`], the encoder is queried with them (see [`Encoder::query`]) and the corrected parameters are returned with the error. Compare them with `params` to see which fields the encoder didn't accept. Fields that can't be corrected are zeroed. Other errors return `params` unchanged.
+    pub fn new_with_query(
+        session: &'a Session<'b>,
+        params: MfxVideoParams,
+    ) -> Result<Self, (MfxStatus, MfxVideoParams)> {
+        match Self::new(session, params.clone()) {
+            Ok(encoder) => Ok(encoder),
+            Err(status @ (MfxStatus::IncompatibleVideoParam | MfxStatus::InvalidVideoParam)) => {
+                let corrected = match Self::query(session, Some(&params)) {
+                    Ok(corrected) | Err((_, corrected)) => corrected,
+                };
+                Err((status, corrected))
+            }
+            Err(status) => Err((status, params)),
+        }
+    }
+
+    /// Submits a frame to the encoder without waiting for the output
+    /// bitstream to be ready. Both [`Encoder::encode`] and
+    /// `Encoder::encode_blocking` are built on top of this.
+    fn queue_encode(
         &mut self,
         controller: &mut EncodeCtrl,
-        mut input: Option<FrameSurface<'_>>,
+        input: Option<&mut FrameSurface<'_>>,
         output: &mut Bitstream<'_>,
-        timeout: Option<u32>,
-    ) -> Result<usize, MfxStatus> {
+    ) -> Result<ffi::mfxSyncPoint, MfxStatus> {
         let lib = get_library().unwrap();
         let session = self.session.inner.0;
-        let encode_start = Instant::now();
-        let buffer_start_size = output.size();
 
         if output.len() < self.suggested_buffer_size {
             debug!(
@@ -107,9 +319,31 @@ impl<'a, 'b: 'a> Encoder<'a, 'b> {
             );
         }
 
-        let surface = input
-            .as_mut()
-            .map_or(std::ptr::null_mut(), |s| s.inner as *mut _);
+        // The library writes the encoded frame straight into the bitstream's buffer
+        if output.is_read_only() {
+            warn!("Output bitstream is read only, it can't be encoded into");
+            return Err(MfxStatus::Unsupported);
+        }
+
+        if input.as_ref().map_or(false, |s| s.is_mapped()) {
+            warn!("Input surface is still mapped, unmap it before encoding");
+            return Err(MfxStatus::ResourceMapped);
+        }
+
+        if self.encoded_order && input.is_some() && controller.inner.FrameType == 0 {
+            warn!("Encoding in encoded order but no frame type was set on the EncodeCtrl");
+        }
+
+        if self.variable_framerate
+            && input
+                .as_ref()
+                .map_or(false, |s| s.timestamp() == ffi::MFX_TIMESTAMP_UNKNOWN as u64)
+        {
+            warn!("Encoding variable frame rate input but the frame has no timestamp");
+        }
+
+        let surface = input.map_or(std::ptr::null_mut(), |s| s.inner as *mut _);
+        controller.update_ext_params();
 
         let mut sync_point: ffi::mfxSyncPoint = std::ptr::null_mut();
 
@@ -123,14 +357,70 @@ impl<'a, 'b: 'a> Encoder<'a, 'b> {
             )
         }
         .into();
-        trace!("Encode frame start = {:?}", status);
+        report_status("MFXVideoENCODE_EncodeFrameAsync", status);
 
         if status != MfxStatus::NoneOrDone {
             return Err(status);
         }
 
-        task::block_in_place(|| self.session.sync(sync_point, timeout))?;
-        // dbg!(unsafe {output.inner.__bindgen_anon_1.__bindgen_anon_1.NumExtParam});
+        Ok(sync_point)
+    }
+
+    /// Waits for the whole frame to be encoded. With partial output enabled the sync returns each time part of the frame is ready, so keep syncing until it's done.
+    fn sync_frame(
+        &self,
+        sync_point: ffi::mfxSyncPoint,
+        timeout: Option<u32>,
+    ) -> Result<(), MfxStatus> {
+        while self.session.sync(sync_point, timeout)? == MfxStatus::NonePartialOutput {}
+        Ok(())
+    }
+
+    #[cfg(feature = "async")]
+    fn sync_partial(
+        &mut self,
+        sync_point: ffi::mfxSyncPoint,
+        output: &mut Bitstream<'_>,
+        buffer_start_size: u32,
+        timeout: Option<u32>,
+    ) -> Result<EncodeOutput, MfxStatus> {
+        let status = task::block_in_place(|| self.session.sync(sync_point, timeout))?;
+
+        let bytes_written = (output.size() - buffer_start_size) as usize;
+        if status == MfxStatus::NonePartialOutput {
+            self.pending_partial = Some(sync_point);
+            Ok(EncodeOutput::Partial(bytes_written))
+        } else {
+            Ok(EncodeOutput::Complete(bytes_written))
+        }
+    }
+
+    /// Takes a single input frame in either encoded or display order and generates its output bitstream. Make sure the output buffer is at least the size of params.BufferSizeInKB after you've created a new encoder.
+    ///
+    /// To mark the end of the encoding sequence, call this function with `input` set to [`None`]. Repeat the call to drain any remaining internally cached bitstreams (one frame at a time) until [`MfxStatus::MoreData`] is returned.
+    ///
+    /// The output bitstream carries the input frame's [`FrameSurface::timestamp`] as its presentation timestamp. [`Bitstream::decode_timestamp`] is derived from the order frames are submitted in, so it only increases monotonically if frames are fed in encoded order (or the encoder reorders them itself in display order mode).
+    ///
+    /// Returns the number of bytes written to output. Returns [`MfxStatus::ResourceMapped`] if `input` is still mapped (see [`FrameSurface::is_mapped`]), the library would read it while it may still be written to. Returns [`MfxStatus::Unsupported`] if `output` was created with [`Bitstream::from_slice`].
+    ///
+    /// See https://spec.oneapi.io/versions/latest/elements/oneVPL/source/API_ref/VPL_func_vid_encode.html#mfxvideoencode-encodeframeasync for more info.
+    #[cfg(feature = "async")]
+    pub async fn encode(
+        &mut self,
+        controller: &mut EncodeCtrl,
+        mut input: Option<FrameSurface<'_>>,
+        output: &mut Bitstream<'_>,
+        timeout: Option<u32>,
+    ) -> Result<usize, MfxStatus> {
+        let encode_start = Instant::now();
+        let buffer_start_size = output.size();
+
+        let retry = self.session.busy_retry();
+        let sync_point = retry
+            .run_async(|| self.queue_encode(controller, input.as_mut(), output))
+            .await?;
+
+        task::block_in_place(|| self.sync_frame(sync_point, timeout))?;
 
         trace!("Encoded frame: {:?}", encode_start.elapsed());
 
@@ -138,12 +428,224 @@ impl<'a, 'b: 'a> Encoder<'a, 'b> {
         Ok(bytes_written as usize)
     }
 
+    /// Same as [`Encoder::encode`] but returns as soon as part of the frame has been written to `output` when partial output is enabled with [`PartialBitstreamParam`], so it can be forwarded before the whole frame is encoded. Useful for ultra low latency streaming.
+    ///
+    /// Returns [`EncodeOutput::Partial`] while there is more of the frame to come, call [`Encoder::next_partial`] until [`EncodeOutput::Complete`] is returned before submitting the next frame. Without partial output enabled this always returns [`EncodeOutput::Complete`].
+    #[cfg(feature = "async")]
+    pub async fn encode_partial(
+        &mut self,
+        controller: &mut EncodeCtrl,
+        mut input: Option<FrameSurface<'_>>,
+        output: &mut Bitstream<'_>,
+        timeout: Option<u32>,
+    ) -> Result<EncodeOutput, MfxStatus> {
+        if self.pending_partial.is_some() {
+            warn!("Previous frame has only been partially returned, call next_partial first");
+            return Err(MfxStatus::UndefinedBehavior);
+        }
+
+        let buffer_start_size = output.size();
+        let retry = self.session.busy_retry();
+        let sync_point = retry
+            .run_async(|| self.queue_encode(controller, input.as_mut(), output))
+            .await?;
+
+        self.sync_partial(sync_point, output, buffer_start_size, timeout)
+    }
+
+    /// Waits for the next part of the frame [`Encoder::encode_partial`] returned [`EncodeOutput::Partial`] for. Returns [`MfxStatus::UndefinedBehavior`] if no frame is partially returned.
+    #[cfg(feature = "async")]
+    pub async fn next_partial(
+        &mut self,
+        output: &mut Bitstream<'_>,
+        timeout: Option<u32>,
+    ) -> Result<EncodeOutput, MfxStatus> {
+        let sync_point = self
+            .pending_partial
+            .take()
+            .ok_or(MfxStatus::UndefinedBehavior)?;
+        let buffer_start_size = output.size();
+        self.sync_partial(sync_point, output, buffer_start_size, timeout)
+    }
+
+    /// Same as [`Encoder::encode`] but encodes `input` with the given QP instead of the one chosen by the encoder. The QP must be within 1-51 for AVC, HEVC and MPEG2 and 1-255 for VP9 and AV1, otherwise [`MfxStatus::InvalidVideoParam`] is returned.
+    ///
+    /// The override only applies to this call and only in [`RateControlMethod::CQP`] mode, other rate control methods ignore it.
+    #[cfg(feature = "async")]
+    pub async fn encode_with_qp(
+        &mut self,
+        controller: &mut EncodeCtrl,
+        input: Option<FrameSurface<'_>>,
+        output: &mut Bitstream<'_>,
+        qp: u16,
+    ) -> Result<usize, MfxStatus> {
+        let params = self.params()?;
+
+        let max_qp = match params.codec() {
+            Codec::AVC | Codec::HEVC | Codec::MPEG2 => 51,
+            Codec::VP9 | Codec::AV1 => 255,
+            _ => return Err(MfxStatus::Unsupported),
+        };
+        if !(1..=max_qp).contains(&qp) {
+            return Err(MfxStatus::InvalidVideoParam);
+        }
+
+        if params.rate_control_method() != Some(RateControlMethod::CQP) {
+            warn!(
+                "Per frame QP is ignored with {:?} rate control",
+                params.rate_control_method()
+            );
+        }
+
+        let previous_qp = controller.inner.QP;
+        controller.set_qp(qp);
+        let result = self.encode(controller, input, output, None).await;
+        controller.inner.QP = previous_qp;
+
+        result
+    }
+
+    /// Same as [`Encoder::encode`] but synchronizes on the current thread
+    /// instead of requiring an async runtime.
+    #[cfg(feature = "blocking")]
+    pub fn encode_blocking(
+        &mut self,
+        controller: &mut EncodeCtrl,
+        mut input: Option<FrameSurface<'_>>,
+        output: &mut Bitstream<'_>,
+        timeout: Option<u32>,
+    ) -> Result<usize, MfxStatus> {
+        let encode_start = Instant::now();
+        let buffer_start_size = output.size();
+
+        let retry = self.session.busy_retry();
+        let sync_point = retry.run(|| self.queue_encode(controller, input.as_mut(), output))?;
+
+        self.sync_frame(sync_point, timeout)?;
+
+        trace!("Encoded frame: {:?}", encode_start.elapsed());
+
+        let bytes_written = output.size() - buffer_start_size;
+        Ok(bytes_written as usize)
+    }
+
+    /// Encodes several frames, keeping up to AsyncDepth of them in flight instead of waiting on every frame like [`Encoder::encode`] does, which caps throughput.
+    ///
+    /// Every in flight frame is encoded into one of AsyncDepth temporary buffers, which are reused for the whole call. Once a frame is done its bitstream is appended to `output`, so `output` needs room for the whole batch. The encoder outputs frames in encoded order, which differs from the order they were given in when it reorders B frames, and holds some back. So the bytes written are returned per output frame, in the order they were written. Frames still held back come out of later calls or when draining with [`Encoder::encode`].
+    #[cfg(feature = "async")]
+    pub async fn encode_batch(
+        &mut self,
+        frames: Vec<(EncodeCtrl, FrameSurface<'_>)>,
+        output: &mut Bitstream<'_>,
+    ) -> Result<Vec<usize>, MfxStatus> {
+        let encode_start = Instant::now();
+        let async_depth = usize::max(self.params()?.async_depth() as usize, 1);
+        let codec = output.codec();
+
+        let retry = self.session.busy_retry();
+
+        let mut buffers = vec![vec![0u8; self.suggested_buffer_size]; async_depth];
+        let mut bitstreams: Vec<_> = buffers
+            .iter_mut()
+            .map(|buffer| Bitstream::with_codec(buffer, codec))
+            .collect();
+        // Indices into bitstreams
+        let mut free: Vec<_> = (0..async_depth).collect();
+        // The output of a submission belongs to its sync point and bitstream, not to the frame
+        // that was submitted. Outputs complete in the order their sync points were returned.
+        let mut in_flight = VecDeque::with_capacity(async_depth);
+
+        let mut bytes_written = Vec::with_capacity(frames.len());
+        for (mut ctrl, mut surface) in frames {
+            if free.is_empty() {
+                let (sync_point, index) = in_flight.pop_front().unwrap();
+                bytes_written.push(self.finish_output(sync_point, &mut bitstreams[index], output)?);
+                free.push(index);
+            }
+
+            let index = *free.last().unwrap();
+            let bitstream = &mut bitstreams[index];
+            match retry
+                .run_async(|| self.queue_encode(&mut ctrl, Some(&mut surface), bitstream))
+                .await
+            {
+                Ok(sync_point) => {
+                    free.pop();
+                    in_flight.push_back((sync_point, index));
+                }
+                // The frame was buffered, no output yet so the bitstream stays free
+                Err(MfxStatus::MoreData) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        for (sync_point, index) in in_flight {
+            bytes_written.push(self.finish_output(sync_point, &mut bitstreams[index], output)?);
+        }
+
+        trace!("Encoded {} frames: {:?}", bytes_written.len(), encode_start.elapsed());
+
+        Ok(bytes_written)
+    }
+
+    /// Waits for the frame behind `sync_point` and moves it from `bitstream` to `output`.
+    #[cfg(feature = "async")]
+    fn finish_output(
+        &self,
+        sync_point: ffi::mfxSyncPoint,
+        bitstream: &mut Bitstream<'_>,
+        output: &mut Bitstream<'_>,
+    ) -> Result<usize, MfxStatus> {
+        task::block_in_place(|| self.sync_frame(sync_point, None))?;
+
+        // Fails if output is full
+        bitstream
+            .drain_to(output)
+            .map_err(|_| MfxStatus::NotEnoughBuffer)
+    }
+
+    /// Drains the frames the encoder still has cached into `output` and closes the encoder. Dropping the encoder closes it straight away, losing those frames.
+    ///
+    /// Returns the number of bytes written to output. On error the encoder is still closed, so anything not yet drained is lost.
+    #[cfg(feature = "async")]
+    pub async fn finish(mut self, output: &mut Bitstream<'_>) -> Result<usize, MfxStatus> {
+        let mut bytes_written = 0;
+        loop {
+            let mut ctrl = EncodeCtrl::new();
+            match self.encode(&mut ctrl, None, output, None).await {
+                Ok(bytes) => bytes_written += bytes,
+                Err(MfxStatus::MoreData) => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(bytes_written)
+    }
+
     /// Returns a surface which can be used as input for the encoder.
     ///
+    /// Only works when the library allocates the input surfaces. Returns [`MfxStatus::Unsupported`] when the session has an external allocator (See [`Session::set_allocator`]) and the encoder takes [`IoPattern::IN_VIDEO_MEMORY`], or when the encoder's IoPattern has no input memory type. Pass your own surfaces to [`Encoder::encode`] in that case.
+    ///
     /// See
     /// https://spec.oneapi.io/versions/latest/elements/oneVPL/source/API_ref/VPL_func_mem.html?highlight=getsurfaceforencode#mfxmemory-getsurfaceforencode
     /// for more info.
     pub fn get_surface<'c: 'a>(&mut self) -> Result<FrameSurface<'c>, MfxStatus> {
+        if self.session.allocator.is_some() && self.io_pattern.contains(IoPattern::IN_VIDEO_MEMORY)
+        {
+            warn!("get_surface can't be used with an external allocator in video memory, the surfaces belong to the allocator. Pass your own surfaces to encode instead.");
+            return Err(MfxStatus::Unsupported);
+        }
+        if !self
+            .io_pattern
+            .intersects(IoPattern::IN_SYSTEM_MEMORY | IoPattern::IN_VIDEO_MEMORY)
+        {
+            warn!(
+                "get_surface needs an input IoPattern, the encoder was set up with {:?}. Pass your own surfaces to encode instead.",
+                self.io_pattern
+            );
+            return Err(MfxStatus::Unsupported);
+        }
+
         let lib = get_library().unwrap();
         let session = self.session.inner.0;
 
@@ -152,7 +654,7 @@ impl<'a, 'b: 'a> Encoder<'a, 'b> {
         let status: MfxStatus =
             unsafe { lib.MFXMemory_GetSurfaceForEncode(session, &mut raw_surface) }.into();
 
-        trace!("Encode get surface = {:?}", status);
+        report_status("MFXMemory_GetSurfaceForEncode", status);
 
         if status != MfxStatus::NoneOrDone {
             return Err(status);
@@ -165,6 +667,8 @@ impl<'a, 'b: 'a> Encoder<'a, 'b> {
 
     /// Stops the current encoding operation and restores internal structures or parameters for a new encoding operation, possibly with new parameters.
     ///
+    /// New parameters (Eg. a higher bitrate) can raise the size needed for the output bitstream. A warning is logged when that happens, check [`Encoder::required_bitstream_len`] afterwards and grow your [`Bitstream`] if needed, otherwise encoded data is dropped once it is full.
+    ///
     /// See https://spec.oneapi.io/versions/latest/elements/oneVPL/source/API_ref/VPL_func_vid_encode.html#mfxvideoencode-reset for more info.
     pub fn reset(&mut self, mut params: MfxVideoParams) -> Result<(), MfxStatus> {
         let lib = get_library().unwrap();
@@ -172,18 +676,37 @@ impl<'a, 'b: 'a> Encoder<'a, 'b> {
 
         let status: MfxStatus = unsafe { lib.MFXVideoENCODE_Reset(session, &mut **params) }.into();
 
-        trace!("Decode reset = {:?}", status);
+        report_status("MFXVideoENCODE_Reset", status);
 
         if status != MfxStatus::NoneOrDone {
             return Err(status);
         }
 
+        if let Some(brc) = params.ext_buffer::<ExternalBrc>() {
+            self._external_brc = Some(brc.clone());
+        }
+        self.variable_framerate = params.is_variable_framerate();
+
         let params = self.params()?;
+        let previous_buffer_size = self.suggested_buffer_size;
         self.suggested_buffer_size = params.suggested_buffer_size();
+        self.encoded_order = params.encode_order() != 0;
+
+        if self.suggested_buffer_size > previous_buffer_size {
+            warn!(
+                "Required bitstream size grew from {} to {} bytes after reset",
+                previous_buffer_size, self.suggested_buffer_size
+            );
+        }
 
         Ok(())
     }
 
+    /// The size output [`Bitstream`]s need to be to hold any encoded frame with the current parameters. Updated by [`Encoder::reset`].
+    pub fn required_bitstream_len(&self) -> usize {
+        self.suggested_buffer_size
+    }
+
     /// Obtains statistics collected during encoding.
     ///
     /// See https://spec.oneapi.io/versions/latest/elements/oneVPL/source/API_ref/VPL_func_vid_encode.html#mfxvideoencode-getencodestat for more info.
@@ -201,7 +724,7 @@ impl<'a, 'b: 'a> Encoder<'a, 'b> {
         let status: MfxStatus =
             unsafe { lib.MFXVideoENCODE_GetEncodeStat(session, &mut stats) }.into();
 
-        trace!("Encode reset = {:?}", status);
+        report_status("MFXVideoENCODE_GetEncodeStat", status);
 
         if status != MfxStatus::NoneOrDone {
             return Err(status);
@@ -222,7 +745,7 @@ impl<'a, 'b: 'a> Encoder<'a, 'b> {
         let status: MfxStatus =
             unsafe { lib.MFXVideoENCODE_GetVideoParam(session, &mut **params) }.into();
 
-        trace!("Encode get params = {:?}", status);
+        report_status("MFXVideoENCODE_GetVideoParam", status);
 
         if status != MfxStatus::NoneOrDone {
             return Err(status);
@@ -250,7 +773,7 @@ impl<'a, 'b: 'a> Encoder<'a, 'b> {
         let status: MfxStatus =
             unsafe { lib.MFXVideoENCODE_Query(session, input_params, &mut **params) }.into();
 
-        trace!("Encoder query = {:?}", status);
+        report_status("MFXVideoENCODE_Query", status);
 
         if status != MfxStatus::NoneOrDone {
             return Err((status, params));
@@ -267,3 +790,1522 @@ impl Drop for Encoder<'_, '_> {
         unsafe { lib.MFXVideoENCODE_Close(session) };
     }
 }
+
+#[cfg(all(test, feature = "async"))]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use tracing_test::traced_test;
+
+    use crate::{
+        bitstream::Bitstream,
+        brc::{BitrateController, BrcFrameCtrl, BrcFrameParam, BrcFrameStatus},
+        constants::{
+            AvcLevel, AvcProfile, BrcStatus, ChromaFormat, Codec, CodingOptionValue, ContentInfo,
+            FourCC, FrameType, IntraRefreshType, IoPattern, MemoryFlag, PRefType,
+            PartialBitstreamGranularity, PicStruct, RateControlMethod, TargetUsage,
+        },
+        frameallocator::FrameAllocator,
+        get_library,
+        utils::{hw_align_height, hw_align_width},
+        videoparams::ExtBuffer,
+        AcceleratorHandle, FrameSurface, Loader, MfxStatus, MfxVideoParams,
+    };
+
+    use super::{
+        AvcRefListCtrl, ChromaLocInfo, EncodeCtrl, EncodeOutput, Encoder, ExtraCodingOption1,
+        ExtraCodingOption2, ExtraCodingOption3, HrdConfig, PartialBitstreamParam,
+        FRAME_ORDER_UNKNOWN,
+    };
+
+    const WIDTH: u16 = 320;
+    const HEIGHT: u16 = 180;
+
+    /// Software encoder params for the 320x180 I420 frames in tests/frozen180.yuv
+    fn software_params(codec: Codec) -> MfxVideoParams {
+        let mut params = MfxVideoParams::default();
+        params.set_codec(codec);
+        params.set_target_usage(TargetUsage::Level4);
+        params.set_rate_control_method(RateControlMethod::VBR);
+        params.set_target_kbps(1000);
+        params.set_framerate(24000, 1001).unwrap();
+        params.set_fourcc(FourCC::IyuvOrI420);
+        params.set_chroma_format(ChromaFormat::YUV420);
+        params.set_io_pattern(IoPattern::IN_SYSTEM_MEMORY);
+        params.set_width(WIDTH);
+        params.set_height(HEIGHT);
+        params.set_crop(WIDTH, HEIGHT);
+        params
+    }
+
+    #[traced_test]
+    #[test]
+    fn encoder_init_with_coding_option1() {
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+        loader.require_encoder(Codec::AVC);
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut params = software_params(Codec::AVC);
+        let mut option1 = ExtraCodingOption1::default();
+        option1.set_au_delimiter(CodingOptionValue::On);
+        option1.set_pic_timing_sei(CodingOptionValue::On);
+        params.add_ext_buffer(option1);
+        assert_eq!(params.num_ext_buffers(), 1);
+
+        let _encoder = session.encoder(params).unwrap();
+    }
+
+    #[traced_test]
+    #[test]
+    fn encoder_init_with_screen_content() {
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+        loader.require_encoder(Codec::HEVC);
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut params = software_params(Codec::HEVC);
+        params.set_content_info(ContentInfo::NonVideoScreen);
+        let option3 = params.ext_buffer::<ExtraCodingOption3>().unwrap();
+        assert_eq!(option3.ContentInfo, ContentInfo::NonVideoScreen.repr() as u16);
+
+        // Setting it again reuses the attached buffer
+        params.set_content_info(ContentInfo::NonVideoScreen);
+        assert_eq!(params.num_ext_buffers(), 1);
+
+        let _encoder = session.encoder(params).unwrap();
+    }
+
+    #[traced_test]
+    #[test]
+    fn encoder_init_with_chroma_loc() {
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+        loader.require_encoder(Codec::HEVC);
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut params = software_params(Codec::HEVC);
+        params.set_chroma_loc(0);
+        let chroma_loc = params.ext_buffer::<ChromaLocInfo>().unwrap();
+        assert_eq!(chroma_loc.ChromaLocInfoPresentFlag, 1);
+
+        let _encoder = session.encoder(params).unwrap();
+    }
+
+    #[traced_test]
+    #[test]
+    fn encoder_init_with_look_ahead() {
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+        loader.require_encoder(Codec::AVC);
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut params = software_params(Codec::AVC);
+        params.set_rate_control_method(RateControlMethod::LA);
+        params.set_look_ahead_depth(20);
+        let option2 = params.ext_buffer::<ExtraCodingOption2>().unwrap();
+        assert_eq!(option2.LookAheadDepth, 20);
+
+        // There's no way to get the statistics out of oneVPL
+        assert_eq!(params.set_look_ahead_statistics(true), Err(MfxStatus::Unsupported));
+        assert_eq!(params.set_look_ahead_statistics(false), Ok(()));
+
+        match session.encoder(params) {
+            Ok(_) => {}
+            // Look ahead is a hardware feature
+            Err(
+                e @ (MfxStatus::Unsupported
+                | MfxStatus::InvalidVideoParam
+                | MfxStatus::IncompatibleVideoParam),
+            ) => tracing::warn!("Skipping, look ahead not supported: {:?}", e),
+            Err(e) => panic!("{:?}", e),
+        }
+    }
+
+    #[traced_test]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn encode_in_encoded_order() {
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+        loader.require_encoder(Codec::HEVC);
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut params = software_params(Codec::HEVC);
+        params.set_encode_order(1);
+        // I and P frames only so encoded order is the same as display order
+        params.set_gop_ref_dist(1);
+        params.set_gop_pic_size(10);
+
+        let mut encoder = session.encoder(params).unwrap();
+        let mut buffer = vec![0u8; encoder.params().unwrap().suggested_buffer_size()];
+        let mut bitstream = Bitstream::with_codec(&mut buffer, Codec::HEVC);
+
+        let frame_size = FrameSurface::frame_size(FourCC::IyuvOrI420, WIDTH, HEIGHT);
+        let mut decode_timestamps = Vec::new();
+
+        for i in 0..10u32 {
+            let mut surface = encoder.get_surface().unwrap();
+            let mut source = std::io::Cursor::new(vec![i as u8; frame_size]);
+            surface
+                .read_raw_frame(&mut source, FourCC::IyuvOrI420)
+                .await
+                .unwrap();
+            surface.set_frame_order(i);
+            // 90KHz clock at 30 fps
+            surface.set_timestamp(i as u64 * 3000);
+
+            let mut ctrl = EncodeCtrl::new();
+            ctrl.set_frame_type(match i {
+                0 => FrameType::I | FrameType::IDR | FrameType::REF,
+                _ => FrameType::P | FrameType::REF,
+            });
+
+            match encoder.encode(&mut ctrl, Some(surface), &mut bitstream, None).await {
+                Ok(bytes) if bytes > 0 => {
+                    decode_timestamps.push(bitstream.decode_timestamp());
+                    bitstream.set_size(0);
+                }
+                Ok(_) | Err(MfxStatus::MoreData) => {}
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+
+        loop {
+            let mut ctrl = EncodeCtrl::new();
+            match encoder.encode(&mut ctrl, None, &mut bitstream, None).await {
+                Ok(_) => {
+                    decode_timestamps.push(bitstream.decode_timestamp());
+                    bitstream.set_size(0);
+                }
+                Err(MfxStatus::MoreData) => break,
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+
+        assert_eq!(decode_timestamps.len(), 10);
+        assert!(decode_timestamps.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[traced_test]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn encoder_finish_drains_cached_frames() {
+        const FRAMES: u8 = 8;
+
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+        loader.require_encoder(Codec::HEVC);
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut params = software_params(Codec::HEVC);
+        // B frames make the encoder hold on to frames
+        params.set_gop_ref_dist(4);
+
+        let mut encoder = session.encoder(params).unwrap();
+        let mut buffer = vec![0u8; 1024 * 1024];
+        let mut bitstream = Bitstream::with_codec(&mut buffer, Codec::HEVC);
+
+        let mut bytes_before_finish = 0;
+        for i in 0..FRAMES {
+            let surface = flat_frame(&mut encoder, i * 8).await;
+            let mut ctrl = EncodeCtrl::new();
+            match encoder.encode(&mut ctrl, Some(surface), &mut bitstream, None).await {
+                Ok(bytes) => bytes_before_finish += bytes,
+                Err(MfxStatus::MoreData) => {}
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+
+        let drained = encoder.finish(&mut bitstream).await.unwrap();
+        assert!(drained > 0);
+        assert_eq!(bitstream.size() as usize, bytes_before_finish + drained);
+
+        // One slice per frame
+        let frames = bitstream.nal_units().filter(|nal| nal.nal_type < 32).count();
+        assert_eq!(frames, FRAMES as usize);
+    }
+
+    #[traced_test]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn encode_variable_framerate() {
+        // 90KHz clock with irregular gaps, like screen capture that only sends frames on change
+        const TIMESTAMPS: [u64; 6] = [0, 1500, 3000, 12000, 12750, 30000];
+
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+        loader.require_encoder(Codec::HEVC);
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut params = software_params(Codec::HEVC);
+        params.set_rate_control_method(RateControlMethod::CQP);
+        params.set_qpi(30);
+        params.set_qpp(30);
+        params.set_gop_ref_dist(1);
+        params.set_variable_framerate();
+        assert!(params.is_variable_framerate());
+
+        let mut encoder = match session.encoder(params) {
+            Ok(encoder) => encoder,
+            Err(e @ (MfxStatus::InvalidVideoParam | MfxStatus::IncompatibleVideoParam)) => {
+                tracing::warn!("Skipping, variable frame rate not supported: {:?}", e);
+                return;
+            }
+            Err(e) => panic!("{:?}", e),
+        };
+        let mut buffer = vec![0u8; 1024 * 1024];
+        let mut bitstream = Bitstream::with_codec(&mut buffer, Codec::HEVC);
+
+        let mut timestamps = Vec::new();
+        let mut decode_timestamps = Vec::new();
+        for (i, timestamp) in TIMESTAMPS.iter().enumerate() {
+            let mut surface = flat_frame(&mut encoder, i as u8 * 8).await;
+            surface.set_timestamp(*timestamp);
+
+            let mut ctrl = EncodeCtrl::new();
+            match encoder.encode(&mut ctrl, Some(surface), &mut bitstream, None).await {
+                Ok(bytes) if bytes > 0 => {
+                    timestamps.push(bitstream.timestamp());
+                    decode_timestamps.push(bitstream.decode_timestamp());
+                    bitstream.set_size(0);
+                }
+                Ok(_) | Err(MfxStatus::MoreData) => {}
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+        loop {
+            let mut ctrl = EncodeCtrl::new();
+            match encoder.encode(&mut ctrl, None, &mut bitstream, None).await {
+                Ok(_) => {
+                    timestamps.push(bitstream.timestamp());
+                    decode_timestamps.push(bitstream.decode_timestamp());
+                    bitstream.set_size(0);
+                }
+                Err(MfxStatus::MoreData) => break,
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+
+        // No B frames, so frames come out in the order they went in with their own timestamps
+        assert_eq!(timestamps, TIMESTAMPS);
+        assert!(
+            decode_timestamps.windows(2).all(|w| w[0] < w[1]),
+            "{:?}",
+            decode_timestamps
+        );
+
+        // Back to a fixed rate, frames without a timestamp no longer warn
+        let mut params = encoder.params().unwrap();
+        params.set_framerate(24000, 1001).unwrap();
+        encoder.reset(params).unwrap();
+        assert!(!encoder.variable_framerate);
+    }
+
+    #[traced_test]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn encode_propagates_timestamp() {
+        const TIMESTAMP: u64 = 90_000;
+
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+        loader.require_encoder(Codec::HEVC);
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut encoder = session.encoder(software_params(Codec::HEVC)).unwrap();
+        let mut buffer = vec![0u8; encoder.params().unwrap().suggested_buffer_size()];
+        let mut bitstream = Bitstream::with_codec(&mut buffer, Codec::HEVC);
+
+        let frame_size = FrameSurface::frame_size(FourCC::IyuvOrI420, WIDTH, HEIGHT);
+        let mut surface = encoder.get_surface().unwrap();
+        let mut source = std::io::Cursor::new(vec![0u8; frame_size]);
+        surface
+            .read_raw_frame(&mut source, FourCC::IyuvOrI420)
+            .await
+            .unwrap();
+        surface.set_timestamp(TIMESTAMP);
+
+        let mut ctrl = EncodeCtrl::new();
+        let mut bytes = match encoder.encode(&mut ctrl, Some(surface), &mut bitstream, None).await {
+            Ok(bytes) => bytes,
+            Err(MfxStatus::MoreData) => 0,
+            Err(e) => panic!("{:?}", e),
+        };
+
+        // The frame may have been cached, drain it
+        while bytes == 0 {
+            let mut ctrl = EncodeCtrl::new();
+            bytes = encoder
+                .encode(&mut ctrl, None, &mut bitstream, None)
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(bitstream.timestamp(), TIMESTAMP);
+    }
+
+    #[traced_test]
+    #[test]
+    fn encoder_init_with_profile_and_level() {
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+        loader.require_encoder(Codec::AVC);
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut params = software_params(Codec::AVC);
+        params.set_codec_profile(AvcProfile::High);
+        params.set_codec_level(AvcLevel::Level4_1);
+        assert_eq!(params.level(), 41);
+
+        let encoder = session.encoder(params).unwrap();
+        let params = encoder.params().unwrap();
+        assert_eq!(params.profile(), u16::from(AvcProfile::High));
+        assert_eq!(params.level(), u16::from(AvcLevel::Level4_1));
+    }
+
+    #[traced_test]
+    #[test]
+    fn encoder_negotiated_frame_info() {
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+        loader.require_encoder(Codec::HEVC);
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut params = software_params(Codec::HEVC);
+        params.set_width(hw_align_width(WIDTH));
+        params.set_height(hw_align_height(HEIGHT, PicStruct::Progressive));
+
+        let encoder = session.encoder(params).unwrap();
+        let params = encoder.params().unwrap();
+
+        let info = params.frame_info();
+        assert_eq!(info.width(), hw_align_width(WIDTH));
+        assert_eq!(info.crop(), (WIDTH, HEIGHT));
+        assert_eq!(params.io_pattern(), IoPattern::IN_SYSTEM_MEMORY);
+    }
+
+    #[traced_test]
+    #[test]
+    fn encoder_get_surface_with_external_allocator() {
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+        loader.require_encoder(Codec::HEVC);
+
+        let mut session = loader.new_session(0).unwrap();
+        let mut allocator = FrameAllocator::new();
+        // The surfaces would come from here, but the encoder should never get to ask for them
+        allocator.set_alloc_callback(Box::new(|_, _| MfxStatus::MemoryAlloc));
+        session.set_allocator(allocator).unwrap();
+
+        let mut params = software_params(Codec::HEVC);
+        params.set_io_pattern(IoPattern::IN_VIDEO_MEMORY);
+
+        let mut encoder = match session.encoder(params) {
+            Ok(encoder) => encoder,
+            Err(
+                e @ (MfxStatus::Unsupported
+                | MfxStatus::InvalidVideoParam
+                | MfxStatus::IncompatibleVideoParam
+                | MfxStatus::MemoryAlloc),
+            ) => {
+                tracing::warn!("Skipping, video memory input not supported: {:?}", e);
+                return;
+            }
+            Err(e) => panic!("{:?}", e),
+        };
+
+        assert_eq!(encoder.get_surface().err(), Some(MfxStatus::Unsupported));
+    }
+
+    /// Fills a new encoder surface with a flat frame
+    async fn flat_frame<'a>(encoder: &mut super::Encoder<'a, '_>, value: u8) -> FrameSurface<'a> {
+        let frame_size = FrameSurface::frame_size(FourCC::IyuvOrI420, WIDTH, HEIGHT);
+        let mut surface = encoder.get_surface().unwrap();
+        let mut source = std::io::Cursor::new(vec![value; frame_size]);
+        surface
+            .read_raw_frame(&mut source, FourCC::IyuvOrI420)
+            .await
+            .unwrap();
+        surface
+    }
+
+    /// A frame of noise that changes with `seed`, so no frame compresses well
+    async fn noise_frame<'a>(
+        encoder: &mut super::Encoder<'a, '_>,
+        seed: usize,
+    ) -> FrameSurface<'a> {
+        let frame_size = FrameSurface::frame_size(FourCC::IyuvOrI420, WIDTH, HEIGHT);
+        let frame: Vec<u8> = (0..frame_size)
+            .map(|j| ((j * 7) ^ (j / 13) ^ (seed * 31)) as u8)
+            .collect();
+        let mut surface = encoder.get_surface().unwrap();
+        surface
+            .read_raw_frame(&mut std::io::Cursor::new(&frame), FourCC::IyuvOrI420)
+            .await
+            .unwrap();
+        surface
+    }
+
+    /// The `T` extension buffer as the encoder actually uses it, which drops options the implementation doesn't support
+    fn negotiated<T: ExtBuffer + Default + Clone>(encoder: &super::Encoder<'_, '_>) -> T {
+        let lib = get_library().unwrap();
+        let mut params = MfxVideoParams::default();
+        params.add_ext_buffer(T::default());
+        let status: MfxStatus = unsafe {
+            lib.MFXVideoENCODE_GetVideoParam(encoder.session.inner.0, &mut **params)
+        }
+        .into();
+        assert_eq!(status, MfxStatus::NoneOrDone);
+        params.ext_buffer::<T>().unwrap().clone()
+    }
+
+    /// Drains the encoder and returns the number of bytes it output
+    async fn drain(encoder: &mut super::Encoder<'_, '_>, bitstream: &mut Bitstream<'_>) -> usize {
+        let mut total = 0;
+        loop {
+            let mut ctrl = EncodeCtrl::new();
+            match encoder.encode(&mut ctrl, None, bitstream, None).await {
+                Ok(bytes) => total += bytes,
+                Err(MfxStatus::MoreData) => break total,
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+    }
+
+    #[traced_test]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn encode_rejects_read_only_bitstream() {
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+        loader.require_encoder(Codec::HEVC);
+
+        let session = loader.new_session(0).unwrap();
+        let mut encoder = session.encoder(software_params(Codec::HEVC)).unwrap();
+        let buffer = vec![0u8; encoder.params().unwrap().suggested_buffer_size()];
+        let mut bitstream = Bitstream::from_slice(&buffer, Codec::HEVC);
+        bitstream.clear();
+
+        let surface = flat_frame(&mut encoder, 128).await;
+        let result = encoder
+            .encode(&mut EncodeCtrl::new(), Some(surface), &mut bitstream, None)
+            .await;
+        assert_eq!(result, Err(MfxStatus::Unsupported));
+        assert!(buffer.iter().all(|&b| b == 0));
+    }
+
+    #[traced_test]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn encode_rejects_mapped_surface() {
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+        loader.require_encoder(Codec::HEVC);
+
+        let session = loader.new_session(0).unwrap();
+        let mut encoder = session.encoder(software_params(Codec::HEVC)).unwrap();
+        let mut buffer = vec![0u8; encoder.params().unwrap().suggested_buffer_size()];
+        let mut bitstream = Bitstream::with_codec(&mut buffer, Codec::HEVC);
+
+        // Mapped for writing and never unmapped
+        let mut surface = flat_frame(&mut encoder, 128).await;
+        surface.map(MemoryFlag::WRITE).unwrap();
+        assert!(surface.is_mapped());
+        let result = encoder
+            .encode(&mut EncodeCtrl::new(), Some(surface), &mut bitstream, None)
+            .await;
+        assert_eq!(result, Err(MfxStatus::ResourceMapped));
+        assert_eq!(bitstream.size(), 0);
+
+        // A short read is unmapped again too
+        let frame_size = FrameSurface::frame_size(FourCC::IyuvOrI420, WIDTH, HEIGHT);
+        let mut surface = encoder.get_surface().unwrap();
+        let mut source = std::io::Cursor::new(vec![128u8; frame_size / 2]);
+        assert_eq!(
+            surface.read_raw_frame(&mut source, FourCC::IyuvOrI420).await,
+            Err(MfxStatus::MoreData)
+        );
+        assert!(!surface.is_mapped());
+        drop(surface);
+
+        // Fine once unmapped
+        let mut surface = flat_frame(&mut encoder, 128).await;
+        surface.map(MemoryFlag::WRITE).unwrap();
+        surface.unmap().unwrap();
+        assert!(!surface.is_mapped());
+        match encoder
+            .encode(&mut EncodeCtrl::new(), Some(surface), &mut bitstream, None)
+            .await
+        {
+            Ok(_) | Err(MfxStatus::MoreData) => {}
+            Err(e) => panic!("{:?}", e),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[traced_test]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn encode_sessions_share_accelerator() {
+        let handle = Arc::new(AcceleratorHandle::vaapi_from_file(None).unwrap());
+        let display = *handle.handle();
+
+        let mut loader1 = Loader::with_shared_accelerator(handle.clone()).unwrap();
+        loader1.use_hardware(true);
+        loader1.require_encoder(Codec::HEVC);
+        let mut loader2 = Loader::with_shared_accelerator(handle.clone()).unwrap();
+        loader2.use_hardware(true);
+        loader2.require_encoder(Codec::HEVC);
+
+        let sessions = [
+            loader1.new_session(0).unwrap(),
+            loader2.new_session(0).unwrap(),
+        ];
+        // Held by both loaders, both sessions and this test
+        assert_eq!(Arc::strong_count(&handle), 5);
+
+        for session in &sessions {
+            assert_eq!(session.va_display(), Some(display));
+            assert_eq!(session.handle(handle.mfx_type()), Ok(display));
+
+            let mut encoder = session.encoder(software_params(Codec::HEVC)).unwrap();
+            let mut buffer = vec![0u8; encoder.params().unwrap().suggested_buffer_size()];
+            let mut bitstream = Bitstream::with_codec(&mut buffer, Codec::HEVC);
+
+            let mut bytes = 0;
+            for value in 0..5 {
+                let surface = flat_frame(&mut encoder, value * 40).await;
+                match encoder
+                    .encode(&mut EncodeCtrl::new(), Some(surface), &mut bitstream, None)
+                    .await
+                {
+                    Ok(written) => bytes += written,
+                    Err(MfxStatus::MoreData) => {}
+                    Err(e) => panic!("{:?}", e),
+                }
+                bitstream.clear();
+            }
+            bytes += drain(&mut encoder, &mut bitstream).await;
+            assert!(bytes > 0);
+        }
+
+        drop(sessions);
+        drop(loader1);
+        drop(loader2);
+        assert_eq!(Arc::strong_count(&handle), 1);
+    }
+
+    #[traced_test]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn encode_with_max_frame_size() {
+        const FRAMES: usize = 10;
+        const MAX_FRAME_SIZE: u32 = 3000;
+
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+        loader.require_encoder(Codec::HEVC);
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut params = software_params(Codec::HEVC);
+        // Far more than the frame size allows
+        params.set_target_kbps(20000);
+        params.set_gop_ref_dist(1);
+        params.set_async_depth(1);
+        let mut option2 = ExtraCodingOption2::default();
+        option2.set_max_frame_size(MAX_FRAME_SIZE);
+        params.add_ext_buffer(option2);
+        let mut option3 = ExtraCodingOption3::default();
+        option3.set_max_frame_size_i(MAX_FRAME_SIZE);
+        option3.set_max_frame_size_p(MAX_FRAME_SIZE);
+        params.add_ext_buffer(option3);
+
+        let mut encoder = match session.encoder(params) {
+            Ok(encoder) => encoder,
+            Err(MfxStatus::Unsupported | MfxStatus::InvalidVideoParam) => {
+                tracing::warn!("Skipping, max frame size is not supported by this runtime");
+                return;
+            }
+            Err(e) => panic!("{:?}", e),
+        };
+        // Implementations without support accept the params but drop the limit
+        let option2: ExtraCodingOption2 = negotiated(&encoder);
+        if option2.max_frame_size() == 0 {
+            tracing::warn!("Skipping, the encoder dropped the max frame size");
+            return;
+        }
+        let option3: ExtraCodingOption3 = negotiated(&encoder);
+        tracing::debug!(
+            "Max frame size {}, I {}, P {}",
+            option2.max_frame_size(),
+            option3.max_frame_size_i(),
+            option3.max_frame_size_p()
+        );
+
+        let mut buffer = vec![0u8; encoder.params().unwrap().suggested_buffer_size()];
+        let mut bitstream = Bitstream::with_codec(&mut buffer, Codec::HEVC);
+
+        let mut sizes = Vec::new();
+        for i in 0..FRAMES {
+            let surface = noise_frame(&mut encoder, i).await;
+
+            bitstream.clear();
+            match encoder
+                .encode(&mut EncodeCtrl::new(), Some(surface), &mut bitstream, None)
+                .await
+            {
+                Ok(bytes) => sizes.push(bytes),
+                Err(MfxStatus::MoreData) => {}
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+
+        assert!(!sizes.is_empty());
+        tracing::debug!("Frame sizes = {:?}", sizes);
+        // The limit isn't exact, allow going a little above it
+        let tolerance = MAX_FRAME_SIZE as usize * 11 / 10;
+        assert!(sizes.iter().all(|size| *size <= tolerance), "{:?}", sizes);
+    }
+
+    #[traced_test]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn encode_with_max_slice_size() {
+        const FRAMES: usize = 4;
+        const MAX_SLICE_SIZE: u32 = 1200;
+
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+        loader.require_encoder(Codec::HEVC);
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut params = software_params(Codec::HEVC);
+        params.set_target_kbps(20000);
+        params.set_gop_ref_dist(1);
+        let mut option2 = ExtraCodingOption2::default();
+        option2.set_max_slice_size(MAX_SLICE_SIZE);
+        assert_eq!(option2.max_slice_size(), MAX_SLICE_SIZE);
+        params.add_ext_buffer(option2);
+
+        let mut encoder = match session.encoder(params) {
+            Ok(encoder) => encoder,
+            Err(MfxStatus::Unsupported | MfxStatus::InvalidVideoParam) => {
+                tracing::warn!("Skipping, max slice size is not supported by this runtime");
+                return;
+            }
+            Err(e) => panic!("{:?}", e),
+        };
+        let option2: ExtraCodingOption2 = negotiated(&encoder);
+        if option2.max_slice_size() == 0 {
+            tracing::warn!("Skipping, the encoder dropped the max slice size");
+            return;
+        }
+
+        let mut buffer = vec![0u8; 4 * 1024 * 1024];
+        let mut bitstream = Bitstream::with_codec(&mut buffer, Codec::HEVC);
+        for i in 0..FRAMES {
+            let surface = noise_frame(&mut encoder, i).await;
+            match encoder
+                .encode(&mut EncodeCtrl::new(), Some(surface), &mut bitstream, None)
+                .await
+            {
+                Ok(_) | Err(MfxStatus::MoreData) => {}
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+        drain(&mut encoder, &mut bitstream).await;
+
+        // Noise doesn't fit a frame in one slice of that size
+        let slices: Vec<_> = bitstream
+            .nal_units()
+            .filter(|nal| nal.nal_type < 32)
+            .map(|nal| nal.length)
+            .collect();
+        assert!(slices.len() > FRAMES, "{:?}", slices);
+        // The limit isn't exact, allow going a little above it
+        let tolerance = MAX_SLICE_SIZE as usize * 11 / 10;
+        assert!(slices.iter().all(|size| *size <= tolerance), "{:?}", slices);
+    }
+
+    #[traced_test]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn encode_with_qp_changes_frame_size() {
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+        loader.require_encoder(Codec::HEVC);
+
+        let session = loader.new_session(0).unwrap();
+
+        // A pattern so the frame doesn't compress to nothing at any QP
+        let frame_size = FrameSurface::frame_size(FourCC::IyuvOrI420, WIDTH, HEIGHT);
+        let frame: Vec<u8> = (0..frame_size).map(|i| ((i * 7) ^ (i / 13)) as u8).collect();
+
+        let mut sizes = Vec::new();
+        for qp in [10, 45] {
+            let mut params = software_params(Codec::HEVC);
+            params.set_rate_control_method(RateControlMethod::CQP);
+            params.set_qpi(30);
+            params.set_qpp(30);
+            // Intra only so both frames are coded the same way
+            params.set_gop_pic_size(1);
+
+            let mut encoder = session.encoder(params).unwrap();
+            let mut buffer = vec![0u8; encoder.params().unwrap().suggested_buffer_size()];
+            let mut bitstream = Bitstream::with_codec(&mut buffer, Codec::HEVC);
+
+            let mut surface = encoder.get_surface().unwrap();
+            surface
+                .read_raw_frame(&mut std::io::Cursor::new(&frame), FourCC::IyuvOrI420)
+                .await
+                .unwrap();
+
+            let mut ctrl = EncodeCtrl::new();
+            let mut size = match encoder
+                .encode_with_qp(&mut ctrl, Some(surface), &mut bitstream, qp)
+                .await
+            {
+                Ok(bytes) => bytes,
+                Err(MfxStatus::MoreData) => 0,
+                Err(e) => panic!("{:?}", e),
+            };
+            // The override only lasts for the call
+            assert_eq!(ctrl.inner.QP, 0);
+            size += drain(&mut encoder, &mut bitstream).await;
+            sizes.push(size);
+
+            assert_eq!(
+                encoder
+                    .encode_with_qp(&mut ctrl, None, &mut bitstream, 52)
+                    .await,
+                Err(MfxStatus::InvalidVideoParam)
+            );
+        }
+
+        // Lower QP means finer quantization and more bits
+        assert!(sizes[0] > sizes[1], "{:?}", sizes);
+    }
+
+    #[traced_test]
+    #[test]
+    fn encoder_reset_grows_required_bitstream_len() {
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+        loader.require_encoder(Codec::HEVC);
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut params = software_params(Codec::HEVC);
+        params.set_max_kbps(1000);
+        let mut encoder = session.encoder(params).unwrap();
+        let initial_len = encoder.required_bitstream_len();
+        assert_eq!(initial_len, encoder.params().unwrap().suggested_buffer_size());
+
+        // Reset can't go above the resolution the encoder was initialized with, raise the bitrate instead
+        let mut params = software_params(Codec::HEVC);
+        params.set_target_kbps(20000);
+        params.set_max_kbps(20000);
+        encoder.reset(params).unwrap();
+
+        assert!(
+            encoder.required_bitstream_len() > initial_len,
+            "{} <= {}",
+            encoder.required_bitstream_len(),
+            initial_len
+        );
+    }
+
+    #[traced_test]
+    #[test]
+    fn encoder_buffer_size_override() {
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+        loader.require_encoder(Codec::HEVC);
+
+        let session = loader.new_session(0).unwrap();
+
+        let default_len = session
+            .encoder(software_params(Codec::HEVC))
+            .unwrap()
+            .required_bitstream_len();
+
+        let requested_kb = (default_len / 1000 * 2) as u16;
+        let mut params = software_params(Codec::HEVC);
+        params.set_buffer_size_in_kb(requested_kb);
+        assert_eq!(params.suggested_buffer_size(), requested_kb as usize * 1000);
+
+        let encoder = session.encoder(params).unwrap();
+        let len = encoder.params().unwrap().suggested_buffer_size();
+        assert_eq!(len, encoder.required_bitstream_len());
+        // Either taken as is or clamped, but never below what the encoder picks itself
+        assert!(
+            default_len <= len && len <= requested_kb as usize * 1000,
+            "{} not within {}..={}",
+            len,
+            default_len,
+            requested_kb as usize * 1000
+        );
+    }
+
+    #[traced_test]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn encode_cbr_with_hrd() {
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+        loader.require_encoder(Codec::AVC);
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut params = software_params(Codec::AVC);
+        params.set_rate_control_method(RateControlMethod::CBR);
+        assert_eq!(
+            params.set_hrd(HrdConfig::new(1000, 500)),
+            Err(MfxStatus::InvalidVideoParam)
+        );
+        assert_eq!(
+            params.set_hrd(
+                HrdConfig::new(1000, 1000)
+                    .buffer_size_in_kb(100)
+                    .initial_delay_in_kb(200)
+            ),
+            Err(MfxStatus::InvalidVideoParam)
+        );
+        assert!(params.ext_buffer::<ExtraCodingOption1>().is_none());
+
+        params
+            .set_hrd(
+                HrdConfig::new(1000, 1000)
+                    .buffer_size_in_kb(250)
+                    .initial_delay_in_kb(125),
+            )
+            .unwrap();
+        assert_eq!(params.num_ext_buffers(), 1);
+
+        let mut encoder = session.encoder(params).unwrap();
+        let mut buffer = vec![0u8; 1024 * 1024];
+        let mut bitstream = Bitstream::with_codec(&mut buffer, Codec::AVC);
+
+        for i in 0..8 {
+            let surface = flat_frame(&mut encoder, i * 32).await;
+            match encoder
+                .encode(&mut EncodeCtrl::new(), Some(surface), &mut bitstream, None)
+                .await
+            {
+                Ok(_) | Err(MfxStatus::MoreData) => {}
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+        assert!(drain(&mut encoder, &mut bitstream).await > 0 || bitstream.size() > 0);
+    }
+
+    #[traced_test]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn encode_closed_gop_segments() {
+        const SEGMENT: u8 = 8;
+
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+        loader.require_encoder(Codec::HEVC);
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut params = software_params(Codec::HEVC);
+        params.set_gop_pic_size(SEGMENT as u16);
+        params.set_gop_ref_dist(4);
+        params.set_gop_opt_flag(true, true);
+
+        let mut encoder = session.encoder(params).unwrap();
+        let mut buffer = vec![0u8; 1024 * 1024];
+        let mut bitstream = Bitstream::with_codec(&mut buffer, Codec::HEVC);
+
+        for i in 0..SEGMENT * 3 {
+            let surface = flat_frame(&mut encoder, i * 8).await;
+            let mut ctrl = EncodeCtrl::new();
+            // Force a keyframe at the start of every segment
+            if i % SEGMENT == 0 {
+                ctrl.set_frame_type(FrameType::I | FrameType::IDR | FrameType::REF);
+            }
+            match encoder.encode(&mut ctrl, Some(surface), &mut bitstream, None).await {
+                Ok(_) | Err(MfxStatus::MoreData) => {}
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+        drain(&mut encoder, &mut bitstream).await;
+
+        let slice_types: Vec<_> = bitstream
+            .nal_units()
+            .map(|nal| nal.nal_type)
+            .filter(|nal_type| *nal_type < 32)
+            .collect();
+        // IDR_W_RADL or IDR_N_LP at the start of every segment
+        let idrs = slice_types.iter().filter(|t| matches!(t, 19 | 20)).count();
+        assert!(idrs >= 3, "{:?}", slice_types);
+        // RASL pictures reference frames from before the keyframe, a closed GOP has none
+        assert!(
+            !slice_types.iter().any(|t| matches!(t, 8 | 9)),
+            "{:?}",
+            slice_types
+        );
+    }
+
+    #[traced_test]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn encode_with_long_term_reference() {
+        const LTR_FRAME: u32 = 0;
+        const RECOVERY_FRAME: u32 = 4;
+
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+        loader.require_encoder(Codec::AVC);
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut params = software_params(Codec::AVC);
+        // Fixed QP so frame sizes only depend on how well a frame is predicted
+        params.set_rate_control_method(RateControlMethod::CQP);
+        params.set_qpi(30);
+        params.set_qpp(30);
+        params.set_gop_pic_size(u16::MAX);
+        params.set_gop_ref_dist(1);
+
+        let mut encoder = session.encoder(params).unwrap();
+        let mut buffer = vec![0u8; 1024 * 1024];
+        let mut bitstream = Bitstream::with_codec(&mut buffer, Codec::AVC);
+
+        // (keyframe, bytes) of every encoded frame
+        let mut frames = Vec::new();
+        for i in 0..=RECOVERY_FRAME {
+            // The recovery frame repeats the long-term reference, the frames in between don't
+            // predict it at all. So it only comes out small when it references the long-term
+            // reference.
+            let seed = if i == RECOVERY_FRAME { LTR_FRAME } else { i };
+            let mut surface = noise_frame(&mut encoder, seed as usize).await;
+            surface.set_frame_order(i);
+
+            let mut ref_list = AvcRefListCtrl::default();
+            if i == LTR_FRAME {
+                ref_list.add_long_term(LTR_FRAME).unwrap();
+            }
+            if i == RECOVERY_FRAME {
+                // Pretend everything since the long-term reference was lost
+                ref_list.add_preferred(LTR_FRAME).unwrap();
+                for lost in LTR_FRAME + 1..RECOVERY_FRAME {
+                    ref_list.add_rejected(lost).unwrap();
+                }
+            }
+            let mut ctrl = EncodeCtrl::new();
+            ctrl.set_ref_list_ctrl(ref_list);
+
+            match encoder.encode(&mut ctrl, Some(surface), &mut bitstream, None).await {
+                Ok(bytes) => frames.push((bitstream.is_keyframe(), bytes)),
+                Err(MfxStatus::MoreData) => {}
+                Err(e @ (MfxStatus::Unsupported | MfxStatus::InvalidVideoParam)) => {
+                    tracing::warn!("Skipping, reference list control not supported: {:?}", e);
+                    return;
+                }
+                Err(e) => panic!("{:?}", e),
+            }
+            bitstream.clear();
+        }
+        loop {
+            let mut ctrl = EncodeCtrl::new();
+            match encoder.encode(&mut ctrl, None, &mut bitstream, None).await {
+                Ok(bytes) => frames.push((bitstream.is_keyframe(), bytes)),
+                Err(MfxStatus::MoreData) => break,
+                Err(e) => panic!("{:?}", e),
+            }
+            bitstream.clear();
+        }
+
+        // No B frames, so frames come out in the order they went in
+        assert_eq!(frames.len(), RECOVERY_FRAME as usize + 1);
+        let (ltr_keyframe, ltr_bytes) = frames[LTR_FRAME as usize];
+        let (recovery_keyframe, recovery_bytes) = frames[RECOVERY_FRAME as usize];
+        assert!(ltr_keyframe);
+        // Recovered from the long-term reference rather than by forcing a keyframe, which would
+        // cost about as much as the long-term reference itself
+        assert!(!recovery_keyframe);
+        assert!(
+            recovery_bytes < ltr_bytes / 4,
+            "recovery frame is {} bytes, long-term reference {} bytes",
+            recovery_bytes,
+            ltr_bytes
+        );
+
+        let mut ref_list = AvcRefListCtrl::default();
+        assert_eq!(ref_list.PreferredRefList[0].FrameOrder, FRAME_ORDER_UNKNOWN);
+        for i in 0..16 {
+            ref_list.add_rejected(i).unwrap();
+        }
+        assert_eq!(ref_list.add_rejected(16), Err(MfxStatus::NotEnoughBuffer));
+    }
+
+    #[traced_test]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn encode_reports_keyframes() {
+        const FRAMES: u8 = 4;
+
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+        loader.require_encoder(Codec::HEVC);
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut params = software_params(Codec::HEVC);
+        params.set_gop_pic_size(FRAMES as u16);
+        // No B frames so every frame after the first is a P frame
+        params.set_gop_ref_dist(1);
+
+        let mut encoder = session.encoder(params).unwrap();
+        let mut buffer = vec![0u8; 1024 * 1024];
+        let mut bitstream = Bitstream::with_codec(&mut buffer, Codec::HEVC);
+
+        let mut frame_types = Vec::new();
+        for i in 0..FRAMES {
+            let surface = flat_frame(&mut encoder, i * 8).await;
+            let mut ctrl = EncodeCtrl::new();
+            match encoder.encode(&mut ctrl, Some(surface), &mut bitstream, None).await {
+                Ok(_) => frame_types.push((bitstream.is_keyframe(), bitstream.frame_type())),
+                Err(MfxStatus::MoreData) => {}
+                Err(e) => panic!("{:?}", e),
+            }
+            bitstream.clear();
+        }
+        loop {
+            let mut ctrl = EncodeCtrl::new();
+            match encoder.encode(&mut ctrl, None, &mut bitstream, None).await {
+                Ok(_) => frame_types.push((bitstream.is_keyframe(), bitstream.frame_type())),
+                Err(MfxStatus::MoreData) => break,
+                Err(e) => panic!("{:?}", e),
+            }
+            bitstream.clear();
+        }
+
+        assert_eq!(frame_types.len(), FRAMES as usize);
+        assert!(frame_types[0].0, "{:?}", frame_types);
+        for (is_keyframe, frame_type) in &frame_types[1..] {
+            assert!(!is_keyframe, "{:?}", frame_types);
+            assert!(frame_type.contains(FrameType::P), "{:?}", frame_types);
+        }
+    }
+
+    #[traced_test]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn encode_multiple_slices() {
+        const FRAMES: u8 = 4;
+
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+        loader.require_encoder(Codec::HEVC);
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut params = software_params(Codec::HEVC);
+        params.set_num_slice(4);
+        params.set_gop_ref_dist(1);
+
+        let mut encoder = session.encoder(params).unwrap();
+        let mut buffer = vec![0u8; 1024 * 1024];
+        let mut bitstream = Bitstream::with_codec(&mut buffer, Codec::HEVC);
+
+        for i in 0..FRAMES {
+            let surface = flat_frame(&mut encoder, i * 32).await;
+            match encoder
+                .encode(&mut EncodeCtrl::new(), Some(surface), &mut bitstream, None)
+                .await
+            {
+                Ok(_) | Err(MfxStatus::MoreData) => {}
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+        drain(&mut encoder, &mut bitstream).await;
+
+        // VCL NAL units are the slices
+        let slices = bitstream.nal_units().filter(|nal| nal.nal_type < 32).count();
+        assert_eq!(slices, FRAMES as usize * 4);
+    }
+
+    #[traced_test]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn encode_partial_output() {
+        const FRAMES: u8 = 4;
+
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+        loader.require_encoder(Codec::HEVC);
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut params = software_params(Codec::HEVC);
+        params.set_num_slice(4);
+        params.set_gop_ref_dist(1);
+        params.set_async_depth(1);
+        let mut partial = PartialBitstreamParam::default();
+        partial.set_granularity(PartialBitstreamGranularity::Slice);
+        params.add_ext_buffer(partial);
+
+        let mut encoder = match session.encoder(params) {
+            Ok(encoder) => encoder,
+            Err(MfxStatus::Unsupported | MfxStatus::InvalidVideoParam) => {
+                tracing::warn!("Skipping, partial output is not supported by this runtime");
+                return;
+            }
+            Err(e) => panic!("{:?}", e),
+        };
+        let mut buffer = vec![0u8; 1024 * 1024];
+        let mut bitstream = Bitstream::with_codec(&mut buffer, Codec::HEVC);
+
+        // Number of parts each output frame was returned in
+        let mut frame_parts = Vec::new();
+        for i in 0.. {
+            // Drain the encoder once all frames have been submitted
+            let surface = match i < FRAMES {
+                true => Some(flat_frame(&mut encoder, i * 32).await),
+                false => None,
+            };
+            let draining = surface.is_none();
+            let mut output = match encoder
+                .encode_partial(&mut EncodeCtrl::new(), surface, &mut bitstream, None)
+                .await
+            {
+                Ok(output) => output,
+                Err(MfxStatus::MoreData) if draining => break,
+                Err(MfxStatus::MoreData) => continue,
+                Err(e) => panic!("{:?}", e),
+            };
+
+            let mut parts = 1;
+            while let EncodeOutput::Partial(bytes) = output {
+                assert!(bytes > 0);
+                output = encoder.next_partial(&mut bitstream, None).await.unwrap();
+                parts += 1;
+            }
+            frame_parts.push(parts);
+        }
+
+        assert_eq!(frame_parts.len(), FRAMES as usize);
+        // One part per slice, give or take how the slices line up with the syncs
+        assert!(frame_parts.iter().all(|parts| *parts > 1), "{:?}", frame_parts);
+
+        // Nothing is pending once the frame is complete
+        assert_eq!(
+            encoder.next_partial(&mut bitstream, None).await,
+            Err(MfxStatus::UndefinedBehavior)
+        );
+    }
+
+    #[traced_test]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn encoded_units_match_nal_units() {
+        const FRAMES: u8 = 3;
+
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+        loader.require_encoder(Codec::HEVC);
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut params = software_params(Codec::HEVC);
+        params.set_gop_ref_dist(1);
+        params.set_num_slice(2);
+        let mut option3 = ExtraCodingOption3::default();
+        option3.set_encoded_units_info(CodingOptionValue::On);
+        params.add_ext_buffer(option3);
+
+        let mut encoder = match session.encoder(params) {
+            Ok(encoder) => encoder,
+            Err(MfxStatus::Unsupported | MfxStatus::InvalidVideoParam) => {
+                tracing::warn!("Skipping, encoded units info is not supported by this runtime");
+                return;
+            }
+            Err(e) => panic!("{:?}", e),
+        };
+        let mut buffer = vec![0u8; 1024 * 1024];
+        let mut bitstream = Bitstream::with_codec(&mut buffer, Codec::HEVC);
+        bitstream.attach_encoded_units_info();
+
+        let mut frames = 0;
+        for i in 0..FRAMES {
+            // One frame per check so the units line up with the data in the bitstream
+            bitstream.clear();
+            let surface = flat_frame(&mut encoder, i * 32).await;
+            match encoder
+                .encode(&mut EncodeCtrl::new(), Some(surface), &mut bitstream, None)
+                .await
+            {
+                Ok(_) => {}
+                Err(MfxStatus::MoreData) => continue,
+                Err(e) => panic!("{:?}", e),
+            }
+
+            let units = bitstream.encoded_units();
+            let nal_units: Vec<_> = bitstream.nal_units().collect();
+            assert_eq!(units.len(), nal_units.len());
+            for (unit, nal) in units.iter().zip(nal_units) {
+                assert_eq!(unit.unit_type, nal.nal_type as u16);
+                // The unit starts at the start code, the NAL unit after it
+                assert!(unit.offset < nal.offset && nal.offset < unit.offset + unit.length);
+            }
+            let total: usize = units.iter().map(|unit| unit.length).sum();
+            assert_eq!(total, bitstream.size() as usize);
+            frames += 1;
+        }
+        assert!(frames > 0);
+    }
+
+    #[traced_test]
+    #[test]
+    fn encoder_new_with_query_returns_corrected_params() {
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+        loader.require_encoder(Codec::HEVC);
+
+        let session = loader.new_session(0).unwrap();
+
+        // The crop can't be larger than the frame
+        let mut params = software_params(Codec::HEVC);
+        params.set_crop(WIDTH + 16, HEIGHT + 16);
+
+        let (status, corrected) = match Encoder::new_with_query(&session, params.clone()) {
+            Ok(_) => panic!("encoder accepted a crop larger than the frame"),
+            Err(e) => e,
+        };
+        assert!(
+            matches!(
+                status,
+                MfxStatus::IncompatibleVideoParam | MfxStatus::InvalidVideoParam
+            ),
+            "{:?}",
+            status
+        );
+        assert_ne!(corrected.crop(), params.crop());
+    }
+
+    #[traced_test]
+    #[test]
+    fn encoder_init_with_gpb() {
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+        loader.require_encoder(Codec::HEVC);
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut params = software_params(Codec::HEVC);
+        params.set_gop_ref_dist(1);
+        let mut option3 = ExtraCodingOption3::default();
+        option3.set_gpb(CodingOptionValue::On);
+        option3.set_pref_type(PRefType::Pyramid);
+        params.add_ext_buffer(option3);
+
+        let option3 = params.ext_buffer::<ExtraCodingOption3>().unwrap();
+        assert!(matches!(option3.gpb(), Some(CodingOptionValue::On)));
+        assert_eq!(option3.pref_type(), Some(PRefType::Pyramid));
+
+        let _encoder = session.encoder(params).unwrap();
+    }
+
+    #[traced_test]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn encode_with_intra_refresh() {
+        const FRAMES: u8 = 30;
+
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+        loader.require_encoder(Codec::HEVC);
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut params = software_params(Codec::HEVC);
+        // Only the first frame should be a keyframe
+        params.set_gop_pic_size(u16::MAX);
+        params.set_gop_ref_dist(1);
+        let mut option2 = ExtraCodingOption2::default();
+        option2.set_intra_refresh(IntraRefreshType::Vertical, 10, 0);
+        params.add_ext_buffer(option2);
+        let mut option3 = ExtraCodingOption3::default();
+        option3.set_intra_refresh_cycle_dist(10);
+        params.add_ext_buffer(option3);
+
+        let mut encoder = session.encoder(params).unwrap();
+        let mut buffer = vec![0u8; 1024 * 1024];
+        let mut bitstream = Bitstream::with_codec(&mut buffer, Codec::HEVC);
+
+        for i in 0..FRAMES {
+            let surface = flat_frame(&mut encoder, i * 8).await;
+            match encoder
+                .encode(&mut EncodeCtrl::new(), Some(surface), &mut bitstream, None)
+                .await
+            {
+                Ok(_) | Err(MfxStatus::MoreData) => {}
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+        drain(&mut encoder, &mut bitstream).await;
+
+        // IDR_W_RADL or IDR_N_LP
+        let idrs = bitstream
+            .nal_units()
+            .filter(|nal| matches!(nal.nal_type, 19 | 20))
+            .count();
+        assert_eq!(idrs, 1);
+    }
+
+    #[traced_test]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn encode_batch_matches_individual_encodes() {
+        const FRAMES: u8 = 16;
+
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+        loader.require_encoder(Codec::HEVC);
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut params = software_params(Codec::HEVC);
+        params.set_async_depth(4);
+        // B frames make the encoder output frames in a different order than they were given in
+        params.set_gop_ref_dist(4);
+
+        // One frame at a time
+        let individual = {
+            let mut encoder = session.encoder(params.clone()).unwrap();
+            let buffer_size = encoder.params().unwrap().suggested_buffer_size();
+            let mut buffer = vec![0u8; buffer_size * FRAMES as usize];
+            let mut bitstream = Bitstream::with_codec(&mut buffer, Codec::HEVC);
+
+            for i in 0..FRAMES {
+                let surface = flat_frame(&mut encoder, i * 8).await;
+                let mut ctrl = EncodeCtrl::new();
+                match encoder.encode(&mut ctrl, Some(surface), &mut bitstream, None).await {
+                    Ok(_) | Err(MfxStatus::MoreData) => {}
+                    Err(e) => panic!("{:?}", e),
+                }
+            }
+            drain(&mut encoder, &mut bitstream).await;
+
+            let mut output = Vec::new();
+            bitstream.drain_to(&mut output).unwrap();
+            output
+        };
+
+        let batch = {
+            let mut encoder = session.encoder(params).unwrap();
+            let buffer_size = encoder.params().unwrap().suggested_buffer_size();
+            let mut buffer = vec![0u8; buffer_size * FRAMES as usize];
+            let mut bitstream = Bitstream::with_codec(&mut buffer, Codec::HEVC);
+
+            let mut frames = Vec::new();
+            for i in 0..FRAMES {
+                frames.push((EncodeCtrl::new(), flat_frame(&mut encoder, i * 8).await));
+            }
+            let bytes = encoder.encode_batch(frames, &mut bitstream).await.unwrap();
+            // Frames held back for reordering come out when draining
+            assert!(bytes.len() <= FRAMES as usize, "{:?}", bytes);
+            assert!(bytes.iter().all(|&bytes| bytes > 0), "{:?}", bytes);
+            assert_eq!(bitstream.size() as usize, bytes.iter().sum::<usize>());
+            drain(&mut encoder, &mut bitstream).await;
+
+            let mut output = Vec::new();
+            bitstream.drain_to(&mut output).unwrap();
+            output
+        };
+
+        assert!(!individual.is_empty());
+        // Same frames in the same order
+        assert!(individual == batch);
+    }
+
+    /// Encodes every frame with the same QP and counts the frames it was asked about
+    struct ConstantQp {
+        qp: i32,
+        frames: Arc<AtomicUsize>,
+    }
+
+    impl BitrateController for ConstantQp {
+        fn frame_ctrl(&mut self, _frame: &BrcFrameParam, ctrl: &mut BrcFrameCtrl) -> MfxStatus {
+            ctrl.set_qp(self.qp);
+            MfxStatus::NoneOrDone
+        }
+
+        fn update(
+            &mut self,
+            frame: &BrcFrameParam,
+            ctrl: &BrcFrameCtrl,
+            status: &mut BrcFrameStatus,
+        ) -> MfxStatus {
+            assert_eq!(ctrl.qp(), self.qp);
+            assert_ne!(frame.coded_frame_size(), 0);
+            self.frames.fetch_add(1, Ordering::SeqCst);
+            status.set_status(BrcStatus::Ok);
+            MfxStatus::NoneOrDone
+        }
+    }
+
+    #[traced_test]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn encode_with_external_brc() {
+        const FRAMES: u8 = 10;
+
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+        loader.require_encoder(Codec::AVC);
+
+        let session = loader.new_session(0).unwrap();
+
+        let frames = Arc::new(AtomicUsize::new(0));
+        let mut params = software_params(Codec::AVC);
+        params.set_external_brc(Box::new(ConstantQp {
+            qp: 30,
+            frames: frames.clone(),
+        }));
+        assert_eq!(params.num_ext_buffers(), 2);
+
+        let mut encoder = match session.encoder(params) {
+            Ok(encoder) => encoder,
+            // External BRC is only implemented by the hardware encoders
+            Err(
+                e @ (MfxStatus::Unsupported
+                | MfxStatus::InvalidVideoParam
+                | MfxStatus::IncompatibleVideoParam),
+            ) => {
+                tracing::warn!("Skipping, external BRC not supported: {:?}", e);
+                return;
+            }
+            Err(e) => panic!("{:?}", e),
+        };
+        let mut buffer = vec![0u8; encoder.required_bitstream_len() * FRAMES as usize];
+        let mut bitstream = Bitstream::with_codec(&mut buffer, Codec::AVC);
+
+        let mut encoded = 0;
+        for i in 0..FRAMES {
+            let surface = flat_frame(&mut encoder, i * 8).await;
+            match encoder
+                .encode(&mut EncodeCtrl::new(), Some(surface), &mut bitstream, None)
+                .await
+            {
+                Ok(_) => encoded += 1,
+                Err(MfxStatus::MoreData) => {}
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+        loop {
+            match encoder
+                .encode(&mut EncodeCtrl::new(), None, &mut bitstream, None)
+                .await
+            {
+                Ok(_) => encoded += 1,
+                Err(MfxStatus::MoreData) => break,
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+
+        assert_eq!(encoded, FRAMES as usize);
+        assert_eq!(frames.load(Ordering::SeqCst), encoded);
+    }
+}
@@ -233,6 +233,7 @@ impl FrameAllocRequest<'_> {
             inner: &mut self.inner.Info,
         }
     }
+    /// The memory type and which component the frames are for. Bits this crate doesn't know about are kept, so this is always `Some`.
     pub fn type_(&self) -> Option<ExtMemFrameType> {
         ExtMemFrameType::try_from(self.inner.Type as ffi::_bindgen_ty_36).ok()
     }
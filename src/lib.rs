@@ -1,17 +1,22 @@
-use std::ffi::c_void;
+use std::ffi::{c_void, CString};
 use std::fmt::Debug;
 use std::fs::File;
 use std::io::Read;
 use std::marker::PhantomData;
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
 use std::{
     io::{self, Write},
     mem,
     ops::Deref,
+    time::Duration,
 };
 
 use bitstream::Bitstream;
-use constants::{ApiVersion, FourCC, IoPattern, PicStruct, Codec, MfxImpl};
+use constants::{
+    AccelerationMode, ApiVersion, Codec, Component, FourCC, ImplementationType, IoPattern, MfxImpl,
+    PicStruct,
+};
 use decode::Decoder;
 use encode::Encoder;
 pub use ffi::MfxStatus;
@@ -23,22 +28,25 @@ use frameallocator::FrameAllocator;
 use intel_onevpl_sys as ffi;
 
 use once_cell::sync::OnceCell;
-use tokio::sync::Mutex;
 #[cfg(target_os = "linux")]
 use tracing::error;
 use tracing::{debug, trace, warn};
-use utils::SharedPtr;
-pub use videoparams::MfxVideoParams;
+use utils::{InFlightPermit, SharedPtr};
+pub use videoparams::{ExtBuffer, MfxVideoParams};
 use vpp::VideoProcessor;
 
-use crate::constants::{ChromaFormat, MemoryFlag};
+use crate::constants::{ChromaFormat, MemId, MemoryFlag};
 use crate::utils::str_from_null_terminated_utf8_i8;
 
 pub mod bitstream;
+pub mod brc;
 pub mod constants;
+mod convert;
 pub mod decode;
 pub mod encode;
 pub mod frameallocator;
+#[cfg(feature = "async")]
+pub mod pipeline;
 #[cfg(test)]
 mod tests;
 pub mod utils;
@@ -46,12 +54,24 @@ mod videoparams;
 pub mod vpp;
 
 static LIBRARY: OnceCell<ffi::vpl> = OnceCell::new();
+/// Set by [`set_library_path`], or to `None` by [`get_library`] when it loads the library by name first. Either way it can only be set once, so the path can't change while the library is being loaded.
+static LIBRARY_PATH: OnceCell<Option<PathBuf>> = OnceCell::new();
+static STATUS_HOOK: RwLock<Option<StatusHook>> = RwLock::new(None);
+
+/// Called with the name of the oneVPL function and the status it returned. See [`set_status_hook`].
+pub type StatusHook = Box<dyn Fn(&str, MfxStatus) + Send + Sync>;
 
 // The loader object remembers all created mfxConfig objects and destroys them during the mfxUnload function call.
 #[derive(Debug)]
 pub struct Loader {
     inner: mfxLoader,
-    accelerator: Option<AcceleratorHandle>,
+    accelerator: Option<Arc<AcceleratorHandle>>,
+    /// Strings passed to the dispatcher as filter properties
+    filter_strings: Vec<CString>,
+    /// The filter of [`Loader::use_hardware`] and the value it was set to, [`Loader::new_session_with_fallback`] changes it and puts it back
+    impl_filter: Option<(Config, ImplementationType)>,
+    /// Same for [`Loader::require_acceleration_mode`]
+    acceleration_filter: Option<(Config, AccelerationMode)>,
 }
 unsafe impl Send for Loader {}
 
@@ -60,13 +80,19 @@ impl Loader {
     pub fn new() -> Result<Self, MfxStatus> {
         let lib = get_library().unwrap();
         let loader = unsafe { lib.MFXLoad() };
+        // Returns a handle rather than a status, report a null handle the way other calls report failure
         if loader.is_null() {
+            report_status("MFXLoad", MfxStatus::Unknown);
             return Err(MfxStatus::Unknown);
         }
+        report_status("MFXLoad", MfxStatus::NoneOrDone);
 
         let mut loader = Self {
             inner: loader,
             accelerator: None,
+            filter_strings: Vec::new(),
+            impl_filter: None,
+            acceleration_filter: None,
         };
 
         debug!("New loader created");
@@ -83,6 +109,15 @@ impl Loader {
         Ok(loader)
     }
 
+    /// Creates a loader that hands `handle` to every session it creates. Create one loader per stream from clones of the same handle to run several sessions on a single VA display instead of opening the device once per stream.
+    ///
+    /// The display is terminated once the last loader or session holding it is dropped.
+    pub fn with_shared_accelerator(handle: Arc<AcceleratorHandle>) -> Result<Self, MfxStatus> {
+        let mut loader = Self::new()?;
+        loader.set_accelerator(handle)?;
+        Ok(loader)
+    }
+
     pub fn new_config(&mut self) -> Result<Config, MfxStatus> {
         Config::new(self)
     }
@@ -91,8 +126,91 @@ impl Loader {
         Session::new(self, index)
     }
 
-    /// Usually you want to open `/dev/dri/renderD128` and pass that in a [`AcceleratorHandle::VAAPI`].
-    pub fn set_accelerator(&mut self, handle: AcceleratorHandle) -> Result<(), MfxStatus> {
+    /// Same as [`Loader::new_session`] but hands the loader over to the session. The returned [`SharedSession`] doesn't borrow anything, so the components created from it can be passed to [`tokio::spawn`]. The loader is unloaded once the session and every component created from it have been dropped.
+    pub fn into_shared_session(self, index: mfxU32) -> Result<SharedSession<'static>, MfxStatus> {
+        let loader = Arc::new(self);
+        let (session, partially_accelerated) = Session::create(&loader, index)?;
+        let mut session = Session::from_raw(&loader, index, session, partially_accelerated);
+        session.owned_loader = Some(loader);
+        Ok(session.into_shared())
+    }
+
+    /// Prefers hardware but accepts software. Creates a session on the `index`th hardware implementation (using the acceleration mode from [`Loader::require_acceleration_mode`] if one was set) and if that fails, on the `index`th software implementation instead. Returns which of the two the session runs on.
+    ///
+    /// The filters of [`Loader::use_hardware`] and [`Loader::require_acceleration_mode`] are lifted while looking for an implementation and put back afterwards, so sessions created later are unaffected.
+    pub fn new_session_with_fallback<'a: 'b, 'b>(
+        &'a mut self,
+        index: mfxU32,
+    ) -> Result<(Session<'b>, ImplementationType), MfxStatus> {
+        let hardware = self.create_session_on(ImplementationType::HARDWARE, index);
+        let (implementation, created) = match hardware {
+            Ok(created) => (ImplementationType::HARDWARE, Ok(created)),
+            Err(status) => {
+                warn!("No hardware session ({:?}), falling back to software", status);
+                // Software implementations don't have an acceleration mode
+                let lifted = match &self.acceleration_filter {
+                    Some((config, _)) => config.replace_filter_property(
+                        "mfxImplDescription.AccelerationMode",
+                        AccelerationMode::NA,
+                        None,
+                    ),
+                    None => Ok(()),
+                };
+                let created = lifted
+                    .and_then(|_| self.create_session_on(ImplementationType::SOFTWARE, index));
+                (ImplementationType::SOFTWARE, created)
+            }
+        };
+        let restored = self.restore_filters();
+
+        let (impl_index, session, partially_accelerated) = created?;
+        // Dropping the session closes it again if the filters couldn't be restored
+        let session = Session::from_raw(self, impl_index, session, partially_accelerated);
+        restored?;
+
+        Ok((session, implementation))
+    }
+
+    /// Creates a raw session on the `index`th implementation of type `implementation` the other filters let through. Returns the index of the implementation among all of them along with the session.
+    fn create_session_on(
+        &self,
+        implementation: ImplementationType,
+        index: mfxU32,
+    ) -> Result<(mfxU32, mfxSession, bool), MfxStatus> {
+        if let Some((config, _)) = &self.impl_filter {
+            config.replace_filter_property("mfxImplDescription.Impl", implementation, None)?;
+        }
+
+        let impl_index = self
+            .implementations()?
+            .iter()
+            .enumerate()
+            .filter(|(_, description)| description.implementation_type() == implementation)
+            .nth(index as usize)
+            .map(|(i, _)| i as mfxU32)
+            .ok_or(MfxStatus::NotFound)?;
+
+        let (session, partially_accelerated) = Session::create(self, impl_index)?;
+        Ok((impl_index, session, partially_accelerated))
+    }
+
+    /// Puts the filters [`Loader::new_session_with_fallback`] changed back to the values the user set.
+    fn restore_filters(&self) -> Result<(), MfxStatus> {
+        if let Some((config, implementation)) = &self.impl_filter {
+            config.replace_filter_property("mfxImplDescription.Impl", *implementation, None)?;
+        }
+        if let Some((config, mode)) = &self.acceleration_filter {
+            config.replace_filter_property("mfxImplDescription.AccelerationMode", *mode, None)?;
+        }
+        Ok(())
+    }
+
+    /// Usually you want to open `/dev/dri/renderD128` and pass that in a [`AcceleratorHandle::VAAPI`]. Sessions created afterwards keep the handle alive, pass an `Arc` to share it with other loaders (see [`Loader::with_shared_accelerator`]).
+    pub fn set_accelerator(
+        &mut self,
+        handle: impl Into<Arc<AcceleratorHandle>>,
+    ) -> Result<(), MfxStatus> {
+        let handle = handle.into();
         self.set_filter_property("mfxHandleType", handle.mfx_type(), None)?;
         self.set_filter_property("mfxHDL", *handle.handle(), None)?;
 
@@ -117,38 +235,7 @@ impl Loader {
     pub fn implementations(
         &self,
     ) -> Result<Vec<ImplDescription<'_>>, MfxStatus> {
-        use std::ptr::null_mut;
-        let mut caps = null_mut();
-        let format = constants::ImplementationCapabilitiesDeliverFormat::Description;
-        let mut status = MfxStatus::NoneOrDone;
-        let mut implementations = Vec::new();
-
-        let lib = get_library().unwrap();
-
-        while status == MfxStatus::NoneOrDone {
-            status = unsafe {
-                lib.MFXEnumImplementations(
-                    self.inner,
-                    implementations.len().try_into().unwrap(),
-                    format.repr(),
-                    &mut caps,
-                )
-            }
-            .into();
-
-            if status == MfxStatus::NotFound {
-                break;
-            }
-            if status != MfxStatus::NoneOrDone {
-                return Err(status);
-            }
-
-            let impl_description = unsafe { ImplDescription::from(&self, caps) };
-
-            implementations.push(impl_description);
-        }
-
-        return Ok(implementations);
+        unsafe { enum_implementations(self.inner) }
     }
 
     /// Instructs the loader only to look for hardware based implementations
@@ -157,8 +244,14 @@ impl Loader {
             true => constants::ImplementationType::HARDWARE,
             false => constants::ImplementationType::SOFTWARE,
         };
-        self.set_filter_property("mfxImplDescription.Impl", value, None)
+        let config = match self.impl_filter.take() {
+            Some((config, _)) => config,
+            None => self.new_config().unwrap(),
+        };
+        config
+            .replace_filter_property("mfxImplDescription.Impl", value, None)
             .unwrap();
+        self.impl_filter = Some((config, value));
     }
     pub fn use_api_version(&mut self, major: u16, minor: u16) {
         self
@@ -187,6 +280,43 @@ impl Loader {
         )
         .unwrap();
     }
+    /// Only look for implementations running on the GPU with this PCI device ID (Eg. `0x9a49`). Useful to pick a specific GPU when there are several, see [`ImplDescription::device_id`] for the IDs available.
+    pub fn require_device(&mut self, device_id: u16) {
+        self
+        .set_filter_property(
+            "mfxImplDescription.mfxDeviceDescription.device.DeviceID",
+            device_id,
+            None,
+        )
+        .unwrap();
+    }
+    /// Only look for implementations whose [`ImplDescription::keywords`] contain `keyword`. Several keywords can be given separated by commas, each gets its own filter so all of them have to be present. The dispatcher looks for every keyword as a substring of the implementation's keywords, so `"VPL"` also matches `"oneVPL"`.
+    ///
+    /// Panics if `keyword` contains a nul byte.
+    pub fn require_keyword(&mut self, keyword: &str) {
+        for keyword in keyword.split(',').map(str::trim).filter(|k| !k.is_empty()) {
+            let keyword = CString::new(keyword).unwrap();
+            self
+            .set_filter_property(
+                "mfxImplDescription.Keywords",
+                keyword.as_ptr() as *mut c_void,
+                None,
+            )
+            .unwrap();
+            self.filter_strings.push(keyword);
+        }
+    }
+    /// Only look for implementations using this acceleration stack. Eg. [`AccelerationMode::VAAPIDrmModeset`] to insist on DRM over X11 on a headless machine.
+    pub fn require_acceleration_mode(&mut self, mode: AccelerationMode) {
+        let config = match self.acceleration_filter.take() {
+            Some((config, _)) => config,
+            None => self.new_config().unwrap(),
+        };
+        config
+            .replace_filter_property("mfxImplDescription.AccelerationMode", mode, None)
+            .unwrap();
+        self.acceleration_filter = Some((config, mode));
+    }
 }
 
 impl Deref for Loader {
@@ -204,17 +334,61 @@ impl Drop for Loader {
     }
 }
 
+/// Lists the implementations `loader` finds with its current filters. `loader` has to outlive the returned descriptions.
+unsafe fn enum_implementations<'a>(
+    loader: mfxLoader,
+) -> Result<Vec<ImplDescription<'a>>, MfxStatus> {
+    use std::ptr::null_mut;
+    let mut caps = null_mut();
+    let format = constants::ImplementationCapabilitiesDeliverFormat::Description;
+    let mut status = MfxStatus::NoneOrDone;
+    let mut implementations = Vec::new();
+
+    let lib = get_library().unwrap();
+
+    while status == MfxStatus::NoneOrDone {
+        status = lib
+            .MFXEnumImplementations(
+                loader,
+                implementations.len().try_into().unwrap(),
+                format.repr(),
+                &mut caps,
+            )
+            .into();
+
+        report_status("MFXEnumImplementations", status);
+
+        if status == MfxStatus::NotFound {
+            break;
+        }
+        if status != MfxStatus::NoneOrDone {
+            return Err(status);
+        }
+
+        let impl_description = ImplDescription::from_raw(loader, caps);
+
+        implementations.push(impl_description);
+    }
+
+    Ok(implementations)
+}
+
 #[derive(Debug)]
 pub struct ImplDescription<'a> {
-    loader: &'a Loader,
+    loader: mfxLoader,
     inner: *mut ffi::mfxImplDescription,
+    phantom: PhantomData<&'a Loader>,
 }
 
 impl<'a> ImplDescription<'a> {
-    pub(crate) unsafe fn from(loader: &'a Loader, value: *mut c_void) -> Self {
+    unsafe fn from_raw(loader: mfxLoader, value: *mut c_void) -> Self {
         let raw = mem::transmute::<*mut c_void, *mut ffi::mfxImplDescription>(value);
 
-        Self { loader, inner: raw }
+        Self {
+            loader,
+            inner: raw,
+            phantom: PhantomData,
+        }
     }
     pub fn name(&self) -> &str {
         unsafe { str_from_null_terminated_utf8_i8(&(*self.inner).ImplName) }
@@ -225,6 +399,22 @@ impl<'a> ImplDescription<'a> {
     pub fn keywords(&self) -> &str {
         unsafe { str_from_null_terminated_utf8_i8(&(*self.inner).Keywords) }
     }
+    /// [`ImplDescription::keywords`] split into the individual keywords. Any of them can be passed to [`Loader::require_keyword`].
+    pub fn keyword_list(&self) -> Vec<&str> {
+        self.keywords()
+            .split(',')
+            .map(str::trim)
+            .filter(|keyword| !keyword.is_empty())
+            .collect()
+    }
+    /// Device ID of the adapter the implementation runs on, as a hex PCI device ID optionally followed by `/` and the adapter index (Eg. `9a49/0`).
+    pub fn device_id(&self) -> &str {
+        unsafe { str_from_null_terminated_utf8_i8(&(*self.inner).Dev.DeviceID) }
+    }
+    /// Whether this is a software or hardware implementation.
+    pub fn implementation_type(&self) -> ImplementationType {
+        ImplementationType::from_bits_truncate(unsafe { (*self.inner).Impl })
+    }
 }
 
 impl Drop for ImplDescription<'_> {
@@ -233,7 +423,7 @@ impl Drop for ImplDescription<'_> {
 
         unsafe {
             lib.MFXDispReleaseImplDescription(
-                self.loader.inner,
+                self.loader,
                 self.inner as *mut _ as *mut c_void,
             )
         };
@@ -250,16 +440,29 @@ impl Config {
         let lib = get_library().unwrap();
         let config = unsafe { lib.MFXCreateConfig(loader.inner) };
         if config.is_null() {
+            report_status("MFXCreateConfig", MfxStatus::Unknown);
             return Err(MfxStatus::Unknown);
         }
+        report_status("MFXCreateConfig", MfxStatus::NoneOrDone);
         return Ok(Self { inner: config });
     }
 
+    /// Sets the property the implementation must match. A config only holds one property, create a new one with [`Loader::new_config`] for every property you want to filter on.
     pub fn set_filter_property(
         self,
         name: &str,
         value: impl Into<utils::FilterProperty>,
         version: Option<mfxStructVersion>,
+    ) -> Result<(), MfxStatus> {
+        self.replace_filter_property(name, value, version)
+    }
+
+    /// Same as [`Config::set_filter_property`] but keeps the config, so the loader can change the value of a filter it holds on to. Setting a property again replaces the earlier value.
+    pub(crate) fn replace_filter_property(
+        &self,
+        name: &str,
+        value: impl Into<utils::FilterProperty>,
+        version: Option<mfxStructVersion>,
     ) -> Result<(), MfxStatus> {
         let lib = get_library().unwrap();
         let version = version.unwrap_or(mfxStructVersion {
@@ -283,6 +486,7 @@ impl Config {
 
         let status =
             unsafe { lib.MFXSetConfigFilterProperty(self.inner, name.as_ptr(), variant) }.into();
+        report_status("MFXSetConfigFilterProperty", status);
 
         debug!(
             "Setting filter property [{} = {:?}] : {:?}",
@@ -297,18 +501,94 @@ impl Config {
     }
 }
 
-#[derive(Debug)]
+/// A frame rate as a fraction, Eg. 30000/1001 for 29.97 FPS. See [`MfxVideoParams::set_framerate`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct FrameRate(u32, u32);
 
 impl FrameRate {
-    pub fn new(numerator: u32, denominator: u32) -> Self {
-        Self(numerator, denominator)
+    /// 0/0, the encoder relies on the timestamp of every frame instead. See [`MfxVideoParams::set_variable_framerate`].
+    pub const VARIABLE: Self = Self(0, 0);
+
+    /// Returns [`MfxStatus::InvalidVideoParam`] if `denominator` is 0, use [`FrameRate::VARIABLE`] for variable frame rate. Rates below 1 or above 1000 FPS are allowed but logged as a warning, they usually come from a swapped numerator and denominator.
+    pub fn new(numerator: u32, denominator: u32) -> Result<Self, MfxStatus> {
+        if denominator == 0 {
+            warn!(
+                "Tried to set frame rate {}/{}, use FrameRate::VARIABLE for variable frame rate",
+                numerator, denominator
+            );
+            return Err(MfxStatus::InvalidVideoParam);
+        }
+
+        Ok(Self::checked(numerator, denominator))
+    }
+
+    /// Warns about unusual rates, `denominator` must not be 0.
+    fn checked(numerator: u32, denominator: u32) -> Self {
+        let framerate = Self(numerator, denominator);
+        let fps = framerate.fps();
+        if !(1.0..=1000.0).contains(&fps) {
+            warn!("Unusual frame rate {}/{} ({} FPS)", numerator, denominator, fps);
+        }
+        framerate
+    }
+
+    /// Picks a fraction for `fps`. NTSC rates like 23.976, 29.97 and 59.94 become 24000/1001, 30000/1001 and 60000/1001, whole rates N/1 and anything else is rounded to a thousandth of a frame.
+    ///
+    /// Returns [`MfxStatus::InvalidVideoParam`] if `fps` is not a finite positive number or the fraction doesn't fit in a `u32`, use [`FrameRate::VARIABLE`] for variable frame rate.
+    pub fn from_fps(fps: f64) -> Result<Self, MfxStatus> {
+        if !fps.is_finite() || fps <= 0.0 || fps * 1001.0 > u32::MAX as f64 {
+            warn!("Tried to create a frame rate from {} FPS", fps);
+            return Err(MfxStatus::InvalidVideoParam);
+        }
+
+        if (fps - fps.round()).abs() < 1e-6 {
+            return Ok(Self::checked(fps.round() as u32, 1));
+        }
+
+        let ntsc = fps * 1001.0 / 1000.0;
+        if (ntsc - ntsc.round()).abs() < 0.01 {
+            return Ok(Self::checked(ntsc.round() as u32 * 1000, 1001));
+        }
+
+        let numerator = (fps * 1000.0).round() as u32;
+        if numerator == 0 {
+            warn!("Tried to create a frame rate from {} FPS", fps);
+            return Err(MfxStatus::InvalidVideoParam);
+        }
+        let (mut a, mut b) = (numerator, 1000);
+        while b != 0 {
+            (a, b) = (b, a % b);
+        }
+        Ok(Self::checked(numerator / a, 1000 / a))
+    }
+
+    pub fn numerator(&self) -> u32 {
+        self.0
+    }
+
+    pub fn denominator(&self) -> u32 {
+        self.1
+    }
+
+    /// Frames per second, 0 for [`FrameRate::VARIABLE`].
+    pub fn fps(&self) -> f64 {
+        if self.1 == 0 {
+            return 0.0;
+        }
+        self.0 as f64 / self.1 as f64
+    }
+
+    pub fn is_variable(&self) -> bool {
+        self.0 == 0 || self.1 == 0
     }
 }
 
-impl From<(u32, u32)> for FrameRate {
-    fn from(value: (u32, u32)) -> Self {
-        Self(value.0, value.1)
+/// Same as [`FrameRate::new`].
+impl TryFrom<(u32, u32)> for FrameRate {
+    type Error = MfxStatus;
+
+    fn try_from(value: (u32, u32)) -> Result<Self, Self::Error> {
+        Self::new(value.0, value.1)
     }
 }
 
@@ -336,7 +616,8 @@ impl From<(u32, u32)> for FrameRate {
 
 #[derive(Debug)]
 pub struct FrameSurfaceBounds {
-    pub pitch: u16,
+    /// See [`FrameSurface::pitch`]
+    pub pitch: u32,
     pub width: u16,
     pub height: u16,
     pub crop_x: u16,
@@ -352,6 +633,8 @@ pub struct FrameSurface<'a> {
     buffer: Arc<Mutex<Vec<u8>>>,
     // I'm not sure if mapping even needs to be tracked. It seems like calling release on a mapped frame surface works without first unmapping the frame surface.
     mapped: bool,
+    /// The AsyncDepth slot of a frame that was returned before it was ready, freed once it is synchronized or dropped
+    in_flight: Option<InFlightPermit<'a>>,
 }
 
 unsafe impl Send for FrameSurface<'_> {}
@@ -363,14 +646,24 @@ impl<'a> FrameSurface<'a> {
     ///
     /// [`Decoder::decode`] calls this automatically.
     pub fn synchronize(&mut self, timeout: Option<u32>) -> Result<(), MfxStatus> {
-        let timeout = timeout.unwrap_or(100);
-        let sync_func = self.interface().Synchronize.unwrap();
-        let status: MfxStatus = unsafe { sync_func(self.inner, timeout) }.into();
-
-        if status != MfxStatus::NoneOrDone {
-            return Err(status);
-        }
+        synchronize_surface(SharedPtr(&mut *self.inner as *mut _), timeout)?;
+        self.in_flight = None;
+        Ok(())
+    }
 
+    /// Same as [`FrameSurface::synchronize`] but waits on a blocking thread instead of blocking the executor.
+    #[cfg(feature = "async")]
+    pub(crate) async fn synchronize_async(
+        &mut self,
+        timeout: Option<u32>,
+    ) -> Result<(), MfxStatus> {
+        // Only the pointer goes to the blocking thread, so the surface can keep borrowing the
+        // component that returned it
+        let surface = SharedPtr(&mut *self.inner as *mut ffi::mfxFrameSurface1);
+        tokio::task::spawn_blocking(move || synchronize_surface(surface, timeout))
+            .await
+            .unwrap()?;
+        self.in_flight = None;
         Ok(())
     }
 
@@ -387,7 +680,7 @@ impl<'a> FrameSurface<'a> {
         // Map surface data to get read access to it
         let status: MfxStatus = unsafe { func(self.inner, access.bits() as u32) }.into();
 
-        trace!("Map framesurface = {:?}", status);
+        report_status("mfxFrameSurfaceInterface::Map", status);
 
         if status != MfxStatus::NoneOrDone {
             return Err(status);
@@ -407,7 +700,7 @@ impl<'a> FrameSurface<'a> {
         // Unmap surface data
         let status: MfxStatus = unsafe { func(self.inner) }.into();
 
-        trace!("Unmap framesurface = {:?}", status);
+        report_status("mfxFrameSurfaceInterface::Unmap", status);
 
         if status != MfxStatus::NoneOrDone {
             return Err(status);
@@ -418,6 +711,11 @@ impl<'a> FrameSurface<'a> {
         Ok(())
     }
 
+    /// True while the surface is mapped with [`FrameSurface::map`] (or by a read that hasn't unmapped it yet). The encoder refuses mapped input surfaces, see [`Encoder::encode`].
+    pub fn is_mapped(&self) -> bool {
+        self.mapped
+    }
+
     /// Decrements the internal reference counter of the surface. See [`ffi::mfxFrameSurfaceInterface::Release`] for more info.
     fn release(&mut self) -> Result<(), MfxStatus> {
         // Get memory mapping function
@@ -426,7 +724,7 @@ impl<'a> FrameSurface<'a> {
         // Release the frame
         let status: MfxStatus = unsafe { func(self.inner) }.into();
 
-        trace!("Release framesurface = {:?}", status);
+        report_status("mfxFrameSurfaceInterface::Release", status);
 
         if status != MfxStatus::NoneOrDone {
             return Err(status);
@@ -440,9 +738,21 @@ impl<'a> FrameSurface<'a> {
         FourCC::from_repr(self.inner.Info.FourCC as ffi::_bindgen_ty_5).unwrap()
     }
 
+    /// Number of bytes in a row (video width in bytes + padding), combined from `PitchHigh` and `PitchLow` so pitches above 65535 of large video memory surfaces are right.
+    pub fn pitch(&self) -> u32 {
+        let low = unsafe { self.inner.Data.__bindgen_anon_2.PitchLow };
+        ((self.inner.Data.PitchHigh as u32) << 16) | low as u32
+    }
+
+    /// Sets both `PitchHigh` and `PitchLow`.
+    pub fn set_pitch(&mut self, pitch: u32) {
+        self.inner.Data.PitchHigh = (pitch >> 16) as u16;
+        self.inner.Data.__bindgen_anon_2.PitchLow = pitch as u16;
+    }
+
     /// pitch = Number of bytes in a row (video width in bytes + padding)
     pub fn bounds(&self) -> FrameSurfaceBounds {
-        let pitch = unsafe { self.inner.Data.__bindgen_anon_2.PitchLow };
+        let pitch = self.pitch();
         let width = unsafe { self.inner.Info.__bindgen_anon_1.__bindgen_anon_1.Width };
         let height = unsafe { self.inner.Info.__bindgen_anon_1.__bindgen_anon_1.Height };
         let crop_x = unsafe { self.inner.Info.__bindgen_anon_1.__bindgen_anon_1.CropX };
@@ -462,387 +772,497 @@ impl<'a> FrameSurface<'a> {
 
     /// b(), g(), r(), and a() provide the buffer for the entire frame. So if you are reading a BGRA frame, you can read the entire frame into the slice returned by b().
     pub fn b<'c, 'd: 'c>(&'c mut self) -> &'d mut [u8] {
-        assert!(unsafe { !self.inner.Data.__bindgen_anon_5.B.is_null() });
-
-        let pitch = unsafe { self.inner.Data.__bindgen_anon_2.PitchLow };
-        let crop_height = unsafe { self.inner.Info.__bindgen_anon_1.__bindgen_anon_1.CropH };
-
-        let length = match self.fourcc() {
-            FourCC::Rgb4OrBgra | FourCC::BGR4 => crop_height as usize * pitch as usize,
-            _ => unimplemented!("{:?}", self.fourcc()),
-        };
-        unsafe { std::slice::from_raw_parts_mut(self.inner.Data.__bindgen_anon_5.B, length) }
+        let ptr = unsafe { self.inner.Data.__bindgen_anon_5.B };
+        let length = self.plane_len(ptr);
+        unsafe { std::slice::from_raw_parts_mut(ptr, length) }
     }
 
     pub fn g<'c, 'd: 'c>(&'c mut self) -> &'d mut [u8] {
-        assert!(unsafe { !self.inner.Data.__bindgen_anon_4.G.is_null() });
-
-        let pitch = unsafe { self.inner.Data.__bindgen_anon_2.PitchLow };
-        let crop_height = unsafe { self.inner.Info.__bindgen_anon_1.__bindgen_anon_1.CropH };
-
-        let length = match self.fourcc() {
-            FourCC::Rgb4OrBgra => crop_height as usize * pitch as usize - 1,
-            _ => unimplemented!("{:?}", self.fourcc()),
-        };
-        unsafe { std::slice::from_raw_parts_mut(self.inner.Data.__bindgen_anon_4.G, length) }
+        let ptr = unsafe { self.inner.Data.__bindgen_anon_4.G };
+        let length = self.plane_len(ptr);
+        unsafe { std::slice::from_raw_parts_mut(ptr, length) }
     }
 
     pub fn r<'c, 'd: 'c>(&'c mut self) -> &'d mut [u8] {
-        assert!(unsafe { !self.inner.Data.__bindgen_anon_3.R.is_null() });
-
-        let pitch = unsafe { self.inner.Data.__bindgen_anon_2.PitchLow };
-        let crop_height = unsafe { self.inner.Info.__bindgen_anon_1.__bindgen_anon_1.CropH };
-
-        let length = match self.fourcc() {
-            FourCC::Rgb4OrBgra => crop_height as usize * pitch as usize - 2,
-            _ => unimplemented!("{:?}", self.fourcc()),
-        };
-        unsafe { std::slice::from_raw_parts_mut(self.inner.Data.__bindgen_anon_3.R, length) }
+        let ptr = unsafe { self.inner.Data.__bindgen_anon_3.R };
+        let length = self.plane_len(ptr);
+        unsafe { std::slice::from_raw_parts_mut(ptr, length) }
     }
 
     pub fn a<'c, 'd: 'c>(&'c mut self) -> &'d mut [u8] {
-        assert!(!self.inner.Data.A.is_null());
-
-        let pitch = unsafe { self.inner.Data.__bindgen_anon_2.PitchLow };
-        let crop_height = unsafe { self.inner.Info.__bindgen_anon_1.__bindgen_anon_1.CropH };
-
-        let length = match self.fourcc() {
-            FourCC::Rgb4OrBgra => crop_height as usize * pitch as usize - 3,
-            _ => unimplemented!("{:?}", self.fourcc()),
-        };
-        unsafe { std::slice::from_raw_parts_mut(self.inner.Data.A, length) }
+        let ptr = self.inner.Data.A;
+        let length = self.plane_len(ptr);
+        unsafe { std::slice::from_raw_parts_mut(ptr, length) }
     }
 
     /// Remember to take pitch into account when writing to
     pub fn y<'c, 'd: 'c>(&'c mut self) -> &'d mut [u8] {
-        assert!(unsafe { !self.inner.Data.__bindgen_anon_3.Y.is_null() });
-
-        let pitch = unsafe { self.inner.Data.__bindgen_anon_2.PitchLow };
-        let crop_height = unsafe { self.inner.Info.__bindgen_anon_1.__bindgen_anon_1.CropH };
-
-        let length = match self.fourcc() {
-            FourCC::NV12 | FourCC::YV12 | FourCC::IyuvOrI420 => {
-                crop_height as usize * pitch as usize
-            }
-            FourCC::NV16 => todo!(),
-            FourCC::YUY2 => todo!(),
-            FourCC::P8 => todo!(),
-            FourCC::P8Texture => todo!(),
-            FourCC::P010 => todo!(),
-            FourCC::P016 => todo!(),
-            FourCC::P210 => todo!(),
-            FourCC::AYUV => todo!(),
-            FourCC::AyuvRgb4 => todo!(),
-            FourCC::UYVY => todo!(),
-            FourCC::Y210 => todo!(),
-            FourCC::Y410 => todo!(),
-            FourCC::Y216 => todo!(),
-            FourCC::Y416 => todo!(),
-            FourCC::NV21 => todo!(),
-            FourCC::I010 => todo!(),
-            FourCC::I210 => todo!(),
-            FourCC::I422 => todo!(),
-            _ => unimplemented!("{:?}", self.fourcc()),
-        };
-        unsafe { std::slice::from_raw_parts_mut(self.inner.Data.__bindgen_anon_3.Y, length) }
+        let ptr = unsafe { self.inner.Data.__bindgen_anon_3.Y };
+        let length = self.plane_len(ptr);
+        unsafe { std::slice::from_raw_parts_mut(ptr, length) }
     }
 
     pub fn u<'c, 'd: 'c>(&'c mut self) -> &'d mut [u8] {
-        assert!(unsafe { !self.inner.Data.__bindgen_anon_4.U.is_null() });
-
-        let pitch = unsafe { self.inner.Data.__bindgen_anon_2.PitchLow };
-        let crop_height = unsafe { self.inner.Info.__bindgen_anon_1.__bindgen_anon_1.CropH };
-
-        let length = match self.fourcc() {
-            FourCC::NV12 | FourCC::YV12 | FourCC::IyuvOrI420 => {
-                (crop_height / 2) as usize * (pitch / 2) as usize
-            }
-            FourCC::NV16 => todo!(),
-            FourCC::YUY2 => todo!(),
-            FourCC::P8 => todo!(),
-            FourCC::P8Texture => todo!(),
-            FourCC::P010 => todo!(),
-            FourCC::P016 => todo!(),
-            FourCC::P210 => todo!(),
-            FourCC::AYUV => todo!(),
-            FourCC::AyuvRgb4 => todo!(),
-            FourCC::UYVY => todo!(),
-            FourCC::Y210 => todo!(),
-            FourCC::Y410 => todo!(),
-            FourCC::Y216 => todo!(),
-            FourCC::Y416 => todo!(),
-            FourCC::NV21 => todo!(),
-            FourCC::I010 => todo!(),
-            FourCC::I210 => todo!(),
-            FourCC::I422 => todo!(),
-            _ => unimplemented!("{:?}", self.fourcc()),
-        };
-        unsafe { std::slice::from_raw_parts_mut(self.inner.Data.__bindgen_anon_4.U, length) }
+        let ptr = unsafe { self.inner.Data.__bindgen_anon_4.U };
+        let length = self.plane_len(ptr);
+        unsafe { std::slice::from_raw_parts_mut(ptr, length) }
     }
 
     pub fn v<'c, 'd: 'c>(&'c mut self) -> &'d mut [u8] {
-        assert!(unsafe { !self.inner.Data.__bindgen_anon_5.V.is_null() });
-
-        let pitch = unsafe { self.inner.Data.__bindgen_anon_2.PitchLow };
-        let crop_height = unsafe { self.inner.Info.__bindgen_anon_1.__bindgen_anon_1.CropH };
-
-        let length = match self.fourcc() {
-            FourCC::NV12 | FourCC::YV12 | FourCC::IyuvOrI420 => {
-                (crop_height / 2) as usize * (pitch / 2) as usize
-            }
-            FourCC::NV16 => todo!(),
-            FourCC::YUY2 => todo!(),
-            FourCC::P8 => todo!(),
-            FourCC::P8Texture => todo!(),
-            FourCC::P010 => todo!(),
-            FourCC::P016 => todo!(),
-            FourCC::P210 => todo!(),
-            FourCC::AYUV => todo!(),
-            FourCC::AyuvRgb4 => todo!(),
-            FourCC::UYVY => todo!(),
-            FourCC::Y210 => todo!(),
-            FourCC::Y410 => todo!(),
-            FourCC::Y216 => todo!(),
-            FourCC::Y416 => todo!(),
-            FourCC::NV21 => todo!(),
-            FourCC::I010 => todo!(),
-            FourCC::I210 => todo!(),
-            FourCC::I422 => todo!(),
-            _ => unimplemented!("{:?}", self.fourcc()),
-        };
-        unsafe { std::slice::from_raw_parts_mut(self.inner.Data.__bindgen_anon_5.V, length) }
+        let ptr = unsafe { self.inner.Data.__bindgen_anon_5.V };
+        let length = self.plane_len(ptr);
+        unsafe { std::slice::from_raw_parts_mut(ptr, length) }
     }
 
-    async fn read_iyuv_or_i420_frame(&mut self) -> Result<(), MfxStatus> {
-        let bounds = self.bounds();
-        let crop_h = bounds.crop_height as usize;
-        let crop_w = bounds.crop_width as usize;
-        let pitch = bounds.pitch as usize;
-        let mut read_offset = 0;
-
-        let y = self.y();
-        let u = self.u();
-        let v = self.v();
-        let buffer = self.buffer.lock().await;
-
-        // Y plane
-        {
-            for i_h in 0..crop_h {
-                let source_offset = i_h * crop_w;
-                let offset = i_h * pitch;
-                let source = &buffer[source_offset..source_offset + crop_w];
-                let target = &mut y[offset..offset + crop_w];
-                target.copy_from_slice(source);
-            }
-            read_offset += crop_h * crop_w;
-        }
-
-        // U plane
-        {
-            let pitch = pitch / 2;
-            let crop_h = crop_h / 2;
-            let crop_w = crop_w / 2;
-            for i_h in 0..crop_h {
-                let source_offset = read_offset + i_h * crop_w;
-                let offset = i_h * pitch;
-                let source = &buffer[source_offset..source_offset + crop_w];
-                let target = &mut u[offset..offset + crop_w];
-                target.copy_from_slice(source);
-            }
-            read_offset += crop_h * crop_w;
-        }
-
-        // V plane
-        {
-            let pitch = pitch / 2;
-            let crop_h = crop_h / 2;
-            let crop_w = crop_w / 2;
-            for i_h in 0..crop_h {
-                let source_offset = read_offset + i_h * crop_w;
-                let offset = i_h * pitch;
-                let source = &buffer[source_offset..source_offset + crop_w];
-                let target = &mut v[offset..offset + crop_w];
-                target.copy_from_slice(source);
-            }
-            // read_offset += crop_h * crop_w;
-        }
-
-        Ok(())
+    /// The interleaved chroma plane of NV12 (UVUV...) and NV21 (VUVU...) frames.
+    ///
+    /// Remember to take pitch into account when writing to
+    pub fn uv<'c, 'd: 'c>(&'c mut self) -> &'d mut [u8] {
+        let ptr = unsafe { self.inner.Data.__bindgen_anon_4.UV };
+        let length = self.plane_len(ptr);
+        unsafe { std::slice::from_raw_parts_mut(ptr, length) }
     }
 
-    async fn read_yv12_frame(&mut self) -> Result<(), MfxStatus> {
-        let bounds = self.bounds();
-        let crop_h = bounds.crop_height as usize;
-        let crop_w = bounds.crop_width as usize;
-        let pitch = bounds.pitch as usize;
-        let mut read_offset = 0;
-
-        let y = self.y();
-        let u = self.u();
-        let v = self.v();
-        let buffer = self.buffer.lock().await;
-
-        // Y plane
-        {
-            for i_h in 0..crop_h {
-                let source_offset = i_h * crop_w;
-                let offset = i_h * pitch;
-                let source = &buffer[source_offset..source_offset + crop_w];
-                let target = &mut y[offset..offset + crop_w];
-                target.copy_from_slice(source);
-            }
-            read_offset += crop_h * crop_w;
+    /// The whole frame of the 16 bit packed formats, Y216 (Y0 U Y1 V for every two pixels) and Y416 (U Y V A for every pixel). Each row is `pitch / 2` samples long. `None` for any other format.
+    ///
+    /// Remember to take pitch into account when writing to
+    pub fn packed16<'c, 'd: 'c>(&'c mut self) -> Option<&'d mut [u16]> {
+        let ptr = match self.fourcc() {
+            FourCC::Y216 => unsafe { self.inner.Data.__bindgen_anon_3.Y16 },
+            FourCC::Y416 => unsafe { self.inner.Data.__bindgen_anon_4.Y416 as *mut u16 },
+            _ => return None,
+        };
+        let length = self.plane_len(ptr as *const u8) / 2;
+        Some(unsafe { std::slice::from_raw_parts_mut(ptr, length) })
+    }
+
+    /// Bytes from `ptr` to the end of the plane it points into. Channels sharing a plane start a few bytes in, Eg. G of a BGRA frame is one byte shorter than B.
+    fn plane_len(&self, ptr: *const u8) -> usize {
+        assert!(!ptr.is_null());
+
+        let planes = self.planes().unwrap_or_default();
+        planes
+            .iter()
+            .map(|plane| {
+                let start = plane.data as usize;
+                (start, start + plane.rows * plane.pitch)
+            })
+            .find(|(start, end)| (*start..*end).contains(&(ptr as usize)))
+            .map(|(_, end)| end - ptr as usize)
+            .unwrap_or_else(|| panic!("{:?} has no plane at {:?}", self.fourcc(), ptr))
+    }
+
+    /// Copies the raw `format` frame in the buffer into the planes of the surface. See [`FrameSurface::read_raw_frame`].
+    fn read_buffer(&mut self, format: FourCC) -> Result<(), MfxStatus> {
+        let surface_format = self.fourcc();
+        // NV21 is NV12 with V and U swapped, as produced by most Android camera pipelines
+        let swap_chroma = matches!(
+            (format, surface_format),
+            (FourCC::NV12, FourCC::NV21) | (FourCC::NV21, FourCC::NV12)
+        );
+        let swap_planes = matches!(
+            (format, surface_format),
+            (FourCC::IyuvOrI420, FourCC::YV12) | (FourCC::YV12, FourCC::IyuvOrI420)
+        );
+        let swap_red_blue = matches!(
+            (format, surface_format),
+            (FourCC::Rgb4OrBgra, FourCC::BGR4) | (FourCC::BGR4, FourCC::Rgb4OrBgra)
+        );
+        // Other formats sharing a layout order their channels differently (Eg. YUY2 and UYVY)
+        if format != surface_format && !swap_chroma && !swap_planes && !swap_red_blue {
+            return Err(MfxStatus::Unsupported);
         }
 
-        // V plane
-        {
-            let pitch = pitch / 2;
-            let crop_h = crop_h / 2;
-            let crop_w = crop_w / 2;
-            for i_h in 0..crop_h {
-                let source_offset = read_offset + i_h * crop_w;
-                let offset = i_h * pitch;
-                let source = &buffer[source_offset..source_offset + crop_w];
-                let target = &mut v[offset..offset + crop_w];
-                target.copy_from_slice(source);
-            }
-            read_offset += crop_h * crop_w;
+        let buffer = self.buffer.lock().unwrap();
+        let mut planes = self.planes()?;
+        if swap_planes {
+            planes.swap(1, 2);
         }
 
-        // U plane
-        {
-            let pitch = pitch / 2;
-            let crop_h = crop_h / 2;
-            let crop_w = crop_w / 2;
-            for i_h in 0..crop_h {
-                let source_offset = read_offset + i_h * crop_w;
-                let offset = i_h * pitch;
-                let source = &buffer[source_offset..source_offset + crop_w];
-                let target = &mut u[offset..offset + crop_w];
-                target.copy_from_slice(source);
+        let mut read_offset = 0;
+        for (i, plane) in planes.iter().enumerate() {
+            for row in 0..plane.rows {
+                let source = &buffer[read_offset..read_offset + plane.width];
+                let target = unsafe {
+                    std::slice::from_raw_parts_mut(plane.data.add(row * plane.pitch), plane.width)
+                };
+                if swap_chroma && i == 1 {
+                    for (pair, chroma) in target.chunks_exact_mut(2).zip(source.chunks_exact(2)) {
+                        pair[0] = chroma[1];
+                        pair[1] = chroma[0];
+                    }
+                } else if swap_red_blue {
+                    for (pixel, color) in target.chunks_exact_mut(4).zip(source.chunks_exact(4)) {
+                        pixel.copy_from_slice(&[color[2], color[1], color[0], color[3]]);
+                    }
+                } else {
+                    target.copy_from_slice(source);
+                }
+                read_offset += plane.width;
             }
-            // read_offset += crop_h * crop_w;
         }
 
         Ok(())
     }
 
-    async fn read_bgra_frame(&mut self) -> Result<(), MfxStatus> {
-        let b = self.b();
-
-        b.copy_from_slice(&self.buffer.lock().await);
-
-        Ok(())
-    }
-
     /// Reads a single frame in the given pixel format.
+    ///
+    /// `format` needs the same [`PlaneLayout`](constants::PlaneLayout) as the surface. NV21 can be read into NV12 surfaces, I420 into YV12 ones and BGRA into RGBA ([`FourCC::BGR4`]) ones (and the other way around), the channels are reordered while copying. Any other format that isn't the surface's own returns [`MfxStatus::Unsupported`], even with the same layout (Eg. YUY2 into a UYVY surface).
+    #[cfg(feature = "async")]
     pub async fn read_raw_frame<R: Read>(
         &mut self,
         source: &mut R,
         format: FourCC,
     ) -> Result<(), MfxStatus> {
-        self.map(MemoryFlag::WRITE).unwrap();
+        self.copy_raw_frame(source, format)
+    }
 
-        match source.read_exact(&mut self.buffer.lock().await) {
-            Ok(_) => {}
-            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
-                return Err(MfxStatus::MoreData);
-            }
+    /// Same as [`FrameSurface::read_raw_frame`] without an async runtime.
+    #[cfg(feature = "blocking")]
+    pub fn read_raw_frame_blocking<R: Read>(
+        &mut self,
+        source: &mut R,
+        format: FourCC,
+    ) -> Result<(), MfxStatus> {
+        self.copy_raw_frame(source, format)
+    }
+
+    /// [`FrameSurface::read_raw_frame`] and `FrameSurface::read_raw_frame_blocking` are built on top of this.
+    fn copy_raw_frame<R: Read>(&mut self, source: &mut R, format: FourCC) -> Result<(), MfxStatus> {
+        self.map(MemoryFlag::WRITE)?;
+
+        let read = source.read_exact(&mut self.buffer.lock().unwrap());
+        let result = match read {
+            Ok(_) => self.read_buffer(format),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Err(MfxStatus::MoreData),
             Err(e) => {
                 warn!("{}", e);
-                return Err(MfxStatus::Unknown);
-            }
-        };
-
-        let read_func = async {
-            match format {
-                FourCC::NV12 => todo!(),
-                FourCC::YV12 => self.read_yv12_frame().await,
-                FourCC::NV16 => todo!(),
-                FourCC::YUY2 => todo!(),
-                FourCC::RGB565 => todo!(),
-                FourCC::RGBP => todo!(),
-                FourCC::RGB3 => todo!(),
-                FourCC::Rgb4OrBgra => self.read_bgra_frame().await,
-                FourCC::P8 => todo!(),
-                FourCC::P8Texture => todo!(),
-                FourCC::P010 => todo!(),
-                FourCC::P016 => todo!(),
-                FourCC::P210 => todo!(),
-                FourCC::BGR4 => todo!(),
-                FourCC::A2RGB10 => todo!(),
-                FourCC::ARGB16 => todo!(),
-                FourCC::ABGR16 => todo!(),
-                FourCC::R16 => todo!(),
-                FourCC::AYUV => todo!(),
-                FourCC::AyuvRgb4 => todo!(),
-                FourCC::UYVY => todo!(),
-                FourCC::Y210 => todo!(),
-                FourCC::Y410 => todo!(),
-                FourCC::Y216 => todo!(),
-                FourCC::Y416 => todo!(),
-                FourCC::NV21 => todo!(),
-                FourCC::IyuvOrI420 => self.read_iyuv_or_i420_frame().await,
-                FourCC::I010 => todo!(),
-                FourCC::I210 => todo!(),
-                FourCC::I422 => todo!(),
-                FourCC::BGRP => todo!(),
+                Err(MfxStatus::Unknown)
             }
         };
 
-        let result: Result<(), MfxStatus> = read_func.await;
-
-        self.unmap().unwrap();
-
-        result
+        // Unmapped on every path, the encoder refuses surfaces that are still mapped. A failed
+        // read is reported over a failed unmap.
+        let unmapped = self.unmap();
+        result.and(unmapped)
     }
 
+    /// Size in bytes of a `width` x `height` frame in `format` without padding. See [`FourCC::plane_layout`].
     pub fn frame_size(format: FourCC, width: u16, height: u16) -> usize {
-        let width = width as usize;
-        let height = height as usize;
-        let wh = width * height;
-        let bit10 = 10 / 8;
+        format.plane_layout().frame_size(width as usize, height as usize)
+    }
 
-        match format {
-            FourCC::IyuvOrI420 | FourCC::NV12 | FourCC::YV12 => wh * 3 / 2,
-            FourCC::I010 | FourCC::P010 => wh * bit10 * 3 / 2,
-            FourCC::YUY2 | FourCC::I422 => wh * 2,
-            FourCC::Y210 => wh * bit10 * 2,
-            FourCC::AYUV => wh * 3,
-            FourCC::Y410 => wh * bit10 * 3,
-            FourCC::Rgb4OrBgra | FourCC::BGR4 => wh * 4,
-            _ => todo!(),
+    /// Copies the entire frame into a contiguous buffer in its native pixel format with the pitch padding stripped. Planes are written in the same order [`FrameSurface::read_raw_frame`] expects them, so the output is [`FrameSurface::frame_size`] bytes long.
+    ///
+    /// Unlike [`io::Read`], the surface is unmapped again afterwards if it was not already mapped.
+    pub fn to_packed_vec(&mut self) -> Result<Vec<u8>, MfxStatus> {
+        let was_mapped = self.mapped;
+        if !was_mapped {
+            self.map(MemoryFlag::READ)?;
         }
-    }
 
-    pub fn pitch_high(&self) -> u16 {
-        self.inner.Data.PitchHigh
-    }
-    pub fn set_pitch_high(&mut self, pitch: u16) {
-        self.inner.Data.PitchHigh = pitch;
-    }
-}
+        let bounds = self.bounds();
+        let result = self.planes().map(|planes| {
+            let mut packed = Vec::with_capacity(Self::frame_size(
+                self.fourcc(),
+                bounds.crop_width,
+                bounds.crop_height,
+            ));
+            for plane in planes {
+                unsafe { copy_plane(&mut packed, plane.data, plane.pitch, plane.width, plane.rows) };
+            }
+            packed
+        });
 
-impl Drop for FrameSurface<'_> {
-    fn drop(&mut self) {
-        if self.mapped {
-            self.unmap().unwrap();
+        if !was_mapped {
+            self.unmap()?;
         }
-        self.release().unwrap();
+
+        result
     }
-}
 
-impl<'a> TryFrom<*mut ffi::mfxFrameSurface1> for FrameSurface<'a> {
-    type Error = MfxStatus;
+    /// Copies the pixels of this surface into `dst` plane by plane, respecting the pitch of both. They need the same format and crop size, otherwise [`MfxStatus::IncompatibleVideoParam`] is returned. The timestamp is copied along with the pixels.
+    ///
+    /// Surfaces that aren't mapped yet are mapped for the copy and unmapped again afterwards. See [`Session::copy_frame`].
+    pub fn copy_to(&mut self, dst: &mut FrameSurface) -> Result<(), MfxStatus> {
+        let (bounds, dst_bounds) = (self.bounds(), dst.bounds());
+        if self.fourcc() != dst.fourcc()
+            || (bounds.crop_width, bounds.crop_height)
+                != (dst_bounds.crop_width, dst_bounds.crop_height)
+        {
+            return Err(MfxStatus::IncompatibleVideoParam);
+        }
 
-    fn try_from(value: *mut ffi::mfxFrameSurface1) -> Result<Self, Self::Error> {
-        if value.is_null() {
-            return Err(MfxStatus::NullPtr);
+        let was_mapped = self.mapped;
+        if !was_mapped {
+            self.map(MemoryFlag::READ)?;
         }
+        let dst_was_mapped = dst.mapped;
+        let result = if dst_was_mapped {
+            Ok(())
+        } else {
+            dst.map(MemoryFlag::WRITE)
+        };
 
-        let format =
-            FourCC::from_repr(unsafe { (*value).Info.FourCC } as ffi::_bindgen_ty_5).unwrap();
-        let width = unsafe { (*value).Info.__bindgen_anon_1.__bindgen_anon_1.CropW };
+        let result = result.and_then(|_| {
+            let planes = self.planes()?;
+            let dst_planes = dst.planes()?;
+            for (plane, dst_plane) in planes.iter().zip(dst_planes.iter()) {
+                for row in 0..plane.rows {
+                    unsafe {
+                        std::ptr::copy_nonoverlapping(
+                            plane.data.add(row * plane.pitch),
+                            dst_plane.data.add(row * dst_plane.pitch),
+                            plane.width,
+                        )
+                    };
+                }
+            }
+            dst.set_timestamp(self.timestamp());
+            Ok(())
+        });
+
+        if !dst_was_mapped && dst.mapped {
+            dst.unmap()?;
+        }
+        if !was_mapped {
+            self.unmap()?;
+        }
+
+        result
+    }
+
+    /// Fills the whole picture with a solid color, Eg. `fill_yuv(16, 128, 128)` for black in limited range. Useful for backgrounds when composing and for test patterns.
+    ///
+    /// Supports the 8 bit YUV formats [`FrameSurface::read_raw_frame`] does, anything else returns [`MfxStatus::Unsupported`]. The surface is mapped for the fill if it isn't already.
+    pub fn fill_yuv(&mut self, y: u8, u: u8, v: u8) -> Result<(), MfxStatus> {
+        let patterns: Vec<&[u8]> = match self.fourcc() {
+            FourCC::IyuvOrI420 | FourCC::I422 => vec![&[y], &[u], &[v]],
+            FourCC::YV12 => vec![&[y], &[v], &[u]],
+            FourCC::NV12 => vec![&[y], &[u, v]],
+            FourCC::NV21 => vec![&[y], &[v, u]],
+            _ => return Err(MfxStatus::Unsupported),
+        };
+        self.fill_planes(&patterns)
+    }
+
+    /// Fills the whole picture with a solid color. Only [`FourCC::Rgb4OrBgra`] and [`FourCC::BGR4`] are supported, anything else returns [`MfxStatus::Unsupported`]. The surface is mapped for the fill if it isn't already.
+    pub fn fill_rgba(&mut self, r: u8, g: u8, b: u8, a: u8) -> Result<(), MfxStatus> {
+        let pattern: &[u8] = match self.fourcc() {
+            FourCC::Rgb4OrBgra => &[b, g, r, a],
+            FourCC::BGR4 => &[r, g, b, a],
+            _ => return Err(MfxStatus::Unsupported),
+        };
+        self.fill_planes(&[pattern])
+    }
+
+    /// Repeats each pattern across every row of the matching plane.
+    fn fill_planes(&mut self, patterns: &[&[u8]]) -> Result<(), MfxStatus> {
+        let was_mapped = self.mapped;
+        if !was_mapped {
+            self.map(MemoryFlag::WRITE)?;
+        }
+
+        let result = self.planes().map(|planes| {
+            for (plane, pattern) in planes.iter().zip(patterns) {
+                for row in 0..plane.rows {
+                    let row = unsafe {
+                        std::slice::from_raw_parts_mut(
+                            plane.data.add(row * plane.pitch),
+                            plane.width,
+                        )
+                    };
+                    for (byte, value) in row.iter_mut().zip(pattern.iter().cycle()) {
+                        *byte = *value;
+                    }
+                }
+            }
+        });
+
+        if !was_mapped {
+            self.unmap()?;
+        }
+
+        result
+    }
+
+    /// The planes of a mapped surface, in the order [`FrameSurface::read_raw_frame`] expects them. Returns [`MfxStatus::NullPtr`] if the surface isn't mapped.
+    fn planes(&self) -> Result<Vec<Plane>, MfxStatus> {
+        let bounds = self.bounds();
+        let layout = self.fourcc().plane_layout();
+        let pitch = bounds.pitch as usize;
+        let data = self.inner.Data;
+
+        // R and Y, G, U and UV, and B and V share pointers
+        let (y, u, v) = unsafe {
+            (
+                data.__bindgen_anon_3.Y,
+                data.__bindgen_anon_4.U,
+                data.__bindgen_anon_5.V,
+            )
+        };
+        let starts = match (self.fourcc(), layout.planes.len()) {
+            // Packed formats start with whichever channel comes first
+            (_, 1) => vec![[y, u, v, data.A]
+                .iter()
+                .copied()
+                .filter(|ptr| !ptr.is_null())
+                .min()
+                .unwrap_or(std::ptr::null_mut())],
+            // YV12 stores V before U
+            (FourCC::YV12, _) => vec![y, v, u],
+            (FourCC::BGRP, _) => vec![v, u, y],
+            _ => vec![y, u, v],
+        };
+
+        starts
+            .into_iter()
+            .zip(0..layout.planes.len())
+            .map(|(start, plane)| {
+                if start.is_null() {
+                    return Err(MfxStatus::NullPtr);
+                }
+                Ok(Plane {
+                    data: start,
+                    pitch: layout.pitch(plane, pitch),
+                    width: layout.row_bytes(plane, bounds.crop_width as usize),
+                    rows: layout.rows(plane, bounds.crop_height as usize),
+                })
+            })
+            .collect()
+    }
+
+    /// Converts the frame to `target` on the CPU and returns it packed (no pitch padding). Useful for one-off conversions, like taking a screenshot, where creating a [`VideoProcessor`] would be overkill.
+    ///
+    /// Supported conversions are between NV12, NV21, I420 or YV12 and BGRA ([`FourCC::Rgb4OrBgra`]) or RGBA ([`FourCC::BGR4`]) in either direction. YUV is treated as limited range and converted with BT.709 for frames 720 lines or taller and BT.601 otherwise. Any other conversion returns [`MfxStatus::Unsupported`].
+    pub fn convert_to(&mut self, target: FourCC) -> Result<Vec<u8>, MfxStatus> {
+        let format = self.fourcc();
+        let source = self.to_packed_vec()?;
+        if format == target {
+            return Ok(source);
+        }
+
+        let bounds = self.bounds();
+        let width = bounds.crop_width as usize;
+        let height = bounds.crop_height as usize;
+        let matrix = convert::ColorMatrix::for_height(height);
+
+        let rgb = [FourCC::Rgb4OrBgra, FourCC::BGR4];
+        let yuv420 = [FourCC::NV12, FourCC::NV21, FourCC::IyuvOrI420, FourCC::YV12];
+
+        let converted = if rgb.contains(&format) && rgb.contains(&target) {
+            Some(convert::swap_red_blue(&source))
+        } else if yuv420.contains(&format) && rgb.contains(&target) {
+            convert::yuv420_to_rgb(&source, format, target, width, height, matrix)
+        } else if rgb.contains(&format) && yuv420.contains(&target) {
+            convert::rgb_to_yuv420(&source, format, target, width, height, matrix)
+        } else {
+            None
+        };
+
+        converted.ok_or(MfxStatus::Unsupported)
+    }
+
+    #[doc = "< Time stamp of the video frame in units of 90KHz. Divide TimeStamp by 90,000 (90 KHz) to obtain the time in seconds. A value of MFX_TIMESTAMP_UNKNOWN indicates that there is no time stamp."]
+    pub fn timestamp(&self) -> u64 {
+        self.inner.Data.TimeStamp
+    }
+
+    /// The encoder copies this to [`Bitstream::timestamp`] of the bitstream the frame ends up in.
+    pub fn set_timestamp(&mut self, timestamp: u64) {
+        self.inner.Data.TimeStamp = timestamp;
+    }
+
+    #[doc = "< Current frame counter for the top field of the current frame. An invalid value of MFX_FRAMEORDER_UNKNOWN indicates that API functions that generate the frame output do not use this frame."]
+    pub fn frame_order(&self) -> u32 {
+        self.inner.Data.FrameOrder
+    }
+
+    /// Sets the display order of this frame. Required when the encoder was created with [`MfxVideoParams::set_encode_order`] enabled since frames are then submitted in encoded order.
+    pub fn set_frame_order(&mut self, order: u32) {
+        self.inner.Data.FrameOrder = order;
+    }
+
+    /// The underlying `mfxFrameSurface1`, for passing the surface to code this crate doesn't wrap.
+    ///
+    /// The pointer is only valid while this [`FrameSurface`] is alive. Don't release it or drop its reference count behind the wrapper's back, the surface is released when the [`FrameSurface`] is dropped.
+    pub fn as_raw(&self) -> *mut ffi::mfxFrameSurface1 {
+        self.inner as *const _ as *mut _
+    }
+
+    /// The memory id the external frame allocator (See [`Session::set_allocator`]) gave this surface, if any. Surfaces allocated by the library itself usually don't have one.
+    pub fn mem_id(&self) -> Option<MemId> {
+        let id = self.inner.Data.MemId;
+        if id.is_null() {
+            None
+        } else {
+            Some(MemId(id))
+        }
+    }
+
+    /// The upper 16 bits of [`FrameSurface::pitch`].
+    pub fn pitch_high(&self) -> u16 {
+        self.inner.Data.PitchHigh
+    }
+    /// Prefer [`FrameSurface::set_pitch`], which sets both halves.
+    pub fn set_pitch_high(&mut self, pitch: u16) {
+        self.inner.Data.PitchHigh = pitch;
+    }
+}
+
+/// A plane of a mapped [`FrameSurface`], `rows` rows of `width` bytes each starting `pitch` bytes apart.
+struct Plane {
+    data: *mut u8,
+    pitch: usize,
+    width: usize,
+    rows: usize,
+}
+
+/// Appends `height` rows of `width` bytes, skipping the padding at the end of each `pitch` long row.
+unsafe fn copy_plane(out: &mut Vec<u8>, plane: *const u8, pitch: usize, width: usize, height: usize) {
+    assert!(!plane.is_null());
+    for row in 0..height {
+        out.extend_from_slice(std::slice::from_raw_parts(plane.add(row * pitch), width));
+    }
+}
+
+fn synchronize_surface(
+    surface: SharedPtr<*mut ffi::mfxFrameSurface1>,
+    timeout: Option<u32>,
+) -> Result<(), MfxStatus> {
+    let timeout = timeout.unwrap_or(100);
+    let interface = unsafe { *(*surface.0).__bindgen_anon_1.FrameInterface };
+    let sync_func = interface.Synchronize.unwrap();
+    let status: MfxStatus = unsafe { sync_func(surface.0, timeout) }.into();
+    report_status("mfxFrameSurfaceInterface::Synchronize", status);
+
+    if status != MfxStatus::NoneOrDone {
+        return Err(status);
+    }
+
+    Ok(())
+}
+
+impl Drop for FrameSurface<'_> {
+    fn drop(&mut self) {
+        if self.mapped {
+            self.unmap().unwrap();
+        }
+        self.release().unwrap();
+    }
+}
+
+impl<'a> TryFrom<*mut ffi::mfxFrameSurface1> for FrameSurface<'a> {
+    type Error = MfxStatus;
+
+    fn try_from(value: *mut ffi::mfxFrameSurface1) -> Result<Self, Self::Error> {
+        if value.is_null() {
+            return Err(MfxStatus::NullPtr);
+        }
+
+        let format =
+            FourCC::from_repr(unsafe { (*value).Info.FourCC } as ffi::_bindgen_ty_5).unwrap();
+        let width = unsafe { (*value).Info.__bindgen_anon_1.__bindgen_anon_1.CropW };
         let height = unsafe { (*value).Info.__bindgen_anon_1.__bindgen_anon_1.CropH };
         let frame_size = Self::frame_size(format, width, height);
 
@@ -851,6 +1271,7 @@ impl<'a> TryFrom<*mut ffi::mfxFrameSurface1> for FrameSurface<'a> {
             read_offset: 0,
             buffer: Arc::new(Mutex::new(vec![0u8; frame_size])),
             mapped: false,
+            in_flight: None,
         };
 
         // If timestamp is 0 set it to unknown
@@ -870,171 +1291,30 @@ impl io::Read for FrameSurface<'_> {
             self.map(MemoryFlag::READ).unwrap();
         }
 
-        let data: ffi::mfxFrameData = self.inner.Data;
-        let info: ffi::mfxFrameInfo = self.inner.Info;
-
-        let bounds = self.bounds();
-        let h = bounds.crop_height as usize;
-        let w = bounds.crop_width as usize;
-        let pitch = bounds.pitch as usize;
+        let planes = self.planes().map_err(|status| {
+            std::io::Error::new(std::io::ErrorKind::Other, format!("{:?}", status))
+        })?;
 
+        // Bytes of previous reads to skip
+        let mut skip = self.read_offset;
         let mut bytes_written = 0;
 
-        'outer: {
-            // FIXME: Remove unwrap and replace with actual error
-            match FourCC::from_repr(info.FourCC as ffi::_bindgen_ty_5).unwrap() {
-                FourCC::IyuvOrI420 | FourCC::YV12 => {
-                    // Y
-                    let y_start = self.read_offset / w;
-                    let total_y_size = w * h;
-                    // dbg!(pitch, w, y_start, h, self.read_offset);
-                    for i in y_start..h {
-                        let offset = i * pitch;
-                        let ptr = unsafe { data.__bindgen_anon_3.Y.offset(offset as isize) };
-                        debug_assert!(!ptr.is_null());
-                        let slice: &[u8] = unsafe { std::slice::from_raw_parts(ptr, w) };
-
-                        // We don't want to write a portion of a slice, only whole slices
-                        let bytes = if slice.len() <= buf.len() {
-                            // FIXME: remove unwrap
-                            buf.write(slice).unwrap()
-                        } else {
-                            0
-                        };
-                        if bytes == 0 {
-                            break 'outer;
-                        }
-                        bytes_written += bytes;
-                    }
-
-                    let pitch = pitch / 2;
-                    let h = h / 2;
-                    let w = w / 2;
-                    let total_uv_size = w * h;
-
-                    // U
-                    let u_start = (self.read_offset + bytes_written - total_y_size) / w;
-                    for i in u_start..h {
-                        let offset = i * pitch;
-                        let ptr = unsafe { data.__bindgen_anon_4.U.offset(offset as isize) };
-                        debug_assert!(!ptr.is_null());
-                        let slice: &[u8] = unsafe { std::slice::from_raw_parts(ptr, w) };
-                        // We don't want to write a portion of a slice, only whole slices
-                        let bytes = if slice.len() <= buf.len() {
-                            // FIXME: remove unwrap
-                            buf.write(slice).unwrap()
-                        } else {
-                            0
-                        };
-                        if bytes == 0 {
-                            break 'outer;
-                        }
-                        bytes_written += bytes;
-                    }
-
-                    // V
-                    let v_start =
-                        (self.read_offset + bytes_written - total_y_size - total_uv_size) / w;
-                    for i in v_start..h {
-                        let offset = i * pitch;
-                        let ptr = unsafe { data.__bindgen_anon_5.V.offset(offset as isize) };
-                        debug_assert!(!ptr.is_null());
-                        let slice: &[u8] = unsafe { std::slice::from_raw_parts(ptr, w) };
-                        // We don't want to write a portion of a slice, only whole slices
-                        let bytes = if slice.len() <= buf.len() {
-                            // FIXME: remove unwrap
-                            buf.write(slice).unwrap()
-                        } else {
-                            0
-                        };
-                        if bytes == 0 {
-                            break 'outer;
-                        }
-                        bytes_written += bytes;
-                    }
-                }
-                FourCC::NV12 => {
-                    let pitch = unsafe { data.__bindgen_anon_2.Pitch } as usize;
-
-                    // Y
-                    let y_start = self.read_offset / w;
-                    let total_y_size = w * h;
-                    // dbg!(pitch, w, y_start, h, self.read_offset);
-                    for i in y_start..h {
-                        let offset = i * pitch;
-                        let ptr = unsafe { data.__bindgen_anon_3.Y.offset(offset as isize) };
-                        debug_assert!(!ptr.is_null());
-                        // dbg!(i, offset, ptr, h, w, y_start);
-                        let slice: &[u8] = unsafe { std::slice::from_raw_parts(ptr, w) };
-
-                        // We don't want to write a portion of a slice, only whole slices
-                        let bytes = if slice.len() <= buf.len() {
-                            // FIXME: remove unwrap
-                            buf.write(slice).unwrap()
-                        } else {
-                            0
-                        };
-                        if bytes == 0 {
-                            break 'outer;
-                        }
-                        bytes_written += bytes;
-                    }
-
-                    let h = h / 2;
-
-                    // U
-                    let u_start = (self.read_offset + bytes_written - total_y_size) / w;
-                    for i in u_start..h {
-                        let offset = i * pitch;
-                        let ptr = unsafe { data.__bindgen_anon_4.UV.offset(offset as isize) };
-                        debug_assert!(!ptr.is_null());
-                        let slice: &[u8] = unsafe { std::slice::from_raw_parts(ptr, w) };
-                        // We don't want to write a portion of a slice, only whole slices
-                        let bytes = if slice.len() <= buf.len() {
-                            // FIXME: remove unwrap
-                            buf.write(slice).unwrap()
-                        } else {
-                            0
-                        };
-                        if bytes == 0 {
-                            break 'outer;
-                        }
-                        bytes_written += bytes;
-                    }
+        'outer: for plane in planes {
+            for row in 0..plane.rows {
+                if skip >= plane.width {
+                    skip -= plane.width;
+                    continue;
                 }
-                // case MFX_FOURCC_NV12:
-                //     // Y
-                //     pitch = data->Pitch;
-                //     for (i = 0; i < h; i++) {
-                //         fwrite(data->Y + i * pitch, 1, w, f);
-                //     }
-                //     // UV
-                //     h /= 2;
-                //     for (i = 0; i < h; i++) {
-                //         fwrite(data->UV + i * pitch, 1, w, f);
-                //     }
-                //     break;
-                // case MFX_FOURCC_RGB4:
-                //     // Y
-                //     pitch = data->Pitch;
-                //     for (i = 0; i < h; i++) {
-                //         fwrite(data->B + i * pitch, 1, pitch, f);
-                //     }
-                //     break;
-                FourCC::Rgb4OrBgra => {
-                    bytes_written += buf.write(&self.b()[self.read_offset..]).unwrap();
+                // We don't want to write a portion of a row, only whole rows
+                if plane.width > buf.len() {
+                    break 'outer;
                 }
-                _ => {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::Unsupported,
-                        format!(
-                            "Unsupported format {:?}",
-                            FourCC::from_repr(info.FourCC as ffi::_bindgen_ty_5)
-                        ),
-                    ));
-                }
-            };
-        };
+                let slice: &[u8] = unsafe {
+                    std::slice::from_raw_parts(plane.data.add(row * plane.pitch), plane.width)
+                };
+                bytes_written += buf.write(slice)?;
+            }
+        }
 
         self.read_offset += bytes_written;
         Ok(bytes_written)
@@ -1046,6 +1326,8 @@ pub enum AcceleratorHandle {
     VAAPI((File, *mut c_void)),
 }
 unsafe impl Send for AcceleratorHandle {}
+// libva serializes calls on a display itself, so one display can be used by sessions on several threads
+unsafe impl Sync for AcceleratorHandle {}
 
 impl AcceleratorHandle {
     #[cfg(target_os = "linux")]
@@ -1085,6 +1367,19 @@ impl AcceleratorHandle {
 
         Ok(Self::VAAPI((file, display)))
     }
+    /// Duplicates the DRM file descriptor and initializes a new VA display on it, so the loader and session can each own a handle that is terminated independently.
+    #[cfg(target_os = "linux")]
+    pub fn try_clone(&self) -> Result<Self, MfxStatus> {
+        match self {
+            AcceleratorHandle::VAAPI((file, _)) => {
+                let file = file.try_clone().map_err(|e| {
+                    error!("Failed to duplicate DRM file descriptor = {}", e);
+                    MfxStatus::InvalidHandle
+                })?;
+                Self::vaapi_from_file(Some(file))
+            }
+        }
+    }
     pub fn handle(&self) -> &*mut c_void {
         match self {
             AcceleratorHandle::VAAPI((_, handle)) => &handle,
@@ -1111,11 +1406,96 @@ impl Drop for AcceleratorHandle {
 #[derive(Debug)]
 pub struct Session<'a> {
     inner: SharedPtr<mfxSession>,
+    /// The loader and implementation index the session was created with, see [`Session::implementation_description`]
+    loader: SharedPtr<mfxLoader>,
+    impl_index: mfxU32,
     allocator: Option<FrameAllocator<'a>>,
-    accelerator: Option<AcceleratorHandle>,
+    accelerator: Option<Arc<AcceleratorHandle>>,
+    partially_accelerated: bool,
+    busy_retry: BusyRetry,
+    /// Set when the session owns its loader, see [`Loader::into_shared_session`]. Dropped after the session is closed.
+    owned_loader: Option<Arc<Loader>>,
     phantom: PhantomData<&'a mfxSession>,
 }
 
+/// How often and how long to wait before re-issuing a call the device was too busy for. See [`Session::set_busy_retry`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct BusyRetry {
+    max_attempts: u32,
+    base_delay: Duration,
+}
+
+/// The longest [`BusyRetry`] waits between two attempts, however many retries came before.
+pub(crate) const MAX_BUSY_DELAY: Duration = Duration::from_millis(100);
+
+/// The result of a call [`BusyRetry`] can re-issue.
+pub(crate) trait BusyStatus {
+    /// The busy status if the call should be re-issued
+    fn busy(&self) -> Option<MfxStatus>;
+}
+
+impl BusyStatus for MfxStatus {
+    fn busy(&self) -> Option<MfxStatus> {
+        match self {
+            MfxStatus::DeviceBusy | MfxStatus::TaskBusy => Some(*self),
+            _ => None,
+        }
+    }
+}
+
+impl<T> BusyStatus for Result<T, MfxStatus> {
+    fn busy(&self) -> Option<MfxStatus> {
+        self.as_ref().err().and_then(|status| status.busy())
+    }
+}
+
+impl BusyRetry {
+    /// How long to wait before retry number `attempt` (starting at 0): `base_delay`, then twice as long and so on, up to [`MAX_BUSY_DELAY`].
+    pub(crate) fn delay(&self, attempt: u32) -> Duration {
+        self.base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(MAX_BUSY_DELAY)
+    }
+
+    /// Calls `call` until it returns something other than [`MfxStatus::DeviceBusy`] or [`MfxStatus::TaskBusy`], sleeping [`BusyRetry::delay`] between attempts. The last result is returned once `max_attempts` retries have been used up.
+    ///
+    /// Blocks the current thread while waiting, async callers use [`BusyRetry::run_async`].
+    pub(crate) fn run<T: BusyStatus>(&self, mut call: impl FnMut() -> T) -> T {
+        let mut result = call();
+        for attempt in 0..self.max_attempts {
+            let status = match result.busy() {
+                Some(status) => status,
+                None => break,
+            };
+            let delay = self.delay(attempt);
+            trace!("{:?}, retrying in {:?}", status, delay);
+            std::thread::sleep(delay);
+            result = call();
+        }
+        result
+    }
+
+    /// Same as [`BusyRetry::run`] but waits without blocking the executor.
+    #[cfg(feature = "async")]
+    pub(crate) async fn run_async<T: BusyStatus>(&self, mut call: impl FnMut() -> T) -> T {
+        let mut attempt = 0;
+        loop {
+            // Scoped so the result (which may hold raw pointers) isn't kept across the sleep
+            let status = {
+                let result = call();
+                match result.busy() {
+                    Some(status) if attempt < self.max_attempts => status,
+                    _ => return result,
+                }
+            };
+            let delay = self.delay(attempt);
+            trace!("{:?}, retrying in {:?}", status, delay);
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
+
 // FIXME: The frame allocator alone is not send or sync, this is dangerous
 // The session might be, pretty sure the AcceleratorHandle isn't specifically Send/Sync though
 unsafe impl Send for Session<'_> {}
@@ -1124,19 +1504,44 @@ unsafe impl Sync for Session<'_> {}
 impl<'a> Session<'a> {
     #[tracing::instrument]
     pub(crate) fn new<'b: 'a>(loader: &'b mut Loader, index: mfxU32) -> Result<Self, MfxStatus> {
+        let (session, partially_accelerated) = Self::create(loader, index)?;
+        Ok(Self::from_raw(loader, index, session, partially_accelerated))
+    }
+
+    /// Creates the raw session without borrowing the loader for the lifetime of the session, so [`Loader::new_session_with_fallback`] can try again after an error.
+    fn create(loader: &Loader, index: mfxU32) -> Result<(mfxSession, bool), MfxStatus> {
         let lib = get_library().unwrap();
         let mut session: mfxSession = unsafe { mem::zeroed() };
         let status: MfxStatus =
             unsafe { lib.MFXCreateSession(loader.inner, index, &mut session) }.into();
+        report_status("MFXCreateSession", status);
 
-        if status != MfxStatus::NoneOrDone {
-            return Err(status);
+        match status {
+            MfxStatus::NoneOrDone => Ok((session, false)),
+            MfxStatus::PartialAcceleration => {
+                warn!("Session is only partially hardware accelerated");
+                Ok((session, true))
+            }
+            status => Err(status),
         }
+    }
 
+    fn from_raw(
+        loader: &Loader,
+        index: mfxU32,
+        session: mfxSession,
+        partially_accelerated: bool,
+    ) -> Self {
         let session = Self {
             inner: SharedPtr(session),
+            loader: SharedPtr(loader.inner),
+            impl_index: index,
             allocator: None,
-            accelerator: None,
+            // The dispatcher already set the loader's handle on the session, keep it alive for as long as the session
+            accelerator: loader.accelerator.clone(),
+            partially_accelerated,
+            busy_retry: BusyRetry::default(),
+            owned_loader: None,
             phantom: PhantomData,
         };
 
@@ -1144,8 +1549,35 @@ impl<'a> Session<'a> {
         debug!("API version = {:?}", session.version().unwrap());
         debug!("Implementation = {:?}", session.implementation().unwrap());
 
-        // FIXME: accelerator should be passed through from the loader if it was already set
-        Ok(session)
+        session
+    }
+
+    /// True if the library returned [`MfxStatus::PartialAcceleration`] when the session was created, meaning hardware was requested but some of the work falls back to software. Check this if you expect full hardware acceleration.
+    pub fn is_partially_accelerated(&self) -> bool {
+        self.partially_accelerated
+    }
+
+    /// Makes decoding, encoding and VPP re-issue a frame up to `max_attempts` times when the library returns [`MfxStatus::DeviceBusy`] or [`MfxStatus::TaskBusy`], which happens under heavy GPU load. The first retry waits `base_delay` and every following one waits twice as long as the one before, but never more than 100ms. The async calls wait without blocking the executor, the blocking ones block the calling thread.
+    ///
+    /// Disabled by default, the busy status is returned straight away.
+    pub fn set_busy_retry(&mut self, max_attempts: u32, base_delay: Duration) {
+        self.busy_retry = BusyRetry {
+            max_attempts,
+            base_delay,
+        };
+    }
+
+    pub(crate) fn busy_retry(&self) -> BusyRetry {
+        self.busy_retry
+    }
+
+    /// Copies the pixels of `src` into `dst`, Eg. to keep a reference frame around or duplicate an output frame without setting up a [`VideoProcessor`]. The API has no surface copy function, so both surfaces are mapped and copied on the CPU, which is slow for video memory surfaces. See [`FrameSurface::copy_to`].
+    pub fn copy_frame(
+        &self,
+        src: &mut FrameSurface,
+        dst: &mut FrameSurface,
+    ) -> Result<(), MfxStatus> {
+        src.copy_to(dst)
     }
 
     pub fn set_allocator(&mut self, mut allocator: FrameAllocator<'a>) -> Result<(), MfxStatus> {
@@ -1154,17 +1586,24 @@ impl<'a> Session<'a> {
             unsafe { lib.MFXVideoCORE_SetFrameAllocator(self.inner.0, &mut allocator.inner) }
                 .into();
 
+        report_status("MFXVideoCORE_SetFrameAllocator", status);
+
         if status != MfxStatus::NoneOrDone {
             return Err(status);
         }
 
-        trace!("Set allocator = {:?}", status);
-
         self.allocator = Some(allocator);
 
         Ok(())
     }
 
+    /// Turns this session into a [`SharedSession`] for creating components that don't borrow it.
+    pub fn into_shared(self) -> SharedSession<'a> {
+        SharedSession {
+            inner: Arc::new(self),
+        }
+    }
+
     // Get a new instances of a decoder tied to this session
     pub fn decoder(&self, params: MfxVideoParams) -> Result<Decoder, MfxStatus> {
         Decoder::new(self, params)
@@ -1188,19 +1627,32 @@ impl<'a> Session<'a> {
         &self,
         bitstream: &mut Bitstream,
         io_pattern: IoPattern,
+    ) -> Result<MfxVideoParams, MfxStatus> {
+        let mut params = MfxVideoParams::default();
+        params.set_io_pattern(io_pattern);
+
+        self.decode_header_with_params(bitstream, params)
+    }
+
+    /// Same as [`Session::decode_header`] but starts from `params` instead of
+    /// a blank [`MfxVideoParams`]. Use this to set things like the async depth
+    /// or extension buffers before the header is parsed. The codec is always
+    /// taken from the bitstream.
+    pub fn decode_header_with_params(
+        &self,
+        bitstream: &mut Bitstream,
+        mut params: MfxVideoParams,
     ) -> Result<MfxVideoParams, MfxStatus> {
         let lib = get_library().unwrap();
 
-        let mut params = MfxVideoParams::default();
         params.set_codec(bitstream.codec());
-        params.set_io_pattern(io_pattern);
 
         let status: MfxStatus = unsafe {
             lib.MFXVideoDECODE_DecodeHeader(self.inner.0, &mut bitstream.inner, &mut **params)
         }
         .into();
 
-        trace!("Decode header = {:?}", status);
+        report_status("MFXVideoDECODE_DecodeHeader", status);
 
         if status != MfxStatus::NoneOrDone {
             return Err(status);
@@ -1226,23 +1678,167 @@ impl<'a> Session<'a> {
         Ok(params)
     }
 
+    /// Same as [`Session::decode_header`] but leaves the bitstream's offset
+    /// and size untouched, so the same data can be parsed or decoded again.
+    pub fn peek_header(
+        &self,
+        bitstream: &mut Bitstream,
+        io_pattern: IoPattern,
+    ) -> Result<MfxVideoParams, MfxStatus> {
+        let mut params = MfxVideoParams::default();
+        params.set_io_pattern(io_pattern);
+
+        self.peek_header_with_params(bitstream, params)
+    }
+
+    /// Same as [`Session::peek_header`] but also returns the stream properties (codec, profile, level, size...) in a [`decode::HeaderInfo`], so you can decide whether to decode the stream without digging through the params. The bitstream is left untouched.
+    pub fn decode_header_info(
+        &self,
+        bitstream: &mut Bitstream,
+        io_pattern: IoPattern,
+    ) -> Result<(MfxVideoParams, decode::HeaderInfo), MfxStatus> {
+        let params = self.peek_header(bitstream, io_pattern)?;
+        let info = params.header_info();
+
+        debug!(
+            "Header: {:?} profile {} level {}",
+            info.codec, info.profile, info.level
+        );
+
+        Ok((params, info))
+    }
+
+    /// Same as [`Session::decode_header_with_params`] but leaves the
+    /// bitstream's offset and size untouched.
+    pub fn peek_header_with_params(
+        &self,
+        bitstream: &mut Bitstream,
+        params: MfxVideoParams,
+    ) -> Result<MfxVideoParams, MfxStatus> {
+        let data_offset = bitstream.inner.DataOffset;
+        let data_length = bitstream.inner.DataLength;
+
+        let result = self.decode_header_with_params(bitstream, params);
+
+        bitstream.inner.DataOffset = data_offset;
+        bitstream.inner.DataLength = data_length;
+
+        result
+    }
+
     pub fn implementation(&self) -> Result<MfxImpl, MfxStatus> {
+        let implementation = self.query_impl()?;
+
+        let implementation =
+            MfxImpl::try_from(implementation as ffi::mfxImplType).unwrap();
+
+        Ok(implementation)
+    }
+
+    /// The description of the implementation this session runs on, Eg. to log its [`ImplDescription::name`] or [`ImplDescription::device_id`].
+    ///
+    /// Enumerates the implementations of the loader the session was created with and returns the one at the index the session was created with. If its implementation type doesn't match the session's (Eg. because the loader's filters changed since) the first implementation of the session's type is returned instead. [`MfxStatus::NotFound`] if there is none.
+    pub fn implementation_description(&self) -> Result<ImplDescription<'_>, MfxStatus> {
+        // MFX_IMPL_BASETYPE
+        let implementation_type = match self.query_impl()? & 0x00ff {
+            base if base == ffi::MFX_IMPL_SOFTWARE as ffi::mfxIMPL => ImplementationType::SOFTWARE,
+            _ => ImplementationType::HARDWARE,
+        };
+
+        let mut implementations = unsafe { enum_implementations(self.loader.0) }?;
+        let index = match implementations.get(self.impl_index as usize) {
+            Some(description) if description.implementation_type() == implementation_type => {
+                self.impl_index as usize
+            }
+            _ => implementations
+                .iter()
+                .position(|description| description.implementation_type() == implementation_type)
+                .ok_or(MfxStatus::NotFound)?,
+        };
+
+        Ok(implementations.swap_remove(index))
+    }
+
+    /// The infrastructure hardware acceleration goes through for this session, Eg. [`AccelerationMode::VAAPI`] on Linux. Software sessions return [`AccelerationMode::NA`].
+    pub fn acceleration_mode(&self) -> Result<AccelerationMode, MfxStatus> {
+        let implementation = self.query_impl()?;
+
+        // MFX_IMPL_VIA_MASK
+        let via = implementation as u32 & 0x0f00;
+        let mode = if via == ffi::MFX_IMPL_VIA_D3D9 as u32 {
+            AccelerationMode::D3D9
+        } else if via == ffi::MFX_IMPL_VIA_D3D11 as u32 {
+            AccelerationMode::D3D11
+        } else if via == ffi::MFX_IMPL_VIA_VAAPI as u32 {
+            AccelerationMode::VAAPI
+        } else if via == ffi::MFX_IMPL_VIA_HDDLUNITE as u32 {
+            AccelerationMode::HDDLUNITE
+        } else {
+            AccelerationMode::NA
+        };
+
+        Ok(mode)
+    }
+
+    /// The memory types `component` natively supports for `params` (Eg. the codec), found with a Query that has no input. Use it to decide whether surfaces have to go through VPP to reach system or video memory.
+    ///
+    /// Only the codec and frame info of `params` are used, extension buffers are ignored.
+    pub fn supported_io_patterns(
+        &self,
+        component: Component,
+        params: &videoparams::VideoParams,
+    ) -> Result<IoPattern, MfxStatus> {
+        let lib = get_library().unwrap();
+        let session = self.inner.0;
+
+        let mut out: ffi::mfxVideoParam = **params;
+        out.IOPattern = 0;
+        out.NumExtParam = 0;
+        out.ExtParam = std::ptr::null_mut();
+
+        // A null input makes Query return the natively supported IOPattern
+        let null = std::ptr::null_mut();
+        let (name, status) = unsafe {
+            match component {
+                Component::Decode => (
+                    "MFXVideoDECODE_Query",
+                    lib.MFXVideoDECODE_Query(session, null, &mut out),
+                ),
+                Component::Encode => (
+                    "MFXVideoENCODE_Query",
+                    lib.MFXVideoENCODE_Query(session, null, &mut out),
+                ),
+                Component::Vpp => (
+                    "MFXVideoVPP_Query",
+                    lib.MFXVideoVPP_Query(session, null, &mut out),
+                ),
+            }
+        };
+        let status: MfxStatus = status.into();
+
+        report_status(name, status);
+
+        if status != MfxStatus::NoneOrDone {
+            return Err(status);
+        }
+
+        Ok(IoPattern::from_bits_truncate(out.IOPattern))
+    }
+
+    fn query_impl(&self) -> Result<ffi::mfxIMPL, MfxStatus> {
         let lib = get_library().unwrap();
 
-        let mut implementation = 0i32;
+        let mut implementation = 0;
 
         let status: MfxStatus =
             unsafe { lib.MFXQueryIMPL(self.inner.0, &mut implementation) }.into();
 
-        trace!("Session implementation = {:?}", status);
+        report_status("MFXQueryIMPL", status);
 
         if status != MfxStatus::NoneOrDone {
             return Err(status);
         }
 
-        let implementation =
-            MfxImpl::try_from(implementation as ffi::mfxImplType).unwrap();
-
         Ok(implementation)
     }
 
@@ -1252,6 +1848,7 @@ impl<'a> Session<'a> {
         let mut version: ffi::mfxVersion = unsafe { mem::zeroed() };
 
         let status = unsafe { lib.MFXQueryVersion(self.inner.0, &mut version) }.into();
+        report_status("MFXQueryVersion", status);
 
         if status != MfxStatus::NoneOrDone {
             return Err(status);
@@ -1263,12 +1860,17 @@ impl<'a> Session<'a> {
     }
 
     /// You should probably be setting the accelerator on the loader then creating a session.
-    pub fn set_accelerator(&mut self, handle: AcceleratorHandle) -> Result<(), MfxStatus> {
+    pub fn set_accelerator(
+        &mut self,
+        handle: impl Into<Arc<AcceleratorHandle>>,
+    ) -> Result<(), MfxStatus> {
+        let handle = handle.into();
         let lib = get_library().unwrap();
         let status = unsafe {
             lib.MFXVideoCORE_SetHandle(self.inner.0, handle.mfx_type(), *handle.handle())
         }
         .into();
+        report_status("MFXVideoCORE_SetHandle", status);
 
         if status != MfxStatus::NoneOrDone {
             return Err(status);
@@ -1279,6 +1881,33 @@ impl<'a> Session<'a> {
         Ok(())
     }
 
+    /// The `VADisplay` of the accelerator set with [`Session::set_accelerator`] or [`Loader::set_accelerator`], so it can be shared with other libva based code (Eg. to create surfaces or run filters outside of this crate).
+    ///
+    /// The display is terminated when the last session or loader holding it is dropped.
+    #[cfg(target_os = "linux")]
+    pub fn va_display(&self) -> Option<*mut c_void> {
+        match &**self.accelerator.as_ref()? {
+            AcceleratorHandle::VAAPI((_, display)) => Some(*display),
+        }
+    }
+
+    /// Returns the handle of type `type_` the library is using for this session (Eg. the `VADisplay` for [`ffi::mfxHandleType_MFX_HANDLE_VA_DISPLAY`]). Use it to check a handle set with [`Session::set_accelerator`] was actually accepted. Returns [`MfxStatus::NotFound`] if no handle of that type has been set.
+    ///
+    /// See https://spec.oneapi.io/versions/latest/elements/oneVPL/source/API_ref/VPL_func_vid_core.html#mfxvideocore-gethandle for more info.
+    pub fn handle(&self, type_: ffi::mfxHandleType) -> Result<*mut c_void, MfxStatus> {
+        let lib = get_library().unwrap();
+        let mut handle: ffi::mfxHDL = std::ptr::null_mut();
+        let status =
+            unsafe { lib.MFXVideoCORE_GetHandle(self.inner.0, type_, &mut handle) }.into();
+        report_status("MFXVideoCORE_GetHandle", status);
+
+        if status != MfxStatus::NoneOrDone {
+            return Err(status);
+        }
+
+        Ok(handle)
+    }
+
     /// Initiates execution of an asynchronous function not already started and returns the status code after the specified asynchronous operation completes. If wait is zero, the function returns immediately. `wait` is in milliseconds and defaults to 1000.
     pub fn sync(
         &self,
@@ -1290,6 +1919,7 @@ impl<'a> Session<'a> {
             lib.MFXVideoCORE_SyncOperation(self.inner.0, sync_point, wait.unwrap_or(1000))
         }
         .into();
+        report_status("MFXVideoCORE_SyncOperation", status);
 
         match status {
             MfxStatus::NoneOrDone => Ok(status),
@@ -1299,6 +1929,60 @@ impl<'a> Session<'a> {
     }
 }
 
+/// A [`Session`] that can be shared by the components created from it. Components created from a [`SharedSession`] own a reference to the session instead of borrowing it, so they can be moved to other threads or tasks. Create it with [`Loader::into_shared_session`] so the session owns its loader too, then the components are `'static` and can be passed to [`tokio::spawn`].
+///
+/// Cloning is cheap, all clones refer to the same session.
+#[derive(Debug, Clone)]
+pub struct SharedSession<'a> {
+    inner: Arc<Session<'a>>,
+}
+
+impl<'a> SharedSession<'a> {
+    // Get a new instances of a decoder that keeps this session alive
+    pub fn decoder(&self, params: MfxVideoParams) -> Result<Decoder<'a, 'a>, MfxStatus> {
+        Decoder::init(SessionRef::Shared(self.inner.clone()), params)
+    }
+
+    // Get a new instances of a encoder that keeps this session alive
+    pub fn encoder(&self, params: MfxVideoParams) -> Result<Encoder<'a, 'a>, MfxStatus> {
+        Encoder::init(SessionRef::Shared(self.inner.clone()), params)
+    }
+
+    // Get a new instances of a video processor that keeps this session alive
+    pub fn video_processor(
+        &self,
+        params: &mut crate::vpp::VppVideoParams,
+    ) -> Result<VideoProcessor<'a, 'a>, MfxStatus> {
+        VideoProcessor::init(SessionRef::Shared(self.inner.clone()), params)
+    }
+}
+
+impl<'a> Deref for SharedSession<'a> {
+    type Target = Session<'a>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+/// How a component refers to the session it was created from.
+#[derive(Debug)]
+pub(crate) enum SessionRef<'a, 'b> {
+    Borrowed(&'a Session<'b>),
+    Shared(Arc<Session<'b>>),
+}
+
+impl<'b> Deref for SessionRef<'_, 'b> {
+    type Target = Session<'b>;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            SessionRef::Borrowed(session) => session,
+            SessionRef::Shared(session) => session,
+        }
+    }
+}
+
 impl Drop for Session<'_> {
     fn drop(&mut self) {
         let lib = get_library().unwrap();
@@ -1306,30 +1990,66 @@ impl Drop for Session<'_> {
     }
 }
 
-// FIXME: This function is not sync, calling this function from multiple threads at the same time results in a race condition
-// Might be able to fix this with an RWLock
-pub fn get_library() -> Result<&'static ffi::vpl, libloading::Error> {
-    if let Some(vpl) = LIBRARY.get() {
-        return Ok(vpl);
-    }
+/// Registers a hook that observes the status of every oneVPL function call this crate makes, replacing any previously registered hook. Useful for diagnosing where an [`MfxStatus::Unknown`] or [`MfxStatus::Unsupported`] comes from.
+///
+/// The hook is called from whatever thread made the call, so keep it cheap. It must not call [`set_status_hook`] or [`clear_status_hook`] itself.
+pub fn set_status_hook(hook: StatusHook) {
+    *STATUS_HOOK.write().unwrap() = Some(hook);
+}
 
-    #[cfg(target_os = "windows")]
-    let library_name = "libvpl";
-    #[cfg(target_os = "linux")]
-    let library_name = "vpl";
-    let lib = {
-        let library_name = libloading::library_filename(library_name);
-        let lib = unsafe { ffi::vpl::new(library_name) }?;
-        lib
-    };
+/// Removes the hook registered with [`set_status_hook`].
+pub fn clear_status_hook() {
+    *STATUS_HOOK.write().unwrap() = None;
+}
 
-    if LIBRARY.set(lib).is_err() {
-        panic!("Failed to set library");
+/// Logs the status returned by a oneVPL function and passes it to the status hook.
+pub(crate) fn report_status(function: &str, status: MfxStatus) {
+    trace!("{} = {:?}", function, status);
+    if let Some(hook) = STATUS_HOOK.read().unwrap().as_ref() {
+        hook(function, status);
     }
+}
+
+/// Sets the path the oneVPL dispatcher library is loaded from instead of searching for `vpl`/`libvpl` by name. This must be called before anything else in this crate uses the library (Eg. [`Loader::new`]).
+///
+/// Returns an error if a path was already set, or if the library has already been loaded by name. That includes failed attempts, a path can't be set after [`get_library`] was called.
+pub fn set_library_path(path: impl AsRef<Path>) -> io::Result<()> {
+    LIBRARY_PATH
+        .set(Some(path.as_ref().to_path_buf()))
+        .map_err(|_| match LIBRARY_PATH.get() {
+            Some(Some(path)) => io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "the vpl library path has already been set to {}",
+                    path.display()
+                ),
+            ),
+            _ => io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "the vpl library has already been loaded",
+            ),
+        })
+}
+
+pub fn get_library() -> Result<&'static ffi::vpl, libloading::Error> {
+    LIBRARY.get_or_try_init(|| {
+        #[cfg(target_os = "windows")]
+        let library_name = "libvpl";
+        #[cfg(target_os = "linux")]
+        let library_name = "vpl";
+
+        let lib = match LIBRARY_PATH.get_or_init(|| None) {
+            Some(path) => unsafe { ffi::vpl::new(path) }?,
+            None => {
+                let library_name = libloading::library_filename(library_name);
+                unsafe { ffi::vpl::new(library_name) }?
+            }
+        };
 
-    debug!("Dynamic library loaded successfully");
+        debug!("Dynamic library loaded successfully");
 
-    Ok(get_library().unwrap())
+        Ok(lib)
+    })
 }
 
 /// Returns the number of detected graphics adapters.
@@ -1339,6 +2059,7 @@ pub fn num_adapters() -> Result<u32, MfxStatus> {
     let mut num = 0u32;
 
     let status = unsafe { lib.MFXQueryAdaptersNumber(&mut num) }.into();
+    report_status("MFXQueryAdaptersNumber", status);
 
     if status != MfxStatus::NoneOrDone {
         return Err(status);
@@ -1392,6 +2113,261 @@ mod functional_tests {
         let _session = loader.new_session(0).unwrap();
 
     }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[traced_test]
+    fn session_va_display() {
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(true);
+
+        let mut session = loader.new_session(0).unwrap();
+        assert_eq!(session.va_display(), None);
+
+        let handle = AcceleratorHandle::vaapi_from_file(None).unwrap();
+        let display = *handle.handle();
+        session.set_accelerator(handle).unwrap();
+
+        assert_eq!(session.va_display(), Some(display));
+    }
+
+    #[test]
+    #[traced_test]
+    fn session_partial_acceleration() {
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+
+        let session = loader.new_session(0).unwrap();
+        // The software implementation never falls back to software
+        assert!(!session.is_partially_accelerated());
+    }
+
+    #[test]
+    #[traced_test]
+    fn session_require_keyword() {
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+        let keywords: Vec<String> = match loader.implementations() {
+            Ok(implementations) if !implementations.is_empty() => {
+                let keywords = implementations[0].keyword_list();
+                if keywords.is_empty() {
+                    warn!("Skipping, implementation has no keywords");
+                    return;
+                }
+                keywords.into_iter().map(str::to_string).collect()
+            }
+            _ => {
+                warn!("Skipping, no software implementations found");
+                return;
+            }
+        };
+
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+        loader.require_keyword(&keywords[0]);
+        let _session = loader.new_session(0).unwrap();
+
+        // Every keyword is matched on its own, whatever order they are listed in
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+        let reversed: Vec<_> = keywords.iter().rev().map(String::as_str).collect();
+        loader.require_keyword(&reversed.join(", "));
+        let _session = loader.new_session(0).unwrap();
+
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+        loader.require_keyword("no such keyword");
+        assert_eq!(loader.new_session(0).err(), Some(MfxStatus::NotFound));
+
+        // All of them have to be present
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+        loader.require_keyword(&format!("{},no such keyword", keywords[0]));
+        assert_eq!(loader.new_session(0).err(), Some(MfxStatus::NotFound));
+    }
+
+    #[test]
+    #[traced_test]
+    fn session_require_device() {
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(true);
+        let device_id = match loader.implementations() {
+            Ok(implementations) if !implementations.is_empty() => {
+                let device_id = implementations[0].device_id();
+                let hex = device_id.split('/').next().unwrap();
+                u16::from_str_radix(hex, 16).unwrap()
+            }
+            _ => {
+                warn!("Skipping, no hardware implementations found");
+                return;
+            }
+        };
+
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(true);
+        loader.require_device(device_id);
+        let _session = loader.new_session(0).unwrap();
+
+        // No such device
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(true);
+        loader.require_device(0xffff);
+        assert_eq!(loader.new_session(0).err(), Some(MfxStatus::NotFound));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[traced_test]
+    fn session_require_acceleration_mode() {
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(true);
+        if !matches!(loader.implementations(), Ok(implementations) if !implementations.is_empty()) {
+            warn!("Skipping, no hardware implementations found");
+            return;
+        }
+
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(true);
+        loader.require_acceleration_mode(AccelerationMode::VAAPI);
+        let _session = loader.new_session(0).unwrap();
+
+        // Not available on Linux
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(true);
+        loader.require_acceleration_mode(AccelerationMode::D3D11);
+        assert_eq!(loader.new_session(0).err(), Some(MfxStatus::NotFound));
+    }
+
+    #[test]
+    #[traced_test]
+    fn decode_supported_io_patterns() {
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+        loader.require_decoder(Codec::HEVC);
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut data = std::fs::read("tests/frozen.hevc").unwrap();
+        let mut bitstream = Bitstream::with_codec(&mut data, Codec::HEVC);
+        bitstream.set_size(bitstream.len());
+        let params = session
+            .decode_header(&mut bitstream, IoPattern::OUT_SYSTEM_MEMORY)
+            .unwrap();
+
+        let patterns = session
+            .supported_io_patterns(Component::Decode, &params)
+            .unwrap();
+        debug!("HEVC decode supports {:?}", patterns);
+        assert!(!patterns.is_empty());
+    }
+
+    #[test]
+    #[traced_test]
+    fn session_implementation_description() {
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+        loader.require_decoder(Codec::HEVC);
+        let session = loader.new_session(0).unwrap();
+
+        let description = session.implementation_description().unwrap();
+        debug!("Session implementation = {}", description.name());
+        assert!(!description.name().is_empty());
+        assert_eq!(description.implementation_type(), ImplementationType::SOFTWARE);
+    }
+
+    #[test]
+    #[traced_test]
+    fn session_with_fallback() {
+        let mut loader = Loader::new().unwrap();
+        loader.require_decoder(Codec::HEVC);
+        let (session, implementation) = loader.new_session_with_fallback(0).unwrap();
+        if implementation == ImplementationType::HARDWARE {
+            assert_ne!(session.acceleration_mode(), Ok(AccelerationMode::NA));
+        } else {
+            assert_eq!(implementation, ImplementationType::SOFTWARE);
+            assert_eq!(session.acceleration_mode(), Ok(AccelerationMode::NA));
+        }
+        drop(session);
+
+        // An acceleration mode no implementation on this machine uses forces the fallback
+        let mut loader = Loader::new().unwrap();
+        loader.require_acceleration_mode(AccelerationMode::HDDLUNITE);
+        let (session, implementation) = loader.new_session_with_fallback(0).unwrap();
+        assert_eq!(implementation, ImplementationType::SOFTWARE);
+        assert!(session.version().is_ok());
+
+        // The acceleration mode filter is back in place for later sessions
+        drop(session);
+        assert!(loader.implementations().unwrap().is_empty());
+        assert!(loader.new_session(0).is_err());
+
+        // Same for the filter of use_hardware, which the fallback lifts as well
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(true);
+        let hardware = loader.implementations().unwrap().len();
+        let (session, implementation) = loader.new_session_with_fallback(0).unwrap();
+        assert_eq!(implementation == ImplementationType::HARDWARE, hardware > 0);
+        drop(session);
+        assert_eq!(loader.implementations().unwrap().len(), hardware);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[traced_test]
+    fn session_acceleration_mode() {
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(true);
+        if !matches!(loader.implementations(), Ok(implementations) if !implementations.is_empty()) {
+            warn!("Skipping, no hardware implementations found");
+            return;
+        }
+        let session = loader.new_session(0).unwrap();
+        assert_eq!(session.acceleration_mode(), Ok(AccelerationMode::VAAPI));
+
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+        let session = loader.new_session(0).unwrap();
+        assert_eq!(session.acceleration_mode(), Ok(AccelerationMode::NA));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[traced_test]
+    fn session_get_va_display_handle() {
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(true);
+
+        let mut session = loader.new_session(0).unwrap();
+
+        let handle = AcceleratorHandle::vaapi_from_file(None).unwrap();
+        let display = *handle.handle();
+        let handle_type = handle.mfx_type();
+        session.set_accelerator(handle).unwrap();
+
+        assert_eq!(session.handle(handle_type), Ok(display));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[traced_test]
+    fn clone_accelerator_handle() {
+        let handle = AcceleratorHandle::vaapi_from_file(None).unwrap();
+        let clone = handle.try_clone().unwrap();
+        assert!(!clone.handle().is_null());
+        assert_ne!(handle.handle(), clone.handle());
+
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(true);
+        loader.set_accelerator(handle).unwrap();
+
+        let mut session = loader.new_session(0).unwrap();
+        session.set_accelerator(clone).unwrap();
+        session.implementation().unwrap();
+
+        // Each handle terminates its own display
+        drop(session);
+        drop(loader);
+    }
 }
 
 pub struct FrameInfo<'a> {
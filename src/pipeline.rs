@@ -0,0 +1,290 @@
+//! Decoding straight into a [`VideoProcessor`], Eg. to scale or convert decoded frames before encoding.
+
+use std::fmt::Debug;
+
+use ffi::MfxStatus;
+use intel_onevpl_sys as ffi;
+use tracing::debug;
+
+use crate::{
+    bitstream::Bitstream, decode::Decoder, vpp::VideoProcessor, vpp::VppVideoParams, FrameSurface,
+    MfxVideoParams, Session,
+};
+
+/// Called with the decoder's new parameters and the VPP parameters that are about to be used, see [`Pipeline::on_param_change`].
+pub type ParamChangeHook<'a> = dyn FnMut(&MfxVideoParams, &mut VppVideoParams) + Send + 'a;
+
+/// Where [`Pipeline::process`] is in following a change of the stream parameters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    Decoding,
+    /// Outputting the frames the decoder still holds from before the change, which was reported with this status
+    DrainingDecoder(MfxStatus),
+    /// Same for the frames the VPP still holds
+    DrainingVpp(MfxStatus),
+}
+
+/// A decoder feeding a [`VideoProcessor`] on the same session.
+///
+/// When the decoder reports [`MfxStatus::VideoParamChanged`] or [`MfxStatus::IncompatibleVideoParam`] (Eg. a resolution switch in an adaptive bitrate stream) the frames from before the change are drained from the decoder and the VPP first. Then the decoder follows the new stream, reset for a compatible change or initialized again from the new header, and the VPP is initialized again with its input matching the new stream, so its output doesn't keep using the old size.
+pub struct Pipeline<'a> {
+    session: &'a Session<'a>,
+    decoder: Decoder<'a, 'a>,
+    vpp: VideoProcessor<'a, 'a>,
+    vpp_params: VppVideoParams,
+    on_param_change: Option<Box<ParamChangeHook<'a>>>,
+    stage: Stage,
+}
+
+impl Debug for Pipeline<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Pipeline")
+            .field("vpp_params", &self.vpp_params)
+            .finish()
+    }
+}
+
+impl<'a> Pipeline<'a> {
+    pub fn new(
+        session: &'a Session<'a>,
+        decode_params: MfxVideoParams,
+        mut vpp_params: VppVideoParams,
+    ) -> Result<Self, MfxStatus> {
+        let decoder = Decoder::new(session, decode_params)?;
+        let vpp = VideoProcessor::new(session, &mut vpp_params)?;
+
+        Ok(Self {
+            session,
+            decoder,
+            vpp,
+            vpp_params,
+            on_param_change: None,
+            stage: Stage::Decoding,
+        })
+    }
+
+    /// Runs `hook` when the stream parameters change, after the VPP input was updated from the decoder's new parameters and before the VPP is initialized again. Change the VPP output here, Eg. to keep the output size in step with the stream. Without a hook the output parameters stay as they were.
+    pub fn on_param_change(
+        &mut self,
+        hook: impl FnMut(&MfxVideoParams, &mut VppVideoParams) + Send + 'a,
+    ) {
+        self.on_param_change = Some(Box::new(hook));
+    }
+
+    /// Decodes the next frame from `bitstream` and runs it through VPP. Pass `None` to drain, same as [`Decoder::decode`].
+    ///
+    /// Keep passing the bitstream while the pipeline drains after a parameter change, a decoder that can't follow the new stream is initialized again from the header in it.
+    pub async fn process(
+        &mut self,
+        mut bitstream: Option<&mut Bitstream<'_>>,
+    ) -> Result<FrameSurface, MfxStatus> {
+        // The frames returned keep the decoder and VPP borrowed, so they're only ever closed and
+        // initialized again in place rather than replaced
+        loop {
+            match self.stage {
+                Stage::Decoding => {
+                    let mut frame =
+                        match self.decoder.decode(bitstream.as_deref_mut(), None, None).await {
+                            Ok(frame) => frame,
+                            Err(
+                                status @ (MfxStatus::VideoParamChanged
+                                | MfxStatus::IncompatibleVideoParam),
+                            ) => {
+                                debug!("Pipeline draining after {:?}", status);
+                                self.stage = Stage::DrainingDecoder(status);
+                                continue;
+                            }
+                            Err(e) => return Err(e),
+                        };
+                    return self.vpp.process(Some(&mut frame), None).await;
+                }
+                Stage::DrainingDecoder(status) => {
+                    let mut frame = match self.decoder.decode(None, None, None).await {
+                        Ok(frame) => frame,
+                        Err(MfxStatus::MoreData) => {
+                            self.stage = Stage::DrainingVpp(status);
+                            continue;
+                        }
+                        Err(e) => return Err(e),
+                    };
+                    match self.vpp.process(Some(&mut frame), None).await {
+                        // Asking for more input would make the caller read more of the bitstream
+                        Err(MfxStatus::MoreData) => continue,
+                        result => return result,
+                    }
+                }
+                Stage::DrainingVpp(status) => {
+                    match self.vpp.process(None, None).await {
+                        Err(MfxStatus::MoreData) => {}
+                        result => return result,
+                    }
+
+                    let decode_params = if status == MfxStatus::IncompatibleVideoParam {
+                        // Reset can't go beyond the size the decoder was initialized with, start
+                        // over from the new header
+                        let bitstream = bitstream.as_deref_mut().ok_or(MfxStatus::NullPtr)?;
+                        let old_params = self.decoder.params()?;
+                        let mut params =
+                            self.session.decode_header(bitstream, old_params.io_pattern())?;
+                        params.set_async_depth(old_params.async_depth());
+                        self.decoder.reinit(params.clone())?;
+                        params
+                    } else {
+                        // Nothing left to drain, only the new parameters are returned
+                        self.decoder.handle_param_change().await?.0
+                    };
+
+                    let mut vpp_params = self.vpp_params.clone();
+                    vpp_params.set_in_from(&decode_params);
+                    if let Some(hook) = self.on_param_change.as_mut() {
+                        hook(&decode_params, &mut vpp_params);
+                    }
+                    // Reset can't go above the size VPP was initialized with either
+                    self.vpp.reinit(&mut vpp_params)?;
+                    self.vpp_params = vpp_params;
+                    self.stage = Stage::Decoding;
+
+                    debug!(
+                        "Pipeline following the new stream, input crop = {:?}",
+                        decode_params.crop()
+                    );
+                }
+            }
+        }
+    }
+
+    pub fn decoder(&self) -> &Decoder<'a, 'a> {
+        &self.decoder
+    }
+
+    /// The parameters the VPP is currently running with.
+    pub fn vpp_params(&self) -> &VppVideoParams {
+        &self.vpp_params
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_test::traced_test;
+
+    use crate::{
+        bitstream::Bitstream,
+        constants::{Codec, IoPattern},
+        vpp::VppVideoParams,
+        Loader, MfxStatus,
+    };
+
+    use super::Pipeline;
+
+    /// Decodes `path` on its own and returns how many frames it holds.
+    async fn count_frames(path: &str) -> usize {
+        let data = std::fs::read(path).unwrap();
+
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+        loader.require_decoder(Codec::HEVC);
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut bitstream = Bitstream::from_slice(&data, Codec::HEVC);
+        let params = session
+            .decode_header(&mut bitstream, IoPattern::OUT_SYSTEM_MEMORY)
+            .unwrap();
+        let decoder = session.decoder(params).unwrap();
+
+        let mut frames = 0;
+        while decoder.drain(&mut bitstream, None).await.is_ok() {
+            frames += 1;
+        }
+        frames
+    }
+
+    /// Runs the stream in `first` followed by the one in `second` through a pipeline that keeps
+    /// the output the size of the stream. Returns the crop of every output frame, along with the
+    /// crops of the two streams.
+    async fn switch_streams(
+        first: &str,
+        second: &str,
+    ) -> (Vec<(u16, u16)>, (u16, u16), (u16, u16)) {
+        let mut data = std::fs::read(first).unwrap();
+        let second = std::fs::read(second).unwrap();
+
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+        loader.require_decoder(Codec::HEVC);
+
+        let session = loader.new_session(0).unwrap();
+
+        let expected = session
+            .decode_header(
+                &mut Bitstream::from_slice(&second, Codec::HEVC),
+                IoPattern::OUT_SYSTEM_MEMORY,
+            )
+            .unwrap()
+            .crop();
+        data.extend_from_slice(&second);
+
+        let mut bitstream = Bitstream::from_slice(&data, Codec::HEVC);
+        let decode_params = session
+            .decode_header(&mut bitstream, IoPattern::OUT_SYSTEM_MEMORY)
+            .unwrap();
+        let first = decode_params.crop();
+        assert_ne!(first, expected);
+
+        let mut vpp_params = VppVideoParams::from(&decode_params);
+        vpp_params.set_io_pattern(IoPattern::SYSTEM_MEMORY);
+
+        let mut pipeline = Pipeline::new(&session, decode_params, vpp_params).unwrap();
+        // Scale nothing, keep the output the size of the stream
+        pipeline.on_param_change(|decode_params, vpp_params| {
+            let io_pattern = vpp_params.io_pattern();
+            *vpp_params = VppVideoParams::from(decode_params);
+            vpp_params.set_io_pattern(io_pattern);
+        });
+
+        let mut crops = Vec::new();
+        let mut draining = false;
+        loop {
+            let bitstream = if draining { None } else { Some(&mut bitstream) };
+            match pipeline.process(bitstream).await {
+                Ok(frame) => {
+                    let bounds = frame.bounds();
+                    crops.push((bounds.crop_width, bounds.crop_height));
+                }
+                Err(MfxStatus::MoreData) if !draining => draining = true,
+                Err(MfxStatus::MoreData) => break,
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+
+        (crops, first, expected)
+    }
+
+    #[traced_test]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn pipeline_tracks_resolution_switch() {
+        let (crops, first, expected) =
+            switch_streams("tests/frozen1080.hevc", "tests/frozen.hevc").await;
+
+        assert_eq!(crops.first(), Some(&first));
+        assert_eq!(crops.last(), Some(&expected));
+        // No frame from before the switch is lost
+        let frames =
+            count_frames("tests/frozen1080.hevc").await + count_frames("tests/frozen.hevc").await;
+        assert_eq!(crops.len(), frames);
+    }
+
+    #[traced_test]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn pipeline_grows_on_resolution_switch() {
+        // The second stream is larger than the one the decoder and VPP were initialized with
+        let (crops, first, expected) =
+            switch_streams("tests/frozen.hevc", "tests/frozen1080.hevc").await;
+
+        assert_eq!(crops.first(), Some(&first));
+        assert_eq!(crops.last(), Some(&expected));
+        let frames =
+            count_frames("tests/frozen.hevc").await + count_frames("tests/frozen1080.hevc").await;
+        assert_eq!(crops.len(), frames);
+    }
+}
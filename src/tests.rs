@@ -1,5 +1,778 @@
+use std::{
+    cell::Cell,
+    io,
+    mem::{self, ManuallyDrop},
+    rc::Rc,
+    time::Duration,
+};
+
+use intel_onevpl_sys as ffi;
+
+use crate::{
+    constants::{
+        ChromaFormat, Codec, ContentInfo, ExtMemFrameType, FieldOrder, FourCC, PicStruct,
+        RateControlMethod,
+    },
+    convert,
+    utils::{aligned_frame, frame_checksum, AlignedFrame, Rect},
+    videoparams::ExtraCodingOption3,
+    vpp::VppVideoParams,
+    BusyRetry, FrameRate, FrameSurface, MfxStatus, MfxVideoParams, MAX_BUSY_DELAY,
+};
+
 fn examples() {
     let t = trycmd::TestCases::new();
     t.register_bins(trycmd::cargo::compile_examples([]).unwrap());
     t.case("examples/*.md");
-}
\ No newline at end of file
+}
+
+/// System memory backing for a [`FrameSurface`] so the pixel handling code can
+/// be tested without a runtime. Planes are laid out back to back in `data`.
+pub(crate) struct TestSurface {
+    surface: Box<ffi::mfxFrameSurface1>,
+    pub data: Vec<u8>,
+}
+
+impl TestSurface {
+    pub fn new(format: FourCC, width: u16, height: u16, pitch: u16) -> Self {
+        Self::with_pitch(format, width, height, pitch as u32)
+    }
+
+    /// Same as [`TestSurface::new`] but allows pitches that need `PitchHigh`
+    pub fn with_pitch(format: FourCC, width: u16, height: u16, pitch: u32) -> Self {
+        let mut surface: Box<ffi::mfxFrameSurface1> = Box::new(unsafe { mem::zeroed() });
+        surface.Info.FourCC = format.repr() as u32;
+        let rect = unsafe { &mut surface.Info.__bindgen_anon_1.__bindgen_anon_1 };
+        rect.Width = width;
+        rect.Height = height;
+        rect.CropW = width;
+        rect.CropH = height;
+        surface.Data.__bindgen_anon_2.PitchLow = pitch as u16;
+        surface.Data.PitchHigh = (pitch >> 16) as u16;
+
+        let pitch = pitch as usize;
+        let height = height as usize;
+        // Odd heights still get a chroma row for the last luma row
+        let chroma_rows = (height + 1) / 2;
+        let (luma_len, chroma_len) = match format {
+            FourCC::IyuvOrI420 | FourCC::YV12 => (pitch * height, pitch / 2 * chroma_rows),
+            FourCC::I422 => (pitch * height, pitch / 2 * height),
+            // Interleaved UV is stored as a single plane
+            FourCC::NV12 | FourCC::NV21 => (pitch * height, pitch * chroma_rows),
+            FourCC::Rgb4OrBgra | FourCC::Y216 | FourCC::Y416 => (pitch * height, 0),
+            _ => unimplemented!("{:?}", format),
+        };
+
+        let mut data = vec![0u8; luma_len + chroma_len * 2];
+        let ptr = data.as_mut_ptr();
+        unsafe {
+            match format {
+                FourCC::Rgb4OrBgra => {
+                    surface.Data.__bindgen_anon_5.B = ptr;
+                    surface.Data.__bindgen_anon_4.G = ptr.add(1);
+                    surface.Data.__bindgen_anon_3.R = ptr.add(2);
+                    surface.Data.A = ptr.add(3);
+                }
+                FourCC::Y216 => {
+                    let samples = ptr as *mut u16;
+                    surface.Data.__bindgen_anon_3.Y16 = samples;
+                    surface.Data.__bindgen_anon_4.U16 = samples.add(1);
+                    surface.Data.__bindgen_anon_5.V16 = samples.add(3);
+                }
+                FourCC::Y416 => {
+                    surface.Data.__bindgen_anon_4.Y416 = ptr as *mut _;
+                }
+                _ => {
+                    surface.Data.__bindgen_anon_3.Y = ptr;
+                    surface.Data.__bindgen_anon_4.U = ptr.add(luma_len);
+                    surface.Data.__bindgen_anon_5.V = ptr.add(luma_len + chroma_len);
+                }
+            }
+        }
+
+        Self { surface, data }
+    }
+
+    /// The surface is never released since there is no runtime to release it to.
+    pub fn frame_surface(&mut self) -> ManuallyDrop<FrameSurface<'_>> {
+        let mut surface = FrameSurface::try_from(&mut *self.surface as *mut _).unwrap();
+        // Nothing to map, the planes always point at `data`
+        surface.mapped = true;
+        ManuallyDrop::new(surface)
+    }
+}
+
+#[test]
+fn i422_round_trip() {
+    let (width, height, pitch) = (16, 8, 32);
+    let frame_size = FrameSurface::frame_size(FourCC::I422, width, height);
+    let input: Vec<u8> = (0..frame_size).map(|i| i as u8).collect();
+
+    let mut backing = TestSurface::new(FourCC::I422, width, height, pitch);
+    let mut surface = backing.frame_surface();
+    surface.buffer.lock().unwrap().copy_from_slice(&input);
+    surface.read_buffer(FourCC::I422).unwrap();
+
+    let mut output = vec![0u8; frame_size];
+    let bytes = io::Read::read(&mut *surface, &mut output).unwrap();
+    assert_eq!(bytes, frame_size);
+    assert_eq!(input, output);
+}
+
+#[test]
+fn nv21_round_trip() {
+    let (width, height, pitch) = (16, 8, 32);
+    let frame_size = FrameSurface::frame_size(FourCC::NV21, width, height);
+    let luma_len = width as usize * height as usize;
+    // Distinct V and U values so a missed swap is visible
+    let mut input = vec![100u8; luma_len];
+    for _ in 0..luma_len / 4 {
+        input.extend_from_slice(&[200, 50]);
+    }
+    assert_eq!(input.len(), frame_size);
+
+    // Same layout on both sides, copied as is
+    let mut backing = TestSurface::new(FourCC::NV21, width, height, pitch);
+    let mut surface = backing.frame_surface();
+    surface.buffer.lock().unwrap().copy_from_slice(&input);
+    surface.read_buffer(FourCC::NV21).unwrap();
+    let mut output = vec![0u8; frame_size];
+    let bytes = io::Read::read(&mut *surface, &mut output).unwrap();
+    assert_eq!(bytes, frame_size);
+    assert_eq!(input, output);
+
+    // Into an NV12 surface the chroma has to be swapped to UV order
+    let mut backing = TestSurface::new(FourCC::NV12, width, height, pitch);
+    let mut surface = backing.frame_surface();
+    surface.buffer.lock().unwrap().copy_from_slice(&input);
+    surface.read_buffer(FourCC::NV21).unwrap();
+    let nv12 = surface.to_packed_vec().unwrap();
+    assert_eq!(nv12[..luma_len], input[..luma_len]);
+    assert!(nv12[luma_len..].chunks_exact(2).all(|uv| uv == [50, 200]));
+
+    // Both describe the same picture
+    assert_eq!(
+        surface.convert_to(FourCC::BGR4).unwrap(),
+        convert::yuv420_to_rgb(
+            &input,
+            FourCC::NV21,
+            FourCC::BGR4,
+            width as usize,
+            height as usize,
+            convert::ColorMatrix::for_height(height as usize),
+        )
+        .unwrap()
+    );
+}
+
+#[test]
+fn read_rgba_into_bgra() {
+    let (width, height, pitch) = (4, 2, 32);
+    let frame_size = FrameSurface::frame_size(FourCC::BGR4, width, height);
+    let input: Vec<u8> = [10, 20, 30, 255].repeat(frame_size / 4);
+
+    let mut backing = TestSurface::new(FourCC::Rgb4OrBgra, width, height, pitch);
+    let mut surface = backing.frame_surface();
+    surface.buffer.lock().unwrap().copy_from_slice(&input);
+    surface.read_buffer(FourCC::BGR4).unwrap();
+    // Red and blue trade places
+    let bgra = surface.to_packed_vec().unwrap();
+    assert!(bgra.chunks_exact(4).all(|pixel| pixel == [30, 20, 10, 255]));
+
+    // Same layout, but there's no reordering from AYUV
+    assert_eq!(surface.read_buffer(FourCC::AYUV), Err(MfxStatus::Unsupported));
+}
+
+#[test]
+fn odd_size_yuv420() {
+    // The last column and row have a chroma sample of their own
+    let (width, height, pitch) = (5, 3, 8);
+    let frame_size = FrameSurface::frame_size(FourCC::IyuvOrI420, width, height);
+    assert_eq!(frame_size, 5 * 3 + 3 * 2 * 2);
+    assert_eq!(FrameSurface::frame_size(FourCC::NV12, width, height), frame_size);
+
+    // Mild colors so nothing clips in RGB and the round trip below stays close
+    let luma: Vec<u8> = (0..15).map(|i| 60 + i * 5).collect();
+    let u: [u8; 6] = [110, 115, 120, 125, 130, 135];
+    let v: [u8; 6] = [145, 140, 135, 130, 125, 120];
+    let i420 = [&luma[..], &u[..], &v[..]].concat();
+    let nv12: Vec<u8> = luma
+        .iter()
+        .copied()
+        .chain(u.iter().zip(v).flat_map(|(u, v)| [*u, v]))
+        .collect();
+
+    let mut backing = TestSurface::new(FourCC::IyuvOrI420, width, height, pitch);
+    let mut surface = backing.frame_surface();
+    surface.buffer.lock().unwrap().copy_from_slice(&i420);
+    surface.read_buffer(FourCC::IyuvOrI420).unwrap();
+    assert_eq!(surface.to_packed_vec().unwrap(), i420);
+
+    let (width, height) = (width as usize, height as usize);
+    let matrix = convert::ColorMatrix::for_height(height);
+    let rgb = surface.convert_to(FourCC::BGR4).unwrap();
+    assert_eq!(rgb.len(), width * height * 4);
+    assert_eq!(
+        rgb,
+        convert::yuv420_to_rgb(&nv12, FourCC::NV12, FourCC::BGR4, width, height, matrix).unwrap()
+    );
+
+    // The bottom right pixel uses the last chroma sample
+    let pixel = [luma[14], u[5], v[5]];
+    let last =
+        convert::yuv420_to_rgb(&pixel, FourCC::IyuvOrI420, FourCC::BGR4, 1, 1, matrix).unwrap();
+    assert_eq!(rgb[14 * 4..], last[..]);
+
+    // And converting back averages only the pixels a cut off block covers
+    let yuv =
+        convert::rgb_to_yuv420(&rgb, FourCC::BGR4, FourCC::IyuvOrI420, width, height, matrix)
+            .unwrap();
+    assert_eq!(yuv.len(), frame_size);
+    for (converted, original) in yuv.iter().zip(&i420) {
+        assert!(converted.abs_diff(*original) <= 2, "{:?} != {:?}", yuv, i420);
+    }
+}
+
+#[test]
+fn y216_round_trip() {
+    let (width, height, pitch) = (16, 8, 96);
+    let frame_size = FrameSurface::frame_size(FourCC::Y216, width, height);
+    assert_eq!(frame_size, width as usize * height as usize * 4);
+    // Samples using the whole 16 bits so a lost high byte is visible
+    let input: Vec<u8> = (0..frame_size / 2)
+        .flat_map(|i| (i as u16).wrapping_mul(0x0101).wrapping_add(0x8000).to_le_bytes())
+        .collect();
+
+    let mut backing = TestSurface::new(FourCC::Y216, width, height, pitch);
+    let mut surface = backing.frame_surface();
+    surface.buffer.lock().unwrap().copy_from_slice(&input);
+    surface.read_buffer(FourCC::Y216).unwrap();
+
+    // Y0 U Y1 V of the first pixel pair, and the first sample of the second row after the padding
+    let samples = surface.packed16().unwrap();
+    assert_eq!(samples[..4], [0x8000, 0x8101, 0x8202, 0x8303]);
+    assert_eq!(samples[pitch as usize / 2], 0x8000u16.wrapping_add(32 * 0x0101));
+
+    assert_eq!(surface.to_packed_vec().unwrap(), input);
+    let mut output = vec![0u8; frame_size];
+    let bytes = io::Read::read(&mut *surface, &mut output).unwrap();
+    assert_eq!(bytes, frame_size);
+    assert_eq!(input, output);
+
+    // Not a 16 bit packed format
+    let mut backing = TestSurface::new(FourCC::NV12, width, height, pitch);
+    assert!(backing.frame_surface().packed16().is_none());
+}
+
+#[test]
+fn plane_layouts() {
+    let (width, height) = (16, 8);
+
+    let nv12 = FourCC::NV12.plane_layout();
+    assert_eq!(nv12.bits_per_sample, 8);
+    assert_eq!(nv12.planes.len(), 2);
+    assert_eq!((nv12.row_bytes(1, width), nv12.rows(1, height)), (16, 4));
+    assert_eq!(nv12.pitch(1, 32), 32);
+    assert_eq!(nv12.frame_size(width, height), 16 * 8 * 3 / 2);
+
+    let i420 = FourCC::IyuvOrI420.plane_layout();
+    assert_eq!(i420.planes.len(), 3);
+    assert_eq!((i420.row_bytes(2, width), i420.rows(2, height)), (8, 4));
+    assert_eq!(i420.pitch(2, 32), 16);
+    assert_eq!(i420.frame_size(width, height), nv12.frame_size(width, height));
+
+    // Same layout as NV12 with 16 bit samples
+    let p010 = FourCC::P010.plane_layout();
+    assert_eq!(p010.bits_per_sample, 10);
+    assert_eq!(p010.planes.len(), 2);
+    assert_eq!((p010.row_bytes(0, width), p010.row_bytes(1, width)), (32, 32));
+    assert_eq!(p010.pitch(1, 64), 64);
+    assert_eq!(p010.frame_size(width, height), 16 * 8 * 3);
+
+    let bgra = FourCC::Rgb4OrBgra.plane_layout();
+    assert_eq!(bgra.planes.len(), 1);
+    assert_eq!(bgra.row_bytes(0, width), 64);
+    assert_eq!(bgra, FourCC::BGR4.plane_layout());
+
+    let yuy2 = FourCC::YUY2.plane_layout();
+    assert_eq!(yuy2.planes.len(), 1);
+    assert_eq!(yuy2.row_bytes(0, width), 32);
+    assert_eq!(yuy2.frame_size(width, height), 16 * 8 * 2);
+    assert_eq!(
+        FrameSurface::frame_size(FourCC::YUY2, width as u16, height as u16),
+        yuy2.frame_size(width, height)
+    );
+}
+
+#[test]
+fn to_packed_vec_strips_pitch() {
+    let (width, height) = (16, 8);
+    for (format, pitch) in [
+        (FourCC::IyuvOrI420, 32),
+        (FourCC::YV12, 32),
+        (FourCC::I422, 32),
+        (FourCC::NV12, 32),
+        (FourCC::NV21, 32),
+        (FourCC::Rgb4OrBgra, 96),
+        (FourCC::Y216, 96),
+        (FourCC::Y416, 160),
+    ] {
+        let mut backing = TestSurface::new(format, width, height, pitch);
+        let mut surface = backing.frame_surface();
+        let packed = surface.to_packed_vec().unwrap();
+        assert_eq!(packed.len(), FrameSurface::frame_size(format, width, height), "{:?}", format);
+    }
+}
+
+#[test]
+fn copy_to_respects_pitch() {
+    let (width, height) = (16, 8);
+    let frame_size = FrameSurface::frame_size(FourCC::IyuvOrI420, width, height);
+    let input: Vec<u8> = (0..frame_size).map(|i| i as u8).collect();
+
+    let mut src_backing = TestSurface::new(FourCC::IyuvOrI420, width, height, 32);
+    let mut src = src_backing.frame_surface();
+    src.buffer.lock().unwrap().copy_from_slice(&input);
+    src.read_buffer(FourCC::IyuvOrI420).unwrap();
+    src.set_timestamp(1234);
+
+    // A different pitch so rows have to be copied one by one
+    let mut dst_backing = TestSurface::new(FourCC::IyuvOrI420, width, height, 64);
+    let mut dst = dst_backing.frame_surface();
+    src.copy_to(&mut dst).unwrap();
+
+    assert_eq!(dst.to_packed_vec().unwrap(), input);
+    assert_eq!(dst.timestamp(), 1234);
+    // The padding is left alone
+    assert!(dst_backing.data[16..64].iter().all(|b| *b == 0));
+
+    let mut nv12_backing = TestSurface::new(FourCC::NV12, width, height, 32);
+    let mut nv12 = nv12_backing.frame_surface();
+    assert_eq!(src.copy_to(&mut nv12), Err(MfxStatus::IncompatibleVideoParam));
+}
+
+#[test]
+fn pitch_above_u16() {
+    let (width, height, pitch) = (16, 4, 70_000);
+    let mut backing = TestSurface::with_pitch(FourCC::IyuvOrI420, width, height, pitch);
+    let mut surface = backing.frame_surface();
+    assert_eq!(surface.pitch(), pitch);
+    assert_eq!(surface.pitch_high(), 1);
+    assert_eq!(surface.bounds().pitch, pitch);
+
+    let pitch = pitch as usize;
+    assert_eq!(surface.y().len(), pitch * 4);
+    assert_eq!(surface.u().len(), pitch / 2 * 2);
+    assert_eq!(surface.v().len(), pitch / 2 * 2);
+
+    surface.fill_yuv(1, 2, 3).unwrap();
+    let packed = surface.to_packed_vec().unwrap();
+    assert_eq!(packed.len(), FrameSurface::frame_size(FourCC::IyuvOrI420, width, height));
+    assert!(packed[..64].iter().all(|b| *b == 1));
+    assert!(packed[64..80].iter().all(|b| *b == 2));
+    drop(surface);
+
+    // Rows are a full pitch apart
+    assert_eq!(backing.data[pitch], 1);
+    assert_eq!(backing.data[pitch - 1], 0);
+}
+
+#[test]
+fn fill_yuv_respects_pitch() {
+    let (width, height, pitch) = (16, 8, 32);
+    let mut backing = TestSurface::new(FourCC::IyuvOrI420, width, height, pitch);
+    backing.data.fill(0xaa);
+
+    let mut surface = backing.frame_surface();
+    surface.fill_yuv(16, 128, 128).unwrap();
+    let packed = surface.to_packed_vec().unwrap();
+    let luma = (width * height) as usize;
+    assert!(packed[..luma].iter().all(|b| *b == 16));
+    assert!(packed[luma..].iter().all(|b| *b == 128));
+    assert_eq!(surface.fill_rgba(0, 0, 0, 255), Err(MfxStatus::Unsupported));
+    drop(surface);
+
+    // The padding is left alone
+    assert!(backing.data[16..32].iter().all(|b| *b == 0xaa));
+
+    let mut nv21_backing = TestSurface::new(FourCC::NV21, width, height, pitch);
+    let mut nv21 = nv21_backing.frame_surface();
+    nv21.fill_yuv(0, 1, 2).unwrap();
+    let packed = nv21.to_packed_vec().unwrap();
+    assert_eq!(packed[luma..luma + 4], [2, 1, 2, 1]);
+
+    let mut bgra_backing = TestSurface::new(FourCC::Rgb4OrBgra, width, height, pitch * 4);
+    let mut bgra = bgra_backing.frame_surface();
+    bgra.fill_rgba(1, 2, 3, 4).unwrap();
+    assert!(bgra.to_packed_vec().unwrap().chunks(4).all(|p| p == [3, 2, 1, 4]));
+}
+
+#[test]
+fn frame_checksum_ignores_padding() {
+    let (width, height, pitch) = (16, 8, 32);
+    let mut backing = TestSurface::new(FourCC::NV12, width, height, pitch);
+    for (i, byte) in backing.data.iter_mut().enumerate() {
+        *byte = (i * 7) as u8;
+    }
+
+    let mut surface = backing.frame_surface();
+    let checksum = frame_checksum(&mut surface).unwrap();
+    assert_eq!(frame_checksum(&mut surface).unwrap(), checksum);
+    drop(surface);
+
+    // Bytes past the width of a row aren't part of the picture
+    backing.data[width as usize] ^= 0xff;
+    let mut surface = backing.frame_surface();
+    assert_eq!(frame_checksum(&mut surface).unwrap(), checksum);
+    drop(surface);
+
+    backing.data[0] ^= 0xff;
+    let mut surface = backing.frame_surface();
+    assert_ne!(frame_checksum(&mut surface).unwrap(), checksum);
+}
+
+#[test]
+fn convert_nv12_gray_to_rgba() {
+    let (width, height, pitch) = (16, 8, 32);
+    let mut backing = TestSurface::new(FourCC::NV12, width, height, pitch);
+    let luma_len = pitch as usize * height as usize;
+    // Limited range gray, 16 + 110 * 255 / 219 = 128 in full range
+    backing.data[..luma_len].fill(126);
+    backing.data[luma_len..].fill(128);
+
+    let mut surface = backing.frame_surface();
+    let rgba = surface.convert_to(FourCC::BGR4).unwrap();
+
+    assert_eq!(rgba.len(), FrameSurface::frame_size(FourCC::BGR4, width, height));
+    for pixel in rgba.chunks_exact(4) {
+        assert_eq!(pixel, [128, 128, 128, 255]);
+    }
+}
+
+#[test]
+fn convert_bgra_gray_to_i420() {
+    let (width, height) = (16, 8);
+    let mut backing = TestSurface::new(FourCC::Rgb4OrBgra, width, height, width * 4);
+    backing.data.fill(128);
+
+    let mut surface = backing.frame_surface();
+    let i420 = surface.convert_to(FourCC::IyuvOrI420).unwrap();
+
+    let luma_len = width as usize * height as usize;
+    assert_eq!(i420.len(), FrameSurface::frame_size(FourCC::IyuvOrI420, width, height));
+    assert!(i420[..luma_len].iter().all(|y| *y == 126));
+    assert!(i420[luma_len..].iter().all(|uv| *uv == 128));
+
+    assert_eq!(surface.convert_to(FourCC::P010), Err(crate::MfxStatus::Unsupported));
+}
+
+#[test]
+fn aligned_frame_sizes() {
+    assert_eq!(
+        aligned_frame(320, 180, PicStruct::Progressive),
+        AlignedFrame {
+            width: 320,
+            height: 192,
+            crop_w: 320,
+            crop_h: 180,
+        }
+    );
+    // Interlaced content is aligned to 32 lines
+    assert_eq!(aligned_frame(320, 180, PicStruct::FieldTff).height, 192);
+    assert_eq!(aligned_frame(320, 200, PicStruct::FieldTff).height, 224);
+
+    let mut params = MfxVideoParams::default();
+    params.set_frame(320, 180, PicStruct::Progressive);
+    assert_eq!((params.width(), params.height()), (320, 192));
+    assert_eq!(params.crop(), (320, 180));
+}
+
+#[test]
+fn pic_struct_classification() {
+    assert!(PicStruct::Progressive.is_progressive());
+    assert!(PicStruct::FrameDoubling.is_progressive());
+    assert!(!PicStruct::Progressive.is_interlaced());
+    assert_eq!(PicStruct::Progressive.field_order(), None);
+
+    assert!(PicStruct::FieldTff.is_interlaced());
+    assert!(!PicStruct::FieldTff.is_progressive());
+    assert_eq!(PicStruct::FieldTff.field_order(), Some(FieldOrder::TopFieldFirst));
+    assert_eq!(PicStruct::FieldBff.field_order(), Some(FieldOrder::BottomFieldFirst));
+
+    // A single field is interlaced content without an order
+    assert!(PicStruct::FieldTop.is_interlaced());
+    assert_eq!(PicStruct::FieldTop.field_order(), None);
+
+    assert!(!PicStruct::Unknown.is_interlaced());
+    assert!(!PicStruct::Unknown.is_progressive());
+
+    for order in [FieldOrder::TopFieldFirst, FieldOrder::BottomFieldFirst] {
+        assert_eq!(PicStruct::from(order).field_order(), Some(order));
+    }
+    assert_eq!(format!("{:?}", PicStruct::FieldBff), "FieldBff");
+}
+
+#[test]
+fn crop_rect_matches_tuple() {
+    let rect = Rect::new(8, 4, 304, 172);
+    assert_eq!(Rect::from((8, 4, 304, 172)), rect);
+    assert_eq!(<(u16, u16, u16, u16)>::from(rect), (8, 4, 304, 172));
+
+    let mut params = MfxVideoParams::default();
+    params.set_crop_rect(rect);
+    assert_eq!(params.crop(), (304, 172));
+    assert_eq!(params.crop_rect(), rect);
+    params.set_crop(320, 180);
+    assert_eq!(params.crop_rect(), Rect::new(8, 4, 320, 180));
+
+    let mut vpp_params = VppVideoParams::default();
+    vpp_params.set_in_crop(8, 4, 304, 172);
+    vpp_params.set_out_crop_rect((8, 4, 304, 172).into());
+    assert_eq!(vpp_params.in_crop_rect(), rect);
+    assert_eq!(vpp_params.out_crop_rect(), vpp_params.in_crop_rect());
+    assert_eq!(vpp_params.out_info().crop(), (304, 172));
+}
+
+#[test]
+fn rate_control_constructors() {
+    let framerate = FrameRate::new(30000, 1001).unwrap();
+    let mut params = MfxVideoParams::cbr(Codec::AVC, 4000, framerate);
+    assert_eq!(params.codec(), Codec::AVC);
+    assert_eq!(params.rate_control_method(), Some(RateControlMethod::CBR));
+    assert_eq!((params.target_kbps(), params.max_kbps()), (4000, 4000));
+    assert_eq!(params.info().frame_rate(), (30000, 1001));
+
+    let framerate = FrameRate::new(25, 1).unwrap();
+    let mut params = MfxVideoParams::vbr(Codec::HEVC, 3000, 6000, framerate);
+    assert_eq!(params.rate_control_method(), Some(RateControlMethod::VBR));
+    assert_eq!((params.target_kbps(), params.max_kbps()), (3000, 6000));
+    assert_eq!(params.info().frame_rate(), (25, 1));
+
+    let params = MfxVideoParams::cqp(Codec::AVC, 22, 24, 26);
+    assert_eq!(params.rate_control_method(), Some(RateControlMethod::CQP));
+    assert_eq!((params.qpi(), params.qpp(), params.qpb()), (22, 24, 26));
+
+    let params = MfxVideoParams::icq(Codec::HEVC, 23);
+    assert_eq!(params.rate_control_method(), Some(RateControlMethod::ICQ));
+    assert_eq!(params.icq_quality(), 23);
+
+    let params = MfxVideoParams::qvbr(Codec::AVC, 28, 2000, 4000);
+    assert_eq!(params.rate_control_method(), Some(RateControlMethod::QVBR));
+    assert_eq!((params.target_kbps(), params.max_kbps()), (2000, 4000));
+    assert_eq!(params.ext_buffer::<ExtraCodingOption3>().unwrap().qvbr_quality(), 28);
+}
+
+#[test]
+fn ext_buffer_setters_share_buffer() {
+    let mut params = MfxVideoParams::qvbr(Codec::AVC, 28, 2000, 4000);
+    let buffers = params.num_ext_buffers();
+
+    // Updates the ExtraCodingOption3 qvbr attached instead of replacing it
+    params.set_content_info(ContentInfo::NonVideoScreen);
+    assert_eq!(params.num_ext_buffers(), buffers);
+    assert_eq!(params.ext_buffer::<ExtraCodingOption3>().unwrap().qvbr_quality(), 28);
+}
+
+#[test]
+#[should_panic]
+fn icq_quality_out_of_range() {
+    MfxVideoParams::icq(Codec::HEVC, 0);
+}
+
+#[test]
+fn framerate_from_fps() {
+    assert_eq!(FrameRate::from_fps(29.97), FrameRate::new(30000, 1001));
+    assert_eq!(FrameRate::from_fps(23.976), FrameRate::new(24000, 1001));
+    assert_eq!(FrameRate::from_fps(59.94), FrameRate::new(60000, 1001));
+    assert_eq!(FrameRate::from_fps(25.0), FrameRate::new(25, 1));
+    assert_eq!(FrameRate::from_fps(12.5), FrameRate::new(25, 2));
+
+    for fps in [0.0, -30.0, 0.0001, 1e12, f64::NAN, f64::INFINITY] {
+        assert_eq!(FrameRate::from_fps(fps), Err(MfxStatus::InvalidVideoParam), "{}", fps);
+    }
+
+    let mut params = MfxVideoParams::cbr(Codec::AVC, 4000, FrameRate::from_fps(29.97).unwrap());
+    assert_eq!(params.framerate(), FrameRate::new(30000, 1001).unwrap());
+    assert_eq!(params.info().frame_rate(), (30000, 1001));
+    assert!(!params.is_variable_framerate());
+
+    params.set_variable_framerate();
+    assert_eq!(params.framerate(), FrameRate::VARIABLE);
+    assert!(params.is_variable_framerate());
+}
+
+#[test]
+fn framerate_zero_denominator() {
+    assert_eq!(FrameRate::new(30, 0), Err(MfxStatus::InvalidVideoParam));
+    assert_eq!(FrameRate::try_from((30, 0)), Err(MfxStatus::InvalidVideoParam));
+    assert_eq!(FrameRate::try_from((30, 1)), FrameRate::new(30, 1));
+
+    let mut params = MfxVideoParams::default();
+    params.set_framerate(30, 1).unwrap();
+    assert_eq!(params.set_framerate(30, 0), Err(MfxStatus::InvalidVideoParam));
+    // Left as it was
+    assert_eq!(params.framerate(), FrameRate::new(30, 1).unwrap());
+
+    let mut params = VppVideoParams::default();
+    assert_eq!(params.set_in_framerate(30, 0), Err(MfxStatus::InvalidVideoParam));
+    assert_eq!(params.set_out_framerate(30, 0), Err(MfxStatus::InvalidVideoParam));
+}
+
+#[test]
+fn ext_mem_frame_types() {
+    // What the decoder asks for when it outputs to video memory
+    let decode = ExtMemFrameType::try_from(
+        (ffi::MFX_MEMTYPE_VIDEO_MEMORY_DECODER_TARGET
+            | ffi::MFX_MEMTYPE_FROM_DECODE
+            | ffi::MFX_MEMTYPE_EXTERNAL_FRAME) as ffi::_bindgen_ty_36,
+    )
+    .unwrap();
+    assert!(decode.is_video_memory());
+    assert!(decode.is_for_decode());
+    assert!(decode.is_external());
+    assert!(!decode.is_system_memory());
+    assert!(!decode.is_for_encode());
+    assert!(!decode.is_internal());
+
+    // Encoder input in system memory
+    let encode = ExtMemFrameType::SystemMemory
+        | ExtMemFrameType::FromEncode
+        | ExtMemFrameType::ExternalFrame;
+    assert!(encode.is_system_memory());
+    assert!(encode.is_for_encode());
+    assert!(!encode.is_video_memory());
+    assert!(!encode.is_for_decode());
+
+    // Encoder reconstructed frames
+    let reconstructed = ExtMemFrameType::VideoMemoryEncoderTarget
+        | ExtMemFrameType::FromEncode
+        | ExtMemFrameType::InternalFrame;
+    assert!(reconstructed.is_video_memory());
+    assert!(reconstructed.is_internal());
+    assert!(!reconstructed.is_external());
+
+    let vpp = ExtMemFrameType::VideoMemoryProcessorTarget | ExtMemFrameType::FromVppOut;
+    assert!(vpp.is_video_memory());
+    assert!(vpp.is_for_vpp());
+    assert!(!vpp.is_for_decode());
+
+    // The aliases are the same bits
+    assert_eq!(
+        ExtMemFrameType::VideoMemoryDecoderTarget,
+        ExtMemFrameType::DXVA2DecoderTargetOrVideoMemoryDecoderTarget
+    );
+    assert_eq!(
+        ExtMemFrameType::ExternalFrame,
+        ExtMemFrameType::PersistentMemoryOrExternalFrame
+    );
+
+    // Unknown bits don't fail the conversion and survive it
+    let unknown = ExtMemFrameType::try_from(
+        (0x8000 | ffi::MFX_MEMTYPE_SYSTEM_MEMORY) as ffi::_bindgen_ty_36,
+    )
+    .unwrap();
+    assert!(unknown.is_system_memory());
+    assert_eq!(unknown.bits() & 0x8000, 0x8000);
+}
+
+#[test]
+fn display_names() {
+    assert_eq!(PicStruct::Progressive.to_string(), "Progressive");
+    assert_eq!(PicStruct::FieldTff.to_string(), "Top field first");
+    assert_eq!(PicStruct::FieldBff.to_string(), "Bottom field first");
+    assert_eq!(ChromaFormat::YUV420.to_string(), "4:2:0");
+    assert_eq!(ChromaFormat::YUV422.to_string(), "4:2:2");
+    assert_eq!(ChromaFormat::YUV444.to_string(), "4:4:4");
+    assert_eq!(ChromaFormat::Monochrome.to_string(), "4:0:0");
+}
+
+/// Returns `busy` for the first `busy_calls` calls, then `NoneOrDone`
+fn busy_device(busy: MfxStatus, busy_calls: u32) -> (impl FnMut() -> MfxStatus, Rc<Cell<u32>>) {
+    let calls = Rc::new(Cell::new(0));
+    let counter = calls.clone();
+    let call = move || {
+        counter.set(counter.get() + 1);
+        if counter.get() <= busy_calls {
+            busy
+        } else {
+            MfxStatus::NoneOrDone
+        }
+    };
+    (call, calls)
+}
+
+#[test]
+fn busy_retry_backs_off_until_done() {
+    let retry = BusyRetry {
+        max_attempts: 3,
+        base_delay: Duration::from_millis(1),
+    };
+
+    let (call, calls) = busy_device(MfxStatus::DeviceBusy, 2);
+    assert_eq!(retry.run(call), MfxStatus::NoneOrDone);
+    assert_eq!(calls.get(), 3);
+
+    let (call, calls) = busy_device(MfxStatus::TaskBusy, 3);
+    assert_eq!(retry.run(call), MfxStatus::NoneOrDone);
+    assert_eq!(calls.get(), 4);
+
+    // Gives up once the attempts are used up
+    let (call, calls) = busy_device(MfxStatus::DeviceBusy, 10);
+    assert_eq!(retry.run(call), MfxStatus::DeviceBusy);
+    assert_eq!(calls.get(), 4);
+
+    // Other errors are not retried
+    let (call, calls) = busy_device(MfxStatus::Unknown, 10);
+    assert_eq!(retry.run(call), MfxStatus::Unknown);
+    assert_eq!(calls.get(), 1);
+
+    // Disabled by default
+    let (call, calls) = busy_device(MfxStatus::DeviceBusy, 1);
+    assert_eq!(BusyRetry::default().run(call), MfxStatus::DeviceBusy);
+    assert_eq!(calls.get(), 1);
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn busy_retry_async_does_not_block_runtime() {
+    let retry = BusyRetry {
+        max_attempts: 3,
+        base_delay: Duration::from_millis(20),
+    };
+
+    // Busy for 3 attempts, so the call waits 20 + 40 + 80ms before it goes through. On a current
+    // thread runtime the ticker only runs while the retry waits without blocking the thread.
+    let (call, calls) = busy_device(MfxStatus::DeviceBusy, 3);
+    let mut ticks = 0;
+    let ticker = async {
+        loop {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            ticks += 1;
+        }
+    };
+    let result = tokio::select! {
+        result = retry.run_async(call) => result,
+        _ = ticker => unreachable!(),
+    };
+    assert_eq!(result, MfxStatus::NoneOrDone);
+    assert_eq!(calls.get(), 4);
+    assert!(ticks >= 10, "only ticked {} times", ticks);
+
+    // Gives up once the attempts are used up
+    let (call, calls) = busy_device(MfxStatus::DeviceBusy, 10);
+    assert_eq!(retry.run_async(call).await, MfxStatus::DeviceBusy);
+    assert_eq!(calls.get(), 4);
+}
+
+#[test]
+fn busy_retry_delay_is_capped() {
+    let retry = BusyRetry {
+        max_attempts: 40,
+        base_delay: Duration::from_millis(10),
+    };
+
+    assert_eq!(retry.delay(0), Duration::from_millis(10));
+    assert_eq!(retry.delay(2), Duration::from_millis(40));
+    assert_eq!(retry.delay(4), MAX_BUSY_DELAY);
+    assert_eq!(retry.delay(39), MAX_BUSY_DELAY);
+}
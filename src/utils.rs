@@ -1,11 +1,17 @@
-use std::ffi::CStr;
+use std::{
+    ffi::CStr,
+    sync::{Condvar, Mutex},
+};
 
 use intel_onevpl_sys as ffi;
+#[cfg(feature = "async")]
+use tokio::sync::Notify;
 
-use crate::constants::PicStruct;
+use crate::{constants::PicStruct, FrameSurface, MfxStatus};
 
 #[derive(Debug, Copy, Clone)]
 pub enum FilterProperty {
+    U16(u16),
     I32(i32),
     U32(u32),
     Ptr(*mut std::ffi::c_void),
@@ -13,6 +19,7 @@ pub enum FilterProperty {
 impl FilterProperty {
     pub fn filter_type(&self) -> ffi::mfxVariantType {
         match self {
+            FilterProperty::U16(_) => ffi::mfxVariantType_MFX_VARIANT_TYPE_U16,
             FilterProperty::I32(_) => ffi::mfxVariantType_MFX_VARIANT_TYPE_I32,
             FilterProperty::U32(_) => ffi::mfxVariantType_MFX_VARIANT_TYPE_U32,
             FilterProperty::Ptr(_) => ffi::mfxVariantType_MFX_VARIANT_TYPE_PTR,
@@ -21,6 +28,7 @@ impl FilterProperty {
     pub(crate) fn data(&self) -> ffi::mfxVariant_data {
         use ffi::mfxVariant_data;
         match *self {
+            FilterProperty::U16(value) => mfxVariant_data { U16: value },
             FilterProperty::I32(value) => mfxVariant_data { I32: value },
             FilterProperty::U32(value) => mfxVariant_data { U32: value },
             FilterProperty::Ptr(value) => mfxVariant_data { Ptr: value },
@@ -28,6 +36,11 @@ impl FilterProperty {
     }
 }
 
+impl From<u16> for FilterProperty {
+    fn from(value: u16) -> Self {
+        Self::U16(value)
+    }
+}
 impl From<u32> for FilterProperty {
     fn from(value: u32) -> Self {
         Self::U32(value)
@@ -65,6 +78,165 @@ pub fn hw_align_height(height: u16, picstruct: PicStruct) -> u16 {
     }
 }
 
+/// Surface size padded for the hardware alongside the visible (crop) size. See [`aligned_frame`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AlignedFrame {
+    pub width: u16,
+    pub height: u16,
+    pub crop_w: u16,
+    pub crop_h: u16,
+}
+
+/// A rectangle in pixels, used for crops. Converts to and from `(x, y, width, height)` tuples.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Rect {
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+}
+
+impl Rect {
+    pub fn new(x: u16, y: u16, width: u16, height: u16) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+}
+
+impl From<(u16, u16, u16, u16)> for Rect {
+    fn from((x, y, width, height): (u16, u16, u16, u16)) -> Self {
+        Self::new(x, y, width, height)
+    }
+}
+
+impl From<Rect> for (u16, u16, u16, u16) {
+    fn from(rect: Rect) -> Self {
+        (rect.x, rect.y, rect.width, rect.height)
+    }
+}
+
+/// Computes the aligned surface size for a `width`x`height` frame with [`hw_align_width`] and [`hw_align_height`], keeping the original size as the crop.
+pub fn aligned_frame(width: u16, height: u16, picstruct: PicStruct) -> AlignedFrame {
+    AlignedFrame {
+        width: hw_align_width(width),
+        height: hw_align_height(height, picstruct),
+        crop_w: width,
+        crop_h: height,
+    }
+}
+
+/// Hashes the visible pixels of `surface` (FNV-1a over [`FrameSurface::to_packed_vec`]), ignoring the pitch padding. The result only depends on the pixels, so it can be compared between runs, machines or hardware and software implementations to check output is bit exact.
+pub fn frame_checksum(surface: &mut FrameSurface) -> Result<u64, MfxStatus> {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0100_0000_01b3;
+
+    let packed = surface.to_packed_vec()?;
+    Ok(packed.iter().fold(OFFSET_BASIS, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(PRIME)
+    }))
+}
+
+/// Bounds the frames in flight to AsyncDepth so submitting more than the library has surfaces for waits instead of failing with [`MfxStatus::MoreSurface`] or [`MfxStatus::AllocTimeoutExpired`]. An AsyncDepth of 0 lets the library decide, so nothing is bounded.
+///
+/// Works without an async runtime so the blocking calls share the same bound as the async ones.
+#[derive(Debug)]
+pub(crate) struct InFlight {
+    limit: usize,
+    state: Mutex<InFlightState>,
+    /// Wakes threads waiting in [`InFlight::acquire_blocking`]
+    freed: Condvar,
+    /// Wakes tasks waiting in [`InFlight::acquire`]
+    #[cfg(feature = "async")]
+    freed_async: Notify,
+}
+
+#[derive(Debug, Default)]
+struct InFlightState {
+    count: usize,
+    /// Most frames that were in flight at once
+    #[cfg(test)]
+    peak: usize,
+}
+
+/// A frame in flight, frees its slot when dropped.
+#[derive(Debug)]
+pub(crate) struct InFlightPermit<'a> {
+    in_flight: &'a InFlight,
+}
+
+impl InFlight {
+    pub(crate) fn new(async_depth: u16) -> Self {
+        Self {
+            limit: match async_depth {
+                0 => usize::MAX,
+                depth => depth as usize,
+            },
+            state: Mutex::new(InFlightState::default()),
+            freed: Condvar::new(),
+            #[cfg(feature = "async")]
+            freed_async: Notify::new(),
+        }
+    }
+
+    fn take(&self, state: &mut InFlightState) -> InFlightPermit<'_> {
+        state.count += 1;
+        #[cfg(test)]
+        {
+            state.peak = state.peak.max(state.count);
+        }
+        InFlightPermit { in_flight: self }
+    }
+
+    fn try_acquire(&self) -> Option<InFlightPermit<'_>> {
+        let mut state = self.state.lock().unwrap();
+        if state.count == self.limit {
+            return None;
+        }
+        Some(self.take(&mut state))
+    }
+
+    /// Waits for a free slot without blocking the executor.
+    #[cfg(feature = "async")]
+    pub(crate) async fn acquire(&self) -> InFlightPermit<'_> {
+        loop {
+            // Created before checking so a slot freed in between still wakes us
+            let freed = self.freed_async.notified();
+            if let Some(permit) = self.try_acquire() {
+                return permit;
+            }
+            freed.await;
+        }
+    }
+
+    /// Waits for a free slot by blocking the current thread.
+    pub(crate) fn acquire_blocking(&self) -> InFlightPermit<'_> {
+        let state = self.state.lock().unwrap();
+        let mut state = self
+            .freed
+            .wait_while(state, |state| state.count == self.limit)
+            .unwrap();
+        self.take(&mut state)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn peak(&self) -> usize {
+        self.state.lock().unwrap().peak
+    }
+}
+
+impl Drop for InFlightPermit<'_> {
+    fn drop(&mut self) {
+        self.in_flight.state.lock().unwrap().count -= 1;
+        self.in_flight.freed.notify_one();
+        #[cfg(feature = "async")]
+        self.in_flight.freed_async.notify_one();
+    }
+}
+
 pub(crate) unsafe fn str_from_null_terminated_utf8(s: &[u8]) -> &str {
     CStr::from_ptr(s.as_ptr() as *const _).to_str().unwrap()
 }
@@ -1,22 +1,97 @@
 use intel_onevpl_sys as ffi;
 use std::{
+    any::Any,
     fmt::Debug,
     mem,
     ops::{Deref, DerefMut},
 };
 
 use crate::{
-    constants::{self, ChromaFormat, Codec, FourCC, IoPattern, RateControlMethod, TargetUsage},
-    FrameInfo,
+    brc::{BitrateController, ExternalBrc},
+    constants::{
+        self, ChromaFormat, Codec, FourCC, IoPattern, PicStruct, ProtectedMode, RateControlMethod,
+        TargetUsage,
+    },
+    decode::HeaderInfo,
+    utils::{aligned_frame, Rect},
+    FrameInfo, FrameRate,
 };
 
-#[derive(Clone, Debug)]
+/// Implemented by the wrappers around the `mfxExt*` structures so they can be
+/// attached to a [`VideoParams`] with [`VideoParams::add_ext_buffer`].
+pub trait ExtBuffer: Debug + Any {
+    /// Pointer to the `mfxExtBuffer` header at the start of the structure.
+    fn header(&mut self) -> *mut ffi::mfxExtBuffer;
+    fn buffer_id(&self) -> u32;
+    fn clone_boxed(&self) -> Box<dyn ExtBuffer>;
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+/// Declares a wrapper around an `mfxExt*` structure whose header is filled in
+/// by [`Default`].
+macro_rules! ext_buffer {
+    ($(#[$meta:meta])* $name:ident, $inner:ty, $id:expr) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy)]
+        pub struct $name {
+            inner: $inner,
+        }
+
+        unsafe impl Send for $name {}
+
+        impl Default for $name {
+            fn default() -> Self {
+                let mut inner: $inner = unsafe { std::mem::zeroed() };
+                inner.Header.BufferId = $id as u32;
+                inner.Header.BufferSz = std::mem::size_of::<$inner>() as u32;
+                Self { inner }
+            }
+        }
+
+        impl std::ops::Deref for $name {
+            type Target = $inner;
+
+            fn deref(&self) -> &Self::Target {
+                &self.inner
+            }
+        }
+
+        impl std::ops::DerefMut for $name {
+            fn deref_mut(&mut self) -> &mut Self::Target {
+                &mut self.inner
+            }
+        }
+
+        impl $crate::videoparams::ExtBuffer for $name {
+            fn header(&mut self) -> *mut intel_onevpl_sys::mfxExtBuffer {
+                &mut self.inner.Header
+            }
+            fn buffer_id(&self) -> u32 {
+                self.inner.Header.BufferId
+            }
+            fn clone_boxed(&self) -> Box<dyn $crate::videoparams::ExtBuffer> {
+                Box::new(*self)
+            }
+            fn as_any(&self) -> &dyn std::any::Any {
+                self
+            }
+            fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+                self
+            }
+        }
+    };
+}
+pub(crate) use ext_buffer;
+
+#[derive(Debug)]
 /// See https://spec.oneapi.io/versions/latest/elements/oneVPL/source/API_ref/VPL_structs_cross_component.html#_CPPv413mfxVideoParam for more info.
 ///
-/// This struct requires extra handling when using. In order for the ExtParam value to be set, you must set it with the result of the [`VideoParams::extra_params`] function.
+/// Extension buffers added with [`VideoParams::add_ext_buffer`] are owned by this struct and ExtParam is kept pointing at them.
 pub struct VideoParams {
     inner: ffi::mfxVideoParam,
-    _extra_params: Vec<Box<ExtraCodingOption>>,
+    ext_buffers: Vec<Box<dyn ExtBuffer>>,
+    ext_buffer_ptrs: Vec<*mut ffi::mfxExtBuffer>,
 }
 
 unsafe impl Send for VideoParams {}
@@ -38,20 +113,82 @@ impl VideoParams {
     pub fn set_io_pattern(&mut self, pattern: IoPattern) {
         self.inner.IOPattern = pattern.bits();
     }
-    // pub fn add_extra_param(&mut self, extra: Box<ExtraCodingOption>) {
-    //     self.extra_params.push(extra);
-    //     self.inner.NumExtParam = self.extra_params.len() as u16;
-    // }
-    // pub(crate) fn extra_params(&self) -> Vec<*mut ffi::mfxExtBuffer> {
-    //     self.extra_params.iter().map(|x| x as *const _ as *mut _).collect()
-    // }
+
+    /// Attaches an extension buffer (Eg. [`ExtraCodingOption1`]). If a buffer with the same id is already attached it is replaced.
+    pub fn add_ext_buffer(&mut self, buffer: impl ExtBuffer) {
+        let buffer_id = buffer.buffer_id();
+        self.ext_buffers.retain(|b| b.buffer_id() != buffer_id);
+        self.ext_buffers.push(Box::new(buffer));
+        self.update_ext_params();
+    }
+
+    /// Detaches and returns the extension buffer of type `T` if one is attached.
+    pub fn remove_ext_buffer<T: ExtBuffer + Clone>(&mut self) -> Option<T> {
+        let index = self
+            .ext_buffers
+            .iter()
+            .position(|b| b.as_any().is::<T>())?;
+        let buffer = self.ext_buffers.remove(index);
+        self.update_ext_params();
+        buffer.as_any().downcast_ref::<T>().cloned()
+    }
+
+    /// Returns the attached extension buffer of type `T`. After Init/GetVideoParam this contains the values the library actually used.
+    pub fn ext_buffer<T: ExtBuffer>(&self) -> Option<&T> {
+        self.ext_buffers
+            .iter()
+            .find_map(|b| b.as_any().downcast_ref::<T>())
+    }
+
+    pub fn ext_buffer_mut<T: ExtBuffer>(&mut self) -> Option<&mut T> {
+        self.ext_buffers
+            .iter_mut()
+            .find_map(|b| b.as_any_mut().downcast_mut::<T>())
+    }
+
+    /// Same as [`VideoParams::ext_buffer_mut`] but attaches a default buffer of type `T` first if there is none. The setters for options living in extension buffers (Eg. [`MfxVideoParams::set_content_info`]) go through this, so calling several of them shares one buffer.
+    pub fn ext_buffer_or_default<T: ExtBuffer + Default>(&mut self) -> &mut T {
+        if self.ext_buffer::<T>().is_none() {
+            self.add_ext_buffer(T::default());
+        }
+        self.ext_buffer_mut::<T>().unwrap()
+    }
+
+    /// Number of attached extension buffers.
+    pub fn num_ext_buffers(&self) -> usize {
+        self.ext_buffers.len()
+    }
+
+    fn update_ext_params(&mut self) {
+        self.ext_buffer_ptrs = self.ext_buffers.iter_mut().map(|b| b.header()).collect();
+        self.inner.NumExtParam = self.ext_buffer_ptrs.len() as u16;
+        self.inner.ExtParam = if self.ext_buffer_ptrs.is_empty() {
+            std::ptr::null_mut()
+        } else {
+            self.ext_buffer_ptrs.as_mut_ptr()
+        };
+    }
+}
+
+impl Clone for VideoParams {
+    fn clone(&self) -> Self {
+        let mut params = Self {
+            inner: self.inner,
+            ext_buffers: self.ext_buffers.iter().map(|b| b.clone_boxed()).collect(),
+            ext_buffer_ptrs: Vec::new(),
+        };
+        // ExtParam still points at our buffers, point it at the clones
+        params.update_ext_params();
+        params
+    }
 }
 
 impl Default for VideoParams {
     fn default() -> Self {
         Self {
             inner: unsafe { mem::zeroed() },
-            _extra_params: Vec::default(),
+            ext_buffers: Vec::new(),
+            ext_buffer_ptrs: Vec::new(),
         }
     }
 }
@@ -85,11 +222,96 @@ pub struct MfxVideoParams {
 }
 
 impl MfxVideoParams {
+    /// Encoder params using [`RateControlMethod::CBR`] at `kbps`. See [`FrameRate::new`] and [`FrameRate::from_fps`] for `framerate`.
+    pub fn cbr(codec: Codec, kbps: u16, framerate: FrameRate) -> Self {
+        let mut params = Self::default();
+        params.set_codec(codec);
+        params.set_rate_control_method(RateControlMethod::CBR);
+        params.set_target_kbps(kbps);
+        params.set_max_kbps(kbps);
+        params.set_frame_rate(framerate);
+        params
+    }
+
+    /// Encoder params using [`RateControlMethod::VBR`], averaging `target_kbps` without going above `max_kbps`.
+    pub fn vbr(
+        codec: Codec,
+        target_kbps: u16,
+        max_kbps: u16,
+        framerate: FrameRate,
+    ) -> Self {
+        let mut params = Self::default();
+        params.set_codec(codec);
+        params.set_rate_control_method(RateControlMethod::VBR);
+        params.set_target_kbps(target_kbps);
+        params.set_max_kbps(max_kbps);
+        params.set_frame_rate(framerate);
+        params
+    }
+
+    /// Encoder params using [`RateControlMethod::CQP`] with a fixed QP for each frame type.
+    pub fn cqp(codec: Codec, qpi: u16, qpp: u16, qpb: u16) -> Self {
+        let mut params = Self::default();
+        params.set_codec(codec);
+        params.set_rate_control_method(RateControlMethod::CQP);
+        params.set_qpi(qpi);
+        params.set_qpp(qpp);
+        params.set_qpb(qpb);
+        params
+    }
+
+    /// Encoder params using [`RateControlMethod::ICQ`]. `quality` is 1 (best) to 51, panics outside of that.
+    pub fn icq(codec: Codec, quality: u16) -> Self {
+        let mut params = Self::default();
+        params.set_codec(codec);
+        params.set_rate_control_method(RateControlMethod::ICQ);
+        params.set_icq_quality(quality);
+        params
+    }
+
+    /// Encoder params using [`RateControlMethod::QVBR`]. QVBR is VBR with a quality target, so besides `quality` (1 best to 51) it needs the same bitrates as [`MfxVideoParams::vbr`]. The quality is set on an attached [`ExtraCodingOption3`].
+    pub fn qvbr(codec: Codec, quality: u16, target_kbps: u16, max_kbps: u16) -> Self {
+        let mut params = Self::default();
+        params.set_codec(codec);
+        params.set_rate_control_method(RateControlMethod::QVBR);
+        params.set_target_kbps(target_kbps);
+        params.set_max_kbps(max_kbps);
+        let mut option3 = ExtraCodingOption3::default();
+        option3.set_qvbr_quality(quality);
+        params.add_ext_buffer(option3);
+        params
+    }
+
+    /// Encoder params matching the frames `source` (Eg. from [`crate::Session::decode_header`]) produces, for transcoding. The frame info (dimensions, crop, frame rate, format, chroma and aspect ratio) is copied and the input memory type is set to the output memory type of `source`. Everything else, like the rate control, is left for the caller.
+    pub fn encode_from_source(source: &MfxVideoParams, codec: Codec) -> Self {
+        let mut params = Self::default();
+        params.set_codec(codec);
+        params.mfx_mut().FrameInfo = source.mfx().FrameInfo;
+
+        let source_pattern = source.io_pattern();
+        let mut pattern = IoPattern::empty();
+        if source_pattern.contains(IoPattern::OUT_VIDEO_MEMORY) {
+            pattern |= IoPattern::IN_VIDEO_MEMORY;
+        }
+        if source_pattern.contains(IoPattern::OUT_SYSTEM_MEMORY) {
+            pattern |= IoPattern::IN_SYSTEM_MEMORY;
+        }
+        params.set_io_pattern(pattern);
+
+        params
+    }
+
     pub fn info(&mut self) -> FrameInfo {
         FrameInfo {
             inner: unsafe { &mut self.inner.__bindgen_anon_1.mfx.FrameInfo },
         }
     }
+    /// Frame parameters, including the aligned width and height. When read from [`crate::encode::Encoder::params`] (or the decoder equivalent) these are the values the library negotiated, so check them before allocating surfaces yourself. The IO pattern is available through [`VideoParams::io_pattern`].
+    pub fn frame_info(&self) -> FrameInfo<'_> {
+        FrameInfo {
+            inner: &self.mfx().FrameInfo,
+        }
+    }
     fn mfx(&self) -> &ffi::mfxInfoMFX {
         unsafe { &self.__bindgen_anon_1.mfx }
     }
@@ -111,6 +333,23 @@ impl MfxVideoParams {
         self.mfx_mut().__bindgen_anon_1.__bindgen_anon_1.GopRefDist = ref_dist;
     }
 
+    #[doc = " Number of slices in each video frame. Each slice contains one or more macro-block rows. If NumSlice equals zero, the encoder may choose any slice partitioning allowed by the codec standard. See also mfxExtCodingOption2::NumMbPerSlice."]
+    pub fn set_num_slice(&mut self, num: u16) {
+        self.mfx_mut().__bindgen_anon_1.__bindgen_anon_1.NumSlice = num;
+    }
+
+    /// A closed GOP doesn't reference frames from the previous GOP, so each GOP can be decoded on its own (Eg. for HLS/DASH segments). A strict GOP makes the encoder follow the GOP structure exactly instead of inserting extra I-frames on scene changes. See the GopOptFlag enumerator for more info.
+    pub fn set_gop_opt_flag(&mut self, closed: bool, strict: bool) {
+        let mut flags = 0;
+        if closed {
+            flags |= ffi::MFX_GOP_CLOSED;
+        }
+        if strict {
+            flags |= ffi::MFX_GOP_STRICT;
+        }
+        self.mfx_mut().__bindgen_anon_1.__bindgen_anon_1.GopOptFlag = flags as u16;
+    }
+
     #[doc = " Max number of all available reference frames (for AVC/HEVC, NumRefFrame defines DPB size). If NumRefFrame = 0, this parameter is not specified.\nSee also NumRefActiveP, NumRefActiveBL0, and NumRefActiveBL1 in the mfxExtCodingOption3 structure, which set a number of active references."]
     pub fn set_num_ref_frame(&mut self, num: u16) {
         self.mfx_mut().__bindgen_anon_1.__bindgen_anon_1.NumRefFrame = num;
@@ -124,6 +363,10 @@ impl MfxVideoParams {
             .InitialDelayInKB = kilobytes;
     }
 
+    pub fn qpi(&self) -> u16 {
+        unsafe { self.mfx().__bindgen_anon_1.__bindgen_anon_1.__bindgen_anon_1.QPI }
+    }
+
     pub fn set_qpi(&mut self, qpi: u16) {
         self.mfx_mut()
             .__bindgen_anon_1
@@ -132,6 +375,10 @@ impl MfxVideoParams {
             .QPI = qpi;
     }
 
+    pub fn target_kbps(&self) -> u16 {
+        unsafe { self.mfx().__bindgen_anon_1.__bindgen_anon_1.__bindgen_anon_2.TargetKbps }
+    }
+
     pub fn set_target_kbps(&mut self, kbps: u16) {
         self.mfx_mut()
             .__bindgen_anon_1
@@ -140,6 +387,10 @@ impl MfxVideoParams {
             .TargetKbps = kbps;
     }
 
+    pub fn max_kbps(&self) -> u16 {
+        unsafe { self.mfx().__bindgen_anon_1.__bindgen_anon_1.__bindgen_anon_3.MaxKbps }
+    }
+
     pub fn set_max_kbps(&mut self, kbps: u16) {
         self.mfx_mut()
             .__bindgen_anon_1
@@ -148,6 +399,10 @@ impl MfxVideoParams {
             .MaxKbps = kbps;
     }
 
+    pub fn qpp(&self) -> u16 {
+        unsafe { self.mfx().__bindgen_anon_1.__bindgen_anon_1.__bindgen_anon_2.QPP }
+    }
+
     pub fn set_qpp(&mut self, qpp: u16) {
         self.mfx_mut()
             .__bindgen_anon_1
@@ -156,6 +411,24 @@ impl MfxVideoParams {
             .QPP = qpp;
     }
 
+    pub fn qpb(&self) -> u16 {
+        unsafe { self.mfx().__bindgen_anon_1.__bindgen_anon_1.__bindgen_anon_3.QPB }
+    }
+
+    pub fn set_qpb(&mut self, qpb: u16) {
+        self.mfx_mut()
+            .__bindgen_anon_1
+            .__bindgen_anon_1
+            .__bindgen_anon_3
+            .QPB = qpb;
+    }
+
+    pub fn rate_control_method(&self) -> Option<RateControlMethod> {
+        RateControlMethod::from_repr(unsafe {
+            self.mfx().__bindgen_anon_1.__bindgen_anon_1.RateControlMethod
+        } as _)
+    }
+
     pub fn set_rate_control_method(&mut self, method: RateControlMethod) {
         self.mfx_mut()
             .__bindgen_anon_1
@@ -167,6 +440,11 @@ impl MfxVideoParams {
         self.mfx_mut().__bindgen_anon_1.__bindgen_anon_1.IdrInterval = interval;
     }
 
+    pub fn encode_order(&self) -> u16 {
+        unsafe { self.mfx().__bindgen_anon_1.__bindgen_anon_1.EncodedOrder }
+    }
+
+    /// When set to 1 the encoder takes input frames in encoded order instead of display order. Every frame passed to [`crate::encode::Encoder::encode`] must then carry its display position ([`crate::FrameSurface::set_frame_order`]) and the [`EncodeCtrl`](crate::encode::EncodeCtrl) must specify its frame type.
     pub fn set_encode_order(&mut self, order: u16) {
         self.mfx_mut()
             .__bindgen_anon_1
@@ -174,6 +452,26 @@ impl MfxVideoParams {
             .EncodedOrder = order;
     }
 
+    /// Lets the decoder return [`MfxStatus::ReallocSurface`] when a work surface is too small for the next frame instead of failing. See [`crate::decode::Decoder::realloc_size`].
+    pub fn set_realloc_request(&mut self, enabled: bool) {
+        self.mfx_mut().__bindgen_anon_1.__bindgen_anon_2.EnableReallocRequest = enabled as u16;
+    }
+
+    pub fn av1_film_grain(&self) -> bool {
+        unsafe { self.mfx().__bindgen_anon_1.__bindgen_anon_2.FilmGrain != 0 }
+    }
+
+    /// Controls whether the AV1 decoder applies the film grain carried in the stream. Disable it to get the clean frames, e.g. to compare quality against the source. Enabled by default when the stream has film grain parameters.
+    ///
+    /// oneVPL exposes this as a decode option (mfxInfoMFX::FilmGrain) rather than an ext buffer; mfxExtAV1FilmGrainParam is only used to read the grain parameters back from output surfaces. Has no effect on other codecs.
+    pub fn set_av1_film_grain(&mut self, enabled: bool) {
+        self.mfx_mut().__bindgen_anon_1.__bindgen_anon_2.FilmGrain = enabled as u16;
+    }
+
+    pub fn icq_quality(&self) -> u16 {
+        unsafe { self.mfx().__bindgen_anon_1.__bindgen_anon_1.__bindgen_anon_2.ICQQuality }
+    }
+
     pub fn set_icq_quality(&mut self, quality: u16) {
         assert!(
             quality >= 1 && quality <= 51,
@@ -186,9 +484,36 @@ impl MfxVideoParams {
             .ICQQuality = quality;
     }
 
-    pub fn set_framerate(&mut self, numerator: u32, denominator: u32) {
-        self.mfx_mut().FrameInfo.FrameRateExtN = numerator;
-        self.mfx_mut().FrameInfo.FrameRateExtD = denominator;
+    pub fn framerate(&self) -> FrameRate {
+        let info = &self.mfx().FrameInfo;
+        FrameRate(info.FrameRateExtN, info.FrameRateExtD)
+    }
+
+    /// Returns [`ffi::MfxStatus::InvalidVideoParam`] if `denominator` is 0, see [`FrameRate::new`]. [`FrameRate::from_fps`] picks the fraction for rates like 29.97.
+    pub fn set_framerate(
+        &mut self,
+        numerator: u32,
+        denominator: u32,
+    ) -> Result<(), ffi::MfxStatus> {
+        self.set_frame_rate(FrameRate::new(numerator, denominator)?);
+        Ok(())
+    }
+
+    fn set_frame_rate(&mut self, framerate: FrameRate) {
+        self.mfx_mut().FrameInfo.FrameRateExtN = framerate.numerator();
+        self.mfx_mut().FrameInfo.FrameRateExtD = framerate.denominator();
+    }
+
+    /// Marks the input as variable frame rate by setting the frame rate to 0/0, so the encoder relies on the [`crate::FrameSurface::timestamp`] of every frame instead. Set a timestamp on every input surface when doing this. Not every encoder or rate control method accepts an unknown frame rate, CQP is the safest.
+    ///
+    /// Use [`MfxVideoParams::set_framerate`] to go back to a constant frame rate.
+    pub fn set_variable_framerate(&mut self) {
+        self.set_frame_rate(FrameRate::VARIABLE);
+    }
+
+    /// True if no frame rate is set, see [`MfxVideoParams::set_variable_framerate`].
+    pub fn is_variable_framerate(&self) -> bool {
+        self.framerate().is_variable()
     }
 
     pub fn set_fourcc(&mut self, format: FourCC) {
@@ -223,6 +548,48 @@ impl MfxVideoParams {
     pub fn codec(&self) -> Codec {
         Codec::from_repr(self.mfx().CodecId as ffi::_bindgen_ty_14).unwrap()
     }
+    #[doc = " Specifies the codec profile; see the CodecProfile enumerator for details. Specify the codec profile explicitly or the API functions will determine the correct profile from other sources, such as resolution and bitrate."]
+    pub fn profile(&self) -> u16 {
+        self.mfx().CodecProfile
+    }
+    #[doc = " Codec level; see the CodecLevel enumerator for details. Specify the codec level explicitly or the functions will determine the correct level from other sources, such as resolution and bitrate."]
+    pub fn level(&self) -> u16 {
+        self.mfx().CodecLevel
+    }
+    /// Pins the profile instead of letting the encoder pick one. Takes a raw value or a codec specific enum, Eg. [`constants::AvcProfile`] or [`constants::HevcProfile`].
+    pub fn set_codec_profile(&mut self, profile: impl Into<u16>) {
+        self.mfx_mut().CodecProfile = profile.into();
+    }
+    /// Pins the level instead of letting the encoder pick one, Eg. to stay within what a hardware decoder supports. Takes a raw value or a codec specific enum, Eg. [`constants::AvcLevel`] or [`constants::HevcLevel`].
+    pub fn set_codec_level(&mut self, level: impl Into<u16>) {
+        self.mfx_mut().CodecLevel = level.into();
+    }
+
+    /// Routes the content through protected memory for DRM playback. Implementations without support for the scheme reject it, check with [`crate::decode::Decoder::query`] or [`crate::encode::Encoder::query`] before Init.
+    pub fn set_protected(&mut self, mode: ProtectedMode) {
+        self.Protected = mode.repr() as u16;
+    }
+
+    /// Returns `None` if the field holds a scheme this crate doesn't know about.
+    pub fn protected(&self) -> Option<ProtectedMode> {
+        ProtectedMode::from_repr(self.Protected as _)
+    }
+
+    /// Summarizes what [`crate::Session::decode_header`] found in the stream.
+    pub fn header_info(&self) -> HeaderInfo {
+        let info = self.frame_info();
+        HeaderInfo {
+            codec: self.codec(),
+            profile: self.profile(),
+            level: self.level(),
+            width: self.width(),
+            height: self.height(),
+            crop: self.crop(),
+            frame_rate: info.frame_rate(),
+            chroma_format: info.chroma_format(),
+            bit_depth: info.bit_depth_luma(),
+        }
+    }
     pub fn set_codec(&mut self, codec: Codec) {
         self.mfx_mut().CodecId = codec as u32;
     }
@@ -278,6 +645,29 @@ impl MfxVideoParams {
             .CropH = height;
     }
 
+    /// Same as [`MfxVideoParams::set_crop`] but also sets the crop offset.
+    pub fn set_crop_rect(&mut self, rect: Rect) {
+        let frame = unsafe { &mut self.mfx_mut().FrameInfo.__bindgen_anon_1.__bindgen_anon_1 };
+        frame.CropX = rect.x;
+        frame.CropY = rect.y;
+        frame.CropW = rect.width;
+        frame.CropH = rect.height;
+    }
+
+    pub fn crop_rect(&self) -> Rect {
+        let frame = unsafe { &self.mfx().FrameInfo.__bindgen_anon_1.__bindgen_anon_1 };
+        Rect::new(frame.CropX, frame.CropY, frame.CropW, frame.CropH)
+    }
+
+    /// Sets the picture structure, the hardware aligned width and height and the crop for a `width`x`height` frame in one go. Prefer this over [`MfxVideoParams::set_width`]/[`MfxVideoParams::set_height`], which don't align the size for you.
+    pub fn set_frame(&mut self, width: u16, height: u16, picstruct: PicStruct) {
+        let frame = aligned_frame(width, height, picstruct);
+        self.mfx_mut().FrameInfo.PicStruct = picstruct.repr() as u16;
+        self.set_width(frame.width);
+        self.set_height(frame.height);
+        self.set_crop(frame.crop_w, frame.crop_h);
+    }
+
     pub fn crop(&self) -> (u16, u16) {
         unsafe {
             (
@@ -311,6 +701,67 @@ impl MfxVideoParams {
                 * 1000
         }
     }
+
+    /// Overrides the size of the encoder's HRD/VBV buffer in KB (1000 bytes), which is also the maximum size of a compressed frame returned by [`MfxVideoParams::suggested_buffer_size`]. Zero lets the encoder pick one based on the bitrate. The encoder may clamp it, read it back from [`crate::encode::Encoder::params`] after initialization.
+    pub fn set_buffer_size_in_kb(&mut self, kilobytes: u16) {
+        self.mfx_mut()
+            .__bindgen_anon_1
+            .__bindgen_anon_1
+            .BufferSizeInKB = kilobytes;
+    }
+
+    /// Applies an [`HrdConfig`], setting the bitrates and buffer sizes and turning on HRD conformance and the NAL HRD parameters in the VUI. The rate control method is left as is, HRD conformance needs [`RateControlMethod::CBR`] or [`RateControlMethod::VBR`].
+    ///
+    /// Returns [`MfxStatus::InvalidVideoParam`](crate::MfxStatus::InvalidVideoParam) and leaves the params untouched if the max bitrate is below the target bitrate or the initial delay is larger than the buffer.
+    pub fn set_hrd(&mut self, config: HrdConfig) -> Result<(), ffi::MfxStatus> {
+        config.validate()?;
+
+        self.set_target_kbps(config.target_kbps);
+        self.set_max_kbps(config.max_kbps);
+        self.set_buffer_size_in_kb(config.buffer_size_in_kb);
+        self.set_initial_delay_in_kb(config.initial_delay_in_kb);
+
+        let option1 = self.ext_buffer_or_default::<ExtraCodingOption1>();
+        option1.set_nal_hrd_conformance(constants::CodingOptionValue::On);
+        option1.set_vui_nal_hrd_parameters(constants::CodingOptionValue::On);
+
+        Ok(())
+    }
+
+    /// Hands rate control over to `controller`, attaching an [`ExternalBrc`] and turning on [`ExtraCodingOption2::set_ext_brc`]. Use [`RateControlMethod::CBR`] or [`RateControlMethod::VBR`]. The encoder keeps the controller alive for as long as it runs.
+    pub fn set_external_brc(&mut self, controller: Box<dyn BitrateController>) {
+        self.add_ext_buffer(ExternalBrc::new(controller));
+        self.ext_buffer_or_default::<ExtraCodingOption2>()
+            .set_ext_brc(constants::CodingOptionValue::On);
+    }
+
+    /// Sets how many frames the look ahead rate control methods ([`RateControlMethod::LA`], [`RateControlMethod::LAICQ`] and [`RateControlMethod::LAHRD`]) analyze before encoding. Zero lets the encoder pick.
+    ///
+    /// See [`MfxVideoParams::set_look_ahead_statistics`] for exporting what the look ahead found.
+    pub fn set_look_ahead_depth(&mut self, depth: u16) {
+        self.ext_buffer_or_default::<ExtraCodingOption2>().set_look_ahead_depth(depth);
+    }
+
+    /// Asks the encoder to run the look ahead externally and export its per frame statistics (`mfxExtLAFrameStatistics`) for a custom [`BitrateController`].
+    ///
+    /// Always fails with [`ffi::MfxStatus::Unsupported`]. The statistics were part of the Media SDK ENC interface, which oneVPL dropped, so no runtime can produce them. Use [`MfxVideoParams::set_external_brc`] without them, the controller still gets the encoded size of every frame.
+    pub fn set_look_ahead_statistics(&mut self, enable: bool) -> Result<(), ffi::MfxStatus> {
+        if enable {
+            return Err(ffi::MfxStatus::Unsupported);
+        }
+
+        Ok(())
+    }
+
+    /// Writes the chroma sample location (0 to 5, see [`ChromaLocInfo::set_sample_loc`]) to the VUI of the encoded stream.
+    pub fn set_chroma_loc(&mut self, loc: u16) {
+        self.ext_buffer_or_default::<ChromaLocInfo>().set_sample_loc(loc, loc);
+    }
+
+    /// Hints the encoder about the content being encoded. Use [`constants::ContentInfo::NonVideoScreen`] for desktop capture so HEVC/AV1 encoders can enable their screen content tools (palette mode, intra block copy).
+    pub fn set_content_info(&mut self, info: constants::ContentInfo) {
+        self.ext_buffer_or_default::<ExtraCodingOption3>().set_content_info(info);
+    }
 }
 
 impl Deref for MfxVideoParams {
@@ -327,108 +778,176 @@ impl DerefMut for MfxVideoParams {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-pub enum ExtraCodingOption {
-    ExtraCodingOption1(ExtraCodingOption1),
-    ExtraCodingOption2(ExtraCodingOption2),
-    ExtraCodingOption3(ExtraCodingOption3),
-}
-
-#[derive(Debug, Clone, Copy)]
-pub struct ExtraCodingOption1 {
-    inner: ffi::mfxExtCodingOption,
+/// HRD (hypothetical reference decoder) settings for streaming over constrained links, applied with [`MfxVideoParams::set_hrd`]. Sizes are in KB (1000 bytes) and zero lets the encoder pick.
+///
+/// Eg. 4 Mbps CBR with a 1 second VBV buffer that starts half full is `HrdConfig::new(4000, 4000).buffer_size_in_kb(500).initial_delay_in_kb(250)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HrdConfig {
+    target_kbps: u16,
+    max_kbps: u16,
+    buffer_size_in_kb: u16,
+    initial_delay_in_kb: u16,
 }
 
-impl Default for ExtraCodingOption1 {
-    fn default() -> Self {
+impl HrdConfig {
+    /// For CBR `max_kbps` should be the same as `target_kbps`.
+    pub fn new(target_kbps: u16, max_kbps: u16) -> Self {
         Self {
-            inner: unsafe { mem::zeroed() },
+            target_kbps,
+            max_kbps,
+            buffer_size_in_kb: 0,
+            initial_delay_in_kb: 0,
         }
     }
-}
 
-impl Deref for ExtraCodingOption1 {
-    type Target = ffi::mfxExtCodingOption;
+    /// Size of the VBV buffer.
+    pub fn buffer_size_in_kb(mut self, kilobytes: u16) -> Self {
+        self.buffer_size_in_kb = kilobytes;
+        self
+    }
 
-    fn deref(&self) -> &Self::Target {
-        &self.inner
+    /// How full the VBV buffer is before the decoder starts decoding.
+    pub fn initial_delay_in_kb(mut self, kilobytes: u16) -> Self {
+        self.initial_delay_in_kb = kilobytes;
+        self
     }
-}
 
-impl DerefMut for ExtraCodingOption1 {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.inner
+    /// Checks the settings are consistent with each other.
+    pub fn validate(&self) -> Result<(), ffi::MfxStatus> {
+        if self.max_kbps < self.target_kbps {
+            return Err(ffi::MfxStatus::InvalidVideoParam);
+        }
+        if self.buffer_size_in_kb != 0 && self.initial_delay_in_kb > self.buffer_size_in_kb {
+            return Err(ffi::MfxStatus::InvalidVideoParam);
+        }
+        Ok(())
     }
 }
 
-impl ExtraCodingOption1 {
-    #[doc = "< If set, CAVLC is used; if unset, CABAC is used for encoding. See the CodingOptionValue enumerator for values of this option."]
-    pub fn set_cavlc(&mut self, option: constants::CodingOptionValue) {
-        (*self).inner.CAVLC = option.repr() as u16;
+ext_buffer!(
+    #[doc = "Wraps mfxExtChromaLocInfo, the chroma sample location (siting) of 4:2:0 frames. See https://spec.oneapi.io/versions/latest/elements/oneVPL/source/API_ref/VPL_structs_cross_component.html#mfxextchromalocinfo for more info."]
+    ChromaLocInfo,
+    ffi::mfxExtChromaLocInfo,
+    ffi::MFX_EXTBUFF_CHROMA_LOC_INFO
+);
+
+impl ChromaLocInfo {
+    /// Sets the chroma_sample_loc_type_top_field and chroma_sample_loc_type_bottom_field syntax elements (0 to 5, see Figure E-1 of the H.264 spec) and marks them as present. 0 (left, MPEG-2 style) is what most 4:2:0 content uses, progressive frames use the same value for both.
+    pub fn set_sample_loc(&mut self, top_field: u16, bottom_field: u16) {
+        self.inner.ChromaLocInfoPresentFlag = 1;
+        self.inner.ChromaSampleLocTypeTopField = top_field;
+        self.inner.ChromaSampleLocTypeBottomField = bottom_field;
     }
 }
 
 #[derive(Debug, Clone, Copy)]
-pub struct ExtraCodingOption2 {
-    inner: ffi::mfxExtCodingOption2,
-}
-
-impl Default for ExtraCodingOption2 {
-    fn default() -> Self {
-        Self {
-            inner: unsafe { mem::zeroed() },
-        }
-    }
+pub enum ExtraCodingOption {
+    ExtraCodingOption1(ExtraCodingOption1),
+    ExtraCodingOption2(ExtraCodingOption2),
+    ExtraCodingOption3(ExtraCodingOption3),
 }
 
-impl Deref for ExtraCodingOption2 {
-    type Target = ffi::mfxExtCodingOption2;
+ext_buffer!(
+    #[doc = "Wraps mfxExtCodingOption. Attach with [`VideoParams::add_ext_buffer`]."]
+    ExtraCodingOption1,
+    ffi::mfxExtCodingOption,
+    ffi::MFX_EXTBUFF_CODING_OPTION
+);
 
-    fn deref(&self) -> &Self::Target {
-        &self.inner
+impl ExtraCodingOption1 {
+    #[doc = "< If set, CAVLC is used; if unset, CABAC is used for encoding. See the CodingOptionValue enumerator for values of this option."]
+    pub fn set_cavlc(&mut self, option: constants::CodingOptionValue) {
+        (*self).inner.CAVLC = option.repr() as u16;
     }
-}
-
-impl DerefMut for ExtraCodingOption2 {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.inner
+    #[doc = "< Set this flag if rate distortion optimization is needed. See the CodingOptionValue enumerator for values of this option."]
+    pub fn set_rate_distortion_opt(&mut self, option: constants::CodingOptionValue) {
+        (*self).inner.RateDistortionOpt = option.repr() as u16;
+    }
+    #[doc = "< Rectangular size of the search window for motion estimation. This parameter is reserved and must be (0, 0)."]
+    pub fn set_mv_search_window(&mut self, x: i16, y: i16) {
+        (*self).inner.MVSearchWindow.x = x;
+        (*self).inner.MVSearchWindow.y = y;
+    }
+    #[doc = "< If set, encoder will use the specified value as the max_dec_frame_buffering syntax element value in VUI. Zero means use the default value."]
+    pub fn set_max_dec_frame_buffering(&mut self, frames: u16) {
+        (*self).inner.MaxDecFrameBuffering = frames;
+    }
+    #[doc = "< Set this flag to insert the Access Unit Delimiter NAL. See the CodingOptionValue enumerator for values of this option."]
+    pub fn set_au_delimiter(&mut self, option: constants::CodingOptionValue) {
+        (*self).inner.AUDelimiter = option.repr() as u16;
+    }
+    #[doc = "< Set this flag to insert the picture timing SEI with pic_struct syntax element. See sub-clauses D.1.2 and D.2.2 of the ISO/IEC 14496-10 specification for the definition of this syntax element. See the CodingOptionValue enumerator for values of this option. The default value is ON."]
+    pub fn set_pic_timing_sei(&mut self, option: constants::CodingOptionValue) {
+        (*self).inner.PicTimingSEI = option.repr() as u16;
+    }
+    #[doc = "< Set this flag to insert NAL HRD parameters in the VUI header. See the CodingOptionValue enumerator for values of this option."]
+    pub fn set_vui_nal_hrd_parameters(&mut self, option: constants::CodingOptionValue) {
+        (*self).inner.VuiNalHrdParameters = option.repr() as u16;
+    }
+    #[doc = "< If this option is turned ON, then AVC encoder produces an HRD conformant bitstream. If it is turned OFF, then the AVC encoder may (but not necessarily) violate HRD conformance. That is, this option can force the encoder to produce an HRD conformant stream, but cannot force it to produce a non-conformant stream. See the CodingOptionValue enumerator for values of this option."]
+    pub fn set_nal_hrd_conformance(&mut self, option: constants::CodingOptionValue) {
+        (*self).inner.NalHrdConformance = option.repr() as u16;
     }
 }
 
+ext_buffer!(
+    #[doc = "Wraps mfxExtCodingOption2. Attach with [`VideoParams::add_ext_buffer`]."]
+    ExtraCodingOption2,
+    ffi::mfxExtCodingOption2,
+    ffi::MFX_EXTBUFF_CODING_OPTION2
+);
+
 impl ExtraCodingOption2 {
     #[doc = "Controls usage of B-frames as reference. See BRefControl enumerator for values of this option.\nThis parameter is valid only during initialization."]
     pub fn set_b_ref_type(&mut self, control: constants::BRefControl) {
         (*self).inner.BRefType = control.repr() as u16;
     }
-}
-
-#[derive(Debug, Clone, Copy)]
-pub struct ExtraCodingOption3 {
-    inner: ffi::mfxExtCodingOption3,
-}
-
-impl Default for ExtraCodingOption3 {
-    fn default() -> Self {
-        Self {
-            inner: unsafe { mem::zeroed() },
-        }
+    /// Enables gradual intra refresh instead of periodic IDR frames. Every `cycle_size` frames each part of the picture has been intra coded once, so a decoder recovers from lost frames without the bitrate spike of a full keyframe. `qp_delta` (-51 to 51) is added to the QP of the intra refreshed macroblocks.
+    pub fn set_intra_refresh(
+        &mut self,
+        type_: constants::IntraRefreshType,
+        cycle_size: u16,
+        qp_delta: i16,
+    ) {
+        (*self).inner.IntRefType = type_.repr() as u16;
+        (*self).inner.IntRefCycleSize = cycle_size;
+        (*self).inner.IntRefQPDelta = qp_delta;
     }
-}
-
-impl Deref for ExtraCodingOption3 {
-    type Target = ffi::mfxExtCodingOption3;
-
-    fn deref(&self) -> &Self::Target {
-        &self.inner
+    #[doc = " Turns on an external BRC functionality. See the CodingOptionValue enumerator for values of this option. This option is ignored if the mfxExtBRC structure is not attached. Use [`MfxVideoParams::set_external_brc`] to set up both."]
+    pub fn set_ext_brc(&mut self, option: constants::CodingOptionValue) {
+        (*self).inner.ExtBRC = option.repr() as u16;
     }
-}
-
-impl DerefMut for ExtraCodingOption3 {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.inner
+    #[doc = " Specifies the depth of the look ahead rate control algorithm. It is the number of frames that the encoder analyzes before encoding. Valid value range is from 10 to 100 inclusive. To instruct the encoder to use the default value the application should zero this field."]
+    pub fn set_look_ahead_depth(&mut self, depth: u16) {
+        (*self).inner.LookAheadDepth = depth;
+    }
+    #[doc = " Specifies the maximum number of macroblocks in a slice. The encoder splits frames into slices so that no slice exceeds this number. It takes priority over mfxInfoMFX::NumSlice. Zero means it is not specified."]
+    pub fn set_num_mb_per_slice(&mut self, num: u16) {
+        (*self).inner.NumMbPerSlice = num;
+    }
+    pub fn max_frame_size(&self) -> u32 {
+        self.inner.MaxFrameSize
+    }
+    #[doc = " Specifies the maximum encoded frame size in bytes. The encoder re-encodes frames that go above it with a higher QP, useful to keep frames within a few packets when streaming over lossy links. Only used with the VBR family of rate control methods, the actual frame size may go slightly above. Zero means it is not specified. See [`ExtraCodingOption3::set_max_frame_size_i`] and [`ExtraCodingOption3::set_max_frame_size_p`] for separate limits per frame type."]
+    pub fn set_max_frame_size(&mut self, bytes: u32) {
+        (*self).inner.MaxFrameSize = bytes;
+    }
+    pub fn max_slice_size(&self) -> u32 {
+        self.inner.MaxSliceSize
+    }
+    #[doc = " Specifies the maximum slice size in bytes. If this parameter is specified other controls over the number of slices are ignored. Keeps every slice within a single packet (Eg. the MTU of RTP). Not all codecs and implementations support it. Zero means it is not specified."]
+    pub fn set_max_slice_size(&mut self, bytes: u32) {
+        (*self).inner.MaxSliceSize = bytes;
     }
 }
 
+ext_buffer!(
+    #[doc = "Wraps mfxExtCodingOption3. Attach with [`VideoParams::add_ext_buffer`]."]
+    ExtraCodingOption3,
+    ffi::mfxExtCodingOption3,
+    ffi::MFX_EXTBUFF_CODING_OPTION3
+);
+
 impl ExtraCodingOption3 {
     #[doc = "< Provides a hint to encoder about the scenario for the encoding session. See the ScenarioInfo enumerator for values of this option."]
     pub fn set_scenario_info(&mut self, info: constants::ScenarioInfo) {
@@ -438,4 +957,47 @@ impl ExtraCodingOption3 {
     pub fn set_content_info(&mut self, info: constants::ContentInfo) {
         (*self).inner.ContentInfo = info.repr() as u16;
     }
+    pub fn qvbr_quality(&self) -> u16 {
+        self.inner.QVBRQuality
+    }
+    #[doc = " Quality factor for the QVBR rate control method, 1 (best) to 51. Used together with TargetKbps and MaxKbps of mfxInfoMFX."]
+    pub fn set_qvbr_quality(&mut self, quality: u16) {
+        (*self).inner.QVBRQuality = quality;
+    }
+    #[doc = " Distance between the beginnings of the intra-refresh cycles in frames. Zero means no distance between cycles."]
+    pub fn set_intra_refresh_cycle_dist(&mut self, distance: u16) {
+        (*self).inner.IntRefCycleDist = distance;
+    }
+    #[doc = " If this flag is set to ON, encoder will fill out the mfxExtEncodedUnitsInfo structure attached to the output bitstream. See the CodingOptionValue enumerator for values of this option. Use [`crate::bitstream::Bitstream::attach_encoded_units_info`] to attach it."]
+    pub fn set_encoded_units_info(&mut self, option: constants::CodingOptionValue) {
+        (*self).inner.EncodedUnitsInfo = option.repr() as u16;
+    }
+    pub fn max_frame_size_i(&self) -> u32 {
+        self.inner.MaxFrameSizeI
+    }
+    #[doc = " Same as [`ExtraCodingOption2::set_max_frame_size`] but only for I frames. Zero means it is not specified."]
+    pub fn set_max_frame_size_i(&mut self, bytes: u32) {
+        (*self).inner.MaxFrameSizeI = bytes;
+    }
+    pub fn max_frame_size_p(&self) -> u32 {
+        self.inner.MaxFrameSizeP
+    }
+    #[doc = " Same as [`ExtraCodingOption2::set_max_frame_size`] but only for P and B frames. Zero means it is not specified."]
+    pub fn set_max_frame_size_p(&mut self, bytes: u32) {
+        (*self).inner.MaxFrameSizeP = bytes;
+    }
+    pub fn gpb(&self) -> Option<constants::CodingOptionValue> {
+        constants::CodingOptionValue::from_repr(self.inner.GPB as _)
+    }
+    #[doc = " Enables Generalized P/B (GPB) frames for HEVC. When ON, every P frame is encoded as a B frame whose references are all in the past (GPB frame). When OFF, regular P frames are used. See the CodingOptionValue enumerator for values of this option."]
+    pub fn set_gpb(&mut self, option: constants::CodingOptionValue) {
+        (*self).inner.GPB = option.repr() as u16;
+    }
+    pub fn pref_type(&self) -> Option<constants::PRefType> {
+        constants::PRefType::from_repr(self.inner.PRefType as _)
+    }
+    #[doc = " When GopRefDist=1, specifies the model of reference list construction and DPB management. See the PRefType enumerator for values of this option."]
+    pub fn set_pref_type(&mut self, pref_type: constants::PRefType) {
+        (*self).inner.PRefType = pref_type.repr() as u16;
+    }
 }
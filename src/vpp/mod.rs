@@ -1,18 +1,23 @@
 use std::{
+    any::Any,
+    fmt::Debug,
+    mem,
     ops::{Deref, DerefMut},
+    sync::Arc,
     time::Instant,
 };
 
 use ffi::MfxStatus;
 use intel_onevpl_sys as ffi;
-use tokio::task;
 use tracing::{trace, warn};
 
+pub use crate::videoparams::ChromaLocInfo;
 use crate::{
-    constants::{ChromaFormat, FourCC, PicStruct},
-    get_library,
-    videoparams::{MfxVideoParams, VideoParams},
-    FrameSurface, Session, utils::SharedPtr,
+    constants::{ChromaFormat, FourCC, ImageStabMode, IoPattern, PicStruct},
+    get_library, report_status,
+    videoparams::{ext_buffer, ExtBuffer, MfxVideoParams, VideoParams},
+    utils::{InFlight, InFlightPermit, Rect, SharedPtr},
+    FrameInfo, FrameInfoMut, FrameRate, FrameSurface, Session, SessionRef,
 };
 
 // pub struct FrameInfo {
@@ -27,8 +32,38 @@ use crate::{
 //     }
 // }
 
+/// Number and size of the surfaces needed on one side of VPP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SurfaceRequirements {
+    pub num_frame_min: u16,
+    pub num_frame_suggested: u16,
+    pub width: u16,
+    pub height: u16,
+}
+
+impl From<&ffi::mfxFrameAllocRequest> for SurfaceRequirements {
+    fn from(request: &ffi::mfxFrameAllocRequest) -> Self {
+        let frame = unsafe { &request.Info.__bindgen_anon_1.__bindgen_anon_1 };
+        Self {
+            num_frame_min: request.NumFrameMin,
+            num_frame_suggested: request.NumFrameSuggested,
+            width: frame.Width,
+            height: frame.Height,
+        }
+    }
+}
+
+/// Surfaces needed by a [`VideoProcessor`], see [`VideoProcessor::query_io_surf`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VppSurfaceRequirements {
+    pub input: SurfaceRequirements,
+    pub output: SurfaceRequirements,
+}
+
 pub struct VideoProcessor<'a, 'b: 'a> {
-    session: &'a Session<'b>,
+    session: SessionRef<'a, 'b>,
+    /// One permit per frame [`VideoProcessor::process`] or `VideoProcessor::process_blocking` may have in flight
+    in_flight: InFlight,
 }
 // unsafe impl Send for VideoProcessor<'_, '_> {}
 
@@ -38,6 +73,38 @@ impl<'a, 'b: 'a> VideoProcessor<'a, 'b> {
         session: &'a Session<'b>,
         params: &mut VppVideoParams,
     ) -> Result<Self, MfxStatus> {
+        Self::init(SessionRef::Borrowed(session), params)
+    }
+
+    pub(crate) fn init(
+        session: SessionRef<'a, 'b>,
+        params: &mut VppVideoParams,
+    ) -> Result<Self, MfxStatus> {
+        Self::init_session(&session, params)?;
+
+        let decoder = Self {
+            session,
+            in_flight: InFlight::new(params.async_depth()),
+        };
+
+        Ok(decoder)
+    }
+
+    /// Closes the video processor and initializes it again with `params`. Unlike [`VideoProcessor::reset`] the new input and output can be larger than the ones it was first initialized with. Drain it first, the frames it still holds are dropped. AsyncDepth stays as it was.
+    #[cfg(feature = "async")]
+    pub(crate) fn reinit(&self, params: &mut VppVideoParams) -> Result<(), MfxStatus> {
+        let lib = get_library().unwrap();
+
+        let status: MfxStatus = unsafe { lib.MFXVideoVPP_Close(self.session.inner.0) }.into();
+        report_status("MFXVideoVPP_Close", status);
+        if status != MfxStatus::NoneOrDone {
+            return Err(status);
+        }
+
+        Self::init_session(&self.session, params)
+    }
+
+    fn init_session(session: &Session, params: &mut VppVideoParams) -> Result<(), MfxStatus> {
         let lib = get_library().unwrap();
 
         assert!(!params.io_pattern().is_empty(), "params IOPattern not set");
@@ -45,24 +112,30 @@ impl<'a, 'b: 'a> VideoProcessor<'a, 'b> {
         let status: MfxStatus =
             unsafe { lib.MFXVideoVPP_Init(session.inner.0, &mut ***params) }.into();
 
-        trace!("VPP init = {:?}", status);
+        report_status("MFXVideoVPP_Init", status);
         
         match status {
             MfxStatus::NoneOrDone => {},
             MfxStatus::WarnIncompatibleVideoParam =>
                 warn!("Incompatible Video Parameters. The function detected some video parameters were incompatible with others; incompatibility resolved."),
+            MfxStatus::FilterSkipped =>
+                warn!("Filter Skipped. The VPP skipped one or more filters requested by the application."),
             _ => return Err(status)
         };
 
-        let decoder = Self { session };
-
-        Ok(decoder)
+        Ok(())
     }
 
-    pub fn queue(&self,
-        frame: Option<&mut FrameSurface<'_>>
-    ) -> Result<FrameSurface, MfxStatus> {
-        let start_time = Instant::now();
+    /// Submits a frame to the video processor without waiting for the
+    /// output surface to be ready. [`VideoProcessor::queue`],
+    /// [`VideoProcessor::process`] and `VideoProcessor::process_blocking` are
+    /// built on top of this. Takes the slot in [`InFlight`] the frame occupies
+    /// so every caller is bounded by AsyncDepth.
+    fn queue_process(
+        &self,
+        _permit: &InFlightPermit<'_>,
+        frame: Option<&mut FrameSurface<'_>>,
+    ) -> Result<FrameSurface<'_>, MfxStatus> {
         let lib = get_library().unwrap();
 
         let mut output_surface = SharedPtr(std::ptr::null_mut());
@@ -73,33 +146,57 @@ impl<'a, 'b: 'a> VideoProcessor<'a, 'b> {
 
             let session = self.session.inner.0;
 
-            // dbg!(sync_point, output_surface);
-
-            let status: MfxStatus =
-                unsafe { lib.MFXVideoVPP_ProcessFrameAsync(session, input, &mut output_surface.0) }
-                    .into();
-
-            trace!("Process frame start = {:?}", status);
+            let status: MfxStatus = unsafe {
+                lib.MFXVideoVPP_ProcessFrameAsync(session, input, &mut output_surface.0)
+            }
+            .into();
+            report_status("MFXVideoVPP_ProcessFrameAsync", status);
 
             if status != MfxStatus::NoneOrDone {
                 return Err(status);
             }
         }
 
-        let output_surface = FrameSurface::try_from(output_surface.0)?;
+        FrameSurface::try_from(output_surface.0)
+    }
 
-        let frame_info = output_surface.inner.Info;
-        let format = FourCC::from_repr(frame_info.FourCC as ffi::_bindgen_ty_5).unwrap();
-        let height = unsafe { frame_info.__bindgen_anon_1.__bindgen_anon_1.CropH };
-        let width = unsafe { frame_info.__bindgen_anon_1.__bindgen_anon_1.CropW };
+    /// Same as [`VideoProcessor::process`] but returns the output surface straight away instead of waiting for it to be processed. Wait for it with [`FrameSurface::synchronize`] before touching the pixels.
+    ///
+    /// The returned frame counts towards AsyncDepth until it is synchronized or dropped. Submitting blocks the thread while AsyncDepth frames are in flight, so holding AsyncDepth unsynchronized frames on the thread calling this blocks it forever. Use [`VideoProcessor::queue_async`] from async code.
+    pub fn queue(&self,
+        mut frame: Option<&mut FrameSurface<'_>>
+    ) -> Result<FrameSurface, MfxStatus> {
+        let start_time = Instant::now();
 
-        trace!(
-            "Queue frame = {:?} {}x{} {:?}",
-            format,
-            width,
-            height,
-            start_time.elapsed()
-        );
+        let permit = self.in_flight.acquire_blocking();
+        let mut output_surface = self
+            .session
+            .busy_retry()
+            .run(|| self.queue_process(&permit, frame.as_deref_mut()))?;
+        output_surface.in_flight = Some(permit);
+
+        trace_frame("Queue", &output_surface, start_time);
+
+        Ok(output_surface)
+    }
+
+    /// Same as [`VideoProcessor::queue`] but waits for a free slot and retries a busy device without blocking the executor.
+    #[cfg(feature = "async")]
+    pub async fn queue_async(
+        &self,
+        mut frame: Option<&mut FrameSurface<'_>>,
+    ) -> Result<FrameSurface, MfxStatus> {
+        let start_time = Instant::now();
+
+        let permit = self.in_flight.acquire().await;
+        let mut output_surface = self
+            .session
+            .busy_retry()
+            .run_async(|| self.queue_process(&permit, frame.as_deref_mut()))
+            .await?;
+        output_surface.in_flight = Some(permit);
+
+        trace_frame("Queue", &output_surface, start_time);
 
         Ok(output_surface)
     }
@@ -107,60 +204,54 @@ impl<'a, 'b: 'a> VideoProcessor<'a, 'b> {
     /// The function processes a single input frame to a single output frame
     /// with internal allocation of output frame.
     ///
+    /// Concurrent calls are limited to the AsyncDepth the processor was
+    /// created with, extra calls wait for a frame to finish before being
+    /// submitted.
+    ///
     /// See
     /// https://spec.oneapi.io/versions/latest/elements/oneVPL/source/API_ref/VPL_func_vid_vpp.html#mfxvideovpp-processframeasync
     /// for more info.
-    // TODO: Use queue function instead of duplicating code
+    #[cfg(feature = "async")]
     pub async fn process(
         &self,
-        frame: Option<&mut FrameSurface<'_>>,
+        mut frame: Option<&mut FrameSurface<'_>>,
         timeout: Option<u32>,
     ) -> Result<FrameSurface, MfxStatus> {
+        // Held until the frame is synchronized
+        let permit = self.in_flight.acquire().await;
         let start_time = Instant::now();
-        let lib = get_library().unwrap();
-
-        let mut output_surface = SharedPtr(std::ptr::null_mut());
-        {
-            let input = frame
-                .map(|f| f.inner as *mut _)
-                .unwrap_or(std::ptr::null_mut());
-
-            let session = self.session.inner.0;
 
-            // dbg!(sync_point, output_surface);
+        let mut output_surface = self
+            .session
+            .busy_retry()
+            .run_async(|| self.queue_process(&permit, frame.as_deref_mut()))
+            .await?;
+        output_surface.synchronize_async(timeout).await?;
 
-            let status: MfxStatus =
-                unsafe { lib.MFXVideoVPP_ProcessFrameAsync(session, input, &mut output_surface.0) }
-                    .into();
+        trace_frame("Process", &output_surface, start_time);
 
-            trace!("Process frame start = {:?}", status);
+        Ok(output_surface)
+    }
 
-            if status != MfxStatus::NoneOrDone {
-                return Err(status);
-            }
-        }
+    /// Same as [`VideoProcessor::process`] but blocks the current thread
+    /// while waiting for the frame to be processed instead of requiring an
+    /// async runtime.
+    #[cfg(feature = "blocking")]
+    pub fn process_blocking(
+        &self,
+        mut frame: Option<&mut FrameSurface<'_>>,
+        timeout: Option<u32>,
+    ) -> Result<FrameSurface, MfxStatus> {
+        let permit = self.in_flight.acquire_blocking();
+        let start_time = Instant::now();
 
-        let mut output_surface = FrameSurface::try_from(output_surface.0)?;
+        let mut output_surface = self
+            .session
+            .busy_retry()
+            .run(|| self.queue_process(&permit, frame.as_deref_mut()))?;
+        output_surface.synchronize(timeout)?;
 
-        let output_surface = task::spawn_blocking(move || {
-            output_surface.synchronize(timeout)?;
-            Ok(output_surface) as Result<FrameSurface, MfxStatus>
-        })
-        .await
-        .unwrap()?;
-
-        let frame_info = output_surface.inner.Info;
-        let format = FourCC::from_repr(frame_info.FourCC as ffi::_bindgen_ty_5).unwrap();
-        let height = unsafe { frame_info.__bindgen_anon_1.__bindgen_anon_1.CropH };
-        let width = unsafe { frame_info.__bindgen_anon_1.__bindgen_anon_1.CropW };
-
-        trace!(
-            "Process frame = {:?} {}x{} {:?}",
-            format,
-            width,
-            height,
-            start_time.elapsed()
-        );
+        trace_frame("Process", &output_surface, start_time);
 
         Ok(output_surface)
     }
@@ -171,19 +262,61 @@ impl<'a, 'b: 'a> VideoProcessor<'a, 'b> {
     /// See
     /// https://spec.oneapi.io/versions/latest/elements/oneVPL/source/API_ref/VPL_func_vid_vpp.html#mfxvideovpp-reset
     /// for more info.
-    pub fn reset(&mut self, mut params: VppVideoParams) -> Result<(), MfxStatus> {
+    ///
+    /// Returns the surfaces needed with the new parameters, as a change in output size or format also changes the output surfaces. See [`VideoProcessor::surface_requirements`].
+    pub fn reset(
+        &mut self,
+        mut params: VppVideoParams,
+    ) -> Result<VppSurfaceRequirements, MfxStatus> {
         let lib = get_library().unwrap();
         let session = self.session.inner.0;
 
         let status: MfxStatus = unsafe { lib.MFXVideoVPP_Reset(session, &mut **params) }.into();
 
-        trace!("VPP reset = {:?}", status);
+        report_status("MFXVideoVPP_Reset", status);
 
         if status != MfxStatus::NoneOrDone {
             return Err(status);
         }
 
-        Ok(())
+        self.surface_requirements()
+    }
+
+    /// The number and size of input and output surfaces needed with the current parameters (see [`VideoProcessor::params`]).
+    pub fn surface_requirements(&self) -> Result<VppSurfaceRequirements, MfxStatus> {
+        let mut params = self.params()?;
+        Self::query_io_surf(&self.session, &mut params)
+    }
+
+    /// Returns the number and size of input and output surfaces VPP needs for `params`.
+    ///
+    /// See
+    /// https://spec.oneapi.io/versions/latest/elements/oneVPL/source/API_ref/VPL_func_vid_vpp.html#mfxvideovpp-queryiosurf
+    /// for more info.
+    pub fn query_io_surf(
+        session: &Session,
+        params: &mut VppVideoParams,
+    ) -> Result<VppSurfaceRequirements, MfxStatus> {
+        let lib = get_library().unwrap();
+        let session = session.inner.0;
+
+        let mut requests: [ffi::mfxFrameAllocRequest; 2] = unsafe { mem::zeroed() };
+
+        let status: MfxStatus = unsafe {
+            lib.MFXVideoVPP_QueryIOSurf(session, &mut ***params, requests.as_mut_ptr())
+        }
+        .into();
+
+        report_status("MFXVideoVPP_QueryIOSurf", status);
+
+        if status != MfxStatus::NoneOrDone {
+            return Err(status);
+        }
+
+        Ok(VppSurfaceRequirements {
+            input: SurfaceRequirements::from(&requests[0]),
+            output: SurfaceRequirements::from(&requests[1]),
+        })
     }
 
     /// Returns surface which can be used as input for VPP.
@@ -200,7 +333,7 @@ impl<'a, 'b: 'a> VideoProcessor<'a, 'b> {
         let status: MfxStatus =
             unsafe { lib.MFXMemory_GetSurfaceForVPP(session, &mut raw_surface) }.into();
 
-        trace!("VPP get input surface = {:?}", status);
+        report_status("MFXMemory_GetSurfaceForVPP", status);
 
         if status != MfxStatus::NoneOrDone {
             return Err(status);
@@ -225,7 +358,7 @@ impl<'a, 'b: 'a> VideoProcessor<'a, 'b> {
         let status: MfxStatus =
             unsafe { lib.MFXMemory_GetSurfaceForVPPOut(session, &mut raw_surface) }.into();
 
-        trace!("VPP get output surface = {:?}", status);
+        report_status("MFXMemory_GetSurfaceForVPPOut", status);
 
         if status != MfxStatus::NoneOrDone {
             return Err(status);
@@ -250,7 +383,7 @@ impl<'a, 'b: 'a> VideoProcessor<'a, 'b> {
         let status: MfxStatus =
             unsafe { lib.MFXVideoVPP_GetVideoParam(session, &mut **params) }.into();
 
-        trace!("VPP get params = {:?}", status);
+        report_status("MFXVideoVPP_GetVideoParam", status);
 
         if status != MfxStatus::NoneOrDone {
             return Err(status);
@@ -278,7 +411,7 @@ impl<'a, 'b: 'a> VideoProcessor<'a, 'b> {
         let status: MfxStatus =
             unsafe { lib.MFXVideoVPP_Query(session, input_params, &mut **params) }.into();
 
-        trace!("VPP query = {:?}", status);
+        report_status("MFXVideoVPP_Query", status);
 
         if status != MfxStatus::NoneOrDone {
             return Err((status, params));
@@ -286,6 +419,112 @@ impl<'a, 'b: 'a> VideoProcessor<'a, 'b> {
 
         Ok(params)
     }
+
+    /// Returns the output formats VPP on this session can convert `in_fourcc` frames to, by querying each of the common formats with [`VideoProcessor::query`].
+    pub fn supported_out_formats(
+        session: &Session,
+        in_fourcc: FourCC,
+    ) -> Result<Vec<FourCC>, MfxStatus> {
+        let (in_chroma_format, in_bitdepth) =
+            format_properties(in_fourcc).ok_or(MfxStatus::Unsupported)?;
+
+        let mut formats = Vec::new();
+        for out_fourcc in QUERY_FORMATS {
+            let (out_chroma_format, out_bitdepth) = format_properties(out_fourcc).unwrap();
+
+            let mut params = VppVideoParams::default();
+            params.set_io_pattern(IoPattern::IN_SYSTEM_MEMORY | IoPattern::OUT_SYSTEM_MEMORY);
+            fill_query_info(params.in_mut(), in_fourcc, in_chroma_format, in_bitdepth);
+            fill_query_info(params.out_mut(), out_fourcc, out_chroma_format, out_bitdepth);
+
+            let supported = match Self::query(session, Some(&params)) {
+                Ok(result) => result.out().FourCC == out_fourcc.repr() as u32,
+                // Still supported, just not fully in hardware or with adjusted parameters
+                Err((
+                    MfxStatus::PartialAcceleration | MfxStatus::WarnIncompatibleVideoParam,
+                    result,
+                )) => result.out().FourCC == out_fourcc.repr() as u32,
+                Err((MfxStatus::Unsupported | MfxStatus::IncompatibleVideoParam, _)) => false,
+                Err((status, _)) => return Err(status),
+            };
+
+            if supported {
+                formats.push(out_fourcc);
+            }
+        }
+
+        Ok(formats)
+    }
+}
+
+/// Output formats probed by [`VideoProcessor::supported_out_formats`].
+const QUERY_FORMATS: [FourCC; 13] = [
+    FourCC::NV12,
+    FourCC::YV12,
+    FourCC::IyuvOrI420,
+    FourCC::NV16,
+    FourCC::YUY2,
+    FourCC::UYVY,
+    FourCC::AYUV,
+    FourCC::Rgb4OrBgra,
+    FourCC::BGR4,
+    FourCC::RGBP,
+    FourCC::P010,
+    FourCC::P210,
+    FourCC::Y210,
+];
+
+/// Fills in a 1280x720 progressive frame of the given format for [`VideoProcessor::supported_out_formats`].
+fn fill_query_info(
+    info: &mut ffi::mfxFrameInfo,
+    fourcc: FourCC,
+    chroma_format: ChromaFormat,
+    bitdepth: u16,
+) {
+    info.FourCC = fourcc.repr() as u32;
+    info.ChromaFormat = chroma_format.repr() as u16;
+    info.PicStruct = PicStruct::Progressive.repr() as u16;
+    info.BitDepthLuma = bitdepth;
+    info.BitDepthChroma = bitdepth;
+    info.Shift = (bitdepth > 8) as u16;
+    info.FrameRateExtN = 30;
+    info.FrameRateExtD = 1;
+    let rect = unsafe { &mut info.__bindgen_anon_1.__bindgen_anon_1 };
+    rect.Width = 1280;
+    rect.Height = 720;
+    rect.CropW = 1280;
+    rect.CropH = 720;
+}
+
+/// Chroma format and bit depth of a FourCC.
+fn format_properties(fourcc: FourCC) -> Option<(ChromaFormat, u16)> {
+    let properties = match fourcc {
+        FourCC::NV12 | FourCC::YV12 | FourCC::IyuvOrI420 => (ChromaFormat::YUV420, 8),
+        FourCC::NV16 | FourCC::YUY2 | FourCC::UYVY | FourCC::I422 => (ChromaFormat::YUV422, 8),
+        FourCC::AYUV | FourCC::Rgb4OrBgra | FourCC::BGR4 | FourCC::RGBP => {
+            (ChromaFormat::YUV444, 8)
+        }
+        FourCC::P010 | FourCC::I010 => (ChromaFormat::YUV420, 10),
+        FourCC::P210 | FourCC::Y210 => (ChromaFormat::YUV422, 10),
+        _ => return None,
+    };
+    Some(properties)
+}
+
+fn trace_frame(action: &str, surface: &FrameSurface, start_time: Instant) {
+    let frame_info = surface.inner.Info;
+    let format = FourCC::from_repr(frame_info.FourCC as ffi::_bindgen_ty_5).unwrap();
+    let height = unsafe { frame_info.__bindgen_anon_1.__bindgen_anon_1.CropH };
+    let width = unsafe { frame_info.__bindgen_anon_1.__bindgen_anon_1.CropW };
+
+    trace!(
+        "{} frame = {:?} {}x{} {:?}",
+        action,
+        format,
+        width,
+        height,
+        start_time.elapsed()
+    );
 }
 
 impl Drop for VideoProcessor<'_, '_> {
@@ -296,6 +535,396 @@ impl Drop for VideoProcessor<'_, '_> {
     }
 }
 
+ext_buffer!(
+    /// Configures the transfer matrix and nominal range of the VPP input and output. See https://spec.oneapi.io/versions/latest/elements/oneVPL/source/API_ref/VPL_structs_vpp.html#mfxextvppvideosignalinfo for more info.
+    VideoSignalInfo,
+    ffi::mfxExtVPPVideoSignalInfo,
+    ffi::MFX_EXTBUFF_VPP_VIDEO_SIGNAL_INFO
+);
+
+impl VideoSignalInfo {
+    fn nominal_range(full: bool) -> u16 {
+        if full {
+            ffi::MFX_NOMINALRANGE_0_255 as u16
+        } else {
+            ffi::MFX_NOMINALRANGE_16_235 as u16
+        }
+    }
+
+    #[doc = "< Nominal range of the input. Full range is 0-255, limited range is 16-235."]
+    pub fn set_in_full_range(&mut self, full: bool) {
+        let range = Self::nominal_range(full);
+        unsafe { self.inner.__bindgen_anon_1.__bindgen_anon_1.In.NominalRange = range };
+    }
+    #[doc = "< Nominal range of the output. Full range is 0-255, limited range is 16-235."]
+    pub fn set_out_full_range(&mut self, full: bool) {
+        let range = Self::nominal_range(full);
+        unsafe { self.inner.__bindgen_anon_1.__bindgen_anon_1.Out.NominalRange = range };
+    }
+}
+
+ext_buffer!(
+    /// Configures the detail/edge enhancement filter. See https://spec.oneapi.io/versions/latest/elements/oneVPL/source/API_ref/VPL_structs_vpp.html#mfxextvppdetail for more info.
+    Detail,
+    ffi::mfxExtVPPDetail,
+    ffi::MFX_EXTBUFF_VPP_DETAIL
+);
+
+impl Detail {
+    #[doc = "< Value of detail/edge enhancement in the range of 0 to 100. Values above 100 are clamped."]
+    pub fn set_factor(&mut self, factor: u16) {
+        self.inner.DetailFactor = factor.min(100);
+    }
+}
+
+ext_buffer!(
+    /// Configures the image stabilization filter. See https://spec.oneapi.io/versions/latest/elements/oneVPL/source/API_ref/VPL_structs_vpp.html#mfxextvppimagestab for more info.
+    ImageStab,
+    ffi::mfxExtVPPImageStab,
+    ffi::MFX_EXTBUFF_VPP_IMAGE_STABILIZATION
+);
+
+impl ImageStab {
+    #[doc = "< Specifies the image stabilization mode. The value must be one of the values of the ImageStabMode enumerator."]
+    pub fn set_mode(&mut self, mode: ImageStabMode) {
+        self.inner.Mode = mode.repr() as u16;
+    }
+}
+
+ext_buffer!(
+    /// Configures how VPP fills the parts of the output surface outside of the output crop. See https://spec.oneapi.io/versions/latest/elements/oneVPL/source/API_ref/VPL_structs_vpp.html#mfxextvppcolorfill for more info.
+    ColorFill,
+    ffi::mfxExtVPPColorFill,
+    ffi::MFX_EXTBUFF_VPP_COLORFILL
+);
+
+impl ColorFill {
+    #[doc = "< Set to ON makes VPP fill the area between Width/Height and Crop borders. See the CodingOptionValue enumerator for values of this option."]
+    pub fn set_enable(&mut self, enable: bool) {
+        self.inner.Enable = if enable {
+            ffi::MFX_CODINGOPTION_ON as u16
+        } else {
+            ffi::MFX_CODINGOPTION_OFF as u16
+        };
+    }
+}
+
+ext_buffer!(
+    /// Configures motion-compensated temporal filtering, a stronger denoiser than the spatial one. See https://spec.oneapi.io/versions/latest/elements/oneVPL/source/API_ref/VPL_structs_vpp.html#mfxextvppmctf for more info.
+    Mctf,
+    ffi::mfxExtVppMctf,
+    ffi::MFX_EXTBUFF_VPP_MCTF
+);
+
+impl Mctf {
+    #[doc = "< 0..20 value (inclusive) to indicate the filter strength of MCTF. The greater the strength value, the larger the change of pixel values. 0 stands for AUTO mode. Values above 20 are clamped."]
+    pub fn set_filter_strength(&mut self, strength: u16) {
+        self.inner.FilterStrength = strength.min(20);
+    }
+}
+
+/// A field of an interlaced frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Top,
+    Bottom,
+}
+
+impl Field {
+    fn pic_type(self) -> u16 {
+        match self {
+            Field::Top => ffi::MFX_PICTYPE_TOPFIELD as u16,
+            Field::Bottom => ffi::MFX_PICTYPE_BOTTOMFIELD as u16,
+        }
+    }
+}
+
+/// What VPP does with the fields of each frame, see [`VppVideoParams::set_field_processing`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldProcessingMode {
+    /// Copies the whole frame.
+    CopyFrame,
+    /// Copies one field of the input into one field of the output.
+    CopyField { input: Field, output: Field },
+    /// Swaps the top and bottom fields.
+    SwapFields,
+}
+
+ext_buffer!(
+    /// Configures copying and swapping fields of interlaced frames. See https://spec.oneapi.io/versions/latest/elements/oneVPL/source/API_ref/VPL_structs_vpp.html#mfxextvppfieldprocessing for more info.
+    FieldProcessing,
+    ffi::mfxExtVPPFieldProcessing,
+    ffi::MFX_EXTBUFF_VPP_FIELD_PROCESSING
+);
+
+impl FieldProcessing {
+    pub fn set_mode(&mut self, mode: FieldProcessingMode) {
+        let (mode, in_field, out_field) = match mode {
+            FieldProcessingMode::CopyFrame => (ffi::MFX_VPP_COPY_FRAME, 0, 0),
+            FieldProcessingMode::CopyField { input, output } => {
+                (ffi::MFX_VPP_COPY_FIELD, input.pic_type(), output.pic_type())
+            }
+            FieldProcessingMode::SwapFields => (ffi::MFX_VPP_SWAP_FIELDS, 0, 0),
+        };
+        self.inner.Mode = mode as u16;
+        self.inner.InField = in_field;
+        self.inner.OutField = out_field;
+    }
+}
+
+/// A 3D lookup table mapping input RGB values to output RGB values, used for color grading and HDR to SDR tone mapping.
+///
+/// Each channel holds `size`³ 16 bit entries indexed by `(r * size + g) * size + b`.
+#[derive(Debug, Clone)]
+pub struct Lut3D {
+    size: u8,
+    channels: [Vec<u16>; 3],
+}
+
+impl Lut3D {
+    /// Supported sizes are 17, 33 and 65 entries per dimension.
+    pub fn new(size: u8) -> Result<Self, MfxStatus> {
+        if ![17, 33, 65].contains(&size) {
+            return Err(MfxStatus::Unsupported);
+        }
+        let len = (size as usize).pow(3);
+        Ok(Self {
+            size,
+            channels: [vec![0; len], vec![0; len], vec![0; len]],
+        })
+    }
+
+    /// A table that maps every color to itself.
+    pub fn identity(size: u8) -> Result<Self, MfxStatus> {
+        let mut lut = Self::new(size)?;
+        let value = |i: u8| (i as u32 * u16::MAX as u32 / (size as u32 - 1)) as u16;
+        for r in 0..size {
+            for g in 0..size {
+                for b in 0..size {
+                    lut.set(r, g, b, [value(r), value(g), value(b)])?;
+                }
+            }
+        }
+        Ok(lut)
+    }
+
+    pub fn size(&self) -> u8 {
+        self.size
+    }
+
+    /// Returns [`MfxStatus::OutOfRange`] if any of the positions isn't below [`Lut3D::size`].
+    fn index(&self, r: u8, g: u8, b: u8) -> Result<usize, MfxStatus> {
+        if r >= self.size || g >= self.size || b >= self.size {
+            return Err(MfxStatus::OutOfRange);
+        }
+        let size = self.size as usize;
+        Ok((r as usize * size + g as usize) * size + b as usize)
+    }
+
+    /// The output color for the input color at the given table position. Returns [`MfxStatus::OutOfRange`] if a position isn't below [`Lut3D::size`].
+    pub fn get(&self, r: u8, g: u8, b: u8) -> Result<[u16; 3], MfxStatus> {
+        let i = self.index(r, g, b)?;
+        Ok([self.channels[0][i], self.channels[1][i], self.channels[2][i]])
+    }
+
+    /// Sets the output color for the input color at the given table position. Returns [`MfxStatus::OutOfRange`] if a position isn't below [`Lut3D::size`].
+    pub fn set(&mut self, r: u8, g: u8, b: u8, rgb: [u16; 3]) -> Result<(), MfxStatus> {
+        let i = self.index(r, g, b)?;
+        for (channel, value) in self.channels.iter_mut().zip(rgb) {
+            channel[i] = value;
+        }
+        Ok(())
+    }
+}
+
+/// Applies a [`Lut3D`] from system memory. See https://spec.oneapi.io/versions/latest/elements/oneVPL/source/API_ref/VPL_structs_vpp.html#mfxextvpp3dlut for more info.
+///
+/// The table is shared between clones so the channel pointers stay valid for as long as any of them is attached.
+#[derive(Clone)]
+pub struct Vpp3DLut {
+    inner: ffi::mfxExtVPP3DLut,
+    lut: Arc<Lut3D>,
+}
+
+unsafe impl Send for Vpp3DLut {}
+
+impl Vpp3DLut {
+    pub fn new(lut: Lut3D) -> Self {
+        let lut = Arc::new(lut);
+
+        let mut inner: ffi::mfxExtVPP3DLut = unsafe { mem::zeroed() };
+        inner.Header.BufferId = ffi::MFX_EXTBUFF_VPP_3DLUT as u32;
+        inner.Header.BufferSz = mem::size_of::<ffi::mfxExtVPP3DLut>() as u32;
+        inner.ChannelMapping = ffi::mfx3DLutChannelMapping_MFX_3DLUT_CHANNEL_MAPPING_DEFAULT;
+        inner.BufferType = ffi::mfxResourceType_MFX_RESOURCE_SYSTEM_SURFACE;
+
+        let system_buffer = unsafe { &mut inner.__bindgen_anon_1.SystemBuffer };
+        for (channel, data) in system_buffer.Channel.iter_mut().zip(lut.channels.iter()) {
+            channel.DataType = ffi::mfxDataType_MFX_DATA_TYPE_U16;
+            channel.Size = lut.size as u32;
+            // The library only reads from the table
+            channel.__bindgen_anon_1.Data16 = data.as_ptr() as *mut u16;
+        }
+
+        Self { inner, lut }
+    }
+
+    pub fn lut(&self) -> &Lut3D {
+        &self.lut
+    }
+}
+
+impl Debug for Vpp3DLut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Vpp3DLut")
+            .field("size", &self.lut.size)
+            .finish()
+    }
+}
+
+impl ExtBuffer for Vpp3DLut {
+    fn header(&mut self) -> *mut ffi::mfxExtBuffer {
+        &mut self.inner.Header
+    }
+    fn buffer_id(&self) -> u32 {
+        self.inner.Header.BufferId
+    }
+    fn clone_boxed(&self) -> Box<dyn ExtBuffer> {
+        Box::new(self.clone())
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Where and how one input of a [`Composite`] is drawn on the output. Inputs are drawn in the order they are submitted to [`VideoProcessor::process`], later ones on top.
+#[derive(Debug, Clone, Copy)]
+#[repr(transparent)]
+pub struct CompositeInput {
+    inner: ffi::mfxVPPCompInputStream,
+}
+
+impl CompositeInput {
+    /// Draws the input scaled into `rect` of the output, fully opaque.
+    pub fn new(rect: Rect) -> Self {
+        let mut inner: ffi::mfxVPPCompInputStream = unsafe { mem::zeroed() };
+        inner.DstX = rect.x as u32;
+        inner.DstY = rect.y as u32;
+        inner.DstW = rect.width as u32;
+        inner.DstH = rect.height as u32;
+        Self { inner }
+    }
+
+    pub fn rect(&self) -> Rect {
+        Rect::new(
+            self.inner.DstX as u16,
+            self.inner.DstY as u16,
+            self.inner.DstW as u16,
+            self.inner.DstH as u16,
+        )
+    }
+
+    /// Blends the whole input with what is below it, 0 is fully transparent and 255 opaque. `None` disables global alpha.
+    pub fn set_global_alpha(&mut self, alpha: Option<u8>) {
+        self.inner.GlobalAlphaEnable = alpha.is_some() as u16;
+        self.inner.GlobalAlpha = alpha.unwrap_or(0) as u16;
+    }
+
+    pub fn global_alpha(&self) -> Option<u8> {
+        (self.inner.GlobalAlphaEnable != 0).then(|| self.inner.GlobalAlpha as u8)
+    }
+
+    /// Makes pixels with a luma value in `min..=max` transparent. `None` disables luma keying.
+    pub fn set_luma_key(&mut self, range: Option<(u16, u16)>) {
+        let (min, max) = range.unwrap_or((0, 0));
+        self.inner.LumaKeyEnable = range.is_some() as u16;
+        self.inner.LumaKeyMin = min;
+        self.inner.LumaKeyMax = max;
+    }
+
+    pub fn luma_key(&self) -> Option<(u16, u16)> {
+        (self.inner.LumaKeyEnable != 0).then(|| (self.inner.LumaKeyMin, self.inner.LumaKeyMax))
+    }
+
+    /// Blends using the alpha channel of the input, which needs to be an RGB format with alpha.
+    pub fn set_pixel_alpha(&mut self, enable: bool) {
+        self.inner.PixelAlphaEnable = enable as u16;
+    }
+
+    pub fn pixel_alpha(&self) -> bool {
+        self.inner.PixelAlphaEnable != 0
+    }
+}
+
+/// Composes several inputs into one output, Eg. to overlay a logo. See https://spec.oneapi.io/versions/latest/elements/oneVPL/source/API_ref/VPL_structs_vpp.html#mfxextvppcomposite for more info.
+///
+/// The inputs are shared between clones so `InputStream` stays valid for as long as any of them is attached.
+#[derive(Clone)]
+pub struct Composite {
+    inner: ffi::mfxExtVPPComposite,
+    inputs: Arc<Vec<CompositeInput>>,
+}
+
+unsafe impl Send for Composite {}
+
+impl Composite {
+    /// The parts of the output no input covers are black.
+    pub fn new(inputs: Vec<CompositeInput>) -> Self {
+        let inputs = Arc::new(inputs);
+
+        let mut inner: ffi::mfxExtVPPComposite = unsafe { mem::zeroed() };
+        inner.Header.BufferId = ffi::MFX_EXTBUFF_VPP_COMPOSITE as u32;
+        inner.Header.BufferSz = mem::size_of::<ffi::mfxExtVPPComposite>() as u32;
+        inner.NumInputStream = inputs.len() as u16;
+        // The library only reads the inputs, CompositeInput is a transparent wrapper
+        inner.InputStream = inputs.as_ptr() as *mut ffi::mfxVPPCompInputStream;
+
+        let mut composite = Self { inner, inputs };
+        composite.set_background(16, 128, 128);
+        composite
+    }
+
+    /// The color of the parts of the output no input covers, in the output format. For RGB outputs the values are R, G and B.
+    pub fn set_background(&mut self, y: u16, u: u16, v: u16) {
+        self.inner.__bindgen_anon_1.Y = y;
+        self.inner.__bindgen_anon_2.U = u;
+        self.inner.__bindgen_anon_3.V = v;
+    }
+
+    pub fn inputs(&self) -> &[CompositeInput] {
+        &self.inputs
+    }
+}
+
+impl Debug for Composite {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Composite")
+            .field("inputs", &self.inputs)
+            .finish()
+    }
+}
+
+impl ExtBuffer for Composite {
+    fn header(&mut self) -> *mut ffi::mfxExtBuffer {
+        &mut self.inner.Header
+    }
+    fn buffer_id(&self) -> u32 {
+        self.inner.Header.BufferId
+    }
+    fn clone_boxed(&self) -> Box<dyn ExtBuffer> {
+        Box::new(self.clone())
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
 #[derive(Debug, Clone, Default)]
 /// Configurations related to video processing. See the definition of the mfxInfoVPP structure for details.
 pub struct VppVideoParams {
@@ -321,6 +950,25 @@ impl VppVideoParams {
         unsafe { &mut (*self).__bindgen_anon_1.vpp.Out }
     }
 
+    /// The input frame parameters, for reading fields the other getters don't cover.
+    pub fn in_info(&self) -> FrameInfo<'_> {
+        FrameInfo { inner: self.in_() }
+    }
+    /// The input frame parameters, for setting fields the other setters don't cover (Eg. aspect ratio or shift).
+    pub fn in_info_mut(&mut self) -> FrameInfoMut<'_> {
+        FrameInfoMut {
+            inner: self.in_mut(),
+        }
+    }
+    pub fn out_info(&self) -> FrameInfo<'_> {
+        FrameInfo { inner: self.out() }
+    }
+    pub fn out_info_mut(&mut self) -> FrameInfoMut<'_> {
+        FrameInfoMut {
+            inner: self.out_mut(),
+        }
+    }
+
     pub fn set_in_crop(&mut self, x: u16, y: u16, w: u16, h: u16) {
         self.in_mut().__bindgen_anon_1.__bindgen_anon_1.CropX = x;
         self.in_mut().__bindgen_anon_1.__bindgen_anon_1.CropY = y;
@@ -333,6 +981,20 @@ impl VppVideoParams {
         self.out_mut().__bindgen_anon_1.__bindgen_anon_1.CropW = w;
         self.out_mut().__bindgen_anon_1.__bindgen_anon_1.CropH = h;
     }
+    pub fn set_in_crop_rect(&mut self, rect: Rect) {
+        self.set_in_crop(rect.x, rect.y, rect.width, rect.height);
+    }
+    pub fn set_out_crop_rect(&mut self, rect: Rect) {
+        self.set_out_crop(rect.x, rect.y, rect.width, rect.height);
+    }
+    pub fn in_crop_rect(&self) -> Rect {
+        let frame = unsafe { &self.in_().__bindgen_anon_1.__bindgen_anon_1 };
+        Rect::new(frame.CropX, frame.CropY, frame.CropW, frame.CropH)
+    }
+    pub fn out_crop_rect(&self) -> Rect {
+        let frame = unsafe { &self.out().__bindgen_anon_1.__bindgen_anon_1 };
+        Rect::new(frame.CropX, frame.CropY, frame.CropW, frame.CropH)
+    }
 
     pub fn set_in_width(&mut self, width: u16) {
         self.in_mut().__bindgen_anon_1.__bindgen_anon_1.Width = width;
@@ -429,14 +1091,82 @@ impl VppVideoParams {
         };
     }
 
-    /// 23.97 FPS == numerator 24000, denominator = 1001
-    pub fn set_in_framerate(&mut self, numerator: u32, denominator: u32) {
-        self.in_mut().FrameRateExtN = numerator;
-        self.in_mut().FrameRateExtD = denominator;
+    /// Makes VPP scale between limited (16-235) and full (0-255) range. Decoded video is usually limited range, so forgetting this when the consumer expects full range results in washed out output.
+    pub fn set_range_conversion(&mut self, in_full: bool, out_full: bool) {
+        let signal_info = self.ext_buffer_or_default::<VideoSignalInfo>();
+        signal_info.set_in_full_range(in_full);
+        signal_info.set_out_full_range(out_full);
     }
-    pub fn set_out_framerate(&mut self, numerator: u32, denominator: u32) {
-        self.out_mut().FrameRateExtN = numerator;
-        self.out_mut().FrameRateExtD = denominator;
+
+    /// Sharpens the output, useful when upscaling. `factor` is clamped to 0-100.
+    pub fn set_detail_enhancement(&mut self, factor: u16) {
+        self.ext_buffer_or_default::<Detail>().set_factor(factor);
+    }
+
+    /// Stabilizes shaky (e.g. handheld) footage.
+    ///
+    /// Not every implementation supports stabilization. If it doesn't, VPP
+    /// initialization still succeeds but the filter is skipped and a warning is
+    /// logged.
+    pub fn set_image_stabilization(&mut self, mode: ImageStabMode) {
+        self.ext_buffer_or_default::<ImageStab>().set_mode(mode);
+    }
+
+    /// Removes temporal noise, Eg. from low light footage before encoding. `strength` is clamped to 0-20, 0 lets the implementation pick.
+    ///
+    /// Not every implementation supports MCTF. Hardware that doesn't skips the filter with a warning, the software implementation may reject the params instead.
+    pub fn set_mctf(&mut self, strength: u16) {
+        self.ext_buffer_or_default::<Mctf>().set_filter_strength(strength);
+    }
+
+    /// Fills the area of the output surface outside of the output crop with black instead of leaving whatever the surface held before.
+    ///
+    /// Combine it with an output crop that has a different aspect ratio than the output surface to letterbox or pillarbox. Eg. to fit 4:3 input into a 16:9 1280x720 output, set the output size to 1280x720 and the output crop to `(160, 0, 960, 720)`, the input is scaled into the crop and the bars on either side are filled.
+    pub fn set_color_fill(&mut self, enable: bool) {
+        self.ext_buffer_or_default::<ColorFill>().set_enable(enable);
+    }
+
+    /// Copies or swaps the fields of interlaced frames. Both sides need an interlaced picture structure ([`PicStruct::FieldTff`] or [`PicStruct::FieldBff`]).
+    ///
+    /// Weaving separate fields into frames doesn't need this, set the input picture structure to [`PicStruct::FieldSingle`] with the input height being the height of one field and the output to [`PicStruct::FieldTff`] or [`PicStruct::FieldBff`]. Swap the two to split frames into fields.
+    pub fn set_field_processing(&mut self, mode: FieldProcessingMode) {
+        self.ext_buffer_or_default::<FieldProcessing>().set_mode(mode);
+    }
+
+    /// Sets the chroma sample location (0 to 5, see [`ChromaLocInfo::set_sample_loc`]) used when resampling chroma, Eg. converting between 4:2:0 and 4:2:2. Getting it wrong shows up as a slight chroma shift after conversion.
+    pub fn set_chroma_loc(&mut self, loc: u16) {
+        self.ext_buffer_or_default::<ChromaLocInfo>().set_sample_loc(loc, loc);
+    }
+
+    /// Applies a 3D lookup table to the output, replacing any table already attached. The table is copied so `lut` can be reused afterwards.
+    pub fn set_3dlut(&mut self, lut: &Lut3D) {
+        self.add_ext_buffer(Vpp3DLut::new(lut.clone()));
+    }
+
+    /// Composes `inputs.len()` input frames into every output frame, replacing any composition already attached. Submit one frame per input in order, VPP returns [`MfxStatus::MoreData`] until it has all of them.
+    ///
+    /// The input frame info describes the largest input, the output the composed frame.
+    pub fn set_composition(&mut self, inputs: Vec<CompositeInput>) {
+        self.add_ext_buffer(Composite::new(inputs));
+    }
+
+    /// Copies the frame info of the frames `params` (Eg. a decoder's) produces to the input, leaving the output alone. See [`VppVideoParams::from`] to set both.
+    pub fn set_in_from(&mut self, params: &MfxVideoParams) {
+        *self.in_mut() = unsafe { (**params).__bindgen_anon_1.mfx.FrameInfo };
+    }
+
+    /// 23.97 FPS == numerator 24000, denominator = 1001. Returns [`MfxStatus::InvalidVideoParam`] if `denominator` is 0, see [`FrameRate::new`].
+    pub fn set_in_framerate(&mut self, numerator: u32, denominator: u32) -> Result<(), MfxStatus> {
+        let framerate = FrameRate::new(numerator, denominator)?;
+        self.in_mut().FrameRateExtN = framerate.numerator();
+        self.in_mut().FrameRateExtD = framerate.denominator();
+        Ok(())
+    }
+    pub fn set_out_framerate(&mut self, numerator: u32, denominator: u32) -> Result<(), MfxStatus> {
+        let framerate = FrameRate::new(numerator, denominator)?;
+        self.out_mut().FrameRateExtN = framerate.numerator();
+        self.out_mut().FrameRateExtD = framerate.denominator();
+        Ok(())
     }
 }
 
@@ -454,7 +1184,7 @@ impl DerefMut for VppVideoParams {
     }
 }
 
-// FIXME: This looks like it's gonna be a use after free
+/// Only the frame info is copied, extension buffers stay with `value`.
 impl From<&MfxVideoParams> for VppVideoParams {
     fn from(value: &MfxVideoParams) -> Self {
         let mut params = Self::default();
@@ -463,3 +1193,557 @@ impl From<&MfxVideoParams> for VppVideoParams {
         params
     }
 }
+
+#[cfg(all(test, feature = "async"))]
+mod tests {
+    use tracing_test::traced_test;
+
+    use crate::{
+        constants::{ChromaFormat, FourCC, ImageStabMode, IoPattern, PicStruct},
+        FrameSurface, Loader, MfxStatus,
+    };
+
+    use super::{
+        ChromaLocInfo, ColorFill, Composite, CompositeInput, Detail, Field, FieldProcessing,
+        FieldProcessingMode, ImageStab, Lut3D, Mctf, VideoProcessor, VideoSignalInfo, Vpp3DLut,
+        VppVideoParams,
+    };
+
+    const WIDTH: u16 = 320;
+    const HEIGHT: u16 = 180;
+
+    /// I420 to I420 in system memory, nothing for VPP to do unless a filter is attached
+    fn passthrough_params() -> VppVideoParams {
+        let mut params = VppVideoParams::default();
+        params.set_io_pattern(IoPattern::IN_SYSTEM_MEMORY | IoPattern::OUT_SYSTEM_MEMORY);
+
+        params.set_in_fourcc(FourCC::IyuvOrI420);
+        params.set_in_picstruct(PicStruct::Progressive);
+        params.set_in_chroma_format(ChromaFormat::YUV420);
+        params.set_in_width(WIDTH);
+        params.set_in_height(HEIGHT);
+        params.set_in_crop(0, 0, WIDTH, HEIGHT);
+        params.set_in_framerate(24000, 1001).unwrap();
+
+        params.set_out_fourcc(FourCC::IyuvOrI420);
+        params.set_out_picstruct(PicStruct::Progressive);
+        params.set_out_chroma_format(ChromaFormat::YUV420);
+        params.set_out_width(WIDTH);
+        params.set_out_height(HEIGHT);
+        params.set_out_crop(0, 0, WIDTH, HEIGHT);
+        params.set_out_framerate(24000, 1001).unwrap();
+
+        params
+    }
+
+    #[traced_test]
+    #[test]
+    fn vpp_init_with_range_conversion() {
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut params = passthrough_params();
+        params.set_range_conversion(false, true);
+        // Setting it again updates the attached buffer instead of adding another
+        params.set_range_conversion(false, true);
+        assert_eq!(params.num_ext_buffers(), 1);
+        assert!(params.ext_buffer::<VideoSignalInfo>().is_some());
+
+        let _vpp = session.video_processor(&mut params).unwrap();
+    }
+
+    #[traced_test]
+    #[test]
+    fn vpp_init_with_detail() {
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut params = passthrough_params();
+        let buffers = params.num_ext_buffers();
+        params.set_detail_enhancement(50);
+        assert_eq!(params.num_ext_buffers(), buffers + 1);
+        assert_eq!(params.ext_buffer::<Detail>().unwrap().DetailFactor, 50);
+
+        let _vpp = session.video_processor(&mut params).unwrap();
+    }
+
+    #[traced_test]
+    #[test]
+    fn vpp_init_with_image_stabilization() {
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut params = passthrough_params();
+        params.set_image_stabilization(ImageStabMode::Upscale);
+        assert!(params.ext_buffer::<ImageStab>().is_some());
+
+        // Init succeeds even if the implementation skips the filter
+        let _vpp = session.video_processor(&mut params).unwrap();
+    }
+
+    #[traced_test]
+    #[test]
+    fn vpp_init_with_mctf() {
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut params = passthrough_params();
+        params.set_mctf(10);
+        params.set_mctf(30);
+        assert_eq!(params.ext_buffer::<Mctf>().unwrap().FilterStrength, 20);
+
+        // Skipped filters are only a warning, but some implementations reject it outright
+        match session.video_processor(&mut params) {
+            Ok(_vpp) => {}
+            Err(status @ (MfxStatus::Unsupported | MfxStatus::InvalidVideoParam)) => {
+                tracing::warn!("Skipping MCTF, not supported: {:?}", status);
+            }
+            Err(status) => panic!("{:?}", status),
+        }
+    }
+
+    #[test]
+    fn identity_lut() {
+        assert_eq!(Lut3D::new(16).unwrap_err(), MfxStatus::Unsupported);
+
+        let mut lut = Lut3D::identity(33).unwrap();
+        assert_eq!(lut.get(0, 0, 0), Ok([0, 0, 0]));
+        assert_eq!(lut.get(32, 0, 16), Ok([u16::MAX, 0, u16::MAX / 2]));
+
+        // Past the end of the table, even where the flattened index would still be in bounds
+        assert_eq!(lut.get(0, 33, 0), Err(MfxStatus::OutOfRange));
+        assert_eq!(lut.get(0, 0, 33), Err(MfxStatus::OutOfRange));
+        assert_eq!(lut.set(33, 0, 0, [0; 3]), Err(MfxStatus::OutOfRange));
+        assert_eq!(lut.get(32, 32, 32), Ok([u16::MAX; 3]));
+    }
+
+    #[traced_test]
+    #[test]
+    fn vpp_init_with_3dlut() {
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut params = passthrough_params();
+        params.set_3dlut(&Lut3D::identity(33).unwrap());
+        assert_eq!(params.ext_buffer::<Vpp3DLut>().unwrap().lut().size(), 33);
+
+        // Software implementations may not support 3DLUT, that's fine as long as they say so
+        match session.video_processor(&mut params) {
+            Ok(_vpp) => {}
+            Err(MfxStatus::Unsupported) | Err(MfxStatus::InvalidVideoParam) => {}
+            Err(e) => panic!("{:?}", e),
+        }
+    }
+
+    #[traced_test]
+    #[test]
+    fn vpp_supported_out_formats() {
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+
+        let session = loader.new_session(0).unwrap();
+
+        let formats = VideoProcessor::supported_out_formats(&session, FourCC::NV12).unwrap();
+        assert!(formats.contains(&FourCC::NV12), "{:?}", formats);
+        assert!(
+            formats.contains(&FourCC::YV12) || formats.contains(&FourCC::IyuvOrI420),
+            "{:?}",
+            formats
+        );
+
+        assert_eq!(
+            VideoProcessor::supported_out_formats(&session, FourCC::P8),
+            Err(MfxStatus::Unsupported)
+        );
+    }
+
+    #[traced_test]
+    #[test]
+    fn vpp_out_info_bit_depth() {
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut params = passthrough_params();
+        params.set_out_fourcc(FourCC::P010);
+        {
+            let mut out = params.out_info_mut();
+            out.set_bit_depth_luma(10);
+            out.set_bit_depth_chroma(10);
+            out.set_shift(1);
+        }
+        assert_eq!(params.out_info().bit_depth_luma(), 10);
+        assert_eq!(params.out_info().shift(), 1);
+        assert_eq!(params.out_bitdepth_chroma(), 10);
+        assert_eq!(params.in_info().bit_depth_luma(), 0);
+
+        let _vpp = session.video_processor(&mut params).unwrap();
+    }
+
+    #[traced_test]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn vpp_color_fill_pillarbox() {
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+
+        let session = loader.new_session(0).unwrap();
+
+        // 4:3 input centered in the 16:9 output, bars on the left and right
+        let mut params = passthrough_params();
+        params.set_in_width(240);
+        params.set_in_crop(0, 0, 240, HEIGHT);
+        params.set_out_crop(40, 0, 240, HEIGHT);
+        params.set_color_fill(true);
+        params.set_color_fill(true);
+        assert_eq!(params.num_ext_buffers(), 1);
+        assert!(params.ext_buffer::<ColorFill>().is_some());
+
+        let mut vpp = match session.video_processor(&mut params) {
+            Ok(vpp) => vpp,
+            Err(MfxStatus::Unsupported) => {
+                tracing::warn!("Skipping color fill, not supported");
+                return;
+            }
+            Err(status) => panic!("{:?}", status),
+        };
+        // Init only warns about the filters it skips
+        if logs_contain("Filter Skipped") {
+            tracing::warn!("Skipping color fill, the filter was skipped");
+            return;
+        }
+
+        let mut input = vpp.get_surface_input().unwrap();
+        input.fill_yuv(200, 128, 128).unwrap();
+        let mut output = match vpp.process(Some(&mut input), None).await {
+            Ok(output) => output,
+            Err(MfxStatus::FilterSkipped) => {
+                tracing::warn!("Skipping color fill, the filter was skipped");
+                return;
+            }
+            Err(status) => panic!("{:?}", status),
+        };
+
+        // Packed rows are as long as the crop but start at the left edge of the surface, so x
+        // 0 to 40 is the left bar
+        let luma = output.to_packed_vec().unwrap();
+        let row = HEIGHT as usize / 2 * 240;
+        let (bar, picture) = (luma[row + 10], luma[row + 160]);
+        assert!(bar.abs_diff(16) <= 2, "bar is {}", bar);
+        assert!(picture.abs_diff(200) <= 2, "picture is {}", picture);
+    }
+
+    #[traced_test]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn vpp_process_bounded_by_async_depth() {
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut params = passthrough_params();
+        params.set_async_depth(2);
+        let mut vpp = session.video_processor(&mut params).unwrap();
+
+        let mut inputs = Vec::new();
+        for i in 0..5 {
+            let mut input = vpp.get_surface_input().unwrap();
+            input.fill_yuv(20 + i * 40, 128, 128).unwrap();
+            inputs.push(input);
+        }
+
+        // More frames at once than AsyncDepth allows, the rest wait their turn
+        let mut inputs = inputs.iter_mut();
+        let outputs = tokio::join!(
+            vpp.process(inputs.next(), None),
+            vpp.process(inputs.next(), None),
+            vpp.process(inputs.next(), None),
+            vpp.process(inputs.next(), None),
+            vpp.process(inputs.next(), None),
+        );
+        let outputs = [outputs.0, outputs.1, outputs.2, outputs.3, outputs.4];
+
+        for (i, output) in outputs.into_iter().enumerate() {
+            let luma = output.unwrap().to_packed_vec().unwrap()[0];
+            assert_eq!(luma, 20 + i as u8 * 40);
+        }
+        assert_eq!(vpp.in_flight.peak(), 2);
+    }
+
+    #[cfg(feature = "blocking")]
+    #[traced_test]
+    #[test]
+    fn vpp_process_blocking_bounded_by_async_depth() {
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut params = passthrough_params();
+        params.set_async_depth(2);
+        let mut vpp = session.video_processor(&mut params).unwrap();
+
+        let mut inputs = Vec::new();
+        for i in 0..5 {
+            let mut input = vpp.get_surface_input().unwrap();
+            input.fill_yuv(20 + i * 40, 128, 128).unwrap();
+            inputs.push(input);
+        }
+
+        // Queued frames hold their slots until they are synchronized or dropped
+        let (first, rest) = inputs.split_first_mut().unwrap();
+        let (second, rest) = rest.split_first_mut().unwrap();
+        let mut first = vpp.queue(Some(first)).unwrap();
+        let second = vpp.queue(Some(second)).unwrap();
+        assert_eq!(vpp.in_flight.peak(), 2);
+
+        let vpp = &vpp;
+        std::thread::scope(|scope| {
+            let third = scope.spawn(move || vpp.process_blocking(Some(&mut rest[0]), None));
+
+            // Without the bound the third frame would go straight through
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            assert!(!third.is_finished());
+
+            first.synchronize(None).unwrap();
+            let luma = third.join().unwrap().unwrap().to_packed_vec().unwrap()[0];
+            assert_eq!(luma, 100);
+        });
+        drop(second);
+        assert_eq!(vpp.in_flight.peak(), 2);
+    }
+
+    #[test]
+    fn vpp_params_ext_buffers_survive_clone() {
+        let mut params = passthrough_params();
+        params.set_async_depth(2);
+        params.add_ext_buffer(Detail::default());
+        params.add_ext_buffer(ColorFill::default());
+        // Replaces the first one rather than adding another
+        params.add_ext_buffer(Detail::default());
+        assert_eq!(params.NumExtParam, 2);
+        assert_eq!(params.num_ext_buffers(), 2);
+
+        /// Every ExtParam entry has to point at a buffer owned by `params`
+        fn assert_points_at_own_buffers(params: &VppVideoParams) {
+            let ext_params =
+                unsafe { std::slice::from_raw_parts(params.ExtParam, params.NumExtParam as usize) };
+            let detail = &params.ext_buffer::<Detail>().unwrap().Header as *const _;
+            let color_fill = &params.ext_buffer::<ColorFill>().unwrap().Header as *const _;
+            // Re-adding Detail moved it to the end
+            assert_eq!(ext_params[0] as *const _, color_fill);
+            assert_eq!(ext_params[1] as *const _, detail);
+        }
+
+        let clone = params.clone();
+        assert_eq!(clone.async_depth(), 2);
+        assert_eq!(clone.NumExtParam, 2);
+        assert_ne!(clone.ExtParam, params.ExtParam);
+        assert_points_at_own_buffers(&clone);
+        assert_points_at_own_buffers(&params);
+
+        // Moving the params doesn't move the buffers
+        let moved = Box::new(params);
+        assert_points_at_own_buffers(&moved);
+        drop(clone);
+        assert_points_at_own_buffers(&moved);
+
+        let mut params = *moved;
+        params.remove_ext_buffer::<Detail>().unwrap();
+        assert_eq!(params.NumExtParam, 1);
+    }
+
+    #[traced_test]
+    #[test]
+    fn vpp_init_with_chroma_loc() {
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut params = passthrough_params();
+        params.set_chroma_loc(0);
+        params.set_chroma_loc(0);
+        assert_eq!(params.num_ext_buffers(), 1);
+        let chroma_loc = params.ext_buffer::<ChromaLocInfo>().unwrap();
+        assert_eq!(chroma_loc.ChromaLocInfoPresentFlag, 1);
+        assert_eq!(chroma_loc.ChromaSampleLocTypeTopField, 0);
+
+        let _vpp = session.video_processor(&mut params).unwrap();
+    }
+
+    #[traced_test]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn vpp_reset_to_smaller_output() {
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut params = passthrough_params();
+        let mut vpp = session.video_processor(&mut params).unwrap();
+        let initial = vpp.surface_requirements().unwrap();
+        assert_eq!((initial.output.width, initial.output.height), (WIDTH, HEIGHT));
+
+        // Reset can't go above the initial size, so scale down
+        let mut params = passthrough_params();
+        params.set_out_width(160);
+        params.set_out_height(96);
+        params.set_out_crop(0, 0, 160, 90);
+        let requirements = vpp.reset(params).unwrap();
+        assert_eq!((requirements.output.width, requirements.output.height), (160, 96));
+        assert_eq!(requirements.input, initial.input);
+
+        let frame_size = FrameSurface::frame_size(FourCC::IyuvOrI420, WIDTH, HEIGHT);
+        let mut source = std::io::Cursor::new(vec![128u8; frame_size]);
+        let mut input = vpp.get_surface_input().unwrap();
+        input
+            .read_raw_frame(&mut source, FourCC::IyuvOrI420)
+            .await
+            .unwrap();
+        let output = vpp.process(Some(&mut input), None).await.unwrap();
+        let bounds = output.bounds();
+        assert_eq!((bounds.width, bounds.height), (160, 96));
+        assert_eq!((bounds.crop_width, bounds.crop_height), (160, 90));
+    }
+
+    #[traced_test]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn vpp_composite_with_global_alpha() {
+        use crate::utils::Rect;
+
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+
+        let session = loader.new_session(0).unwrap();
+
+        let background = CompositeInput::new(Rect::new(0, 0, WIDTH, HEIGHT));
+        // Half transparent over the right half of the frame
+        let mut overlay = CompositeInput::new(Rect::new(WIDTH / 2, 0, WIDTH / 2, HEIGHT));
+        overlay.set_global_alpha(Some(128));
+        assert_eq!(overlay.global_alpha(), Some(128));
+        assert_eq!(overlay.luma_key(), None);
+
+        let mut params = passthrough_params();
+        params.set_composition(vec![background, overlay]);
+        assert_eq!(params.ext_buffer::<Composite>().unwrap().inputs().len(), 2);
+
+        let mut vpp = match session.video_processor(&mut params) {
+            Ok(vpp) => vpp,
+            Err(status @ (MfxStatus::Unsupported | MfxStatus::InvalidVideoParam)) => {
+                tracing::warn!("Skipping composition, not supported: {:?}", status);
+                return;
+            }
+            Err(status) => panic!("{:?}", status),
+        };
+
+        let mut input = vpp.get_surface_input().unwrap();
+        input.fill_yuv(50, 128, 128).unwrap();
+        assert_eq!(
+            vpp.process(Some(&mut input), None).await.unwrap_err(),
+            MfxStatus::MoreData
+        );
+
+        let mut input = vpp.get_surface_input().unwrap();
+        input.fill_yuv(250, 128, 128).unwrap();
+        let mut output = vpp.process(Some(&mut input), None).await.unwrap();
+
+        let luma = output.to_packed_vec().unwrap();
+        let row = HEIGHT as usize / 2 * WIDTH as usize;
+        let (left, right) = (luma[row + 10], luma[row + WIDTH as usize - 10]);
+        assert!(left.abs_diff(50) <= 2, "background is {}", left);
+        // 50 + (250 - 50) * 128 / 255
+        assert!(right.abs_diff(150) <= 2, "blended overlay is {}", right);
+    }
+
+    #[traced_test]
+    #[test]
+    fn vpp_init_with_field_weaving() {
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+
+        let session = loader.new_session(0).unwrap();
+
+        // Single 320x90 fields woven into 320x180 top field first frames
+        let mut params = passthrough_params();
+        params.set_in_picstruct(PicStruct::FieldSingle);
+        params.set_in_height(96);
+        params.set_in_crop(0, 0, WIDTH, HEIGHT / 2);
+        params.set_out_picstruct(PicStruct::FieldTff);
+        params.set_out_height(192);
+        let _vpp = session.video_processor(&mut params).unwrap();
+    }
+
+    #[traced_test]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn vpp_swap_fields() {
+        let mut loader = Loader::new().unwrap();
+        loader.use_hardware(false);
+
+        let session = loader.new_session(0).unwrap();
+
+        let mut params = passthrough_params();
+        params.set_in_picstruct(PicStruct::FieldTff);
+        params.set_out_picstruct(PicStruct::FieldTff);
+        params.set_field_processing(FieldProcessingMode::CopyField {
+            input: Field::Bottom,
+            output: Field::Top,
+        });
+        params.set_field_processing(FieldProcessingMode::SwapFields);
+        assert_eq!(params.num_ext_buffers(), 1);
+        assert_eq!(
+            params.ext_buffer::<FieldProcessing>().unwrap().Mode,
+            intel_onevpl_sys::MFX_VPP_SWAP_FIELDS as u16
+        );
+
+        let mut vpp = match session.video_processor(&mut params) {
+            Ok(vpp) => vpp,
+            Err(MfxStatus::Unsupported) => {
+                tracing::warn!("Skipping field processing, not supported");
+                return;
+            }
+            Err(status) => panic!("{:?}", status),
+        };
+        // Init only warns about the filters it skips
+        if logs_contain("Filter Skipped") {
+            tracing::warn!("Skipping field processing, the filter was skipped");
+            return;
+        }
+
+        // Top field (even rows) dark, bottom field (odd rows) bright
+        let (width, height) = (WIDTH as usize, HEIGHT as usize);
+        let mut frame = vec![128u8; FrameSurface::frame_size(FourCC::IyuvOrI420, WIDTH, HEIGHT)];
+        for (y, row) in frame[..width * height].chunks_exact_mut(width).enumerate() {
+            row.fill(if y % 2 == 0 { 50 } else { 200 });
+        }
+        let mut input = vpp.get_surface_input().unwrap();
+        input
+            .read_raw_frame(&mut std::io::Cursor::new(frame), FourCC::IyuvOrI420)
+            .await
+            .unwrap();
+        let mut output = match vpp.process(Some(&mut input), None).await {
+            Ok(output) => output,
+            Err(MfxStatus::FilterSkipped) => {
+                tracing::warn!("Skipping field processing, the filter was skipped");
+                return;
+            }
+            Err(status) => panic!("{:?}", status),
+        };
+
+        let luma = output.to_packed_vec().unwrap();
+        let row = height / 2 * width;
+        let (top, bottom) = (luma[row + 10], luma[row + width + 10]);
+        assert!(top.abs_diff(200) <= 2, "top field is {}", top);
+        assert!(bottom.abs_diff(50) <= 2, "bottom field is {}", bottom);
+    }
+}
@@ -0,0 +1,29 @@
+use std::{env, path::PathBuf};
+
+use onevpl::{get_library, set_library_path, Loader};
+
+// Runs in its own test binary so no other test has loaded the library yet.
+#[test]
+fn load_library_from_explicit_path() {
+    let library_name = if cfg!(target_os = "windows") {
+        "libvpl"
+    } else {
+        "vpl"
+    };
+    let library_name = libloading::library_filename(library_name);
+
+    // Same variable the build script uses to find the library
+    let path = match env::var("LIBVPL_LIBRARY_PATH") {
+        Ok(dir) => PathBuf::from(dir).join(library_name),
+        Err(_) => PathBuf::from(library_name),
+    };
+
+    set_library_path(&path).unwrap();
+    assert!(set_library_path(&path).is_err());
+
+    get_library().unwrap();
+    let _loader = Loader::new().unwrap();
+
+    // Library is loaded, changing the path is no longer possible
+    assert!(set_library_path(&path).is_err());
+}